@@ -55,3 +55,24 @@ async fn test_preroll_main_test_utils() {
     //     .await;
     // }
 }
+
+#[async_std::test]
+async fn test_preroll_test_utils_in_parallel() {
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            async_std::task::spawn(async {
+                let client = test_utils::create_client().await.unwrap();
+
+                client
+                    .get("/api/v1/test-preroll-setup-routes")
+                    .recv_string()
+                    .await
+                    .unwrap()
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        assert_eq!(task.await, "preroll successfully set route in v1");
+    }
+}
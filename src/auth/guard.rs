@@ -0,0 +1,231 @@
+use std::fmt::{self, Display};
+
+use tide::{Middleware, Next, Request, Route, StatusCode};
+
+use crate::middleware::extension_types::Principal;
+
+/// The scopes a caller needs for a route, checked via [`GuardRouteExt::guard`].
+///
+/// Requests without a [`Principal`] get a `401 Unauthorized` error with the code `"unauthenticated"`. Those whose
+/// principal lacks the scopes get a `403 Forbidden` error with the code `"insufficient_scope"`, naming the missing
+/// scopes.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::auth::Scopes;
+/// use preroll::prelude::*;
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server
+///         .at("users")
+///         .guard(Scopes::any(["users:read", "users:write"]))
+///         .get(|_| async { Ok("[]") });
+///
+///     server
+///         .at("users/:id")
+///         .guard(Scopes::all(["users:write", "users:delete"]))
+///         .delete(|_| async { Ok("") });
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scopes {
+    scopes: Vec<String>,
+    all: bool,
+}
+
+impl Scopes {
+    /// Callers need at least one of `scopes`.
+    pub fn any<S: Into<String>>(scopes: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            scopes: scopes.into_iter().map(Into::into).collect(),
+            all: false,
+        }
+    }
+
+    /// Callers need every one of `scopes`.
+    pub fn all<S: Into<String>>(scopes: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            scopes: scopes.into_iter().map(Into::into).collect(),
+            all: true,
+        }
+    }
+
+    /// The scopes `principal` is missing, or none if it is allowed.
+    fn missing(&self, principal: &Principal) -> Vec<&str> {
+        let missing: Vec<&str> = self
+            .scopes
+            .iter()
+            .filter(|scope| !principal.has_scope(scope))
+            .map(String::as_str)
+            .collect();
+        let allowed = if self.all {
+            missing.is_empty()
+        } else {
+            self.scopes.is_empty() || missing.len() < self.scopes.len()
+        };
+        if allowed {
+            Vec::new()
+        } else {
+            missing
+        }
+    }
+
+    /// Check that the principal of `req` has these scopes.
+    fn check<State>(&self, req: &Request<State>) -> tide::Result<()> {
+        let principal = match req.ext::<Principal>() {
+            Some(principal) => principal,
+            None => {
+                return Err(crate::Error::with_code(
+                    "unauthenticated",
+                    StatusCode::Unauthorized,
+                    "Authentication is required",
+                ))
+            }
+        };
+
+        let missing = self.missing(principal);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        log::debug!(
+            "Denied {} missing {}",
+            principal,
+            MissingScopes(&missing, self.all)
+        );
+        Err(crate::Error::with_code(
+            "insufficient_scope",
+            StatusCode::Forbidden,
+            format!("Missing {}", MissingScopes(&missing, self.all)),
+        ))
+    }
+}
+
+/// Names the missing scopes, e.g. `the scope "users:write"` or `one of the scopes "a", "b"`.
+struct MissingScopes<'a>(&'a [&'a str], bool);
+
+impl Display for MissingScopes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let MissingScopes(scopes, all) = *self;
+        match (scopes.len(), all) {
+            (1, _) => f.write_str("the scope ")?,
+            (_, true) => f.write_str("the scopes ")?,
+            (_, false) => f.write_str("one of the scopes ")?,
+        }
+        for (i, scope) in scopes.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "\"{}\"", scope)?;
+        }
+        Ok(())
+    }
+}
+
+/// An extension trait for guarding routes by the scopes of their callers.
+pub trait GuardRouteExt {
+    /// Only run the route's handlers added after this for callers with `scopes`, as described in [`Scopes`].
+    ///
+    /// Authentication middleware, which inserts the [`Principal`], must run before the guard, e.g. added to the
+    /// server or to an enclosing group.
+    fn guard(&mut self, scopes: Scopes) -> &mut Self;
+}
+
+impl<'a, State> GuardRouteExt for Route<'a, State>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    fn guard(&mut self, scopes: Scopes) -> &mut Self {
+        self.with(ScopesMiddleware { scopes })
+    }
+}
+
+/// Checks the [`Scopes`] of requests before they are handled.
+#[derive(Debug)]
+struct ScopesMiddleware {
+    scopes: Scopes,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ScopesMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.scopes.check(&req)?;
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestClientOptions, TestResult};
+
+    struct Authenticate;
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Authenticate {
+        async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            if let Some(scopes) = req.header("X-Scopes").map(|v| v.last().to_string()) {
+                req.set_ext(Principal::new("ada").with_scopes(scopes.split(' ')));
+            }
+            Ok(next.run(req).await)
+        }
+    }
+
+    #[async_std::test]
+    async fn guards_routes_by_scope() -> TestResult<()> {
+        let options = TestClientOptions::new().with(Authenticate);
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: Route<'_, Arc<()>>| {
+                server
+                    .at("users")
+                    .guard(Scopes::any(["users:read", "users:write"]))
+                    .get(|_| async { Ok("[]") })
+                    .guard(Scopes::all(["users:write", "users:admin"]))
+                    .post(|_| async { Ok("created") });
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.get("/api/v1/users").await?;
+        assert!(assert_status(&mut res, 401)
+            .await
+            .contains("unauthenticated"));
+
+        let mut res = client
+            .get("/api/v1/users")
+            .header("X-Scopes", "orders:read")
+            .await?;
+        let body = assert_status(&mut res, 403).await;
+        assert!(body.contains("insufficient_scope"));
+        assert!(body.contains(r#"Missing one of the scopes \"users:read\", \"users:write\""#));
+
+        let mut res = client
+            .get("/api/v1/users")
+            .header("X-Scopes", "users:write")
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "[]");
+
+        let mut res = client
+            .post("/api/v1/users")
+            .header("X-Scopes", "users:write")
+            .await?;
+        assert!(assert_status(&mut res, 403)
+            .await
+            .contains(r#"Missing the scope \"users:admin\""#));
+
+        let mut res = client
+            .post("/api/v1/users")
+            .header("X-Scopes", "users:write users:admin")
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "created");
+        Ok(())
+    }
+}
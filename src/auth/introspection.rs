@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use surf::{Client, Config};
+use tide::http::auth::{AuthenticationScheme, Authorization, BasicAuth};
+use tide::http::headers::{self, HeaderValue};
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+
+use crate::client;
+use crate::middleware::extension_types::Principal;
+use crate::setup::Result;
+
+/// The characters encoded in client credentials, as by `application/x-www-form-urlencoded`.
+const FORM_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'*')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_');
+
+/// The most tokens whose introspection results are cached at once.
+const MAX_CACHED: usize = 10_000;
+
+/// Cached active introspections, with when they expire.
+type IntrospectionsByToken = HashMap<String, (Arc<Introspection>, Instant)>;
+
+/// The result of introspecting a token, as defined by [RFC 7662](https://www.rfc-editor.org/rfc/rfc7662#section-2.2).
+///
+/// [`IntrospectionMiddleware`] inserts this into requests with active tokens, for claims beyond the
+/// [`Principal`][crate::Principal]'s, via `req.ext::<Introspection>()`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[non_exhaustive]
+pub struct Introspection {
+    /// Whether the token is active, i.e. issued, unexpired, and unrevoked.
+    pub active: bool,
+    /// The token's scopes, separated by spaces.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// The client the token was issued to.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// The human readable name of the token's resource owner.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// The subject of the token, usually the id of its resource owner.
+    #[serde(default)]
+    pub sub: Option<String>,
+    /// When the token expires, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub exp: Option<u64>,
+    /// Any other claims, such as `aud` and `iss`.
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+impl Introspection {
+    /// The caller of an active token: its `sub`, or else its `username` or `client_id`, with its scopes.
+    fn principal(&self) -> Principal {
+        let id = self
+            .sub
+            .as_ref()
+            .or(self.username.as_ref())
+            .or(self.client_id.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        let scopes = self.scope.as_deref().unwrap_or_default().split_whitespace();
+        Principal::new(id).with_scopes(scopes)
+    }
+}
+
+/// Middleware which authenticates requests with opaque bearer tokens, via an OAuth 2.0
+/// [token introspection](https://www.rfc-editor.org/rfc/rfc7662) endpoint.
+///
+/// - Tokens are sent to the endpoint with the service's client credentials, as Basic auth.
+/// - For active tokens, a [`Principal`][crate::Principal] is inserted into the request, with the token's `sub`, or
+///   else its `username` or `client_id`, as its id, and with the scopes in its `scope`. The full
+///   [`Introspection`] is inserted as well.
+/// - Active results are cached for up to a minute, by default, and never past the token's `exp`, so that each request
+///   does not wait on the endpoint. A revoked token may therefore be accepted until its result expires.
+/// - Requests with inactive tokens get a `401 Unauthorized` error with the code `"invalid_token"`. Those whose token
+///   could not be introspected get a `503 Service Unavailable` error with the code `"introspection_failed"`.
+/// - Requests without a bearer token are handled without a principal, so that routes can still be public, or be
+///   guarded via [`GuardRouteExt::guard`][crate::prelude::GuardRouteExt::guard].
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use preroll::auth::{IntrospectionMiddleware, Scopes};
+/// use preroll::prelude::*;
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     let introspection = IntrospectionMiddleware::new(
+///         "https://auth.example.com/oauth2/introspect",
+///         "orders-service",
+///         "client-secret",
+///     )
+///     .expect("Invalid introspection endpoint");
+///
+///     server
+///         .at("orders")
+///         .with(introspection)
+///         .guard(Scopes::any(["orders:read"]))
+///         .get(|_| async { Ok("[]") });
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct IntrospectionMiddleware {
+    client: Client,
+    url: String,
+    credentials: HeaderValue,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<IntrospectionsByToken>>,
+}
+
+impl IntrospectionMiddleware {
+    /// Introspect tokens at `url`, authenticated as the client `client_id` with `client_secret`.
+    pub fn new(url: &str, client_id: &str, client_secret: &str) -> Result<Self> {
+        Self::with_config(Config::new(), url, client_id, client_secret)
+    }
+
+    fn with_config(
+        config: Config,
+        url: &str,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<Self> {
+        let client = client::with_options(config, url, client::default_timeout()?, 2)?;
+        // Client credentials are form encoded before being base64 encoded, as in RFC 6749 section 2.3.1.
+        let encode = |value| utf8_percent_encode(value, FORM_ENCODE_SET).to_string();
+        let credentials = BasicAuth::new(encode(client_id), encode(client_secret)).value();
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            credentials,
+            cache_ttl: Duration::from_secs(60),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// How long active results are cached for, at most. Defaults to one minute. Zero disables caching.
+    #[must_use]
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// The introspection of `token`, from the cache or the endpoint.
+    async fn introspect(&self, token: &str) -> tide::Result<Arc<Introspection>> {
+        if let Some(introspection) = self.cached(token) {
+            return Ok(introspection);
+        }
+
+        let body = Body::from_form(&[("token", token), ("token_type_hint", "access_token")])?;
+        let introspection: Introspection = self
+            .client
+            .post(&self.url)
+            .header(headers::AUTHORIZATION, self.credentials.clone())
+            .header(headers::ACCEPT, "application/json")
+            .body(body)
+            .recv_json()
+            .await?;
+        let introspection = Arc::new(introspection);
+        if introspection.active {
+            self.cache(token, introspection.clone());
+        }
+        Ok(introspection)
+    }
+
+    fn cached(&self, token: &str) -> Option<Arc<Introspection>> {
+        let mut cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match cache.get(token) {
+            Some((introspection, expires)) if *expires > Instant::now() => {
+                Some(introspection.clone())
+            }
+            Some(_) => {
+                cache.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn cache(&self, token: &str, introspection: Arc<Introspection>) {
+        let ttl = match introspection.exp {
+            Some(exp) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                self.cache_ttl
+                    .min(Duration::from_secs(exp).saturating_sub(now))
+            }
+            None => self.cache_ttl,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (_, expires)| *expires > now);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+        }
+        cache.insert(token.to_string(), (introspection, now + ttl));
+    }
+}
+
+/// The bearer token of `req`, if it has one.
+fn bearer_token<State>(req: &Request<State>) -> Option<String> {
+    match Authorization::from_headers(req) {
+        Ok(Some(authorization)) if authorization.scheme() == AuthenticationScheme::Bearer => {
+            Some(authorization.credentials().to_string())
+        }
+        _ => None,
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for IntrospectionMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let token = match bearer_token(&req) {
+            Some(token) => token,
+            None => return Ok(next.run(req).await),
+        };
+
+        let introspection = match self.introspect(&token).await {
+            Ok(introspection) => introspection,
+            Err(error) => {
+                log::warn!("Introspecting a token at {} failed: {}", self.url, error);
+                return Err(crate::Error::with_code(
+                    "introspection_failed",
+                    StatusCode::ServiceUnavailable,
+                    "The token could not be checked",
+                ));
+            }
+        };
+        if !introspection.active {
+            let mut res = Response::new(StatusCode::Unauthorized);
+            res.insert_header(headers::WWW_AUTHENTICATE, "Bearer error=\"invalid_token\"");
+            res.set_error(crate::Error::with_code(
+                "invalid_token",
+                StatusCode::Unauthorized,
+                "The token is invalid, expired, or revoked",
+            ));
+            return Ok(res);
+        }
+
+        req.set_ext(introspection.principal());
+        req.set_ext(Introspection::clone(&introspection));
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::json;
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestClientOptions, TestResult};
+
+    #[async_std::test]
+    async fn introspects_tokens() -> TestResult<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let mut provider = tide::with_state(requests.clone());
+        provider
+            .at("/introspect")
+            .post(|mut req: Request<Arc<AtomicUsize>>| async move {
+                req.state().fetch_add(1, Ordering::SeqCst);
+                let client = BasicAuth::from_headers(&req)?
+                    .map(|auth| format!("{}:{}", auth.username(), auth.password()));
+                assert_eq!(client.as_deref(), Some("orders:s%C3%A9cret%3A"));
+                let form: HashMap<String, String> = req.body_form().await?;
+                let body = match form.get("token").map(String::as_str) {
+                    Some("good") => json!({
+                        "active": true,
+                        "sub": "ada",
+                        "scope": "orders:read orders:write",
+                        "iss": "https://auth.example.com",
+                    }),
+                    _ => json!({ "active": false }),
+                };
+                Ok(body)
+            });
+        let introspection = IntrospectionMiddleware::with_config(
+            Config::new().set_http_client(provider),
+            "http://auth.internal/introspect",
+            "orders",
+            "sécret:",
+        )
+        .map_err(|error| tide::Error::from_str(500, error.to_string()))?;
+
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: Route<'_, Arc<()>>| {
+                server.at("me").get(|req: Request<Arc<()>>| async move {
+                    let principal = req.ext::<Principal>().cloned();
+                    let issuer = req
+                        .ext::<Introspection>()
+                        .and_then(|introspection| introspection.claims.get("iss").cloned());
+                    Ok(format!("{:?} {:?}", principal, issuer))
+                });
+            },
+            TestClientOptions::new().with(introspection),
+        )
+        .await?;
+
+        for _ in 0..2 {
+            let mut res = client
+                .get("/api/v1/me")
+                .header("Authorization", "Bearer good")
+                .await?;
+            assert_eq!(
+                assert_status(&mut res, 200).await,
+                "Some(Principal { id: \"ada\", scopes: [\"orders:read\", \"orders:write\"] }) \
+                 Some(String(\"https://auth.example.com\"))"
+            );
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let mut res = client
+            .get("/api/v1/me")
+            .header("Authorization", "Bearer revoked")
+            .await?;
+        assert!(assert_status(&mut res, 401).await.contains("invalid_token"));
+
+        let mut res = client.get("/api/v1/me").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "None None");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
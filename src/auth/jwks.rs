@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use serde::Deserialize;
+use serde_json::Value;
+use surf::{Client, Config};
+
+use crate::client;
+use crate::setup::Result;
+use crate::utils::random_fraction;
+
+/// A public key from a JSON Web Key Set, as cached by [`JwksCache`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[non_exhaustive]
+pub struct Jwk {
+    /// The id of the key, which tokens name in their `kid` header.
+    #[serde(default)]
+    pub kid: Option<String>,
+    /// The key type, e.g. `"RSA"` or `"EC"`.
+    pub kty: String,
+    /// The algorithm the key is for, e.g. `"RS256"`.
+    #[serde(default)]
+    pub alg: Option<String>,
+    /// What the key is for, e.g. `"sig"`.
+    #[serde(default, rename = "use")]
+    pub key_use: Option<String>,
+    /// The modulus of an RSA key, as base64url.
+    #[serde(default)]
+    pub n: Option<String>,
+    /// The exponent of an RSA key, as base64url.
+    #[serde(default)]
+    pub e: Option<String>,
+    /// The curve of an EC key, e.g. `"P-256"`.
+    #[serde(default)]
+    pub crv: Option<String>,
+    /// The x coordinate of an EC key, as base64url.
+    #[serde(default)]
+    pub x: Option<String>,
+    /// The y coordinate of an EC key, as base64url.
+    #[serde(default)]
+    pub y: Option<String>,
+    /// The certificate chain of the key, as base64 DER.
+    #[serde(default)]
+    pub x5c: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Value>,
+}
+
+/// Public keys from a JSON Web Key Set url, such as an identity provider's `jwks_uri`, cached by their `kid`.
+///
+/// Keys are fetched on first use, and then refreshed:
+/// - In the background, every [`refresh_interval`][Self::refresh_interval] give or take 10%, so that many instances
+///   do not refresh at once.
+/// - When a token names an unknown `kid`, e.g. just after the provider rotated its keys, at most once per
+///   [`cooldown`][Self::cooldown], so that tokens with made up ids cannot flood the provider with requests.
+///
+/// When a refresh fails, the keys from the last successful one are kept, so that an unavailable provider does not
+/// make every token invalid.
+///
+/// Clones share their keys.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::auth::JwksCache;
+///
+/// # #[allow(dead_code)]
+/// async fn verify(jwks: &JwksCache, kid: &str) -> tide::Result<()> {
+///     let key = jwks
+///         .get(kid)
+///         .await?
+///         .ok_or_else(|| tide::Error::from_str(401, "Token signed by an unknown key"))?;
+///     // ... verify the token's signature with `key`.
+///     Ok(())
+/// }
+///
+/// # #[allow(dead_code)]
+/// fn setup() -> preroll::SetupResult<JwksCache> {
+///     JwksCache::new("https://auth.example.com/.well-known/jwks.json")
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct JwksCache {
+    inner: Arc<Inner>,
+    refresh_interval: Duration,
+    cooldown: Duration,
+}
+
+#[derive(Debug)]
+struct Inner {
+    client: Client,
+    url: String,
+    keys: RwLock<HashMap<String, Arc<Jwk>>>,
+    /// When the keys were last refreshed, held while refreshing so that concurrent misses refresh once.
+    refreshed: Mutex<Option<Instant>>,
+}
+
+impl JwksCache {
+    /// Cache the keys at `url`, refreshed every hour, and at most every 30 seconds for unknown keys.
+    pub fn new(url: &str) -> Result<Self> {
+        Self::with_config(Config::new(), url)
+    }
+
+    fn with_config(config: Config, url: &str) -> Result<Self> {
+        let client = client::with_options(config, url, client::default_timeout()?, 2)?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                client,
+                url: url.to_string(),
+                keys: RwLock::new(HashMap::new()),
+                refreshed: Mutex::new(None),
+            }),
+            refresh_interval: Duration::from_secs(60 * 60),
+            cooldown: Duration::from_secs(30),
+        })
+    }
+
+    /// How often keys are refreshed in the background, give or take 10%. Defaults to one hour.
+    #[must_use]
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    /// The least time between refreshes for unknown keys. Defaults to 30 seconds.
+    #[must_use]
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// The key with `kid`, refreshing the keys if it is unknown, or `None` if it is still unknown.
+    pub async fn get(&self, kid: &str) -> tide::Result<Option<Arc<Jwk>>> {
+        if let Some(key) = self.cached(kid) {
+            return Ok(Some(key));
+        }
+
+        let mut refreshed = self.inner.refreshed.lock().await;
+        // Another request may have refreshed the keys while this one waited.
+        if let Some(key) = self.cached(kid) {
+            return Ok(Some(key));
+        }
+        match *refreshed {
+            Some(at) if at.elapsed() < self.cooldown => {}
+            last_refresh => {
+                if last_refresh.is_none() {
+                    let inner = Arc::downgrade(&self.inner);
+                    async_std::task::spawn(refresh_periodically(inner, self.refresh_interval));
+                }
+                *refreshed = Some(Instant::now());
+                self.inner.refresh().await?;
+            }
+        }
+        Ok(self.cached(kid))
+    }
+
+    fn cached(&self, kid: &str) -> Option<Arc<Jwk>> {
+        self.inner
+            .keys
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(kid)
+            .cloned()
+    }
+}
+
+impl Inner {
+    /// Replace the cached keys with those at the url.
+    async fn refresh(&self) -> tide::Result<()> {
+        let set: JwkSet = self.client.get(&self.url).recv_json().await?;
+        // Keys which fail to parse, e.g. of types this does not know, are skipped rather than failing the whole set.
+        let keys: HashMap<String, Arc<Jwk>> = set
+            .keys
+            .into_iter()
+            .filter_map(|key| serde_json::from_value::<Jwk>(key).ok())
+            .filter_map(|key| Some((key.kid.clone()?, Arc::new(key))))
+            .collect();
+        log::debug!("Fetched {} keys from {}", keys.len(), self.url);
+        *self
+            .keys
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = keys;
+        Ok(())
+    }
+}
+
+/// Refresh the keys every `interval`, with jitter, until the cache is dropped.
+async fn refresh_periodically(inner: Weak<Inner>, interval: Duration) {
+    loop {
+        async_std::task::sleep(interval.mul_f64(0.9 + 0.2 * random_fraction())).await;
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        if let Err(error) = inner.refresh().await {
+            log::warn!(
+                "Refreshing keys from {} failed, keeping the previous keys: {}",
+                inner.url,
+                error
+            );
+        }
+        *inner.refreshed.lock().await = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::json;
+    use tide::Request;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn caches_and_rotates_keys() -> tide::Result<()> {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let mut provider = tide::with_state(fetches.clone());
+        provider
+            .at("/jwks.json")
+            .get(|req: Request<Arc<AtomicUsize>>| async move {
+                let kid = match req.state().fetch_add(1, Ordering::SeqCst) {
+                    0 => "key-1",
+                    _ => "key-2",
+                };
+                Ok(json!({ "keys": [
+                    { "kid": kid, "kty": "RSA", "alg": "RS256", "use": "sig", "n": "AQAB", "e": "AQAB" },
+                    { "kty": "RSA", "n": "AQAB", "e": "AQAB" },
+                ] }))
+            });
+        let jwks = JwksCache::with_config(
+            Config::new().set_http_client(provider),
+            "http://auth.internal/jwks.json",
+        )
+        .map_err(|error| tide::Error::from_str(500, error.to_string()))?
+        .cooldown(Duration::ZERO);
+
+        let key = jwks.get("key-1").await?;
+        assert_eq!(
+            key.and_then(|key| key.alg.clone()).as_deref(),
+            Some("RS256")
+        );
+        jwks.get("key-1").await?;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // An unknown key refreshes the set, which now has the rotated key.
+        assert!(jwks.get("key-2").await?.is_some());
+        assert!(jwks.get("key-1").await?.is_none());
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+
+        let jwks = jwks.cooldown(Duration::from_secs(60));
+        assert!(jwks.get("key-2").await?.is_some());
+        assert!(jwks.get("unknown").await?.is_none());
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}
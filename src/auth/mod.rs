@@ -0,0 +1,24 @@
+//! Authentication and authorization of requests, by the [`Principal`][crate::Principal] which authentication
+//! middleware inserted.
+//!
+//! - [`JwksCache`] caches the public keys of an identity provider by their `kid`, for verifying the signatures of its
+//!   tokens, and refreshes them as the provider rotates them.
+//! - [`IntrospectionMiddleware`] authenticates opaque tokens via an OAuth 2.0 introspection endpoint, inserting the
+//!   principal of active ones.
+//! - [`ServiceTokens`] mints short-lived signed tokens for calls to other services, and authenticates the services
+//!   calling this one by theirs. Requires the `"service-auth"` feature.
+//! - [`Scopes`] guards routes via [`GuardRouteExt::guard`][crate::prelude::GuardRouteExt::guard], so that handlers
+//!   only run for callers with the scopes they need.
+
+mod guard;
+mod introspection;
+mod jwks;
+#[cfg(feature = "service-auth")]
+mod service;
+
+pub use guard::{GuardRouteExt, Scopes};
+pub use introspection::{Introspection, IntrospectionMiddleware};
+pub use jwks::{Jwk, JwksCache};
+#[cfg(feature = "service-auth")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "service-auth")))]
+pub use service::{ServiceAuthMiddleware, ServiceClaims, ServiceTokenSigner, ServiceTokens};
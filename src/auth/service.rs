@@ -0,0 +1,373 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::eyre;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use surf::middleware::{Middleware as ClientMiddleware, Next as ClientNext};
+use tide::http::auth::{AuthenticationScheme, Authorization};
+use tide::http::headers;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::metadata::service_metadata;
+use crate::middleware::extension_types::Principal;
+use crate::setup::Result;
+
+/// The header of every service token: HS256 JWTs.
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// How far the clocks of services may drift apart.
+const LEEWAY: Duration = Duration::from_secs(30);
+
+/// The claims of a service token, as [`ServiceAuthMiddleware`] inserts into requests via
+/// `req.ext::<ServiceClaims>()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ServiceClaims {
+    /// The calling service's name.
+    pub iss: String,
+    /// The called service's name.
+    pub aud: String,
+    /// When the token was minted, in seconds since the Unix epoch.
+    pub iat: u64,
+    /// When the token expires, in seconds since the Unix epoch.
+    pub exp: u64,
+}
+
+/// Short-lived signed tokens for authenticating calls between services, rather than trusting the internal network.
+///
+/// - Outgoing requests from a [`preroll::client`][crate::client] carry a token, via
+///   [`signer`][Self::signer], naming this service as the caller and the called service as the audience.
+/// - Incoming requests are checked via [`middleware`][Self::middleware], which inserts a
+///   [`Principal`][crate::Principal] with the caller's service name as its id, and the [`ServiceClaims`].
+///
+/// Tokens are JWTs signed with HS256, by a secret shared between the services, from `SERVICE_AUTH_SECRET`. Any
+/// service with the secret can mint tokens naming any caller, so the secret should only be shared within a trust
+/// boundary. Tokens expire after a minute by default, so that leaked tokens are soon useless.
+///
+/// Service names are those of the [service metadata][crate::ServiceMetadata].
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use preroll::auth::ServiceTokens;
+/// use preroll::SetupResult;
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// struct AppState {
+///     accounts: surf::Client,
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn setup_app_state() -> SetupResult<AppState> {
+///     let tokens = ServiceTokens::from_env()?;
+///     Ok(AppState {
+///         // Requests to the accounts service carry a token for it.
+///         accounts: preroll::client::new("http://accounts.internal/api/v1/")?
+///             .with(tokens.signer("accounts")),
+///     })
+/// }
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<AppState>>) -> SetupResult<()> {
+///     // Only the billing service may call this route.
+///     let tokens = ServiceTokens::from_env()?;
+///     server
+///         .at("internal/invoices")
+///         .with(tokens.middleware().allow(["billing"]))
+///         .post(|_| async { Ok("") });
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ServiceTokens {
+    secret: Arc<Vec<u8>>,
+    ttl: Duration,
+}
+
+impl ServiceTokens {
+    /// Tokens signed with `secret`, which every calling and called service must share.
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: Arc::new(secret.into()),
+            ttl: Duration::from_secs(60),
+        }
+    }
+
+    /// Tokens signed with the secret in `SERVICE_AUTH_SECRET`, which must be set, and at least 32 bytes.
+    pub fn from_env() -> Result<Self> {
+        let secret = env::var("SERVICE_AUTH_SECRET")
+            .map_err(|_| eyre!("SERVICE_AUTH_SECRET must be set for service tokens"))?;
+        if secret.len() < 32 {
+            return Err(eyre!("SERVICE_AUTH_SECRET must be at least 32 bytes"));
+        }
+        Ok(Self::new(secret))
+    }
+
+    /// How long minted tokens are valid for. Defaults to one minute.
+    #[must_use]
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// A token for calling the service `audience`, from this service.
+    pub fn mint(&self, audience: &str) -> String {
+        let iat = unix_time();
+        let claims = ServiceClaims {
+            iss: service_metadata().service.clone(),
+            aud: audience.to_string(),
+            iat,
+            exp: iat + self.ttl.as_secs(),
+        };
+        // Claims of strings and numbers always serialize.
+        let claims = serde_json::to_string(&claims).unwrap_or_default();
+        let payload = format!("{}.{}", encode(HEADER), encode(claims));
+        let signature = encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// The claims of `token`, if it is validly signed, unexpired, and for this service.
+    pub fn verify(&self, token: &str) -> Option<ServiceClaims> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let (header, claims) = payload.split_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        // `verify_slice` compares in constant time.
+        self.mac(payload).verify_slice(&signature).ok()?;
+        if base64::decode_config(header, base64::URL_SAFE_NO_PAD).ok()? != HEADER.as_bytes() {
+            return None;
+        }
+
+        let claims = base64::decode_config(claims, base64::URL_SAFE_NO_PAD).ok()?;
+        let claims: ServiceClaims = serde_json::from_slice(&claims).ok()?;
+        let now = unix_time();
+        let leeway = LEEWAY.as_secs();
+        let valid = claims.aud == service_metadata().service
+            && claims.iat <= now + leeway
+            && now < claims.exp + leeway;
+        valid.then_some(claims)
+    }
+
+    /// Client middleware which adds a token for `audience` to each outgoing request, via
+    /// `Authorization: Bearer {token}`.
+    pub fn signer(&self, audience: &str) -> ServiceTokenSigner {
+        ServiceTokenSigner {
+            tokens: self.clone(),
+            audience: audience.to_string(),
+        }
+    }
+
+    /// Middleware which only lets requests with a valid token for this service through.
+    pub fn middleware(&self) -> ServiceAuthMiddleware {
+        ServiceAuthMiddleware {
+            tokens: self.clone(),
+            allowed: None,
+        }
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+impl std::fmt::Debug for ServiceTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceTokens")
+            .field("secret", &crate::redact::REDACTED)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+fn encode(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Client middleware which adds service tokens to outgoing requests, from [`ServiceTokens::signer`].
+#[derive(Debug, Clone)]
+pub struct ServiceTokenSigner {
+    tokens: ServiceTokens,
+    audience: String,
+}
+
+#[surf::utils::async_trait]
+impl ClientMiddleware for ServiceTokenSigner {
+    async fn handle(
+        &self,
+        mut req: surf::Request,
+        client: surf::Client,
+        next: ClientNext<'_>,
+    ) -> surf::Result<surf::Response> {
+        // Each request, and each retry of it, gets a fresh token.
+        let token = self.tokens.mint(&self.audience);
+        req.insert_header(headers::AUTHORIZATION, format!("Bearer {}", token));
+        next.run(req, client).await
+    }
+}
+
+/// Middleware which authenticates calling services by their tokens, from [`ServiceTokens::middleware`].
+///
+/// Requests without a valid token get a `401 Unauthorized` error with the code `"invalid_service_token"`, and those
+/// from services which are not [allowed][Self::allow] get a `403 Forbidden` error with the code
+/// `"service_not_allowed"`.
+#[derive(Debug, Clone)]
+pub struct ServiceAuthMiddleware {
+    tokens: ServiceTokens,
+    allowed: Option<BTreeSet<String>>,
+}
+
+impl ServiceAuthMiddleware {
+    /// Only let requests from the services named in `services` through, rather than from any service with the secret.
+    #[must_use]
+    pub fn allow<S: Into<String>>(mut self, services: impl IntoIterator<Item = S>) -> Self {
+        self.allowed
+            .get_or_insert_with(BTreeSet::new)
+            .extend(services.into_iter().map(Into::into));
+        self
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ServiceAuthMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let claims = match Authorization::from_headers(&req) {
+            Ok(Some(authorization)) if authorization.scheme() == AuthenticationScheme::Bearer => {
+                self.tokens.verify(authorization.credentials())
+            }
+            _ => None,
+        };
+        let claims = match claims {
+            Some(claims) => claims,
+            None => {
+                let mut res = Response::new(StatusCode::Unauthorized);
+                res.insert_header(headers::WWW_AUTHENTICATE, "Bearer");
+                res.set_error(crate::Error::with_code(
+                    "invalid_service_token",
+                    StatusCode::Unauthorized,
+                    "A valid service token is required",
+                ));
+                return Ok(res);
+            }
+        };
+
+        if self
+            .allowed
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(&claims.iss))
+        {
+            log::warn!(
+                "Rejected a request from the service \"{}\": {} {}",
+                claims.iss,
+                req.method(),
+                req.url().path()
+            );
+            return Err(crate::Error::with_code(
+                "service_not_allowed",
+                StatusCode::Forbidden,
+                format!("The service \"{}\" may not call this route", claims.iss),
+            ));
+        }
+
+        req.set_ext(Principal::new(claims.iss.clone()));
+        req.set_ext(claims);
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surf::Config;
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestClientOptions, TestResult};
+
+    #[test]
+    fn verifies_tokens() {
+        let tokens = ServiceTokens::new("a-secret-which-is-at-least-32-bytes");
+        let service = &service_metadata().service;
+
+        let claims = tokens.verify(&tokens.mint(service));
+        assert_eq!(claims.map(|claims| claims.iss), Some(service.clone()));
+
+        // Tokens for other services, or signed with other secrets, are rejected.
+        assert!(tokens.verify(&tokens.mint("other-service")).is_none());
+        let other = ServiceTokens::new("another-secret-at-least-32-bytes-long");
+        assert!(tokens.verify(&other.mint(service)).is_none());
+
+        // As are tokens whose claims were changed after signing.
+        let token = tokens.mint("other-service");
+        let forged = format!(
+            r#"{{"iss":"billing","aud":"{}","iat":0,"exp":99999999999}}"#,
+            service
+        );
+        let mut parts: Vec<String> = token.split('.').map(String::from).collect();
+        parts[1] = encode(forged);
+        assert!(tokens.verify(&parts.join(".")).is_none());
+    }
+
+    #[async_std::test]
+    async fn authenticates_services() -> TestResult<()> {
+        let tokens = ServiceTokens::new("a-secret-which-is-at-least-32-bytes");
+        let service = service_metadata().service.clone();
+        let mut downstream = tide::new();
+        downstream
+            .at("*")
+            .with(tokens.middleware().allow([service.clone()]))
+            .get(|req: Request<()>| async move {
+                Ok(req
+                    .ext::<Principal>()
+                    .map(|principal| principal.id.clone())
+                    .unwrap_or_default())
+            });
+        let client = crate::client::with_config(
+            Config::new().set_http_client(downstream),
+            "http://downstream.internal/",
+        )
+        .map_err(|error| tide::Error::from_str(500, error.to_string()))?
+        .with(tokens.signer(&service));
+
+        let mut res = client.get("whoami").await?;
+        assert_eq!(res.body_string().await?, service);
+
+        let options = TestClientOptions::new().with(tokens.middleware().allow(["billing"]));
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: Route<'_, Arc<()>>| {
+                server.at("invoices").get(|_| async { Ok("[]") });
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.get("/api/v1/invoices").await?;
+        assert!(assert_status(&mut res, 401)
+            .await
+            .contains("invalid_service_token"));
+
+        let mut res = client
+            .get("/api/v1/invoices")
+            .header("Authorization", format!("Bearer {}", tokens.mint(&service)))
+            .await?;
+        assert!(assert_status(&mut res, 403)
+            .await
+            .contains("service_not_allowed"));
+        Ok(())
+    }
+}
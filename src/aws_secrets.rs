@@ -0,0 +1,189 @@
+//! Resolving `aws-sm://` and `aws-ssm://` references in the environment, for the `"aws-secrets"` feature.
+
+use std::collections::HashMap;
+use std::env;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use serde_json::{json, Value};
+
+use crate::aws::Aws;
+use crate::setup::Result;
+
+const SECRETS_MANAGER_PREFIX: &str = "aws-sm://";
+const PARAMETER_STORE_PREFIX: &str = "aws-ssm://";
+
+/// A reference to a value stored in AWS.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Reference {
+    /// A Secrets Manager secret id or ARN, and optionally a key within its JSON value.
+    SecretsManager { id: String, key: Option<String> },
+    /// A Parameter Store parameter name, decrypted if it is a `SecureString`.
+    ParameterStore { name: String },
+}
+
+impl Reference {
+    fn parse(value: &str) -> Option<Result<Self>> {
+        let reference = if let Some(rest) = value.strip_prefix(SECRETS_MANAGER_PREFIX) {
+            let (id, key) = match rest.split_once('#') {
+                Some((id, key)) => (id, Some(key.to_string())),
+                None => (rest, None),
+            };
+            Reference::SecretsManager {
+                id: id.to_string(),
+                key,
+            }
+        } else if let Some(name) = value.strip_prefix(PARAMETER_STORE_PREFIX) {
+            Reference::ParameterStore {
+                name: name.to_string(),
+            }
+        } else {
+            return None;
+        };
+
+        match &reference {
+            Reference::SecretsManager { id, .. } if id.is_empty() => {
+                Some(Err(eyre!("Missing secret id in \"{}\"", value)))
+            }
+            Reference::ParameterStore { name } if name.is_empty() => {
+                Some(Err(eyre!("Missing parameter name in \"{}\"", value)))
+            }
+            _ => Some(Ok(reference)),
+        }
+    }
+}
+
+/// Fetch the secret or parameter a reference is to.
+async fn fetch(aws: &Aws, reference: &Reference) -> Result<String> {
+    match reference {
+        Reference::SecretsManager { id, .. } => {
+            let response = aws
+                .call(
+                    "secretsmanager",
+                    "1.1",
+                    "secretsmanager.GetSecretValue",
+                    json!({ "SecretId": id }),
+                )
+                .await?;
+            response["SecretString"]
+                .as_str()
+                .map(ToString::to_string)
+                .ok_or_else(|| eyre!("Secret \"{}\" has no SecretString", id))
+        }
+        Reference::ParameterStore { name } => {
+            let response = aws
+                .call(
+                    "ssm",
+                    "1.1",
+                    "AmazonSSM.GetParameter",
+                    json!({ "Name": name, "WithDecryption": true }),
+                )
+                .await?;
+            response["Parameter"]["Value"]
+                .as_str()
+                .map(ToString::to_string)
+                .ok_or_else(|| eyre!("Parameter \"{}\" has no value", name))
+        }
+    }
+}
+
+/// Pick the value for a reference out of its fetched secret.
+fn select(reference: &Reference, secret: &str) -> Result<String> {
+    match reference {
+        Reference::SecretsManager { id, key: Some(key) } => {
+            let value: Value = serde_json::from_str(secret)
+                .wrap_err_with(|| format!("Secret \"{}\" is not a JSON object", id))?;
+            match &value[key.as_str()] {
+                Value::String(value) => Ok(value.clone()),
+                Value::Null => Err(eyre!("Secret \"{}\" has no key \"{}\"", id, key)),
+                other => Ok(other.to_string()),
+            }
+        }
+        _ => Ok(secret.to_string()),
+    }
+}
+
+/// Replace every environment variable whose value is an `aws-sm://` or `aws-ssm://` reference with the referenced value.
+///
+/// Each secret or parameter is fetched once, even if it is referenced by several variables.
+pub(crate) async fn resolve_references() -> Result<()> {
+    let mut references = Vec::new();
+    for (name, value) in env::vars() {
+        if let Some(reference) = Reference::parse(&value) {
+            let reference = reference.wrap_err_with(|| format!("Invalid reference in {}", name))?;
+            references.push((name, reference));
+        }
+    }
+    if references.is_empty() {
+        return Ok(());
+    }
+
+    let aws = Aws::from_env()
+        .wrap_err("AWS credentials are required to resolve aws-sm:// and aws-ssm:// references")?;
+    let mut cache: HashMap<Reference, String> = HashMap::new();
+
+    for (name, reference) in references {
+        // Cache whole secrets, so that several keys of one secret share a fetch.
+        let fetched = match &reference {
+            Reference::SecretsManager { id, .. } => Reference::SecretsManager {
+                id: id.clone(),
+                key: None,
+            },
+            other => other.clone(),
+        };
+
+        if !cache.contains_key(&fetched) {
+            let secret = fetch(&aws, &fetched)
+                .await
+                .wrap_err_with(|| format!("Could not resolve {}", name))?;
+            cache.insert(fetched.clone(), secret);
+        }
+
+        let secret = cache.get(&fetched).map(String::as_str).unwrap_or_default();
+        let value =
+            select(&reference, secret).wrap_err_with(|| format!("Could not resolve {}", name))?;
+        env::set_var(&name, value);
+        log::info!("Resolved {} from AWS", name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_references() -> Result<()> {
+        assert_eq!(
+            Reference::parse("aws-sm://prod/service/db#password").transpose()?,
+            Some(Reference::SecretsManager {
+                id: "prod/service/db".to_string(),
+                key: Some("password".to_string()),
+            })
+        );
+        assert_eq!(
+            Reference::parse("aws-ssm:///prod/service/api-key").transpose()?,
+            Some(Reference::ParameterStore {
+                name: "/prod/service/api-key".to_string(),
+            })
+        );
+        assert_eq!(
+            Reference::parse("postgres://localhost/service").transpose()?,
+            None
+        );
+        assert!(Reference::parse("aws-sm://#password").transpose().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn selects_json_keys() -> Result<()> {
+        let reference = Reference::SecretsManager {
+            id: "db".to_string(),
+            key: Some("port".to_string()),
+        };
+        assert_eq!(select(&reference, r#"{"port": 5432}"#)?, "5432");
+        assert!(select(&reference, r#"{"host": "db"}"#).is_err());
+        assert!(select(&reference, "not json").is_err());
+        Ok(())
+    }
+}
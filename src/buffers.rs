@@ -0,0 +1,157 @@
+//! A pool of buffers for serializing JSON response bodies, which are returned to the pool once the body is sent.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use futures_lite::{AsyncBufRead, AsyncRead};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tide::http::mime;
+use tide::Body;
+
+/// The most buffers kept in the pool.
+const MAX_POOLED: usize = 256;
+
+/// The largest buffer kept in the pool, in bytes. Larger buffers are freed, rather than kept around for every response.
+const MAX_CAPACITY: usize = 64 * 1024;
+
+/// The capacity of new buffers, in bytes.
+const INITIAL_CAPACITY: usize = 1024;
+
+static POOL: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool::default()));
+
+#[derive(Debug, Default)]
+struct Pool {
+    buffers: Vec<Vec<u8>>,
+    stats: BufferStats,
+}
+
+/// The usage of the buffer pool, as reported in `/monitor/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct BufferStats {
+    /// Buffers in the pool now.
+    pooled: usize,
+    /// Buffers taken from the pool.
+    reused: u64,
+    /// Buffers allocated because the pool was empty.
+    allocated: u64,
+    /// Buffers freed rather than returned to the pool, because it was full or they were too large.
+    discarded: u64,
+}
+
+/// The usage of the buffer pool.
+pub(crate) fn buffer_stats() -> BufferStats {
+    let pool = lock();
+    BufferStats {
+        pooled: pool.buffers.len(),
+        ..pool.stats.clone()
+    }
+}
+
+/// An `application/json` body of `value`, serialized into a pooled buffer.
+pub(crate) fn json_body<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<Body> {
+    let mut reader = PooledReader {
+        buffer: take(),
+        read: 0,
+    };
+    serde_json::to_writer(&mut reader.buffer, value)?;
+
+    let len = reader.buffer.len();
+    let mut body = Body::from_reader(reader, Some(len));
+    body.set_mime(mime::JSON);
+    Ok(body)
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Pool> {
+    POOL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn take() -> Vec<u8> {
+    let mut pool = lock();
+    match pool.buffers.pop() {
+        Some(buffer) => {
+            pool.stats.reused += 1;
+            buffer
+        }
+        None => {
+            pool.stats.allocated += 1;
+            Vec::with_capacity(INITIAL_CAPACITY)
+        }
+    }
+}
+
+fn give_back(mut buffer: Vec<u8>) {
+    let mut pool = lock();
+    if buffer.capacity() > MAX_CAPACITY || pool.buffers.len() >= MAX_POOLED {
+        pool.stats.discarded += 1;
+        return;
+    }
+    buffer.clear();
+    pool.buffers.push(buffer);
+}
+
+/// Reads a pooled buffer, and returns it to the pool when dropped.
+struct PooledReader {
+    buffer: Vec<u8>,
+    read: usize,
+}
+
+impl AsyncRead for PooledReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let len = buf.len().min(self.buffer.len() - self.read);
+        let start = self.read;
+        buf[..len].copy_from_slice(&self.buffer[start..start + len]);
+        self.read += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncBufRead for PooledReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        Poll::Ready(Ok(&this.buffer[this.read..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.read = (self.read + amt).min(self.buffer.len());
+    }
+}
+
+impl Drop for PooledReader {
+    fn drop(&mut self) {
+        give_back(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn reuses_buffers() -> tide::Result<()> {
+        let body = json_body(&json!({ "id": 7 }))?;
+        assert_eq!(body.len(), Some(8));
+        assert_eq!(body.mime(), &mime::JSON);
+        let before = buffer_stats();
+        assert_eq!(body.into_string().await?, r#"{"id":7}"#);
+
+        // The buffer is back in the pool, for the next body to take.
+        let body = json_body(&[1, 2, 3])?;
+        assert!(buffer_stats().reused > before.reused);
+        assert_eq!(body.into_json::<Vec<u8>>().await?, [1, 2, 3]);
+
+        let large = "a".repeat(MAX_CAPACITY);
+        let before = buffer_stats();
+        drop(json_body(&large)?);
+        assert!(buffer_stats().discarded > before.discarded);
+        Ok(())
+    }
+}
@@ -1 +1,3 @@
+pub(crate) mod admin;
 pub mod monitor;
+pub mod static_files;
@@ -1,12 +1,18 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::sync::Arc;
 use std::time::Instant;
 
 use once_cell::sync::OnceCell;
 use serde::Serialize;
-use tide::{Body, Server};
+use tide::Server;
 
+use crate::buffers::{buffer_stats, json_body, BufferStats};
+use crate::canary::{canary_stats, CanaryStats};
+use crate::client::{client_stats, ClientStats};
+use crate::scheduler::{task_stats, TaskStats};
 use crate::utils::HOSTNAME;
+use crate::workers::{worker_stats, WorkerStats};
 
 static SERVICE_NAME: OnceCell<&'static str> = OnceCell::new();
 static START_TIME: OnceCell<Instant> = OnceCell::new();
@@ -36,9 +42,14 @@ where
                 .get()
                 .map(|start| start.elapsed().as_secs_f64())
                 .unwrap_or(f64::NEG_INFINITY),
+            tasks: task_stats(),
+            buffers: buffer_stats(),
+            clients: client_stats(),
+            canaries: canary_stats(),
+            workers: worker_stats(),
         };
 
-        Body::from_json(&status)
+        Ok(json_body(&status)?)
     });
 }
 
@@ -48,6 +59,15 @@ struct Status<'host> {
     hostname: &'host str,
     service: &'static str,
     uptime: f64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tasks: BTreeMap<&'static str, TaskStats>,
+    buffers: BufferStats,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    clients: BTreeMap<String, ClientStats>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    canaries: BTreeMap<&'static str, CanaryStats>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    workers: Vec<WorkerStats>,
 }
 
 // TODO(Jeremiah):
@@ -0,0 +1,563 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::fs;
+use percent_encoding::percent_decode_str;
+use tide::http::cache::{CacheControl, CacheDirective};
+use tide::http::conditional::{ETag, IfModifiedSince, IfNoneMatch, LastModified};
+use tide::http::headers::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+use tide::http::mime::{self, Mime};
+use tide::{Body, Endpoint, Request, Response, Route, StatusCode};
+
+/// The extensions of precompressed siblings and their encodings, in order of preference.
+const PRECOMPRESSED: [(&str, &str); 2] = [(".br", "br"), (".gz", "gzip")];
+
+/// An endpoint which serves files from a directory, mounted via [`StaticFilesExt::serve_static`][crate::prelude::StaticFilesExt::serve_static].
+///
+/// - Content types are detected from file extensions, falling back to the file's contents.
+/// - Responses have `Cache-Control`, `ETag`, and `Last-Modified` headers, and conditional requests get `304 Not Modified`.
+/// - Files with precompressed `.br` or `.gz` siblings, e.g. `app.js.br`, are served from those to clients which accept
+///   them, with `Content-Encoding` and `Vary: Accept-Encoding` headers, unless [disabled][StaticFiles::precompressed].
+/// - Paths which would escape the directory, including via symlinks, are rejected with `403 Forbidden`.
+/// - Requests for a directory serve its `index.html`, if it has one. Otherwise, they are `404 Not Found`,
+///   unless [directory listings][StaticFiles::directory_listing] are enabled.
+/// - With [`spa_fallback`][StaticFiles::spa_fallback], paths which match no file serve the root `index.html` instead,
+///   for single-page apps with client-side routing.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// use preroll::prelude::*;
+/// use preroll::StaticFiles;
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// pub fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     let assets = StaticFiles::new("./public")
+///         .expect("The ./public directory must exist")
+///         .max_age(Duration::from_secs(24 * 60 * 60));
+///
+///     // Serves e.g. ./public/app.css at /api/v1/assets/app.css
+///     server.serve_static("/assets", assets);
+/// }
+/// ```
+///
+/// A single-page app, served at the root alongside the API, from `custom_setup`:
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use preroll::prelude::*;
+/// use preroll::{SetupResult, StaticFiles};
+/// use tide::Server;
+///
+/// # #[allow(dead_code)]
+/// pub async fn custom_setup(mut server: Server<Arc<()>>) -> SetupResult<Server<Arc<()>>> {
+///     let app = StaticFiles::new("./frontend/dist")?.spa_fallback(true);
+///
+///     // e.g. /settings/profile serves ./frontend/dist/index.html, but /api/v2/missing is still a 404.
+///     server.at("/").serve_static("/", app);
+///     Ok(server)
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: Arc<PathBuf>,
+    max_age: Duration,
+    index: Option<String>,
+    directory_listing: bool,
+    spa_fallback: bool,
+    api_prefix: String,
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    /// Serve files from this directory, which must exist.
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let root = dir.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+
+        Ok(Self {
+            root: Arc::new(root),
+            max_age: Duration::from_secs(60 * 60),
+            index: Some("index.html".to_string()),
+            directory_listing: false,
+            spa_fallback: false,
+            api_prefix: "/api".to_string(),
+            precompressed: true,
+        })
+    }
+
+    /// How long clients and caches may reuse a file without revalidating it. Defaults to one hour.
+    ///
+    /// A duration of zero sends `Cache-Control: no-cache`, so that clients always revalidate.
+    #[must_use]
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// The file to serve for requests to a directory. Defaults to `index.html`.
+    #[must_use]
+    pub fn index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(ToString::to_string);
+        self
+    }
+
+    /// Whether to list the contents of directories which have no index file. Defaults to `false`.
+    #[must_use]
+    pub fn directory_listing(mut self, directory_listing: bool) -> Self {
+        self.directory_listing = directory_listing;
+        self
+    }
+
+    /// Whether to serve the root index file for `GET` requests which match no file, so that the client-side routes
+    /// of a single-page app can be loaded directly. Defaults to `false`.
+    ///
+    /// Paths under the [`api_prefix`][StaticFiles::api_prefix] never fall back.
+    #[must_use]
+    pub fn spa_fallback(mut self, spa_fallback: bool) -> Self {
+        self.spa_fallback = spa_fallback;
+        self
+    }
+
+    /// The path prefix of requests which must not fall back to the index file, so that API clients get a `404 Not Found`
+    /// rather than HTML. Defaults to `/api`, where `preroll::main!` mounts routes.
+    #[must_use]
+    pub fn api_prefix(mut self, api_prefix: &str) -> Self {
+        self.api_prefix = api_prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Whether to serve the precompressed `.br` and `.gz` siblings of files, e.g. `app.js.br` for `app.js`, to clients
+    /// which accept them. Defaults to `true`.
+    #[must_use]
+    pub fn precompressed(mut self, precompressed: bool) -> Self {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Resolve a request path to a path within the root, or `None` if it would escape the root.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+
+        let mut path = PathBuf::new();
+        for component in Path::new(decoded.as_ref()).components() {
+            match component {
+                Component::Normal(part) if !part.to_string_lossy().contains('\\') => {
+                    path.push(part)
+                }
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+
+        Some(self.root.join(path))
+    }
+
+    /// The most preferred precompressed sibling of `path` which the request accepts, and its encoding, and whether
+    /// `path` has any precompressed siblings at all.
+    async fn precompressed_file<State>(
+        &self,
+        req: &Request<State>,
+        path: &Path,
+    ) -> (Option<(PathBuf, &'static str)>, bool) {
+        if !self.precompressed {
+            return (None, false);
+        }
+
+        let accept_encoding = req
+            .header(ACCEPT_ENCODING)
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|value| value.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .unwrap_or_default();
+        let mut has_siblings = false;
+        for (extension, encoding) in PRECOMPRESSED {
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(extension);
+            // Siblings may be symlinks too, which must not lead outside of the root either.
+            let sibling = match fs::canonicalize(PathBuf::from(sibling)).await {
+                Ok(sibling) if sibling.starts_with(self.root.as_path()) => {
+                    PathBuf::from(sibling.into_os_string())
+                }
+                _ => continue,
+            };
+            if !sibling.is_file() {
+                continue;
+            }
+            has_siblings = true;
+            if accepts_encoding(&accept_encoding, encoding) {
+                return (Some((sibling, encoding)), true);
+            }
+        }
+        (None, has_siblings)
+    }
+
+    async fn serve_file<State>(&self, req: &Request<State>, path: &Path) -> tide::Result {
+        let (precompressed, has_siblings) = self.precompressed_file(req, path).await;
+        let served = precompressed
+            .as_ref()
+            .map_or(path, |(sibling, _)| sibling.as_path());
+        let metadata = fs::metadata(served).await?;
+        let modified = metadata.modified().ok();
+
+        let etag = ETag::new_weak(format!(
+            "{:x}-{:x}",
+            metadata.len(),
+            modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or_default()
+        ));
+
+        let mut res = if is_not_modified(req, &etag, modified)? {
+            Response::new(StatusCode::NotModified)
+        } else {
+            let mut res = Response::new(StatusCode::Ok);
+            let mut body = Body::from_file(served).await?;
+            if precompressed.is_some() {
+                // The type is that of the original file, e.g. `text/javascript` for `app.js.br`.
+                let mime = match path
+                    .extension()
+                    .and_then(|ext| Mime::from_extension(ext.to_string_lossy()))
+                {
+                    Some(mime) => mime,
+                    None => Body::from_file(path).await?.mime().clone(),
+                };
+                body.set_mime(mime);
+            }
+            res.set_body(body);
+            res
+        };
+
+        if let Some((_, encoding)) = precompressed {
+            res.insert_header(CONTENT_ENCODING, encoding);
+        }
+        if has_siblings {
+            res.append_header(VARY, "Accept-Encoding");
+        }
+
+        etag.apply(&mut res);
+        if let Some(modified) = modified {
+            LastModified::new(modified).apply(&mut res);
+        }
+        let mut cache_control = CacheControl::new();
+        if self.max_age.as_secs() == 0 {
+            cache_control.push(CacheDirective::NoCache);
+        } else {
+            cache_control.push(CacheDirective::Public);
+            cache_control.push(CacheDirective::MaxAge(self.max_age));
+        }
+        cache_control.apply(&mut res);
+
+        Ok(res)
+    }
+
+    /// Respond to a path which matches no file, with the root index file if [`spa_fallback`][StaticFiles::spa_fallback] applies.
+    async fn not_found<State>(&self, req: &Request<State>) -> tide::Result {
+        let path = req.url().path();
+        let is_api = path == self.api_prefix
+            || path
+                .strip_prefix(self.api_prefix.as_str())
+                .is_some_and(|rest| rest.starts_with('/'));
+
+        if let (true, false, Some(index)) = (self.spa_fallback, is_api, &self.index) {
+            let index = self.root.join(index);
+            if index.is_file() {
+                return self.serve_file(req, &index).await;
+            }
+        }
+
+        Ok(Response::new(StatusCode::NotFound))
+    }
+
+    async fn serve_listing<State>(&self, req: &Request<State>, dir: &Path) -> tide::Result {
+        let mut names = Vec::new();
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = futures_lite::StreamExt::next(&mut entries).await {
+            let entry = entry?;
+            let mut name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type().await?.is_dir() {
+                name.push('/');
+            }
+            names.push(name);
+        }
+        names.sort();
+
+        let base = req.url().path().trim_end_matches('/');
+        let items: String = names
+            .iter()
+            .map(|name| {
+                let name = escape_html(name);
+                format!("<li><a href=\"{}/{}\">{}</a></li>\n", base, name, name)
+            })
+            .collect();
+
+        let mut res = Response::new(StatusCode::Ok);
+        res.set_content_type(mime::HTML);
+        res.set_body(format!(
+            "<!DOCTYPE html>\n<html><body><h1>{}</h1>\n<ul>\n{}</ul></body></html>\n",
+            escape_html(req.url().path()),
+            items
+        ));
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State> Endpoint<State> for StaticFiles
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let request_path = req.param("path").unwrap_or("");
+
+        let path = match self.resolve(request_path) {
+            Some(path) => path,
+            None => {
+                log::warn!("Rejected static file path: {:?}", request_path);
+                return Ok(Response::new(StatusCode::Forbidden));
+            }
+        };
+
+        // Resolve symlinks, and make sure they do not lead outside of the root either.
+        let path = match fs::canonicalize(&path).await {
+            Ok(path) => PathBuf::from(path.into_os_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.not_found(&req).await,
+            Err(e) => return Err(e.into()),
+        };
+        if !path.starts_with(self.root.as_path()) {
+            log::warn!("Rejected static file path outside of root: {:?}", path);
+            return Ok(Response::new(StatusCode::Forbidden));
+        }
+
+        if !path.is_dir() {
+            return self.serve_file(&req, &path).await;
+        }
+
+        if let Some(index) = &self.index {
+            let index = path.join(index);
+            if index.is_file() {
+                return self.serve_file(&req, &index).await;
+            }
+        }
+
+        if self.directory_listing {
+            self.serve_listing(&req, &path).await
+        } else {
+            self.not_found(&req).await
+        }
+    }
+}
+
+/// Whether the request's `If-None-Match` or `If-Modified-Since` match, so that it can get a `304 Not Modified`.
+pub(crate) fn is_not_modified<State>(
+    req: &Request<State>,
+    etag: &ETag,
+    modified: Option<SystemTime>,
+) -> tide::Result<bool> {
+    // If-None-Match takes precedence over If-Modified-Since, and uses weak comparison.
+    if let Some(if_none_match) = IfNoneMatch::from_headers(req)? {
+        let opaque = |tag: &ETag| tag.value().as_str().trim_start_matches("W/").to_string();
+        return Ok(
+            if_none_match.wildcard() || if_none_match.iter().any(|tag| opaque(tag) == opaque(etag))
+        );
+    }
+
+    match (IfModifiedSince::from_headers(req)?, modified) {
+        (Some(since), Some(modified)) => {
+            // HTTP dates have a resolution of seconds.
+            let since = since.modified().duration_since(UNIX_EPOCH).ok();
+            let modified = modified.duration_since(UNIX_EPOCH).ok();
+            Ok(
+                matches!((since, modified), (Some(since), Some(modified)) if modified.as_secs() <= since.as_secs()),
+            )
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Whether an `Accept-Encoding` header accepts `encoding`, explicitly or via `*`, and not with `q=0`.
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    let mut wildcard = false;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let accepted = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .is_none_or(|q| q > 0.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return accepted;
+        }
+        if name == "*" {
+            wildcard = accepted;
+        }
+    }
+    wildcard
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Extension methods for serving [`StaticFiles`] from a Tide route.
+pub trait StaticFilesExt {
+    /// Serve the files at `path` and below, e.g. `server.serve_static("/assets", StaticFiles::new("./public")?)`.
+    fn serve_static(&mut self, path: &str, files: StaticFiles);
+}
+
+impl<'a, State> StaticFilesExt for Route<'a, State>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    fn serve_static(&mut self, path: &str, files: StaticFiles) {
+        let path = path.trim_end_matches('/');
+        self.at(path).get(files.clone());
+        self.at(&format!("{}/", path)).get(files.clone());
+        self.at(path).at("*path").get(files);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn serves_files_with_cache_headers() -> TestResult<()> {
+        let root = std::env::temp_dir().join(format!("preroll-static-{}", std::process::id()));
+        fs::create_dir_all(root.join("docs"))?;
+        fs::write(root.join("app.css"), "body {}")?;
+        fs::write(root.join("docs/readme.txt"), "hello")?;
+
+        let files = StaticFiles::new(&root)?.directory_listing(true);
+        let client = test_utils::create_client((), move |mut server: Route<'_, Arc<()>>| {
+            server.serve_static("/assets", files.clone());
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/assets/app.css").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "body {}");
+        assert_eq!(res.content_type(), Some(mime::CSS));
+        assert_eq!(res["cache-control"], "public, max-age=3600");
+        let etag = res["etag"].as_str().to_string();
+
+        let res = client
+            .get("/api/v1/assets/app.css")
+            .header("If-None-Match", etag)
+            .await?;
+        assert_eq!(res.status(), StatusCode::NotModified);
+
+        let mut res = client.get("/api/v1/assets/docs").await?;
+        let listing = assert_status(&mut res, 200).await;
+        assert!(listing.contains("<a href=\"/api/v1/assets/docs/readme.txt\">readme.txt</a>"));
+
+        let res = client
+            .get("/api/v1/assets/docs/..%2f..%2f..%2fetc%2fpasswd")
+            .await?;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        let res = client.get("/api/v1/assets/missing.js").await?;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn serves_precompressed_files() -> TestResult<()> {
+        let root =
+            std::env::temp_dir().join(format!("preroll-precompressed-{}", std::process::id()));
+        fs::create_dir_all(&root)?;
+        fs::write(root.join("app.js"), "render()")?;
+        fs::write(root.join("app.js.br"), "brotli")?;
+        fs::write(root.join("app.js.gz"), "gzip")?;
+        fs::write(root.join("app.css"), "body {}")?;
+
+        let files = StaticFiles::new(&root)?;
+        let client = test_utils::create_client((), move |mut server: Route<'_, Arc<()>>| {
+            server.serve_static("/assets", files.clone());
+        })
+        .await?;
+
+        for (accept_encoding, body, encoding) in [
+            ("gzip, deflate, br", "brotli", Some("br")),
+            ("gzip, br;q=0", "gzip", Some("gzip")),
+            ("identity", "render()", None),
+        ] {
+            let mut res = client
+                .get("/api/v1/assets/app.js")
+                .header("Accept-Encoding", accept_encoding)
+                .await?;
+            assert_eq!(assert_status(&mut res, 200).await, body);
+            assert_eq!(res.content_type(), Some(mime::JAVASCRIPT));
+            assert_eq!(res.header("Content-Encoding").map(|v| v.as_str()), encoding);
+            assert_eq!(res["vary"], "Accept-Encoding");
+        }
+
+        let mut res = client
+            .get("/api/v1/assets/app.css")
+            .header("Accept-Encoding", "br")
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "body {}");
+        assert!(res.header("Content-Encoding").is_none());
+        assert!(res.header("Vary").is_none());
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn falls_back_to_index_for_spa_routes() -> TestResult<()> {
+        let root = std::env::temp_dir().join(format!("preroll-spa-{}", std::process::id()));
+        fs::create_dir_all(&root)?;
+        fs::write(root.join("index.html"), "<div id=\"app\"></div>")?;
+        fs::write(root.join("app.js"), "render()")?;
+
+        let mut server = tide::new();
+        server.at("/api/v1/hello").get(|_| async { Ok("Hello") });
+        server
+            .at("/")
+            .serve_static("/", StaticFiles::new(&root)?.spa_fallback(true));
+        let client = surf::Client::with_http_client(server);
+
+        for path in ["/", "/settings/profile", "/apiary"] {
+            let mut res = client.get(format!("http://localhost{}", path)).await?;
+            assert_eq!(assert_status(&mut res, 200).await, "<div id=\"app\"></div>");
+            assert_eq!(res.content_type(), Some(mime::HTML));
+        }
+
+        let mut res = client.get("http://localhost/app.js").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "render()");
+
+        let mut res = client.get("http://localhost/api/v1/hello").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "Hello");
+
+        let res = client.get("http://localhost/api/v1/missing").await?;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+//! Caching policies for routes, via [`CacheRouteExt::cache`][crate::prelude::CacheRouteExt::cache], rather than
+//! `Cache-Control` strings set by each handler.
+//!
+//! - The policy sets `Cache-Control` and `Expires` on successful and `304 Not Modified` responses. Errors are left
+//!   uncached.
+//! - Responses which already have a `Cache-Control` header keep it, so handlers can still override the policy.
+//! - Like `Route::with`, the policy applies to the route's handlers added after it.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! use preroll::cache::Policy;
+//! use preroll::prelude::*;
+//! use tide::Route;
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     // `Cache-Control: public, max-age=300, stale-while-revalidate=60`
+//!     server
+//!         .at("countries")
+//!         .cache(Policy::public(300).stale_while_revalidate(Duration::from_secs(60)))
+//!         .get(|_| async { Ok("[]") });
+//!
+//!     // `Cache-Control: no-store`
+//!     server.at("me").no_store().get(|_| async { Ok("{}") });
+//! }
+//! ```
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use tide::http::cache::{CacheControl, CacheDirective, Expires};
+use tide::{Middleware, Next, Request, Route, StatusCode};
+
+/// How clients and caches may cache the responses of a route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    directives: Vec<CacheDirective>,
+    max_age: Option<Duration>,
+}
+
+impl Policy {
+    /// Cacheable by clients and shared caches, such as CDNs, for `seconds`.
+    pub fn public(seconds: u64) -> Self {
+        Self::max_age(CacheDirective::Public, seconds)
+    }
+
+    /// Cacheable by clients only, not shared caches, for `seconds`, e.g. for responses about the authenticated user.
+    pub fn private(seconds: u64) -> Self {
+        Self::max_age(CacheDirective::Private, seconds)
+    }
+
+    /// Cacheable, but revalidated with the server before each use, e.g. via `ETag`.
+    pub fn no_cache() -> Self {
+        Self {
+            directives: vec![CacheDirective::NoCache],
+            max_age: None,
+        }
+    }
+
+    /// Never stored by clients or caches, e.g. for responses with secrets.
+    pub fn no_store() -> Self {
+        Self {
+            directives: vec![CacheDirective::NoStore],
+            max_age: None,
+        }
+    }
+
+    fn max_age(visibility: CacheDirective, seconds: u64) -> Self {
+        let max_age = Duration::from_secs(seconds);
+        Self {
+            directives: vec![visibility, CacheDirective::MaxAge(max_age)],
+            max_age: Some(max_age),
+        }
+    }
+
+    /// How long shared caches may cache responses for, instead of the max age.
+    #[must_use]
+    pub fn shared_max_age(self, shared_max_age: Duration) -> Self {
+        self.directive(CacheDirective::SMaxAge(shared_max_age))
+    }
+
+    /// How long stale responses may be used for while they are revalidated in the background.
+    #[must_use]
+    pub fn stale_while_revalidate(self, duration: Duration) -> Self {
+        self.directive(CacheDirective::StaleWhileRevalidate(duration))
+    }
+
+    /// How long stale responses may be used for when revalidating them fails.
+    #[must_use]
+    pub fn stale_if_error(self, duration: Duration) -> Self {
+        self.directive(CacheDirective::StaleIfError(duration))
+    }
+
+    /// That stale responses must be revalidated before use.
+    #[must_use]
+    pub fn must_revalidate(self) -> Self {
+        self.directive(CacheDirective::MustRevalidate)
+    }
+
+    /// That responses never change while fresh, e.g. for assets with hashes in their names.
+    #[must_use]
+    pub fn immutable(self) -> Self {
+        self.directive(CacheDirective::Immutable)
+    }
+
+    fn directive(mut self, directive: CacheDirective) -> Self {
+        self.directives.push(directive);
+        self
+    }
+
+    /// The `Cache-Control` and `Expires` headers of the policy, for responses sent now.
+    fn headers(&self) -> (CacheControl, Expires) {
+        let mut cache_control = CacheControl::new();
+        for directive in &self.directives {
+            cache_control.push(directive.clone());
+        }
+        // Responses which must not be reused have already expired, for HTTP/1.0 caches which ignore `Cache-Control`.
+        let expires = match self.max_age {
+            Some(max_age) => Expires::new(max_age),
+            None => Expires::new_at(UNIX_EPOCH),
+        };
+        (cache_control, expires)
+    }
+}
+
+/// An extension trait for setting the caching policy of routes.
+pub trait CacheRouteExt {
+    /// Set `Cache-Control` and `Expires` on the responses of the route's handlers added after this, by `policy`.
+    fn cache(&mut self, policy: Policy) -> &mut Self;
+
+    /// The same as `cache(Policy::no_store())`.
+    fn no_store(&mut self) -> &mut Self;
+}
+
+impl<'a, State> CacheRouteExt for Route<'a, State>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    fn cache(&mut self, policy: Policy) -> &mut Self {
+        self.with(CachePolicyMiddleware { policy })
+    }
+
+    fn no_store(&mut self) -> &mut Self {
+        self.cache(Policy::no_store())
+    }
+}
+
+/// Sets the headers of a [`Policy`] on responses.
+#[derive(Debug)]
+struct CachePolicyMiddleware {
+    policy: Policy,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CachePolicyMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+        let cacheable = res.status().is_success() || res.status() == StatusCode::NotModified;
+        if cacheable && res.header("Cache-Control").is_none() {
+            let (cache_control, expires) = self.policy.headers();
+            cache_control.apply(&mut res);
+            expires.apply(&mut res);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn sets_cache_headers() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: Route<'_, Arc<()>>| {
+            server
+                .at("countries")
+                .cache(Policy::public(300).stale_while_revalidate(Duration::from_secs(60)))
+                .get(|_| async { Ok("[]") });
+            server.at("me").no_store().get(|_| async { Ok("{}") });
+            server
+                .at("custom")
+                .cache(Policy::private(60))
+                .get(|_| async {
+                    Ok(tide::Response::builder(200)
+                        .header("Cache-Control", "no-cache")
+                        .build())
+                });
+            server
+                .at("missing")
+                .cache(Policy::public(300))
+                .get(|_| async { Err::<String, _>(tide::Error::from_str(404, "Not found")) });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/countries").await?;
+        assert_status(&mut res, 200).await;
+        assert_eq!(
+            res["cache-control"],
+            "public, max-age=300, stale-while-revalidate=60"
+        );
+        let expires = Expires::from_headers(&res)?.map(|expires| expires.expiration());
+        let expected = SystemTime::now() + Duration::from_secs(300);
+        assert!(expires.is_some_and(|expires| {
+            expected.duration_since(expires).unwrap_or_default() < Duration::from_secs(5)
+        }));
+
+        let mut res = client.get("/api/v1/me").await?;
+        assert_status(&mut res, 200).await;
+        assert_eq!(res["cache-control"], "no-store");
+        assert_eq!(res["expires"], "Thu, 01 Jan 1970 00:00:00 GMT");
+
+        let mut res = client.get("/api/v1/custom").await?;
+        assert_status(&mut res, 200).await;
+        assert_eq!(res["cache-control"], "no-cache");
+
+        let mut res = client.get("/api/v1/missing").await?;
+        assert_status(&mut res, 404).await;
+        assert!(res.header("Cache-Control").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//! Canary rollouts of a rewritten endpoint within a service, by routing a share of its requests to the new version.
+//!
+//! A [`Canary`] is an endpoint which sends a percentage of requests to the canary version, and the rest to the stable
+//! version. Requests with an `X-Canary` header of `true` or `false` go to the canary or the stable version regardless,
+//! e.g. for testing the canary before it takes any traffic.
+//!
+//! The requests, server errors, and latencies of each version are reported under `canaries` in `/monitor/status`, by
+//! the name of the canary.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::canary::Canary;
+//! use tide::{Request, Route};
+//!
+//! async fn search(_req: Request<Arc<()>>) -> tide::Result<String> {
+//!     Ok("[]".to_string())
+//! }
+//!
+//! async fn search_v2(_req: Request<Arc<()>>) -> tide::Result<String> {
+//!     Ok("[]".to_string())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     // 10% of searches use the rewrite, as do requests with `X-Canary: true`.
+//!     server
+//!         .at("search")
+//!         .get(Canary::new("search", search, search_v2).percent(10.0));
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tide::{Endpoint, Request};
+
+use crate::utils::random_fraction;
+
+static STATS: Lazy<Mutex<BTreeMap<&'static str, CanaryStats>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The requests to each version of a canary, as reported in `/monitor/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct CanaryStats {
+    stable: VariantStats,
+    canary: VariantStats,
+}
+
+/// The requests to one version of a canary.
+#[derive(Debug, Clone, Default, Serialize)]
+struct VariantStats {
+    requests: u64,
+    /// Responses with a `5xx` status.
+    server_errors: u64,
+    /// Seconds.
+    mean_latency: f64,
+    /// Seconds.
+    max_latency: f64,
+    #[serde(skip)]
+    total_latency: f64,
+}
+
+/// The request stats of all canaries, by name.
+pub(crate) fn canary_stats() -> BTreeMap<&'static str, CanaryStats> {
+    lock().clone()
+}
+
+fn lock() -> std::sync::MutexGuard<'static, BTreeMap<&'static str, CanaryStats>> {
+    STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn record_stats(name: &'static str, to_canary: bool, server_error: bool, latency: Duration) {
+    let mut stats = lock();
+    let stats = stats.entry(name).or_default();
+    let stats = if to_canary {
+        &mut stats.canary
+    } else {
+        &mut stats.stable
+    };
+
+    stats.requests += 1;
+    if server_error {
+        stats.server_errors += 1;
+    }
+    let latency = latency.as_secs_f64();
+    stats.total_latency += latency;
+    stats.mean_latency = stats.total_latency / stats.requests as f64;
+    stats.max_latency = stats.max_latency.max(latency);
+}
+
+/// An endpoint which splits requests between a stable and a canary version, as described in the
+/// [module docs][self].
+#[derive(Debug)]
+pub struct Canary<S, C> {
+    name: &'static str,
+    stable: S,
+    canary: C,
+    percent: f64,
+    header: &'static str,
+}
+
+impl<S, C> Canary<S, C> {
+    /// Split requests between `stable` and `canary`, reporting them under `name`. Sends no requests to `canary` until
+    /// [`percent`][Self::percent] is set, except those with an `X-Canary: true` header.
+    pub fn new(name: &'static str, stable: S, canary: C) -> Self {
+        lock().entry(name).or_default();
+        Self {
+            name,
+            stable,
+            canary,
+            percent: 0.0,
+            header: "X-Canary",
+        }
+    }
+
+    /// The percentage of requests to send to the canary version, from `0.0` to `100.0`.
+    #[must_use]
+    pub fn percent(mut self, percent: f64) -> Self {
+        self.percent = percent.clamp(0.0, 100.0);
+        self
+    }
+
+    /// The header which picks the version of a request, by a value of `true` or `false`. Defaults to `X-Canary`.
+    #[must_use]
+    pub fn header(mut self, header: &'static str) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Whether to send `req` to the canary version.
+    fn to_canary<State>(&self, req: &Request<State>) -> bool {
+        match req.header(self.header).map(|value| value.last().as_str()) {
+            Some(value) if value.eq_ignore_ascii_case("true") => true,
+            Some(value) if value.eq_ignore_ascii_case("false") => false,
+            _ => random_fraction() * 100.0 < self.percent,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State, S, C> Endpoint<State> for Canary<S, C>
+where
+    State: Clone + Send + Sync + 'static,
+    S: Endpoint<State>,
+    C: Endpoint<State>,
+{
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let to_canary = self.to_canary(&req);
+        let start = Instant::now();
+        let result = if to_canary {
+            self.canary.call(req).await
+        } else {
+            self.stable.call(req).await
+        };
+
+        let server_error = match &result {
+            Ok(res) => res.status().is_server_error(),
+            Err(error) => error.status().is_server_error(),
+        };
+        record_stats(self.name, to_canary, server_error, start.elapsed());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn splits_requests() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: Route<'_, Arc<()>>| {
+            server.at("half").get(
+                Canary::new(
+                    "test_half",
+                    |_| async { Ok("stable") },
+                    |_| async { Err::<String, _>(tide::Error::from_str(500, "canary failed")) },
+                )
+                .percent(50.0),
+            );
+            server.at("none").get(Canary::new(
+                "test_none",
+                |_| async { Ok("stable") },
+                |_| async { Ok("canary") },
+            ));
+        })
+        .await?;
+
+        let mut res = client
+            .get("/api/v1/none")
+            .header("X-Canary", "true")
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "canary");
+        for _ in 0..10 {
+            let mut res = client.get("/api/v1/none").await?;
+            assert_eq!(assert_status(&mut res, 200).await, "stable");
+        }
+        let stats = &canary_stats()["test_none"];
+        assert_eq!((stats.stable.requests, stats.canary.requests), (10, 1));
+
+        for _ in 0..100 {
+            client.get("/api/v1/half").await?;
+        }
+        let stats = &canary_stats()["test_half"];
+        assert_eq!(stats.stable.requests + stats.canary.requests, 100);
+        assert!(stats.canary.requests > 10 && stats.stable.requests > 10);
+        assert_eq!(stats.canary.server_errors, stats.canary.requests);
+        assert_eq!(stats.stable.server_errors, 0);
+        Ok(())
+    }
+}
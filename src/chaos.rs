@@ -0,0 +1,233 @@
+//! Fault injection for resilience testing, such as checking that clients time out, retry, and degrade gracefully when
+//! this service is slow or failing.
+//!
+//! [`ChaosMiddleware`] delays a fraction of requests, and fails a fraction of them with an error, on the routes it is
+//! added to, or on those under its [`paths`][ChaosMiddleware::paths].
+//!
+//! It does nothing unless `CHAOS` is `true`, so that it can be left in place and switched on for game days. In
+//! production, `CHAOS_IN_PRODUCTION` must be `true` as well.
+//!
+//! - Delayed requests are handled after the delay, and have an `X-Chaos: latency` header.
+//! - Failed requests are not handled, and get an error with the code `"injected_fault"` and an `X-Chaos: error` header.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! use preroll::chaos::ChaosMiddleware;
+//! use tide::Route;
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     // With `CHAOS=true`, delay 10% of searches by 2s, and fail 5% of them with a 503.
+//!     let chaos = ChaosMiddleware::new()
+//!         .latency(0.1, Duration::from_secs(2))
+//!         .errors(0.05, 503);
+//!     server.at("search").with(chaos).get(|_| async { Ok("[]") });
+//! }
+//! ```
+
+use std::convert::TryInto;
+use std::env;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::utils::random_fraction;
+use crate::Environment;
+
+/// Middleware which injects latency and errors, as described in the [module docs][self].
+#[derive(Debug, Clone)]
+pub struct ChaosMiddleware {
+    enabled: bool,
+    paths: Vec<String>,
+    latency_rate: f64,
+    latency: Duration,
+    error_rate: f64,
+    error_status: StatusCode,
+}
+
+impl ChaosMiddleware {
+    /// Middleware which injects nothing until [`latency`][Self::latency] or [`errors`][Self::errors] are set, and only
+    /// if enabled via `CHAOS`.
+    pub fn new() -> Self {
+        let enabled = is_enabled(
+            Environment::current(),
+            env::var("CHAOS").ok().as_deref(),
+            env::var("CHAOS_IN_PRODUCTION").ok().as_deref(),
+        );
+        if enabled {
+            log::warn!("Fault injection is enabled, via CHAOS");
+        }
+        Self {
+            enabled,
+            paths: Vec::new(),
+            latency_rate: 0.0,
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            error_status: StatusCode::ServiceUnavailable,
+        }
+    }
+
+    /// Only inject faults into requests whose paths start with one of `paths`, e.g. `/api/v1/search`, rather than
+    /// into every request which reaches the middleware.
+    #[must_use]
+    pub fn paths<I>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Delay a fraction `rate` of requests, from `0.0` to `1.0`, by `latency`.
+    #[must_use]
+    pub fn latency(mut self, rate: f64, latency: Duration) -> Self {
+        self.latency_rate = rate.clamp(0.0, 1.0);
+        self.latency = latency;
+        self
+    }
+
+    /// Fail a fraction `rate` of requests, from `0.0` to `1.0`, with an error with `status`, e.g. `503`.
+    #[must_use]
+    pub fn errors<S>(mut self, rate: f64, status: S) -> Self
+    where
+        S: TryInto<StatusCode>,
+        S::Error: Debug,
+    {
+        self.error_rate = rate.clamp(0.0, 1.0);
+        self.error_status = status.try_into().unwrap_or(StatusCode::ServiceUnavailable);
+        self
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.paths.is_empty()
+            || self
+                .paths
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+impl Default for ChaosMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether fault injection is enabled, from the values of `CHAOS` and `CHAOS_IN_PRODUCTION`.
+fn is_enabled(environment: Environment, chaos: Option<&str>, in_production: Option<&str>) -> bool {
+    let is_true =
+        |value: Option<&str>| value.is_some_and(|value| value.trim().eq_ignore_ascii_case("true"));
+    is_true(chaos) && (!environment.is_production() || is_true(in_production))
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ChaosMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !self.enabled || !self.matches(req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+
+        if random_fraction() < self.error_rate {
+            let error = crate::Error::with_code(
+                "injected_fault",
+                self.error_status,
+                "A fault was injected into the request",
+            );
+            let mut res = tide::Response::new(self.error_status);
+            res.insert_header("X-Chaos", "error");
+            res.set_error(error);
+            return Ok(res);
+        }
+
+        let delayed = random_fraction() < self.latency_rate;
+        if delayed {
+            async_std::task::sleep(self.latency).await;
+        }
+        let mut res = next.run(req).await;
+        if delayed {
+            res.insert_header("X-Chaos", "latency");
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestClientOptions, TestResult};
+
+    #[test]
+    fn is_enabled_by_env() {
+        assert!(is_enabled(Environment::Staging, Some("true"), None));
+        assert!(!is_enabled(Environment::Staging, None, Some("true")));
+        assert!(!is_enabled(Environment::Production, Some("true"), None));
+        assert!(is_enabled(
+            Environment::Production,
+            Some("TRUE"),
+            Some("true")
+        ));
+    }
+
+    #[async_std::test]
+    async fn injects_faults() -> TestResult<()> {
+        let chaos = ChaosMiddleware {
+            enabled: true,
+            ..ChaosMiddleware::new()
+        };
+        let options = TestClientOptions::new().with(
+            chaos
+                .clone()
+                .paths(["/api/v1/fail"])
+                .errors(1.0, StatusCode::BadGateway),
+        );
+        let options = options.with(
+            chaos
+                .paths(["/api/v1/slow"])
+                .latency(1.0, Duration::from_millis(50)),
+        );
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: Route<'_, Arc<()>>| {
+                for path in ["fail", "slow", "fine"] {
+                    server.at(path).get(|_| async { Ok("handled") });
+                }
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.get("/api/v1/fail").await?;
+        assert_eq!(
+            res.header("X-Chaos").map(|v| v.last().as_str()),
+            Some("error")
+        );
+        assert!(assert_status(&mut res, 502)
+            .await
+            .contains("injected_fault"));
+
+        let start = Instant::now();
+        let mut res = client.get("/api/v1/slow").await?;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            res.header("X-Chaos").map(|v| v.last().as_str()),
+            Some("latency")
+        );
+        assert_eq!(assert_status(&mut res, 200).await, "handled");
+
+        let mut res = client.get("/api/v1/fine").await?;
+        assert!(res.header("X-Chaos").is_none());
+        assert_eq!(assert_status(&mut res, 200).await, "handled");
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+//! Command-line arguments for the binary produced by `preroll::main!`.
+
+use std::convert::Infallible;
+use std::env;
+use std::path::PathBuf;
+
+use color_eyre::eyre::{eyre, WrapErr};
+
+use crate::setup::Result;
+
+/// The subcommand the binary was started with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Run the server. The default.
+    #[default]
+    Serve,
+    /// Run pending database migrations and exit.
+    Migrate,
+    /// Print the mounted route table and exit.
+    Routes,
+    /// Validate configuration and dependency connectivity, and exit.
+    Check,
+}
+
+/// The command-line arguments accepted by `preroll::main!`, which override their environment variable equivalents.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Args {
+    pub(crate) command: Command,
+    pub(crate) help: bool,
+    pub(crate) port: Option<u16>,
+    pub(crate) host: Option<String>,
+    pub(crate) config: Option<PathBuf>,
+    pub(crate) log_level: Option<log::LevelFilter>,
+}
+
+impl Args {
+    /// Parse the arguments the process was started with.
+    pub(crate) fn from_env() -> Result<Self> {
+        Self::parse(pico_args::Arguments::from_env())
+    }
+
+    fn parse(mut args: pico_args::Arguments) -> Result<Self> {
+        let command = match args.subcommand()?.as_deref() {
+            None | Some("serve") => Command::Serve,
+            Some("migrate") => Command::Migrate,
+            Some("routes") => Command::Routes,
+            Some("check") => Command::Check,
+            Some(other) => {
+                return Err(eyre!(
+                    "Unknown command \"{}\". See --help for usage.",
+                    other
+                ))
+            }
+        };
+
+        let parsed = Self {
+            command,
+            help: args.contains(["-h", "--help"]),
+            port: args
+                .opt_value_from_str("--port")
+                .wrap_err("Invalid --port")?,
+            host: args.opt_value_from_str("--host")?,
+            config: args.opt_value_from_os_str("--config", |value| {
+                Ok::<_, Infallible>(PathBuf::from(value))
+            })?,
+            log_level: args
+                .opt_value_from_str("--log-level")
+                .wrap_err("Invalid --log-level")?,
+        };
+
+        let remaining = args.finish();
+        if !remaining.is_empty() {
+            return Err(eyre!(
+                "Unexpected argument(s): {:?}. See --help for usage.",
+                remaining
+            ));
+        }
+
+        Ok(parsed)
+    }
+
+    /// Load the `--config` file, and then apply the overrides to the environment, which the rest of setup reads from.
+    pub(crate) fn apply(&self) -> Result<()> {
+        if let Some(config) = &self.config {
+            dotenv::from_path(config).map_err(|e| {
+                eyre!(
+                    "Could not load --config file \"{}\": {}",
+                    config.display(),
+                    e
+                )
+            })?;
+        }
+
+        if let Some(port) = self.port {
+            env::set_var("PORT", port.to_string());
+        }
+        if let Some(host) = &self.host {
+            env::set_var("HOST", host);
+        }
+        if let Some(log_level) = self.log_level {
+            env::set_var("LOGLEVEL", log_level.to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// The `routes` output: the route prefixes mounted by `preroll::main!`.
+///
+/// Routes added by the custom setup function, or as admin routes, are not known, and are not listed.
+pub(crate) fn route_table(routes: &[(String, &str)], admin_listener: bool) -> String {
+    let monitor = if admin_listener {
+        "GET, preroll builtin (admin listener only)"
+    } else {
+        "GET, preroll builtin"
+    };
+    let mut table = vec![
+        ("/monitor/ping".to_string(), monitor),
+        ("/monitor/status".to_string(), monitor),
+    ];
+    for (prefix, name) in routes {
+        table.push((format!("{}/*", prefix), name));
+    }
+    #[cfg(debug_assertions)]
+    table.push((
+        "/internal-error".to_string(),
+        "GET, preroll builtin (debug builds only)",
+    ));
+
+    let mut output = String::new();
+    for (path, source) in table {
+        output.push_str(&format!("{:<24} {}\n", path, source));
+    }
+    output.push_str(
+        "\nRoutes added by the custom setup function, or as admin routes, are not listed.",
+    );
+    output
+}
+
+/// The `--help` output.
+pub(crate) fn usage(service_name: &str) -> String {
+    format!(
+        "{service_name}
+
+USAGE:
+    {service_name} [COMMAND] [OPTIONS]
+
+COMMANDS:
+    serve                    Run the server. The default.
+    migrate                  Run pending database migrations from PGMIGRATIONS, and exit.
+    routes                   Print the mounted route table, and exit.
+    check                    Validate configuration and connectivity to dependencies, and exit.
+
+OPTIONS:
+    --port <PORT>            The port to listen on. Overrides PORT.
+    --host <HOST>            The hostname to listen on. Overrides HOST.
+    --config <FILE>          Load environment variables from a .env-format file.
+                             Variables which are already set take precedence.
+    --log-level <LEVEL>      The log level filter, e.g. info or debug. Overrides LOGLEVEL.
+    -h, --help               Print this help and exit.",
+        service_name = service_name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(pico_args::Arguments::from_vec(
+            args.iter().map(Into::into).collect(),
+        ))
+    }
+
+    #[test]
+    fn parses_all_options() -> Result<()> {
+        assert_eq!(
+            parse(&[
+                "--port",
+                "3000",
+                "--host=0.0.0.0",
+                "--config",
+                "service.env",
+                "--log-level",
+                "warn"
+            ])?,
+            Args {
+                command: Command::Serve,
+                help: false,
+                port: Some(3000),
+                host: Some("0.0.0.0".to_string()),
+                config: Some(PathBuf::from("service.env")),
+                log_level: Some(log::LevelFilter::Warn),
+            }
+        );
+        assert_eq!(parse(&[])?, Args::default());
+        Ok(())
+    }
+
+    #[test]
+    fn lists_routes_by_version() {
+        let table = route_table(
+            &[
+                ("/api/v1".to_string(), "service::routes_v1"),
+                ("/api/v2".to_string(), "service::routes_v2"),
+            ],
+            false,
+        );
+        assert!(table.contains("/monitor/ping            GET, preroll builtin\n"));
+        assert!(table.contains("/api/v1/*                service::routes_v1\n"));
+        assert!(table.contains("/api/v2/*                service::routes_v2\n"));
+
+        let table = route_table(&[], true);
+        assert!(
+            table.contains("/monitor/ping            GET, preroll builtin (admin listener only)\n")
+        );
+    }
+
+    #[test]
+    fn parses_commands() -> Result<()> {
+        assert_eq!(parse(&["serve"])?.command, Command::Serve);
+        assert_eq!(parse(&["migrate"])?.command, Command::Migrate);
+        assert_eq!(parse(&["routes"])?.command, Command::Routes);
+
+        let args = parse(&["check", "--port", "3000"])?;
+        assert_eq!(args.command, Command::Check);
+        assert_eq!(args.port, Some(3000));
+
+        assert!(parse(&["deploy"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_and_unknown_arguments() {
+        assert!(parse(&["--port", "http"]).is_err());
+        assert!(parse(&["--log-level", "loud"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+}
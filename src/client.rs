@@ -0,0 +1,442 @@
+//! A pre-configured client for outgoing HTTP requests, so that every service's calls to other services behave consistently.
+//!
+//! Clients from [`client::new`][new] have:
+//! - The base url, which relative request urls are joined to.
+//! - A `User-Agent` of `{service}/{version}`, from the [service metadata][crate::ServiceMetadata].
+//! - A timeout, from `CLIENT_TIMEOUT` in seconds. Defaults to `30`.
+//! - The `X-Request-Id` of the request being handled, if called from within a request handler.
+//! - Logging of every request, at `debug`, and of every response, at `info`, or `warn` for errors.
+//! - With the `"honeycomb"` feature, a tracing span for every request, propagated via `X-Honeycomb-Trace`.
+//! - Request counts, error counts, and latency histograms for each downstream host, reported under `clients` in
+//!   `/monitor/status`.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::SetupResult;
+//! use tide::Request;
+//!
+//! # #[allow(dead_code)]
+//! struct AppState {
+//!     accounts: surf::Client,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_app_state() -> SetupResult<AppState> {
+//!     Ok(AppState {
+//!         accounts: preroll::client::new("http://accounts.internal/api/v1/")?,
+//!     })
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn get_account(req: Request<Arc<AppState>>) -> tide::Result<String> {
+//!     // Requests http://accounts.internal/api/v1/accounts/{id}, with this request's X-Request-Id.
+//!     let path = format!("accounts/{}", req.param("id")?);
+//!     Ok(req.state().accounts.get(path).recv_string().await?)
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use cfg_if::cfg_if;
+use color_eyre::eyre::{eyre, WrapErr};
+use kv_log_macro::{debug, info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use surf::http::headers::USER_AGENT;
+use surf::http::Method;
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Config, Request, Response, Url};
+
+use crate::metadata::service_metadata;
+use crate::middleware::requestid::current_request_id;
+use crate::setup::Result;
+
+cfg_if! {
+    if #[cfg(feature = "honeycomb")] {
+        use tracing_futures::Instrument;
+
+        use crate::middleware::honeycomb::propagation::{Propagation, PROPAGATION_HTTP_HEADER};
+    }
+}
+
+static STATS: Lazy<Mutex<BTreeMap<String, ClientStats>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The upper bounds of the latency buckets of [`ClientStats`], in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// The outgoing requests to a downstream host, as reported in `/monitor/status`.
+///
+/// All counts are totals since the process started, so that the rates and latencies of any period are the difference
+/// between two reports of it, as monitoring systems compute them.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClientStats {
+    requests: u64,
+    /// Responses with a `4xx` status.
+    client_errors: u64,
+    /// Responses with a `5xx` status.
+    server_errors: u64,
+    /// Requests which got no response, e.g. due to a timeout or a refused connection.
+    failures: u64,
+    /// A histogram of latencies: how many requests took at most each bucket's `le` seconds.
+    latency_buckets: Vec<LatencyBucket>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LatencyBucket {
+    /// Seconds.
+    le: f64,
+    count: u64,
+}
+
+impl Default for ClientStats {
+    fn default() -> Self {
+        Self {
+            requests: 0,
+            client_errors: 0,
+            server_errors: 0,
+            failures: 0,
+            latency_buckets: LATENCY_BUCKETS
+                .iter()
+                .map(|&le| LatencyBucket { le, count: 0 })
+                .collect(),
+        }
+    }
+}
+
+fn record_stats(host: String, result: &surf::Result<Response>, latency: Duration) {
+    let mut stats = STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let stats = stats.entry(host).or_default();
+
+    stats.requests += 1;
+    match result {
+        Ok(res) if res.status().is_client_error() => stats.client_errors += 1,
+        Ok(res) if res.status().is_server_error() => stats.server_errors += 1,
+        Ok(_) => {}
+        Err(_) => stats.failures += 1,
+    }
+
+    let latency = latency.as_secs_f64();
+    for bucket in stats
+        .latency_buckets
+        .iter_mut()
+        .filter(|bucket| latency <= bucket.le)
+    {
+        bucket.count += 1;
+    }
+}
+
+/// The outgoing request stats of all clients, by downstream host.
+pub(crate) fn client_stats() -> BTreeMap<String, ClientStats> {
+    STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+/// Create a client for outgoing requests to `base_url`, with the behavior described in the [module docs][self].
+///
+/// The base url should end with a `/`, so that relative request urls are joined below it.
+pub fn new(base_url: &str) -> Result<Client> {
+    with_config(Config::new(), base_url)
+}
+
+/// Create a client from a surf `Config`, such as one with a custom http client, with preroll's defaults on top.
+pub(crate) fn with_config(config: Config, base_url: &str) -> Result<Client> {
+    with_options(config, base_url, default_timeout()?, 0)
+}
+
+/// The timeout of outgoing requests, from `CLIENT_TIMEOUT` in seconds. Defaults to `30`.
+pub(crate) fn default_timeout() -> Result<Duration> {
+    let timeout: u64 = env::var("CLIENT_TIMEOUT")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(30))
+        .wrap_err("CLIENT_TIMEOUT must be a number of seconds")?;
+
+    Ok(Duration::from_secs(timeout))
+}
+
+/// Create a client with a timeout, and which retries failed `GET`, `HEAD`, and `OPTIONS` requests up to `retries` times.
+pub(crate) fn with_options(
+    config: Config,
+    base_url: &str,
+    timeout: Duration,
+    retries: u32,
+) -> Result<Client> {
+    let base_url = Url::parse(base_url)
+        .wrap_err_with(|| format!("Invalid client base url: \"{}\"", base_url))?;
+    let metadata = service_metadata();
+
+    let client: Client = config
+        .set_base_url(base_url)
+        .set_timeout(Some(timeout))
+        .add_header(
+            USER_AGENT,
+            format!("{}/{}", metadata.service, metadata.version),
+        )
+        .map_err(|error| eyre!("Invalid User-Agent for the client: {}", error))?
+        .try_into()?;
+
+    // Retries are outermost, so that each attempt is logged, traced, and counted.
+    Ok(client
+        .with(RetryMiddleware { retries })
+        .with(ClientMiddleware))
+}
+
+/// Retry requests which got no response or a `5xx`, with exponential backoff from 100ms.
+///
+/// Only methods without a body are retried, as surf requests cannot be cloned with their body.
+#[derive(Debug)]
+struct RetryMiddleware {
+    retries: u32,
+}
+
+#[surf::utils::async_trait]
+impl Middleware for RetryMiddleware {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        if self.retries == 0
+            || !matches!(req.method(), Method::Get | Method::Head | Method::Options)
+        {
+            return next.run(req, client).await;
+        }
+
+        let mut attempt = 0;
+        loop {
+            let result = next.run(req.clone(), client.clone()).await;
+            let retryable = match &result {
+                Ok(res) => res.status().is_server_error(),
+                Err(_) => true,
+            };
+            if !retryable || attempt >= self.retries {
+                return result;
+            }
+
+            attempt += 1;
+            log::debug!(
+                "Retrying {} {}, attempt {}",
+                req.method(),
+                req.url(),
+                attempt
+            );
+            async_std::task::sleep(Duration::from_millis(100 << (attempt - 1).min(10))).await;
+        }
+    }
+}
+
+/// Propagate the request id and trace, and log and trace every outgoing request.
+#[derive(Debug)]
+struct ClientMiddleware;
+
+#[surf::utils::async_trait]
+impl Middleware for ClientMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        client: Client,
+        next: Next<'_>,
+    ) -> surf::Result<Response> {
+        if req.header("X-Request-Id").is_none() {
+            if let Some(request_id) = current_request_id() {
+                req.insert_header("X-Request-Id", request_id.as_str());
+            }
+        }
+
+        let method = req.method();
+        let url = req.url().to_string();
+        let host = match (req.url().host_str(), req.url().port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => "(no host)".to_string(),
+        };
+        let request_id = req
+            .header("X-Request-Id")
+            .map(|id| id.last().to_string())
+            .unwrap_or_default();
+
+        debug!("Outgoing Request", {
+            method: method.as_ref(),
+            url: url,
+            request_id: request_id,
+        });
+
+        let start = Instant::now();
+
+        cfg_if! {
+            if #[cfg(feature = "honeycomb")] {
+                let span = tracing::info_span!(
+                    "HTTP Client Request",
+                    method = method.as_ref(),
+                    url = url.as_str()
+                );
+                let result = async move {
+                    if let Ok((trace_id, span_id)) = tracing_honeycomb::current_dist_trace_ctx() {
+                        let propagation = Propagation {
+                            trace_id: trace_id.to_string(),
+                            parent_id: span_id.to_string(),
+                            dataset: String::new(),
+                            trace_context: serde_json::json!({}),
+                        };
+                        req.insert_header(PROPAGATION_HTTP_HEADER, propagation.marshal_trace_context());
+                    }
+
+                    let result = next.run(req, client).await;
+                    match &result {
+                        Ok(res) => tracing::info!(status = res.status() as u16, "HTTP Client Response"),
+                        Err(error) => tracing::warn!(error = %error, "HTTP Client Error"),
+                    }
+                    result
+                }
+                .instrument(span)
+                .await;
+            } else {
+                let result = next.run(req, client).await;
+            }
+        }
+
+        let latency = start.elapsed();
+        record_stats(host, &result, latency);
+
+        let elapsed = format!("{:?}", latency);
+        match &result {
+            Ok(res) if res.status().is_client_error() || res.status().is_server_error() => {
+                warn!("Outgoing Response: {}", res.status().canonical_reason(), {
+                    status: res.status() as u16,
+                    method: method.as_ref(),
+                    url: url,
+                    request_id: request_id,
+                    elapsed: elapsed,
+                });
+            }
+            Ok(res) => {
+                info!("Outgoing Response", {
+                    status: res.status() as u16,
+                    method: method.as_ref(),
+                    url: url,
+                    request_id: request_id,
+                    elapsed: elapsed,
+                });
+            }
+            Err(error) => {
+                warn!("Outgoing Request Failed", {
+                    method: method.as_ref(),
+                    url: url,
+                    message: format!("{:?}", error),
+                    request_id: request_id,
+                    elapsed: elapsed,
+                });
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tide::{Request as TideRequest, Response, Route};
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn propagates_request_ids() -> TestResult<()> {
+        let mut downstream = tide::new();
+        downstream
+            .at("/api/v1/echo")
+            .get(|req: TideRequest<()>| async move {
+                let header = |name| {
+                    req.header(name)
+                        .map(|values| values.last().to_string())
+                        .unwrap_or_default()
+                };
+                Ok(format!(
+                    "{} {}",
+                    header("X-Request-Id"),
+                    header("User-Agent")
+                ))
+            });
+        let client = with_config(
+            Config::new().set_http_client(downstream),
+            "http://downstream/api/v1/",
+        )
+        .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+
+        let client = test_utils::create_client(client, |mut server: Route<'_, Arc<Client>>| {
+            server
+                .at("proxy")
+                .get(|req: TideRequest<Arc<Client>>| async move {
+                    req.state().get("echo").recv_string().await
+                });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/proxy").await?;
+        let request_id = res
+            .header("X-Request-Id")
+            .map(|id| id.last().to_string())
+            .unwrap_or_default();
+        let body = assert_status(&mut res, 200).await;
+        assert_eq!(
+            body,
+            format!(
+                "{} {}/{}",
+                request_id,
+                service_metadata().service,
+                service_metadata().version
+            )
+        );
+
+        let stats = client_stats();
+        assert!(stats["downstream"].requests >= 1);
+        assert_eq!(stats["downstream"].failures, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn retries_server_errors() -> TestResult<()> {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut downstream = tide::with_state(attempts.clone());
+        downstream
+            .at("/flaky")
+            .get(|req: TideRequest<Arc<AtomicUsize>>| async move {
+                match req.state().fetch_add(1, Ordering::SeqCst) {
+                    0 => Ok(Response::new(503)),
+                    _ => Ok(Response::new(200)),
+                }
+            })
+            .post(|req: TideRequest<Arc<AtomicUsize>>| async move {
+                req.state().fetch_add(1, Ordering::SeqCst);
+                Ok(Response::new(503))
+            });
+        let client = with_options(
+            Config::new().set_http_client(downstream),
+            "http://flaky/",
+            Duration::from_secs(1),
+            2,
+        )
+        .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+
+        let res = client.get("flaky").await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // Requests with a body are not retried.
+        attempts.store(0, Ordering::SeqCst);
+        let res = client.post("flaky").await?;
+        assert_eq!(res.status(), 503);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! The context of a request, via [`ContextRequestExt::context`][crate::prelude::ContextRequestExt::context].
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use tide::{Endpoint, Request};
+
+use crate::middleware::extension_types::{CorrelationId, Principal, RequestId, Tenant};
+
+/// Who a request is from and what it is for, bundled for handlers to pass to helpers as one argument, rather than
+/// looking up each extension and header themselves.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::prelude::*;
+/// use preroll::RequestContext;
+/// use tide::Request;
+///
+/// fn greeting(context: &RequestContext) -> String {
+///     match (&context.principal, context.locale.as_deref()) {
+///         (Some(principal), Some(locale)) if locale.starts_with("fr") => format!("Bonjour, {}", principal),
+///         (Some(principal), _) => format!("Hello, {}", principal),
+///         (None, _) => "Hello".to_string(),
+///     }
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn greet(req: Request<()>) -> tide::Result<String> {
+///     Ok(greeting(&req.context()))
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct RequestContext {
+    /// The id of the request, from [`RequestIdMiddleware`][crate::middleware::RequestIdMiddleware].
+    pub request_id: Option<RequestId>,
+    /// The correlation id of the request, if middleware inserted one.
+    pub correlation_id: Option<CorrelationId>,
+    /// The pattern of the route which matched the request, e.g. `/api/v1/users/:id`, for routes mounted via
+    /// [`OpenApiRouteExt::documented`][crate::prelude::OpenApiRouteExt::documented].
+    pub route: Option<String>,
+    /// The IP address of the client, from the `Forwarded` or `X-Forwarded-For` headers, or else the peer address.
+    pub client_ip: Option<IpAddr>,
+    /// The authenticated caller, if authentication middleware inserted one.
+    pub principal: Option<Principal>,
+    /// The tenant the request is for, if middleware inserted one.
+    pub tenant: Option<Tenant>,
+    /// The client's most preferred language, from `Accept-Language`, e.g. `"en-US"`.
+    pub locale: Option<String>,
+}
+
+/// An extension trait for the [`RequestContext`] of a request.
+pub trait ContextRequestExt {
+    /// The context of the request, as it is when called.
+    fn context(&self) -> RequestContext;
+}
+
+impl<State> ContextRequestExt for Request<State> {
+    fn context(&self) -> RequestContext {
+        RequestContext {
+            request_id: self.ext::<RequestId>().cloned(),
+            correlation_id: self.ext::<CorrelationId>().cloned(),
+            route: self.ext::<MatchedRoute>().map(|route| route.0.to_string()),
+            client_ip: self.remote().and_then(parse_ip),
+            principal: self.ext::<Principal>().cloned(),
+            tenant: self.ext::<Tenant>().cloned(),
+            locale: self
+                .header("Accept-Language")
+                .and_then(|header| preferred_language(header.last().as_str())),
+        }
+    }
+}
+
+/// The pattern of the route which matched a request.
+#[derive(Debug, Clone)]
+pub(crate) struct MatchedRoute(pub(crate) Arc<str>);
+
+/// An endpoint which records the pattern of its route on each request.
+pub(crate) struct WithMatchedRoute<E> {
+    pub(crate) route: Arc<str>,
+    pub(crate) endpoint: E,
+}
+
+#[tide::utils::async_trait]
+impl<State, E> Endpoint<State> for WithMatchedRoute<E>
+where
+    State: Clone + Send + Sync + 'static,
+    E: Endpoint<State>,
+{
+    async fn call(&self, mut req: Request<State>) -> tide::Result {
+        req.set_ext(MatchedRoute(self.route.clone()));
+        self.endpoint.call(req).await
+    }
+}
+
+/// An IP address, with or without a port, e.g. `203.0.113.7` or `[2001:db8::1]:443`.
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    addr.parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| addr.trim_matches(|c| c == '[' || c == ']').parse())
+        .ok()
+}
+
+/// The language with the highest quality in an `Accept-Language` header, or the first of those tied for it.
+fn preferred_language(header: &str) -> Option<String> {
+    let mut best: Option<(&str, f32)> = None;
+    for entry in header.split(',') {
+        let mut parts = entry.split(';');
+        let language = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if language.is_empty() || language == "*" || quality <= 0.0 {
+            continue;
+        }
+        if best.is_none_or(|(_, best)| quality > best) {
+            best = Some((language, quality));
+        }
+    }
+    best.map(|(language, _)| language.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::http::Method;
+    use tide::{Middleware, Next};
+
+    use super::*;
+    use crate::openapi::Operation;
+    use crate::prelude::*;
+    use crate::test_utils::{self, assert_status, TestClientOptions, TestResult};
+
+    #[test]
+    fn parses_headers() {
+        assert_eq!(
+            preferred_language("fr;q=0.8, en-US, *;q=0.1").as_deref(),
+            Some("en-US")
+        );
+        assert_eq!(
+            preferred_language("de;q=0.5, fr;q=0.9, es;q=0.9").as_deref(),
+            Some("fr")
+        );
+        assert_eq!(preferred_language("*"), None);
+
+        assert_eq!(parse_ip("203.0.113.7"), "203.0.113.7".parse().ok());
+        assert_eq!(parse_ip("203.0.113.7:8080"), "203.0.113.7".parse().ok());
+        assert_eq!(parse_ip("[2001:db8::1]:443"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_ip("_hidden"), None);
+    }
+
+    struct Authenticate;
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Authenticate {
+        async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            req.set_ext(Principal::new("ada").with_scopes(["users:read"]));
+            req.set_ext(Tenant::new("acme"));
+            Ok(next.run(req).await)
+        }
+    }
+
+    #[async_std::test]
+    async fn bundles_request_context() -> TestResult<()> {
+        let options = TestClientOptions::new().with(Authenticate);
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: tide::Route<'_, Arc<()>>| {
+                server.at("users/:id").documented(
+                    Method::Get,
+                    Operation::new("Get a user"),
+                    |req: Request<Arc<()>>| async move {
+                        let context = req.context();
+                        Ok(format!(
+                            "{} {:?} {:?} {:?} {:?} {}",
+                            context.principal.map(|p| p.to_string()).unwrap_or_default(),
+                            context.tenant.map(|t| t.to_string()),
+                            context.route,
+                            context.client_ip,
+                            context.locale,
+                            context.request_id.is_some(),
+                        ))
+                    },
+                );
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client
+            .get("/api/v1/users/7")
+            .header("X-Forwarded-For", "203.0.113.7, 10.0.0.1")
+            .header("Accept-Language", "en-NZ, en;q=0.9")
+            .await?;
+        assert_eq!(
+            assert_status(&mut res, 200).await,
+            r#"ada Some("acme") Some("/api/v1/users/:id") Some(203.0.113.7) Some("en-NZ") true"#
+        );
+        Ok(())
+    }
+}
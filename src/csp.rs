@@ -0,0 +1,372 @@
+//! A typed `Content-Security-Policy`, with a nonce per request for inline scripts and styles.
+//!
+//! Add a [`ContentSecurityPolicy`] as middleware to the routes which serve HTML, or to the whole server, and read the
+//! nonce of each request via [`CspRequestExt::csp_nonce`][crate::prelude::CspRequestExt::csp_nonce] to put on its
+//! inline `<script>` and `<style>` tags.
+//!
+//! - Hosts and schemes are percent-encoded where they would otherwise break the policy, such as at `;` or `,`, so that a
+//!   host from configuration cannot add directives of its own.
+//! - A nonce is only generated for requests whose policy uses [`Source::Nonce`], and is 128 random bits as hex.
+//! - Responses which already have a `Content-Security-Policy` header keep it.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::csp::{ContentSecurityPolicy, Source};
+//! use preroll::prelude::*;
+//! use tide::{Request, Response, Route};
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     // `default-src 'self'; script-src 'nonce-...' 'strict-dynamic'; object-src 'none'; base-uri 'none';
+//!     // frame-ancestors 'none'; connect-src 'self' https://api.example.com`
+//!     let csp = ContentSecurityPolicy::strict()
+//!         .connect_src([Source::SelfOrigin, Source::host("https://api.example.com")]);
+//!
+//!     server.at("dashboard").with(csp).get(|req: Request<Arc<()>>| async move {
+//!         let nonce = req.csp_nonce().unwrap_or_default();
+//!         Ok(Response::builder(200)
+//!             .content_type(tide::http::mime::HTML)
+//!             .body(format!(r#"<script nonce="{}">start()</script>"#, nonce))
+//!             .build())
+//!     });
+//! }
+//! ```
+
+use std::fmt::Write;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use tide::{Middleware, Next, Request};
+use uuid::Uuid;
+
+/// Characters which would end a source, or the directive it is in.
+const SOURCE_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b';').add(b',').add(b'\'').add(b'"');
+
+/// A source of content in a [`ContentSecurityPolicy`] directive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Source {
+    /// `'self'`, the origin of the page.
+    SelfOrigin,
+    /// `'none'`, which allows nothing.
+    None,
+    /// `'nonce-...'`, with the nonce of each request.
+    Nonce,
+    /// `'strict-dynamic'`, which trusts scripts loaded by nonced scripts.
+    StrictDynamic,
+    /// `'unsafe-inline'`, which allows any inline script or style.
+    UnsafeInline,
+    /// `'unsafe-eval'`, which allows `eval()`.
+    UnsafeEval,
+    /// A host, e.g. `https://cdn.example.com` or `*.example.com`.
+    Host(String),
+    /// A scheme, e.g. `https:` or `data:`.
+    Scheme(String),
+}
+
+impl Source {
+    /// A host, e.g. `https://cdn.example.com` or `*.example.com`.
+    pub fn host(host: impl AsRef<str>) -> Self {
+        Self::Host(utf8_percent_encode(host.as_ref(), SOURCE_ENCODE_SET).to_string())
+    }
+
+    /// A scheme, e.g. `"https"` or `"data"`, with or without its `:`.
+    pub fn scheme(scheme: impl AsRef<str>) -> Self {
+        let scheme = scheme.as_ref().trim_end_matches(':');
+        Self::Scheme(format!(
+            "{}:",
+            utf8_percent_encode(scheme, SOURCE_ENCODE_SET)
+        ))
+    }
+
+    fn write(&self, policy: &mut String, nonce: Option<&str>) {
+        match self {
+            Self::SelfOrigin => policy.push_str("'self'"),
+            Self::None => policy.push_str("'none'"),
+            Self::Nonce => {
+                let _ = write!(policy, "'nonce-{}'", nonce.unwrap_or_default());
+            }
+            Self::StrictDynamic => policy.push_str("'strict-dynamic'"),
+            Self::UnsafeInline => policy.push_str("'unsafe-inline'"),
+            Self::UnsafeEval => policy.push_str("'unsafe-eval'"),
+            Self::Host(value) | Self::Scheme(value) => policy.push_str(value),
+        }
+    }
+}
+
+/// A `Content-Security-Policy` for responses, as middleware.
+///
+/// See [`preroll::csp`][crate::csp] for an example.
+#[derive(Debug, Clone, Default)]
+pub struct ContentSecurityPolicy {
+    directives: Vec<(&'static str, Vec<Source>)>,
+    upgrade_insecure_requests: bool,
+    report_uri: Option<String>,
+    report_only: bool,
+}
+
+impl ContentSecurityPolicy {
+    /// An empty policy, which allows everything.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A strict policy, which allows content from the page's origin, and only scripts with the request's nonce or
+    /// loaded by them.
+    ///
+    /// `default-src 'self'; script-src 'nonce-...' 'strict-dynamic'; object-src 'none'; base-uri 'none';
+    /// frame-ancestors 'none'`
+    #[must_use]
+    pub fn strict() -> Self {
+        Self::new()
+            .default_src([Source::SelfOrigin])
+            .script_src([Source::Nonce, Source::StrictDynamic])
+            .object_src([Source::None])
+            .base_uri([Source::None])
+            .frame_ancestors([Source::None])
+    }
+
+    /// `default-src`, for content without a directive of its own.
+    #[must_use]
+    pub fn default_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("default-src", sources)
+    }
+
+    /// `script-src`, for scripts.
+    #[must_use]
+    pub fn script_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("script-src", sources)
+    }
+
+    /// `style-src`, for stylesheets and inline styles.
+    #[must_use]
+    pub fn style_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("style-src", sources)
+    }
+
+    /// `connect-src`, for `fetch()`, XHR, WebSockets, and `EventSource`s.
+    #[must_use]
+    pub fn connect_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("connect-src", sources)
+    }
+
+    /// `img-src`, for images.
+    #[must_use]
+    pub fn img_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("img-src", sources)
+    }
+
+    /// `font-src`, for fonts.
+    #[must_use]
+    pub fn font_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("font-src", sources)
+    }
+
+    /// `object-src`, for `<object>` and `<embed>`.
+    #[must_use]
+    pub fn object_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("object-src", sources)
+    }
+
+    /// `base-uri`, for `<base>`.
+    #[must_use]
+    pub fn base_uri(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("base-uri", sources)
+    }
+
+    /// `form-action`, for where forms may be submitted to.
+    #[must_use]
+    pub fn form_action(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("form-action", sources)
+    }
+
+    /// `frame-ancestors`, for the pages which may embed this one.
+    #[must_use]
+    pub fn frame_ancestors(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("frame-ancestors", sources)
+    }
+
+    /// `upgrade-insecure-requests`, which loads `http:` URLs via `https:` instead.
+    #[must_use]
+    pub fn upgrade_insecure_requests(mut self) -> Self {
+        self.upgrade_insecure_requests = true;
+        self
+    }
+
+    /// `report-uri`, where browsers report violations to.
+    #[must_use]
+    pub fn report_uri(mut self, uri: impl AsRef<str>) -> Self {
+        self.report_uri = Some(utf8_percent_encode(uri.as_ref(), SOURCE_ENCODE_SET).to_string());
+        self
+    }
+
+    /// Send the policy as `Content-Security-Policy-Report-Only`, so that violations are reported but not blocked, e.g.
+    /// while rolling a policy out.
+    #[must_use]
+    pub fn report_only(mut self) -> Self {
+        self.report_only = true;
+        self
+    }
+
+    /// Set `name` to `sources`, replacing any sources it had.
+    fn directive(mut self, name: &'static str, sources: impl IntoIterator<Item = Source>) -> Self {
+        let sources = sources.into_iter().collect();
+        match self.directives.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => *existing = sources,
+            None => self.directives.push((name, sources)),
+        }
+        self
+    }
+
+    fn uses_nonce(&self) -> bool {
+        self.directives
+            .iter()
+            .any(|(_, sources)| sources.contains(&Source::Nonce))
+    }
+
+    fn header_name(&self) -> &'static str {
+        if self.report_only {
+            "Content-Security-Policy-Report-Only"
+        } else {
+            "Content-Security-Policy"
+        }
+    }
+
+    /// The policy as a header value, with `nonce` for [`Source::Nonce`].
+    fn render(&self, nonce: Option<&str>) -> String {
+        fn separate(policy: &mut String) {
+            if !policy.is_empty() {
+                policy.push_str("; ");
+            }
+        }
+
+        let mut policy = String::new();
+        for (name, sources) in &self.directives {
+            separate(&mut policy);
+            policy.push_str(name);
+            for source in sources {
+                policy.push(' ');
+                source.write(&mut policy, nonce);
+            }
+        }
+        if self.upgrade_insecure_requests {
+            separate(&mut policy);
+            policy.push_str("upgrade-insecure-requests");
+        }
+        if let Some(uri) = &self.report_uri {
+            separate(&mut policy);
+            policy.push_str("report-uri ");
+            policy.push_str(uri);
+        }
+        policy
+    }
+}
+
+/// The nonce of a request's [`ContentSecurityPolicy`].
+#[derive(Debug, Clone)]
+struct CspNonce(String);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ContentSecurityPolicy {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let nonce = if self.uses_nonce() {
+            let nonce = Uuid::new_v4().to_simple().to_string();
+            req.set_ext(CspNonce(nonce.clone()));
+            Some(nonce)
+        } else {
+            None
+        };
+
+        let mut res = next.run(req).await;
+        if res.header(self.header_name()).is_none() {
+            res.insert_header(self.header_name(), self.render(nonce.as_deref()));
+        }
+        Ok(res)
+    }
+}
+
+/// An extension trait for the `Content-Security-Policy` nonce of a request.
+pub trait CspRequestExt {
+    /// The nonce of the request, if its [`ContentSecurityPolicy`] uses [`Source::Nonce`].
+    fn csp_nonce(&self) -> Option<&str>;
+}
+
+impl<State> CspRequestExt for Request<State> {
+    fn csp_nonce(&self) -> Option<&str> {
+        self.ext::<CspNonce>().map(|nonce| nonce.0.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn sets_policy_with_nonce() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server
+                .at("page")
+                .with(
+                    ContentSecurityPolicy::strict()
+                        .connect_src([
+                            Source::SelfOrigin,
+                            Source::host("https://api.example.com; script-src *"),
+                        ])
+                        .img_src([Source::SelfOrigin, Source::scheme("data")])
+                        .report_uri("/csp-reports"),
+                )
+                .get(|req: Request<Arc<()>>| async move {
+                    Ok(req.csp_nonce().unwrap_or_default().to_string())
+                });
+            server
+                .at("report-only")
+                .with(
+                    ContentSecurityPolicy::new()
+                        .default_src([Source::SelfOrigin])
+                        .upgrade_insecure_requests()
+                        .report_only(),
+                )
+                .get(|req: Request<Arc<()>>| async move { Ok(format!("{:?}", req.csp_nonce())) });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/page").await?;
+        let policy = res
+            .header("Content-Security-Policy")
+            .map(|policy| policy.as_str().to_string());
+        let nonce = assert_status(&mut res, 200).await;
+        assert_eq!(nonce.len(), 32);
+        assert_eq!(
+            policy.as_deref(),
+            Some(
+                format!(
+                    "default-src 'self'; script-src 'nonce-{}' 'strict-dynamic'; object-src 'none'; \
+                     base-uri 'none'; frame-ancestors 'none'; \
+                     connect-src 'self' https://api.example.com%3B%20script-src%20*; img-src 'self' data:; \
+                     report-uri /csp-reports",
+                    nonce
+                )
+                .as_str()
+            )
+        );
+
+        let mut second = client.get("/api/v1/page").await?;
+        assert_ne!(assert_status(&mut second, 200).await, nonce);
+
+        let mut res = client.get("/api/v1/report-only").await?;
+        assert!(res.header("Content-Security-Policy").is_none());
+        assert_eq!(
+            res.header("Content-Security-Policy-Report-Only")
+                .map(|policy| policy.as_str()),
+            Some("default-src 'self'; upgrade-insecure-requests")
+        );
+        assert_eq!(assert_status(&mut res, 200).await, "None");
+        Ok(())
+    }
+}
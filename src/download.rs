@@ -0,0 +1,336 @@
+//! File download responses, with support for resumable downloads.
+//!
+//! [`serve_file`] and [`FileDownload`] respond with a file from disk:
+//! - `Content-Disposition` names the file, as an `attachment` unless [`inline`][FileDownload::inline].
+//! - `ETag` and `Last-Modified` are set, and conditional requests get `304 Not Modified`.
+//! - A single `Range` of bytes gets a `206 Partial Content`, or a `416 Range Not Satisfiable` if it is past the end.
+//!   Requests for multiple ranges get the whole file.
+//! - `If-Range` only lets a `Range` apply if the file has not changed since the client's partial download.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::download::FileDownload;
+//! use tide::Request;
+//!
+//! # #[allow(dead_code)]
+//! async fn get_report(req: Request<Arc<()>>) -> tide::Result {
+//!     let id: u64 = req.param("id")?.parse()?;
+//!
+//!     FileDownload::new(format!("/var/reports/{}.pdf", id))
+//!         .file_name(format!("Report #{}.pdf", id))
+//!         .respond(&req)
+//!         .await
+//! }
+//! ```
+
+use std::convert::TryFrom;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use async_std::fs::{self, File};
+use futures_lite::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tide::http::conditional::{ETag, LastModified};
+use tide::http::headers::{ACCEPT_RANGES, CONTENT_RANGE, IF_RANGE};
+use tide::http::{mime, Method, Mime};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::builtins::static_files::is_not_modified;
+
+/// Characters which must be percent-encoded in a `filename*` parameter (RFC 5987's `attr-char`).
+const FILENAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Respond with the file at `path`, as an attachment named after the file.
+///
+/// A missing file is a `404 Not Found`.
+pub async fn serve_file<State>(req: &Request<State>, path: impl AsRef<Path>) -> tide::Result {
+    FileDownload::new(path.as_ref()).respond(req).await
+}
+
+/// A file download response, for files which may be large or resumed.
+#[derive(Debug, Clone)]
+pub struct FileDownload {
+    path: PathBuf,
+    file_name: Option<String>,
+    inline: bool,
+}
+
+impl FileDownload {
+    /// Download the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file_name: None,
+            inline: false,
+        }
+    }
+
+    /// The name the client should save the file as. Defaults to the file's own name.
+    #[must_use]
+    pub fn file_name(mut self, file_name: impl Into<String>) -> Self {
+        self.file_name = Some(file_name.into());
+        self
+    }
+
+    /// Whether browsers should display the file rather than save it. Defaults to `false`.
+    #[must_use]
+    pub fn inline(mut self, inline: bool) -> Self {
+        self.inline = inline;
+        self
+    }
+
+    /// Respond to `req` with the file, or the requested range of it.
+    pub async fn respond<State>(&self, req: &Request<State>) -> tide::Result {
+        let metadata = match fs::metadata(&self.path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(Response::new(StatusCode::NotFound)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Response::new(StatusCode::NotFound))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+
+        // Strong, unlike for static files, as If-Range only matches strong validators.
+        let etag = ETag::new(format!(
+            "{:x}-{:x}",
+            len,
+            modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_nanos())
+                .unwrap_or_default()
+        ));
+        let last_modified = modified.map(LastModified::new);
+
+        let mut res = if is_not_modified(req, &etag, modified)? {
+            Response::new(StatusCode::NotModified)
+        } else {
+            let if_range_matches = match req.header(IF_RANGE).map(|value| value.last().as_str()) {
+                None => true,
+                Some(validator) if validator.starts_with('"') => validator == etag.value().as_str(),
+                Some(date) => last_modified
+                    .as_ref()
+                    .is_some_and(|modified| date == modified.value().as_str()),
+            };
+            let range = match req.header("Range") {
+                Some(range) if req.method() == Method::Get && if_range_matches => {
+                    parse_range(range.last().as_str(), len)
+                }
+                _ => None,
+            };
+
+            match range {
+                None => {
+                    let mut res = Response::new(StatusCode::Ok);
+                    res.set_body(Body::from_file(&self.path).await?);
+                    res
+                }
+                Some(Err(())) => {
+                    let mut res = Response::new(StatusCode::RequestedRangeNotSatisfiable);
+                    res.insert_header(CONTENT_RANGE, format!("bytes */{}", len));
+                    res
+                }
+                Some(Ok((start, end))) => {
+                    let mut file = File::open(&self.path).await?;
+                    file.seek(SeekFrom::Start(start)).await?;
+                    let part_len = end - start + 1;
+
+                    let mut res = Response::new(StatusCode::PartialContent);
+                    res.insert_header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+                    let part = BufReader::new(file.take(part_len));
+                    res.set_body(Body::from_reader(part, usize::try_from(part_len).ok()));
+                    res.set_content_type(self.content_type());
+                    res
+                }
+            }
+        };
+
+        res.insert_header(ACCEPT_RANGES, "bytes");
+        res.insert_header("Content-Disposition", self.content_disposition());
+        etag.apply(&mut res);
+        if let Some(last_modified) = last_modified {
+            last_modified.apply(&mut res);
+        }
+        Ok(res)
+    }
+
+    fn content_type(&self) -> Mime {
+        self.path
+            .extension()
+            .and_then(|extension| Mime::from_extension(extension.to_string_lossy()))
+            .unwrap_or(mime::BYTE_STREAM)
+    }
+
+    fn content_disposition(&self) -> String {
+        let disposition = if self.inline { "inline" } else { "attachment" };
+        let file_name = match &self.file_name {
+            Some(file_name) => file_name.clone(),
+            None => match self.path.file_name() {
+                Some(file_name) => file_name.to_string_lossy().into_owned(),
+                None => return disposition.to_string(),
+            },
+        };
+
+        // A plain ASCII fallback for old clients, and the exact name for the rest.
+        let ascii: String = file_name
+            .chars()
+            .map(|c| match c {
+                '"' | '\\' => '_',
+                c if c.is_ascii() && !c.is_ascii_control() => c,
+                _ => '_',
+            })
+            .collect();
+        if ascii == file_name {
+            format!("{}; filename=\"{}\"", disposition, ascii)
+        } else {
+            format!(
+                "{}; filename=\"{}\"; filename*=UTF-8''{}",
+                disposition,
+                ascii,
+                utf8_percent_encode(&file_name, FILENAME_ENCODE_SET)
+            )
+        }
+    }
+}
+
+/// The inclusive byte range of a single-range `Range` header, or `Err` if it is not satisfiable.
+///
+/// `None` if the header is not a valid single byte range, in which case the whole file is served.
+fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // The last `end` bytes.
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => len.saturating_sub(1),
+            end => {
+                let end: u64 = end.parse().ok()?;
+                // An end before the start makes the header invalid, rather than unsatisfiable (RFC 7233 §2.1).
+                if end < start {
+                    return None;
+                }
+                end.min(len.saturating_sub(1))
+            }
+        };
+        if start >= len {
+            return Some(Err(()));
+        }
+        (start, end)
+    };
+
+    Some(Ok(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn serves_ranges() -> TestResult<()> {
+        let path =
+            std::env::temp_dir().join(format!("preroll-download-{}.json", std::process::id()));
+        std::fs::write(&path, "0123456789")?;
+
+        let file = path.clone();
+        let client = test_utils::create_client((), move |mut server: Route<'_, Arc<()>>| {
+            let file = file.clone();
+            server.at("report").get(move |req: Request<Arc<()>>| {
+                let file = file.clone();
+                async move {
+                    FileDownload::new(file)
+                        .file_name("Report é.json")
+                        .respond(&req)
+                        .await
+                }
+            });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/report").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "0123456789");
+        assert_eq!(res["accept-ranges"], "bytes");
+        assert_eq!(
+            res["content-disposition"],
+            "attachment; filename=\"Report _.json\"; filename*=UTF-8''Report%20%C3%A9.json"
+        );
+        let etag = res["etag"].last().to_string();
+
+        let mut res = client
+            .get("/api/v1/report")
+            .header("Range", "bytes=2-4")
+            .header("If-Range", etag.as_str())
+            .await?;
+        assert_eq!(assert_status(&mut res, 206).await, "234");
+        assert_eq!(res["content-range"], "bytes 2-4/10");
+        assert_eq!(res.content_type(), Some(mime::JSON));
+
+        let mut res = client
+            .get("/api/v1/report")
+            .header("Range", "bytes=-3")
+            .await?;
+        assert_eq!(assert_status(&mut res, 206).await, "789");
+
+        let mut res = client
+            .get("/api/v1/report")
+            .header("Range", "bytes=5-2")
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "0123456789");
+
+        let mut res = client
+            .get("/api/v1/report")
+            .header("Range", "bytes=8-")
+            .header("If-Range", "\"stale\"")
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "0123456789");
+
+        let res = client
+            .get("/api/v1/report")
+            .header("Range", "bytes=10-")
+            .await?;
+        assert_eq!(res.status(), 416);
+        assert_eq!(res["content-range"], "bytes */10");
+
+        let res = client
+            .get("/api/v1/report")
+            .header("If-None-Match", etag.as_str())
+            .await?;
+        assert_eq!(res.status(), 304);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
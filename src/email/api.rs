@@ -0,0 +1,155 @@
+//! Sending email via a provider's HTTP API.
+
+use std::fmt;
+
+use serde_json::{json, Value};
+use surf::Client;
+use tide::StatusCode;
+
+use super::{Email, Mailer};
+
+/// An email provider with an HTTP API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ApiProvider {
+    /// [Postmark](https://postmarkapp.com/developer/api/email-api), with a server token as the API key.
+    Postmark,
+    /// [SendGrid](https://docs.sendgrid.com/api-reference/mail-send/mail-send), with an API key.
+    SendGrid,
+}
+
+/// A mailer which sends via a provider's HTTP API.
+#[derive(Clone)]
+pub struct ApiMailer {
+    client: Client,
+    provider: ApiProvider,
+    api_key: String,
+}
+
+impl ApiMailer {
+    /// A mailer for `provider`, authenticated with `api_key`.
+    pub fn new(provider: ApiProvider, api_key: impl Into<String>) -> Self {
+        Self {
+            client: Client::new(),
+            provider,
+            api_key: api_key.into(),
+        }
+    }
+}
+
+impl fmt::Debug for ApiMailer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiMailer")
+            .field("provider", &self.provider)
+            .finish_non_exhaustive()
+    }
+}
+
+#[tide::utils::async_trait]
+impl Mailer for ApiMailer {
+    async fn send(&self, email: &Email) -> tide::Result<()> {
+        let request = match self.provider {
+            ApiProvider::Postmark => self
+                .client
+                .post("https://api.postmarkapp.com/email")
+                .header("X-Postmark-Server-Token", self.api_key.as_str())
+                .body(postmark_body(email)),
+            ApiProvider::SendGrid => self
+                .client
+                .post("https://api.sendgrid.com/v3/mail/send")
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .body(sendgrid_body(email)),
+        };
+
+        let mut res = request.await.map_err(|error| {
+            tide::Error::from_str(
+                StatusCode::BadGateway,
+                format!("Sending email via {:?} failed: {}", self.provider, error),
+            )
+        })?;
+        if !res.status().is_success() {
+            let body = res.body_string().await.unwrap_or_default();
+            // Rejected messages are not retried, but failures of the provider are.
+            let status = if res.status().is_client_error() {
+                StatusCode::BadRequest
+            } else {
+                StatusCode::BadGateway
+            };
+            return Err(tide::Error::from_str(
+                status,
+                format!(
+                    "{:?} responded with {}: {}",
+                    self.provider,
+                    res.status(),
+                    body
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn postmark_body(email: &Email) -> Value {
+    let join = |addresses: &[String]| match addresses {
+        [] => Value::Null,
+        addresses => Value::String(addresses.join(", ")),
+    };
+    json!({
+        "From": email.from,
+        "To": join(&email.to),
+        "Cc": join(&email.cc),
+        "Bcc": join(&email.bcc),
+        "ReplyTo": email.reply_to,
+        "Subject": email.subject,
+        "TextBody": email.text,
+        "HtmlBody": email.html,
+    })
+}
+
+fn sendgrid_body(email: &Email) -> Value {
+    let mailboxes = |addresses: &[String]| {
+        addresses
+            .iter()
+            .map(|address| mailbox(address))
+            .collect::<Vec<_>>()
+    };
+
+    let mut personalization = json!({ "to": mailboxes(&email.to) });
+    if !email.cc.is_empty() {
+        personalization["cc"] = json!(mailboxes(&email.cc));
+    }
+    if !email.bcc.is_empty() {
+        personalization["bcc"] = json!(mailboxes(&email.bcc));
+    }
+
+    // SendGrid requires plain text before HTML.
+    let content: Vec<Value> = [("text/plain", &email.text), ("text/html", &email.html)]
+        .iter()
+        .filter_map(|(kind, body)| {
+            body.as_ref()
+                .map(|body| json!({ "type": kind, "value": body }))
+        })
+        .collect();
+
+    let mut body = json!({
+        "personalizations": [personalization],
+        "from": mailbox(email.from.as_deref().unwrap_or_default()),
+        "subject": email.subject,
+        "content": content,
+    });
+    if let Some(reply_to) = &email.reply_to {
+        body["reply_to"] = mailbox(reply_to);
+    }
+    body
+}
+
+/// A SendGrid mailbox, from e.g. `"Example <hello@example.com>"`.
+fn mailbox(mailbox: &str) -> Value {
+    match mailbox.rsplit_once('<') {
+        Some((name, _)) if !name.trim().is_empty() => json!({
+            "email": super::address_of(mailbox),
+            "name": name.trim().trim_matches('"'),
+        }),
+        _ => json!({ "email": super::address_of(mailbox) }),
+    }
+}
@@ -0,0 +1,558 @@
+//! Transactional email, for the `"email"` feature.
+//!
+//! [`Emailer`] sends [`Email`]s via a [`Mailer`] backend, and is set up by preroll as
+//! [`Resources::emailer`][crate::Resources::emailer] from `EMAIL_BACKEND`:
+//! - `"smtp"`: [`SmtpMailer`], from `SMTP_URL`.
+//! - `"postmark"` or `"sendgrid"`: [`ApiMailer`], with the API key from `EMAIL_API_KEY`.
+//! - `"log"`: Logs messages instead of sending them. The default in development.
+//! - `"capture"`: [`CaptureMailer`], which keeps messages for test assertions. The default in tests.
+//!
+//! [`send_later`][Emailer::send_later] sends in the background, retrying failures, so that requests do not wait on
+//! the mail server. Messages still being sent when the service shuts down get up to [`DRAIN_TIMEOUT`] to finish, but
+//! are not persisted, and so are lost if the process is killed.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use once_cell::sync::Lazy;
+//! use preroll::email::{Emailer, EmailTemplate};
+//! use serde_json::json;
+//! use tide::{Request, Response, StatusCode};
+//!
+//! pub struct AppState {
+//!     emailer: Emailer,
+//! }
+//!
+//! static WELCOME: Lazy<EmailTemplate> = Lazy::new(|| {
+//!     EmailTemplate::new("Welcome, {{ name }}!")
+//!         .text("Hi {{ name }}, thanks for signing up.")
+//!         .html("<p>Hi {{ name }}, thanks for signing up.</p>")
+//! });
+//!
+//! # #[allow(dead_code)]
+//! async fn sign_up(req: Request<Arc<AppState>>) -> tide::Result {
+//!     let email = WELCOME.render("ada@example.com", &json!({ "name": "Ada" }))?;
+//!     req.state().emailer.send_later(email);
+//!     Ok(Response::new(StatusCode::Created))
+//! }
+//! ```
+
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::eyre;
+use serde::Serialize;
+use serde_json::Value;
+use tide::StatusCode;
+
+use crate::setup::Result;
+use crate::Environment;
+
+mod api;
+mod smtp;
+
+pub use api::{ApiMailer, ApiProvider};
+pub use smtp::SmtpMailer;
+
+/// How long shutdown waits for messages from [`send_later`][Emailer::send_later] to be sent.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The delays before each retry of a message from [`send_later`][Emailer::send_later].
+const RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+/// The number of messages from [`send_later`][Emailer::send_later] which are still being sent.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// An email message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Email {
+    /// The sender, which defaults to the [`Emailer`]'s, e.g. `"Example <hello@example.com>"`.
+    pub from: Option<String>,
+    /// The recipients.
+    pub to: Vec<String>,
+    /// The carbon copy recipients.
+    pub cc: Vec<String>,
+    /// The blind carbon copy recipients, who are not listed in the message.
+    pub bcc: Vec<String>,
+    /// The address replies go to, if not the sender.
+    pub reply_to: Option<String>,
+    /// The subject line.
+    pub subject: String,
+    /// The plain text body.
+    pub text: Option<String>,
+    /// The HTML body.
+    pub html: Option<String>,
+}
+
+impl Email {
+    /// A message to `to`, which needs a [`text`][Email::text] or [`html`][Email::html] body.
+    pub fn new(to: impl Into<String>, subject: impl Into<String>) -> Self {
+        Self {
+            to: vec![to.into()],
+            subject: subject.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Set the sender.
+    #[must_use]
+    pub fn from(mut self, from: impl Into<String>) -> Self {
+        self.from = Some(from.into());
+        self
+    }
+
+    /// Add a recipient.
+    #[must_use]
+    pub fn to(mut self, to: impl Into<String>) -> Self {
+        self.to.push(to.into());
+        self
+    }
+
+    /// Add a carbon copy recipient.
+    #[must_use]
+    pub fn cc(mut self, cc: impl Into<String>) -> Self {
+        self.cc.push(cc.into());
+        self
+    }
+
+    /// Add a blind carbon copy recipient.
+    #[must_use]
+    pub fn bcc(mut self, bcc: impl Into<String>) -> Self {
+        self.bcc.push(bcc.into());
+        self
+    }
+
+    /// Set the address replies go to.
+    #[must_use]
+    pub fn reply_to(mut self, reply_to: impl Into<String>) -> Self {
+        self.reply_to = Some(reply_to.into());
+        self
+    }
+
+    /// Set the plain text body.
+    #[must_use]
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// Set the HTML body.
+    #[must_use]
+    pub fn html(mut self, html: impl Into<String>) -> Self {
+        self.html = Some(html.into());
+        self
+    }
+
+    /// A `400 Bad Request` error if the message cannot be sent as it is.
+    fn validate(&self) -> tide::Result<()> {
+        let invalid = |message: String| Err(tide::Error::from_str(StatusCode::BadRequest, message));
+
+        if self.to.is_empty() && self.cc.is_empty() && self.bcc.is_empty() {
+            return invalid("Emails must have a recipient".to_string());
+        }
+        if self.text.is_none() && self.html.is_none() {
+            return invalid("Emails must have a text or HTML body".to_string());
+        }
+        if self.from.is_none() {
+            return invalid("Emails must have a sender, or EMAIL_FROM must be set".to_string());
+        }
+
+        let addresses = self
+            .from
+            .iter()
+            .chain(&self.to)
+            .chain(&self.cc)
+            .chain(&self.bcc)
+            .chain(&self.reply_to);
+        for address in addresses {
+            if !address_of(address).contains('@') || address.contains(['\r', '\n']) {
+                return invalid(format!("Invalid email address \"{}\"", address));
+            }
+        }
+        if self.subject.contains(['\r', '\n']) {
+            return invalid("Email subjects must be a single line".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The bare address of a mailbox such as `"Example <hello@example.com>"`.
+pub(crate) fn address_of(mailbox: &str) -> &str {
+    match mailbox.rsplit_once('<') {
+        Some((_, address)) => address.trim_end().trim_end_matches('>'),
+        None => mailbox.trim(),
+    }
+}
+
+/// A way of sending email, such as SMTP or a provider's HTTP API.
+#[tide::utils::async_trait]
+pub trait Mailer: fmt::Debug + Send + Sync + 'static {
+    /// Send a message, which has a sender and at least one recipient.
+    async fn send(&self, email: &Email) -> tide::Result<()>;
+}
+
+/// Sends email via a [`Mailer`], with a default sender.
+#[derive(Debug, Clone)]
+pub struct Emailer {
+    mailer: Arc<dyn Mailer>,
+    captured: Option<CaptureMailer>,
+    from: Option<String>,
+}
+
+impl Emailer {
+    /// An emailer which sends via `mailer`.
+    pub fn new(mailer: impl Mailer) -> Self {
+        Self {
+            mailer: Arc::new(mailer),
+            captured: None,
+            from: None,
+        }
+    }
+
+    /// An emailer which captures messages rather than sending them, and the [`CaptureMailer`] to assert on them with.
+    pub fn capture() -> (Self, CaptureMailer) {
+        let captured = CaptureMailer::new();
+        let mut emailer = Self::new(captured.clone());
+        emailer.captured = Some(captured.clone());
+        (emailer, captured)
+    }
+
+    /// The emailer for the backend in `EMAIL_BACKEND`, with the default sender from `EMAIL_FROM`.
+    pub(crate) fn from_env(environment: Environment) -> Result<Self> {
+        let backend = match env::var("EMAIL_BACKEND") {
+            Ok(backend) => backend,
+            Err(_) if environment == Environment::Test => "capture".to_string(),
+            Err(_) if environment.is_development() => "log".to_string(),
+            Err(_) => {
+                return Err(eyre!(
+                    "EMAIL_BACKEND must be set in {}",
+                    environment.as_str()
+                ))
+            }
+        };
+        let api_key = || env::var("EMAIL_API_KEY").map_err(|_| eyre!("EMAIL_API_KEY must be set"));
+
+        let emailer = match backend.as_str() {
+            "smtp" => {
+                let url = env::var("SMTP_URL").map_err(|_| eyre!("SMTP_URL must be set"))?;
+                Self::new(SmtpMailer::from_url(&url)?)
+            }
+            "postmark" => Self::new(ApiMailer::new(ApiProvider::Postmark, api_key()?)),
+            "sendgrid" => Self::new(ApiMailer::new(ApiProvider::SendGrid, api_key()?)),
+            "log" => Self::new(LogMailer),
+            "capture" => Self::capture().0,
+            other => return Err(eyre!("Unknown EMAIL_BACKEND \"{}\"", other)),
+        };
+
+        Ok(match env::var("EMAIL_FROM") {
+            Ok(from) => emailer.default_from(from),
+            Err(_) => emailer,
+        })
+    }
+
+    /// Set the sender of messages which do not have one.
+    #[must_use]
+    pub fn default_from(mut self, from: impl Into<String>) -> Self {
+        self.from = Some(from.into());
+        self
+    }
+
+    /// The messages sent so far, if this emailer captures them rather than sending them.
+    pub fn captured(&self) -> Option<&CaptureMailer> {
+        self.captured.as_ref()
+    }
+
+    /// Send a message, and wait for it to be accepted.
+    ///
+    /// Invalid messages, e.g. without a recipient or body, are a `400 Bad Request` error, and failures to send are
+    /// a `502 Bad Gateway` error.
+    pub async fn send(&self, email: Email) -> tide::Result<()> {
+        let email = self.prepare(email)?;
+        self.mailer.send(&email).await
+    }
+
+    /// Send a message in the background, retrying failures to send up to 3 times.
+    ///
+    /// Invalid messages and failures to send are logged.
+    pub fn send_later(&self, email: Email) {
+        let email = match self.prepare(email) {
+            Ok(email) => email,
+            Err(error) => {
+                log::error!("Not sending invalid email: {}", error);
+                return;
+            }
+        };
+
+        let mailer = self.mailer.clone();
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        async_std::task::spawn(async move {
+            let mut delays = RETRY_DELAYS.iter();
+            loop {
+                let error = match mailer.send(&email).await {
+                    Ok(()) => break,
+                    Err(error) => error,
+                };
+                match delays.next() {
+                    Some(delay) if error.status().is_server_error() => {
+                        log::warn!(
+                            "Sending email \"{}\" failed, retrying in {:?}: {}",
+                            email.subject,
+                            delay,
+                            error
+                        );
+                        async_std::task::sleep(*delay).await;
+                    }
+                    _ => {
+                        log::error!("Sending email \"{}\" failed: {}", email.subject, error);
+                        break;
+                    }
+                }
+            }
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    fn prepare(&self, mut email: Email) -> tide::Result<Email> {
+        if email.from.is_none() {
+            email.from = self.from.clone();
+        }
+        email.validate()?;
+        Ok(email)
+    }
+}
+
+/// A message with `{{ name }}` placeholders, which are filled in from serialized data.
+///
+/// Values are HTML-escaped in the HTML body. Nested values are named with dots, e.g. `{{ user.name }}`,
+/// and placeholders without a value are an error.
+#[derive(Debug, Clone)]
+pub struct EmailTemplate {
+    subject: String,
+    text: Option<String>,
+    html: Option<String>,
+}
+
+impl EmailTemplate {
+    /// A template with the given subject line.
+    pub fn new(subject: impl Into<String>) -> Self {
+        Self {
+            subject: subject.into(),
+            text: None,
+            html: None,
+        }
+    }
+
+    /// Set the plain text body.
+    #[must_use]
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// Set the HTML body.
+    #[must_use]
+    pub fn html(mut self, html: impl Into<String>) -> Self {
+        self.html = Some(html.into());
+        self
+    }
+
+    /// A message to `to`, with the placeholders filled in from `data`.
+    pub fn render(&self, to: impl Into<String>, data: &impl Serialize) -> tide::Result<Email> {
+        let data = serde_json::to_value(data)?;
+
+        let mut email = Email::new(to, render(&self.subject, &data, false)?);
+        if let Some(text) = &self.text {
+            email.text = Some(render(text, &data, false)?);
+        }
+        if let Some(html) = &self.html {
+            email.html = Some(render(html, &data, true)?);
+        }
+        Ok(email)
+    }
+}
+
+fn render(template: &str, data: &Value, escape_html: bool) -> tide::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some((before, after)) = rest.split_once("{{") {
+        let (name, after) = after.split_once("}}").ok_or_else(|| {
+            tide::Error::from_str(
+                StatusCode::InternalServerError,
+                "Unclosed placeholder in email template",
+            )
+        })?;
+        let name = name.trim();
+
+        let value = match data.pointer(&format!("/{}", name.replace('.', "/"))) {
+            Some(Value::String(value)) => value.clone(),
+            Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+            _ => {
+                return Err(tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!("Email template value \"{}\" is missing", name),
+                ))
+            }
+        };
+
+        rendered.push_str(before);
+        if escape_html {
+            rendered.push_str(&escape(&value));
+        } else {
+            rendered.push_str(&value);
+        }
+        rest = after;
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A mailer which keeps messages rather than sending them, for test assertions.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureMailer {
+    messages: Arc<Mutex<Vec<Email>>>,
+}
+
+impl CaptureMailer {
+    /// A mailer without any messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The messages sent so far.
+    pub fn messages(&self) -> Vec<Email> {
+        self.lock().clone()
+    }
+
+    /// Take the messages sent so far, leaving none.
+    pub fn take(&self) -> Vec<Email> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    /// Wait up to 5 seconds for at least `count` messages, e.g. from [`send_later`][Emailer::send_later],
+    /// and take them.
+    pub async fn wait_for(&self, count: usize) -> Vec<Email> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.lock().len() < count && Instant::now() < deadline {
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+        self.take()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Email>> {
+        self.messages.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[tide::utils::async_trait]
+impl Mailer for CaptureMailer {
+    async fn send(&self, email: &Email) -> tide::Result<()> {
+        self.lock().push(email.clone());
+        Ok(())
+    }
+}
+
+/// A mailer which logs messages rather than sending them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogMailer;
+
+#[tide::utils::async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> tide::Result<()> {
+        log::info!(
+            "Email to {:?}: {}\n{}",
+            email.to,
+            email.subject,
+            email
+                .text
+                .as_deref()
+                .or(email.html.as_deref())
+                .unwrap_or_default()
+        );
+        Ok(())
+    }
+}
+
+/// Wait up to [`DRAIN_TIMEOUT`] for messages from [`send_later`][Emailer::send_later] to be sent.
+pub(crate) async fn drain() {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            log::warn!(
+                "{} email(s) still being sent after {:?}",
+                PENDING.load(Ordering::SeqCst),
+                DRAIN_TIMEOUT
+            );
+            break;
+        }
+        async_std::task::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::TestResult;
+
+    #[async_std::test]
+    async fn renders_and_captures_emails() -> TestResult<()> {
+        let template = EmailTemplate::new("Welcome, {{ user.name }}")
+            .text("You are user #{{ user.id }}.")
+            .html("<p>Hi {{user.name}}</p>");
+        let data = json!({ "user": { "name": "<Ada>", "id": 7 } });
+
+        let (emailer, captured) = Emailer::capture();
+        let emailer = emailer.default_from("Example <hello@example.com>");
+        emailer.send_later(template.render("ada@example.com", &data)?);
+
+        let messages = captured.wait_for(1).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].from.as_deref(),
+            Some("Example <hello@example.com>")
+        );
+        assert_eq!(messages[0].subject, "Welcome, <Ada>");
+        assert_eq!(messages[0].text.as_deref(), Some("You are user #7."));
+        assert_eq!(messages[0].html.as_deref(), Some("<p>Hi &lt;Ada&gt;</p>"));
+
+        let missing = template.render("ada@example.com", &json!({}));
+        assert!(missing.is_err());
+
+        let invalid = Email::new("ada@example.com\r\nBcc: eve@example.com", "Hi").text("Hi");
+        let error = emailer.send(invalid).await.err();
+        assert_eq!(
+            error.map(|error| error.status()),
+            Some(StatusCode::BadRequest)
+        );
+        assert!(captured.messages().is_empty());
+        Ok(())
+    }
+}
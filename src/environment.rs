@@ -0,0 +1,104 @@
+use std::env;
+use std::fmt;
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tide::Request;
+
+static CURRENT: OnceCell<Environment> = OnceCell::new();
+
+/// The kind of environment the service is running in, parsed once from `ENVIRONMENT`.
+///
+/// Available via [`Environment::current`], [`Resources::environment`][crate::Resources::environment],
+/// and [`EnvironmentRequestExt::env`][crate::prelude::EnvironmentRequestExt::env].
+///
+/// `ENVIRONMENT` is matched by prefix, so that e.g. `production-eu` is [`Production`][Environment::Production].
+/// Unset or unrecognized values are [`Development`][Environment::Development].
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::Environment;
+///
+/// assert_eq!(Environment::from_name("production-eu"), Environment::Production);
+/// assert_eq!(Environment::from_name("local"), Environment::Development);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    /// `ENVIRONMENT` starting with `dev`, or unset or unrecognized.
+    Development,
+    /// `ENVIRONMENT` starting with `stag`.
+    Staging,
+    /// `ENVIRONMENT` starting with `prod`. Uses the JSON logger, and does not load `.env`.
+    Production,
+    /// `ENVIRONMENT` starting with `test`.
+    Test,
+}
+
+impl Environment {
+    /// The environment of this process, from `ENVIRONMENT` as it was when first read.
+    pub fn current() -> Self {
+        *CURRENT.get_or_init(|| {
+            env::var("ENVIRONMENT")
+                .map(|name| Self::from_name(&name))
+                .unwrap_or(Self::Development)
+        })
+    }
+
+    /// The environment for an `ENVIRONMENT` value, matched by prefix.
+    #[must_use]
+    pub fn from_name(name: &str) -> Self {
+        let name = name.trim().to_ascii_lowercase();
+        if name.starts_with("prod") {
+            Self::Production
+        } else if name.starts_with("stag") {
+            Self::Staging
+        } else if name.starts_with("test") {
+            Self::Test
+        } else {
+            Self::Development
+        }
+    }
+
+    /// Whether this is [`Production`][Environment::Production].
+    #[must_use]
+    pub fn is_production(self) -> bool {
+        self == Self::Production
+    }
+
+    /// Whether this is [`Development`][Environment::Development].
+    #[must_use]
+    pub fn is_development(self) -> bool {
+        self == Self::Development
+    }
+
+    /// The lowercase name of the environment, e.g. `"staging"`.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Development => "development",
+            Self::Staging => "staging",
+            Self::Production => "production",
+            Self::Test => "test",
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An extension trait for reading the [`Environment`] from a request handler.
+pub trait EnvironmentRequestExt {
+    /// The environment the service is running in, as in [`Environment::current`].
+    fn env(&self) -> Environment;
+}
+
+impl<State> EnvironmentRequestExt for Request<State> {
+    fn env(&self) -> Environment {
+        Environment::current()
+    }
+}
@@ -0,0 +1,349 @@
+use std::convert::TryInto;
+use std::fmt::{self, Debug};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tide::http::other::RetryAfter;
+use tide::StatusCode;
+
+/// An error with a stable, machine-readable code, so that clients can branch on the code rather than parse the message,
+/// or with details for the client such as the remaining quota or the id of a conflicting resource.
+///
+/// The code is included in error responses as [`JsonError::code`][crate::JsonError::code], and in the response's log
+/// and trace. The details are included as [`JsonError::details`][crate::JsonError::details].
+///
+/// Errors for throttling and unavailability also tell clients when to retry, via a `Retry-After` header and
+/// [`JsonError::retry_after`][crate::JsonError::retry_after].
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::Error;
+/// use serde_json::json;
+/// use tide::{Request, StatusCode};
+///
+/// # #[allow(dead_code)]
+/// async fn get_user(req: Request<()>) -> tide::Result<String> {
+///     let id = req.param("id")?;
+///     Err(Error::with_code(
+///         "user_not_found",
+///         StatusCode::NotFound,
+///         format!("No user with id {}", id),
+///     ))
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn create_user(_req: Request<()>) -> tide::Result<String> {
+///     Err(Error::with_details(
+///         StatusCode::Conflict,
+///         "A user with that email already exists",
+///         json!({ "user_id": 7 }),
+///     ))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    code: Option<String>,
+    message: String,
+    details: Option<Value>,
+    retry_after: Option<RetryAfter>,
+}
+
+impl Error {
+    /// A `tide::Error` with `status`, a message for humans, and a code for machines, e.g. `"user_not_found"`.
+    pub fn with_code<S>(
+        code: impl Into<String>,
+        status: S,
+        message: impl Into<String>,
+    ) -> tide::Error
+    where
+        S: TryInto<StatusCode>,
+        S::Error: Debug,
+    {
+        tide::Error::new(
+            status,
+            Self {
+                code: Some(code.into()),
+                message: message.into(),
+                details: None,
+                retry_after: None,
+            },
+        )
+    }
+
+    /// A `tide::Error` with `status`, a message, and serializable details for the client.
+    pub fn with_details<S>(status: S, message: impl Into<String>, details: Value) -> tide::Error
+    where
+        S: TryInto<StatusCode>,
+        S::Error: Debug,
+    {
+        tide::Error::new(
+            status,
+            Self {
+                code: None,
+                message: message.into(),
+                details: Some(details),
+                retry_after: None,
+            },
+        )
+    }
+
+    /// A `503 Service Unavailable` error with the code `"service_unavailable"`, for clients to retry after `duration`.
+    pub fn service_unavailable_for(duration: Duration) -> tide::Error {
+        tide::Error::new(
+            StatusCode::ServiceUnavailable,
+            Self {
+                code: Some("service_unavailable".to_string()),
+                message: "The service is temporarily unavailable".to_string(),
+                details: None,
+                retry_after: Some(RetryAfter::new(duration)),
+            },
+        )
+    }
+
+    /// A `429 Too Many Requests` error with the code `"rate_limited"`, for clients to retry at `until`,
+    /// e.g. a `SystemTime` or a `chrono::DateTime<Utc>`.
+    pub fn rate_limited_until(until: impl Into<SystemTime>) -> tide::Error {
+        tide::Error::new(
+            StatusCode::TooManyRequests,
+            Self {
+                code: Some("rate_limited".to_string()),
+                message: "Too many requests".to_string(),
+                details: None,
+                retry_after: Some(RetryAfter::new_at(until.into())),
+            },
+        )
+    }
+
+    /// A `429 Too Many Requests` error with the code `"quota_exceeded"` and serializable details, such as the quota
+    /// and its usage, for clients to retry at `until`, once the quota's period is over.
+    pub fn quota_exceeded_until(until: impl Into<SystemTime>, details: Value) -> tide::Error {
+        tide::Error::new(
+            StatusCode::TooManyRequests,
+            Self {
+                code: Some("quota_exceeded".to_string()),
+                message: "Quota exceeded".to_string(),
+                details: Some(details),
+                retry_after: Some(RetryAfter::new_at(until.into())),
+            },
+        )
+    }
+
+    /// The `preroll::Error` within a `tide::Error`, if it is one.
+    pub fn of(error: &tide::Error) -> Option<&Self> {
+        error.downcast_ref::<Self>()
+    }
+
+    /// The machine-readable code.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    /// The message for humans.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The details for the client.
+    pub fn details(&self) -> Option<&Value> {
+        self.details.as_ref()
+    }
+
+    /// When the client should retry.
+    pub fn retry_after(&self) -> Option<&RetryAfter> {
+        self.retry_after.as_ref()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The problems with the fields of a request, which are all reported together rather than only the first.
+///
+/// Responded with as a `422 Unprocessable Entity` error, with the problems as [`JsonError::fields`][crate::JsonError::fields],
+/// even if converted into a `tide::Error` via `?`.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::ValidationErrors;
+/// use serde::Deserialize;
+/// use tide::Request;
+///
+/// #[derive(Deserialize)]
+/// struct NewUser {
+///     name: String,
+///     email: String,
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn create_user(mut req: Request<()>) -> tide::Result<String> {
+///     let user: NewUser = req.body_json().await?;
+///
+///     let mut errors = ValidationErrors::new();
+///     if user.name.is_empty() {
+///         errors.add("name", "required", "A name is required");
+///     }
+///     if !user.email.contains('@') {
+///         errors.add("email", "invalid_email", "Not an email address");
+///     }
+///     errors.into_result()?;
+///
+///     Ok(user.name)
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationErrors {
+    fields: Vec<FieldError>,
+}
+
+/// A problem with a field of a request, as collected in [`ValidationErrors`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FieldError {
+    /// The path of the field, e.g. `"address.city"` or `"items[2].quantity"`.
+    pub path: String,
+    /// The message for humans.
+    pub message: String,
+    /// The machine-readable code, e.g. `"required"`.
+    pub code: String,
+}
+
+impl ValidationErrors {
+    /// No problems, yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a problem with the field at `path`, with a code for machines, e.g. `"required"`, and a message for humans.
+    pub fn add(
+        &mut self,
+        path: impl Into<String>,
+        code: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.fields.push(FieldError {
+            path: path.into(),
+            message: message.into(),
+            code: code.into(),
+        });
+    }
+
+    /// Whether there are no problems.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The problems, in the order they were added.
+    pub fn fields(&self) -> &[FieldError] {
+        &self.fields
+    }
+
+    /// `Ok` if there are no problems, or else a `422 Unprocessable Entity` error.
+    pub fn into_result(self) -> tide::Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(tide::Error::new(StatusCode::UnprocessableEntity, self))
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid fields: ")?;
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}: {}", field.path, field.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// The response to some common database errors, for [`Hooks::sqlx_errors`][crate::Hooks::sqlx_errors]:
+/// - `RowNotFound` is a `404 Not Found`, with the code `"not_found"`.
+/// - Unique constraint violations are a `409 Conflict`, with the code `"unique_violation"`.
+/// - Foreign key violations are a `422 Unprocessable Entity`, with the code `"foreign_key_violation"`.
+///
+/// Constraint violations include the name of the constraint in their details, e.g. `{ "constraint": "users_email_key" }`.
+/// Other errors are left as `500 Internal Server Error`s.
+///
+/// ## Example:
+///
+/// Responding to check constraint violations as well:
+///
+/// ```
+/// use preroll::{map_sqlx_error, Error, Hooks};
+///
+/// # #[allow(dead_code)]
+/// fn setup_hooks() -> Hooks<()> {
+///     Hooks::new().sqlx_errors(|error: &sqlx::Error| match error {
+///         sqlx::Error::Database(db_error) if db_error.code().as_deref() == Some("23514") => Some(
+///             Error::with_code("check_violation", 422, db_error.message()),
+///         ),
+///         error => map_sqlx_error(error),
+///     })
+/// }
+/// ```
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub fn map_sqlx_error(error: &sqlx::Error) -> Option<tide::Error> {
+    let db_error = match error {
+        sqlx::Error::RowNotFound => {
+            return Some(Error::with_code(
+                "not_found",
+                StatusCode::NotFound,
+                "Not found",
+            ))
+        }
+        sqlx::Error::Database(db_error) => db_error,
+        _ => return None,
+    };
+
+    // https://www.postgresql.org/docs/current/errcodes-appendix.html
+    let (status, code) = match db_error.code().as_deref() {
+        Some("23505") => (StatusCode::Conflict, "unique_violation"),
+        Some("23503") => (StatusCode::UnprocessableEntity, "foreign_key_violation"),
+        _ => return None,
+    };
+    Some(tide::Error::new(
+        status,
+        Error {
+            code: Some(code.to_string()),
+            message: db_error.message().to_string(),
+            details: db_error
+                .constraint()
+                .map(|constraint| serde_json::json!({ "constraint": constraint })),
+            retry_after: None,
+        },
+    ))
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_sqlx_errors() {
+        let error = map_sqlx_error(&sqlx::Error::RowNotFound);
+        assert_eq!(
+            error.as_ref().map(tide::Error::status),
+            Some(StatusCode::NotFound)
+        );
+        assert_eq!(
+            error.as_ref().and_then(Error::of).and_then(Error::code),
+            Some("not_found")
+        );
+
+        assert!(map_sqlx_error(&sqlx::Error::PoolTimedOut).is_none());
+    }
+}
@@ -0,0 +1,566 @@
+//! Typed extraction from requests, via [`ExtractRequestExt`][crate::prelude::ExtractRequestExt].
+//!
+//! Extraction failures are client errors which say what was wrong and where:
+//! - JSON bodies which are not `application/json` are a `415 Unsupported Media Type` error.
+//! - JSON bodies over the size limit are a `413 Payload Too Large` error.
+//! - Malformed JSON is a `400 Bad Request` error.
+//! - JSON which does not match the expected type is a `422 Unprocessable Entity` error, with the path of the offending
+//!   value in [`JsonError::fields`][crate::JsonError::fields].
+//! - Query parameters which are missing or do not parse are a `400 Bad Request` error naming the parameter, with the
+//!   code `"missing_query_parameter"` or `"invalid_query_parameter"`.
+//! - Path parameters which do not parse are a `400 Bad Request` error naming the parameter, with the code
+//!   `"invalid_path_parameter"`.
+//!
+//! ## Example:
+//!
+//! ```
+//! use preroll::prelude::*;
+//! use serde::Deserialize;
+//! use tide::Request;
+//!
+//! #[derive(Deserialize)]
+//! #[serde(deny_unknown_fields)] // Rejects unknown fields, with their path.
+//! struct NewUser {
+//!     name: String,
+//!     address: Address,
+//! }
+//!
+//! #[derive(Deserialize)]
+//! struct Address {
+//!     city: String,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn create_user(mut req: Request<()>) -> tide::Result<String> {
+//!     // E.g. `{"name": "Ada", "address": {"city": 7}}` is a 422, with the path `address.city`.
+//!     let user: NewUser = req.body_json_validated().await?;
+//!     Ok(format!("{} from {}", user.name, user.address.city))
+//! }
+//!
+//! #[derive(Deserialize)]
+//! struct ListParams {
+//!     #[serde(default)]
+//!     ids: Vec<u64>,
+//!     #[serde(default = "default_limit")]
+//!     limit: u32,
+//!     filter: Option<Filter>,
+//! }
+//!
+//! #[derive(Deserialize)]
+//! struct Filter {
+//!     status: String,
+//! }
+//!
+//! fn default_limit() -> u32 {
+//!     20
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn list_users(req: Request<()>) -> tide::Result<String> {
+//!     // E.g. `?ids=1,2&filter[status]=active`, or `?ids[]=1&ids[]=2`.
+//!     // `?limit=many` is a 400, naming `limit`.
+//!     let params: ListParams = req.query_typed()?;
+//!     let status = params.filter.map(|filter| filter.status);
+//!     Ok(format!("{:?} {} {:?}", params.ids, params.limit, status))
+//! }
+//!
+//! #[derive(Debug, Deserialize)]
+//! #[serde(rename_all = "snake_case")]
+//! enum Role {
+//!     Admin,
+//!     Member,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn list_role_members(req: Request<()>) -> tide::Result<String> {
+//!     // E.g. for `/orgs/:org_id/roles/:role`, `/orgs/7/roles/admin`.
+//!     // `/orgs/seven/roles/admin` is a 400, naming `org_id`.
+//!     let org_id: i64 = req.param_typed("org_id")?;
+//!     let role: Role = req.param_typed("role")?;
+//!     Ok(format!("{} {:?}", org_id, role))
+//! }
+//! ```
+
+use futures_lite::AsyncReadExt;
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use tide::{Request, StatusCode};
+
+use crate::pagination::{Pagination, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::ValidationErrors;
+
+pub(crate) mod params;
+
+use params::{Param, ParamDeserializer};
+
+/// The default limit on the size of JSON bodies: 1 MiB.
+pub const DEFAULT_MAX_JSON_SIZE: usize = 1024 * 1024;
+
+/// An extension trait for typed extraction from requests.
+#[tide::utils::async_trait]
+pub trait ExtractRequestExt {
+    /// Read the body as JSON of type `T`, of up to [`DEFAULT_MAX_JSON_SIZE`].
+    ///
+    /// See the [module documentation][crate::extract] for the errors.
+    async fn body_json_validated<T: DeserializeOwned>(&mut self) -> tide::Result<T>;
+
+    /// Read the body as JSON of type `T`, of up to `max_size` bytes.
+    async fn body_json_validated_max<T: DeserializeOwned>(
+        &mut self,
+        max_size: usize,
+    ) -> tide::Result<T>;
+
+    /// Read the query string as type `T`.
+    ///
+    /// Nested parameters use brackets, e.g. `filter[status]=active`, and lists are either comma-separated,
+    /// e.g. `ids=1,2`, or repeated, e.g. `ids[]=1&ids[]=2`. Missing parameters use `#[serde(default)]`s,
+    /// and an empty value is `None`.
+    fn query_typed<T: DeserializeOwned>(&self) -> tide::Result<T>;
+
+    /// Read the path parameter `name` as type `T`, e.g. a `Uuid`, an `i64`, or an enum of unit variants.
+    fn param_typed<T: DeserializeOwned>(&self, name: &str) -> tide::Result<T>;
+
+    /// Read the [`Pagination`] query parameters, of up to [`MAX_PAGE_SIZE`] items.
+    ///
+    /// See the [`pagination` module documentation][crate::pagination] for the parameters and errors.
+    fn pagination(&self) -> tide::Result<Pagination>;
+
+    /// Read the [`Pagination`] query parameters, of `default_limit` items unless requested otherwise, and up to
+    /// `max_limit` items.
+    fn pagination_with(&self, default_limit: u32, max_limit: u32) -> tide::Result<Pagination>;
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> ExtractRequestExt for Request<State> {
+    async fn body_json_validated<T: DeserializeOwned>(&mut self) -> tide::Result<T> {
+        self.body_json_validated_max(DEFAULT_MAX_JSON_SIZE).await
+    }
+
+    async fn body_json_validated_max<T: DeserializeOwned>(
+        &mut self,
+        max_size: usize,
+    ) -> tide::Result<T> {
+        let is_json = self.content_type().is_some_and(|mime| {
+            mime.essence() == "application/json" || mime.subtype().ends_with("+json")
+        });
+        if !is_json {
+            return Err(tide::Error::from_str(
+                StatusCode::UnsupportedMediaType,
+                "Expected an application/json body",
+            ));
+        }
+
+        let too_large = || {
+            tide::Error::from_str(
+                StatusCode::PayloadTooLarge,
+                format!("JSON bodies are limited to {} bytes", max_size),
+            )
+        };
+        if self.len().is_some_and(|len| len > max_size) {
+            return Err(too_large());
+        }
+
+        let mut json = Vec::new();
+        self.take_body()
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut json)
+            .await?;
+        if json.len() > max_size {
+            return Err(too_large());
+        }
+
+        serde_json::from_slice(&json).map_err(|error| json_error(&json, &error))
+    }
+
+    fn query_typed<T: DeserializeOwned>(&self) -> tide::Result<T> {
+        let params = Param::parse(self.url().query_pairs());
+        T::deserialize(ParamDeserializer::new(params, "")).map_err(|error| error.into_tide("query"))
+    }
+
+    fn param_typed<T: DeserializeOwned>(&self, name: &str) -> tide::Result<T> {
+        let raw = self.param(name)?;
+        let param = percent_decode_str(raw).decode_utf8_lossy().into_owned();
+        T::deserialize(ParamDeserializer::new(Param::Value(param), name))
+            .map_err(|error| error.into_tide("path"))
+    }
+
+    fn pagination(&self) -> tide::Result<Pagination> {
+        self.pagination_with(DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE)
+    }
+
+    fn pagination_with(&self, default_limit: u32, max_limit: u32) -> tide::Result<Pagination> {
+        Pagination::from_request(self, default_limit, max_limit)
+    }
+}
+
+/// A `400 Bad Request` error for malformed JSON, or else a `422 Unprocessable Entity` error with the path of the
+/// value which does not match.
+fn json_error(json: &[u8], error: &serde_json::Error) -> tide::Error {
+    if !error.is_data() {
+        return tide::Error::from_str(StatusCode::BadRequest, format!("Malformed JSON: {}", error));
+    }
+
+    // The message, without the position, e.g. "invalid type: string \"7\", expected u32".
+    let full_message = error.to_string();
+    let message = full_message
+        .rsplit_once(" at line ")
+        .map_or(full_message.as_str(), |(message, _)| message);
+
+    let offset = offset_of(json, error.line(), error.column());
+    let mut stack = json_stack(json, offset);
+    let mut path = path_of(&stack);
+    let code = match message {
+        m if m.starts_with("missing field") => {
+            // Reported at the end of the object which is missing the field, rather than at one of its keys.
+            if let (Some(Frame::Object(key)), Some(field)) =
+                (stack.last_mut(), message.split('`').nth(1))
+            {
+                *key = Some(field.to_string());
+                path = path_of(&stack);
+            }
+            "missing_field"
+        }
+        m if m.starts_with("unknown field") => "unknown_field",
+        m if m.starts_with("invalid type") => "invalid_type",
+        _ => "invalid_value",
+    };
+
+    let mut errors = ValidationErrors::new();
+    errors.add(path, code, message);
+    tide::Error::new(StatusCode::UnprocessableEntity, errors)
+}
+
+/// The byte offset of a 1-based line and column, as reported by `serde_json`.
+fn offset_of(json: &[u8], line: usize, column: usize) -> usize {
+    let line_start: usize = json
+        .split(|b| *b == b'\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.len() + 1)
+        .sum();
+    line_start + column.saturating_sub(1)
+}
+
+enum Frame {
+    Object(Option<String>),
+    Array(usize),
+}
+
+/// The objects and arrays containing the value being read at `offset`.
+fn json_stack(json: &[u8], offset: usize) -> Vec<Frame> {
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut expecting_key = false;
+
+    let mut i = 0;
+    while i < offset.min(json.len()) {
+        match json[i] {
+            b'{' => {
+                stack.push(Frame::Object(None));
+                expecting_key = true;
+            }
+            b'[' => stack.push(Frame::Array(0)),
+            b'}' | b']' => {
+                stack.pop();
+            }
+            b',' => match stack.last_mut() {
+                Some(Frame::Array(index)) => *index += 1,
+                Some(Frame::Object(key)) => {
+                    *key = None;
+                    expecting_key = true;
+                }
+                None => {}
+            },
+            b'"' => {
+                // Strings are read whole, even past the offset, so that the key being read is known.
+                let end = string_end(json, i + 1);
+                if expecting_key {
+                    if let Some(Frame::Object(key)) = stack.last_mut() {
+                        let raw = &json[i..(end + 1).min(json.len())];
+                        *key = Some(
+                            serde_json::from_slice(raw)
+                                .unwrap_or_else(|_| String::from_utf8_lossy(raw).into_owned()),
+                        );
+                    }
+                    expecting_key = false;
+                }
+                i = end;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    stack
+}
+
+/// The path of the innermost value of a stack, e.g. `items[2].quantity`.
+fn path_of(stack: &[Frame]) -> String {
+    let mut path = String::new();
+    for frame in stack {
+        match frame {
+            Frame::Object(Some(key)) => path = join_path(&path, key),
+            Frame::Object(None) => {}
+            Frame::Array(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    path
+}
+
+/// The index of the closing quote of a string which starts at `start`.
+fn string_end(json: &[u8], start: usize) -> usize {
+    let mut i = start;
+    while i < json.len() {
+        match json[i] {
+            b'\\' => i += 2,
+            b'"' => return i,
+            _ => i += 1,
+        }
+    }
+    json.len()
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+    use crate::JsonError;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Order {
+        #[allow(dead_code)]
+        items: Vec<Item>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Item {
+        #[allow(dead_code)]
+        sku: String,
+        #[allow(dead_code)]
+        quantity: u32,
+    }
+
+    #[test]
+    fn finds_json_paths() {
+        let json = br#"{"items": [{"sku": "a", "quantity": 1}, {"sku": "b", "quantity": "2"}]}"#;
+        let offset = json.iter().rposition(|b| *b == b'"').unwrap_or_default();
+        assert_eq!(path_of(&json_stack(json, offset)), "items[1].quantity");
+    }
+
+    #[async_std::test]
+    async fn validates_json_bodies() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server
+                .at("orders")
+                .post(|mut req: Request<Arc<()>>| async move {
+                    let order: Order = req.body_json_validated_max(256).await?;
+                    Ok(format!("{}", order.items.len()))
+                });
+        })
+        .await?;
+
+        let cases = [
+            (
+                r#"{"items": [{"sku": "a", "quantity": 1}, {"sku": "b", "quantity": "2"}]}"#,
+                "items[1].quantity",
+                "invalid_type",
+            ),
+            (
+                "{\"items\": [\n  {\"sku\": \"a\"}\n]}",
+                "items[0].quantity",
+                "missing_field",
+            ),
+            (
+                r#"{"items": [], "coupon": "FREE"}"#,
+                "coupon",
+                "unknown_field",
+            ),
+        ];
+        for (body, path, code) in cases {
+            let mut res = client
+                .post("/api/v1/orders")
+                .body(body)
+                .content_type("application/json")
+                .await?;
+            let error: JsonError = res.body_json().await?;
+            assert_eq!(error.status, 422, "{}", body);
+            assert_eq!(error.fields[0].path, path, "{}", body);
+            assert_eq!(error.fields[0].code, code, "{}", body);
+        }
+
+        let mut res = client
+            .post("/api/v1/orders")
+            .body(r#"{"items": [}"#)
+            .content_type("application/json")
+            .await?;
+        assert_status(&mut res, 400).await;
+
+        let mut res = client.post("/api/v1/orders").body("{}").await?;
+        assert_status(&mut res, 415).await;
+
+        let mut res = client
+            .post("/api/v1/orders")
+            .body(format!(
+                r#"{{"items": [], "padding": "{}"}}"#,
+                "x".repeat(256)
+            ))
+            .content_type("application/json")
+            .await?;
+        assert_status(&mut res, 413).await;
+
+        let mut res = client
+            .post("/api/v1/orders")
+            .body(r#"{"items": [{"sku": "a", "quantity": 1}]}"#)
+            .content_type("application/json")
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "1");
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct ListParams {
+        #[serde(default)]
+        ids: Vec<u64>,
+        #[serde(default = "default_limit")]
+        limit: u32,
+        filter: Option<Filter>,
+        sort: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Filter {
+        status: String,
+    }
+
+    fn default_limit() -> u32 {
+        20
+    }
+
+    #[async_std::test]
+    async fn reads_typed_queries() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("users").get(|req: Request<Arc<()>>| async move {
+                let params: ListParams = req.query_typed()?;
+                let status = params.filter.map(|filter| filter.status);
+                Ok(format!(
+                    "{:?} {} {:?} {}",
+                    params.ids, params.limit, status, params.sort
+                ))
+            });
+        })
+        .await?;
+
+        let cases = [
+            ("sort=name", "[] 20 None name"),
+            (
+                "ids=1,2&limit=5&filter[status]=active&sort=name",
+                "[1, 2] 5 Some(\"active\") name",
+            ),
+            ("ids[]=1&ids[]=2&sort=a%20b", "[1, 2] 20 None a b"),
+            ("ids=3&ids=4&sort=name", "[3, 4] 20 None name"),
+        ];
+        for (query, body) in cases {
+            let mut res = client.get(format!("/api/v1/users?{}", query)).await?;
+            assert_eq!(assert_status(&mut res, 200).await, body, "{}", query);
+        }
+
+        let cases = [
+            (
+                "limit=many&sort=name",
+                "invalid_query_parameter",
+                "\"limit\"",
+            ),
+            ("ids=1,x&sort=name", "invalid_query_parameter", "\"ids[1]\""),
+            (
+                "filter=active&sort=name",
+                "invalid_query_parameter",
+                "\"filter\"",
+            ),
+            (
+                "filter[other]=1&sort=name",
+                "missing_query_parameter",
+                "\"filter[status]\"",
+            ),
+            ("limit=5", "missing_query_parameter", "\"sort\""),
+        ];
+        for (query, code, parameter) in cases {
+            let mut res = client.get(format!("/api/v1/users?{}", query)).await?;
+            let error: JsonError = res.body_json().await?;
+            assert_eq!(error.status, 400, "{}", query);
+            assert_eq!(error.code.as_deref(), Some(code), "{}", query);
+            assert!(
+                error.message.contains(parameter),
+                "{}: {}",
+                query,
+                error.message
+            );
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Role {
+        Admin,
+        Member,
+    }
+
+    #[async_std::test]
+    async fn reads_typed_path_params() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server
+                .at("users/:id/roles/:role/:rank")
+                .get(|req: Request<Arc<()>>| async move {
+                    let id: uuid::Uuid = req.param_typed("id")?;
+                    let role: Role = req.param_typed("role")?;
+                    let rank: i64 = req.param_typed("rank")?;
+                    Ok(format!("{} {:?} {}", id, role, rank))
+                });
+        })
+        .await?;
+
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let mut res = client
+            .get(format!("/api/v1/users/{}/roles/admin/-3", id))
+            .await?;
+        assert_eq!(
+            assert_status(&mut res, 200).await,
+            format!("{} Admin -3", id)
+        );
+
+        let cases = [
+            ("7/roles/admin/1", "\"id\""),
+            (&format!("{}/roles/owner/1", id), "\"role\""),
+            (&format!("{}/roles/member/first", id), "\"rank\""),
+        ];
+        for (path, parameter) in cases {
+            let mut res = client.get(format!("/api/v1/users/{}", path)).await?;
+            let error: JsonError = res.body_json().await?;
+            assert_eq!(error.status, 400, "{}", path);
+            assert_eq!(
+                error.code.as_deref(),
+                Some("invalid_path_parameter"),
+                "{}",
+                path
+            );
+            assert!(
+                error.message.contains(parameter),
+                "{}: {}",
+                path,
+                error.message
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,436 @@
+//! A `serde` deserializer for query strings and path parameters, which keeps track of the parameter being read so
+//! that errors can name it.
+//!
+//! Query parameters are nested with brackets, e.g. `filter[status]=active`, and lists are either repeated parameters,
+//! e.g. `ids[]=1&ids[]=2` or `ids=1&ids=2`, or comma-separated, e.g. `ids=1,2`.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::de::value::StrDeserializer;
+use serde::de::{
+    self, DeserializeSeed, Error as _, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use tide::StatusCode;
+
+use crate::Error;
+
+/// A query string, parsed into nested parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Param {
+    Value(String),
+    List(Vec<Param>),
+    Map(Vec<(String, Param)>),
+}
+
+impl Param {
+    /// Parse a query string, e.g. `page=2&filter[status]=active&ids=1,2`.
+    pub(crate) fn parse(pairs: impl Iterator<Item = (impl AsRef<str>, impl Into<String>)>) -> Self {
+        let mut root = Param::Map(Vec::new());
+        for (key, value) in pairs {
+            let key = key.as_ref();
+            let (name, nested) = key.split_at(key.find('[').unwrap_or(key.len()));
+            let segments: Vec<&str> = std::iter::once(name)
+                .chain(
+                    nested
+                        .split('[')
+                        .skip(1)
+                        .map(|segment| segment.trim_end_matches(']')),
+                )
+                .collect();
+            root.insert(&segments, value.into());
+        }
+        root
+    }
+
+    fn insert(&mut self, segments: &[&str], value: String) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+
+        // `ids[]=1` appends to a list, which starts out as an empty placeholder map.
+        if segment.is_empty() {
+            if matches!(self, Param::Map(entries) if entries.is_empty()) {
+                *self = Param::List(Vec::new());
+            }
+            if let Param::List(items) = self {
+                items.push(if rest.is_empty() {
+                    Param::Value(value)
+                } else {
+                    let mut item = Param::Map(Vec::new());
+                    item.insert(rest, value);
+                    item
+                });
+            }
+            return;
+        }
+
+        let entries = match self {
+            Param::Map(entries) => entries,
+            _ => return,
+        };
+        let index = match entries.iter().position(|(key, _)| key == segment) {
+            Some(index) => index,
+            None => {
+                entries.push((segment.to_string(), Param::Map(Vec::new())));
+                entries.len() - 1
+            }
+        };
+        let entry = &mut entries[index].1;
+        if !rest.is_empty() {
+            entry.insert(rest, value);
+            return;
+        }
+        match entry {
+            Param::Map(nested) if nested.is_empty() => *entry = Param::Value(value),
+            Param::Value(first) => {
+                *entry = Param::List(vec![
+                    Param::Value(std::mem::take(first)),
+                    Param::Value(value),
+                ])
+            }
+            Param::List(items) => items.push(Param::Value(value)),
+            // Conflicts with nested parameters of the same name.
+            Param::Map(_) => {}
+        }
+    }
+}
+
+/// A problem with the parameter at `path`, or with the parameters as a whole if not known.
+#[derive(Debug)]
+pub(crate) struct ParamError {
+    path: Option<String>,
+    message: String,
+}
+
+impl ParamError {
+    /// The error with `path`, unless a more specific one was already known.
+    fn at(mut self, path: &str) -> Self {
+        if self.path.is_none() {
+            self.path = Some(path.to_string());
+        }
+        self
+    }
+
+    /// A `400 Bad Request` error naming the parameter, with the code e.g. `"missing_query_parameter"` or
+    /// `"invalid_path_parameter"` for the `kind` of parameter.
+    pub(crate) fn into_tide(self, kind: &str) -> tide::Error {
+        let mut path = self.path.unwrap_or_default();
+        // Reported by the struct which is missing the field, rather than by the field itself.
+        if let Some(field) = self
+            .message
+            .strip_prefix("missing field")
+            .and_then(|rest| rest.split('`').nth(1))
+        {
+            path = join_path(&path, field);
+            return Error::with_code(
+                format!("missing_{}_parameter", kind),
+                StatusCode::BadRequest,
+                format!("Missing {} parameter \"{}\"", kind, path),
+            );
+        }
+
+        let message = if path.is_empty() {
+            format!("Invalid {} parameters: {}", kind, self.message)
+        } else {
+            format!("Invalid {} parameter \"{}\": {}", kind, path, self.message)
+        };
+        Error::with_code(
+            format!("invalid_{}_parameter", kind),
+            StatusCode::BadRequest,
+            message,
+        )
+    }
+}
+
+impl Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+impl de::Error for ParamError {
+    fn custom<T: Display>(message: T) -> Self {
+        Self {
+            path: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Deserializes a parameter, which is at `path`, e.g. `filter[status]`.
+pub(crate) struct ParamDeserializer {
+    param: Param,
+    path: String,
+}
+
+impl ParamDeserializer {
+    pub(crate) fn new(param: Param, path: impl Into<String>) -> Self {
+        Self {
+            param,
+            path: path.into(),
+        }
+    }
+
+    /// The value of the parameter, or the last value if repeated.
+    fn value(&self) -> Result<&str, ParamError> {
+        match &self.param {
+            Param::Value(value) => Ok(value),
+            Param::List(items) => match items.last() {
+                Some(Param::Value(value)) => Ok(value),
+                _ => Err(de::Error::custom("expected a value")),
+            },
+            Param::Map(_) => Err(de::Error::custom("expected a value")),
+        }
+    }
+
+    fn parse<T>(&self) -> Result<T, ParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.value().map_err(|error| error.at(&self.path))?;
+        value
+            .parse()
+            .map_err(|error| de::Error::custom(format!("{} (got \"{}\")", error, value)))
+            .map_err(|error: ParamError| error.at(&self.path))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+            let value = self.parse()?;
+            visitor.$visit::<ParamError>(value).map_err(|error| error.at(&self.path))
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for ParamDeserializer {
+    type Error = ParamError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        let ParamDeserializer { param, path } = self;
+        match param {
+            Param::Value(value) => visitor.visit_string(value),
+            Param::List(items) => visitor.visit_seq(ParamSeq::new(items, &path)),
+            Param::Map(entries) => visitor.visit_map(ParamMap::new(entries, &path)),
+        }
+        .map_err(|error| error.at(&path))
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        let value = self.value().map_err(|error| error.at(&self.path))?;
+        visitor
+            .visit_str::<ParamError>(value)
+            .map_err(|error| error.at(&self.path))
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        self.deserialize_string(visitor)
+    }
+
+    /// An empty value, e.g. `cursor=`, is `None`.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        if self.param == Param::Value(String::new()) {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ParamError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ParamError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// A single value is a comma-separated list, e.g. `ids=1,2`.
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        let ParamDeserializer { param, path } = self;
+        let items = match param {
+            Param::Value(value) => value
+                .split(',')
+                .filter(|item| !item.is_empty())
+                .map(|item| Param::Value(item.to_string()))
+                .collect(),
+            Param::List(items) => items,
+            Param::Map(_) => {
+                return Err(ParamError::custom("expected a list").at(&path));
+            }
+        };
+        visitor
+            .visit_seq(ParamSeq::new(items, &path))
+            .map_err(|error| error.at(&path))
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        let ParamDeserializer { param, path } = self;
+        match param {
+            Param::Map(entries) => visitor
+                .visit_map(ParamMap::new(entries, &path))
+                .map_err(|error| error.at(&path)),
+            _ => Err(ParamError::custom("expected nested parameters").at(&path)),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ParamError> {
+        self.deserialize_map(visitor)
+    }
+
+    /// Only unit variants, e.g. `sort=newest`.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ParamError> {
+        let value = self.value().map_err(|error| error.at(&self.path))?;
+        let deserializer: StrDeserializer<'_, ParamError> = value.into_deserializer();
+        visitor
+            .visit_enum(deserializer)
+            .map_err(|error| error.at(&self.path))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ParamError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bytes byte_buf tuple tuple_struct
+    }
+}
+
+struct ParamSeq {
+    items: std::iter::Enumerate<std::vec::IntoIter<Param>>,
+    path: String,
+}
+
+impl ParamSeq {
+    fn new(items: Vec<Param>, path: &str) -> Self {
+        Self {
+            items: items.into_iter().enumerate(),
+            path: path.to_string(),
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for ParamSeq {
+    type Error = ParamError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, ParamError> {
+        match self.items.next() {
+            Some((index, param)) => seed
+                .deserialize(ParamDeserializer {
+                    param,
+                    path: format!("{}[{}]", self.path, index),
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+struct ParamMap {
+    entries: std::vec::IntoIter<(String, Param)>,
+    value: Option<(String, Param)>,
+    path: String,
+}
+
+impl ParamMap {
+    fn new(entries: Vec<(String, Param)>, path: &str) -> Self {
+        Self {
+            entries: entries.into_iter(),
+            value: None,
+            path: path.to_string(),
+        }
+    }
+}
+
+impl<'de> MapAccess<'de> for ParamMap {
+    type Error = ParamError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ParamError> {
+        match self.entries.next() {
+            Some((key, param)) => {
+                let path = join_path(&self.path, &key);
+                let deserializer: StrDeserializer<'_, ParamError> =
+                    key.as_str().into_deserializer();
+                let key = seed
+                    .deserialize(deserializer)
+                    .map_err(|error| error.at(&path))?;
+                self.value = Some((path, param));
+                Ok(Some(key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, ParamError> {
+        let (path, param) = self
+            .value
+            .take()
+            .ok_or_else(|| ParamError::custom("expected a value"))?;
+        seed.deserialize(ParamDeserializer { param, path })
+    }
+}
+
+/// The path of a nested parameter, e.g. `filter[status]`.
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}[{}]", path, key)
+    }
+}
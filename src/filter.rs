@@ -0,0 +1,515 @@
+//! Filtering and sorting of lists via query parameters, e.g. `?sort=-created_at&filter[status]=active`, translated
+//! into SQL for allowlisted columns only.
+//!
+//! A [`ListSpec`] declares which columns may be filtered, with their types, and which may be sorted by:
+//! - `filter[column]=value` filters by equality, and `filter[column][op]=value` by one of the [`Op`]s, e.g.
+//!   `filter[age][gte]=18`. The values of `in` are comma-separated, e.g. `filter[status][in]=active,invited`.
+//! - `sort=column` sorts ascending, and `sort=-column` descending. Several columns are comma-separated, e.g.
+//!   `sort=-created_at,id`.
+//!
+//! Columns which are not allowlisted, unknown operators, and values which do not parse as the column's type are a
+//! `400 Bad Request` error naming the parameter, with the code `"invalid_query_parameter"`.
+//!
+//! Column names only ever come from the [`ListSpec`], and values are always bound as query parameters, so the SQL of a
+//! [`ListQuery`] is safe to format into a query.
+//!
+//! ## Example:
+//!
+//! ```
+//! use once_cell::sync::Lazy;
+//! use preroll::filter::{FieldType, ListSpec};
+//! use tide::Request;
+//!
+//! static USERS: Lazy<ListSpec> = Lazy::new(|| {
+//!     ListSpec::new()
+//!         .filter("status", FieldType::Text)
+//!         .filter("age", FieldType::Integer)
+//!         .sort("created_at")
+//!         .sort("name")
+//!         .default_sort("-created_at")
+//! });
+//!
+//! # #[allow(dead_code)]
+//! async fn list_users(req: Request<()>) -> tide::Result<String> {
+//!     // E.g. `?sort=name&filter[status]=active&filter[age][gte]=18`.
+//!     let list = USERS.parse(&req)?;
+//!     let sql = format!(
+//!         "SELECT id, name FROM users WHERE org_id = $1 AND {} {}",
+//!         list.filter_sql(2),
+//!         list.sort_sql()
+//!     );
+//!     // `... WHERE org_id = $1 AND "status" = $2 AND "age" >= $3 ORDER BY "name" ASC`,
+//!     // for `list.bind(sqlx::query(&sql).bind(org_id))` with the `postgres` feature.
+//!     Ok(sql)
+//! }
+//! ```
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use tide::{Request, StatusCode};
+use uuid::Uuid;
+
+use crate::extract::params::Param;
+use crate::Error;
+
+/// The type of a filterable column, which its values must parse as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FieldType {
+    /// E.g. `active`.
+    Text,
+    /// E.g. `18`.
+    Integer,
+    /// E.g. `1.5`.
+    Float,
+    /// `true` or `false`.
+    Bool,
+    /// E.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+    Uuid,
+    /// An RFC 3339 timestamp, e.g. `2021-06-01T00:00:00Z`.
+    Timestamp,
+}
+
+/// A comparison of a column with a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Op {
+    /// `eq`, or no operator.
+    Eq,
+    /// `ne`.
+    Ne,
+    /// `lt`.
+    Lt,
+    /// `lte`.
+    Lte,
+    /// `gt`.
+    Gt,
+    /// `gte`.
+    Gte,
+    /// `in`, with comma-separated values.
+    In,
+}
+
+impl Op {
+    fn parse(op: &str) -> Option<Self> {
+        Some(match op {
+            "eq" => Self::Eq,
+            "ne" => Self::Ne,
+            "lt" => Self::Lt,
+            "lte" => Self::Lte,
+            "gt" => Self::Gt,
+            "gte" => Self::Gte,
+            "in" => Self::In,
+            _ => return None,
+        })
+    }
+
+    fn sql(self) -> &'static str {
+        match self {
+            Self::Eq => "=",
+            Self::Ne => "<>",
+            Self::Lt => "<",
+            Self::Lte => "<=",
+            Self::Gt => ">",
+            Self::Gte => ">=",
+            Self::In => "= ANY",
+        }
+    }
+}
+
+/// A value to filter by, parsed as the column's [`FieldType`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum FilterValue {
+    /// Of a [`FieldType::Text`] column.
+    Text(String),
+    /// Of a [`FieldType::Integer`] column.
+    Integer(i64),
+    /// Of a [`FieldType::Float`] column.
+    Float(f64),
+    /// Of a [`FieldType::Bool`] column.
+    Bool(bool),
+    /// Of a [`FieldType::Uuid`] column.
+    Uuid(Uuid),
+    /// Of a [`FieldType::Timestamp`] column.
+    Timestamp(DateTime<Utc>),
+}
+
+impl FilterValue {
+    fn parse(field_type: FieldType, value: &str) -> Result<Self, String> {
+        Ok(match field_type {
+            FieldType::Text => Self::Text(value.to_string()),
+            FieldType::Integer => {
+                Self::Integer(value.parse().map_err(|error| format!("{}", error))?)
+            }
+            FieldType::Float => Self::Float(value.parse().map_err(|error| format!("{}", error))?),
+            FieldType::Bool => Self::Bool(value.parse().map_err(|error| format!("{}", error))?),
+            FieldType::Uuid => Self::Uuid(value.parse().map_err(|error| format!("{}", error))?),
+            FieldType::Timestamp => Self::Timestamp(
+                DateTime::parse_from_rfc3339(value)
+                    .map_err(|error| format!("{}", error))?
+                    .with_timezone(&Utc),
+            ),
+        })
+    }
+}
+
+/// The columns of a list which may be filtered and sorted by.
+#[derive(Debug, Clone, Default)]
+pub struct ListSpec {
+    filters: Vec<(&'static str, FieldType)>,
+    sorts: Vec<&'static str>,
+    default_sort: Vec<Sort>,
+}
+
+/// A filter of a [`ListQuery`], e.g. `"age" >= 18`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    /// The allowlisted column.
+    pub column: &'static str,
+    /// The comparison.
+    pub op: Op,
+    /// The values, of which there is exactly one unless the comparison is [`Op::In`].
+    pub values: Vec<FilterValue>,
+}
+
+/// A sort of a [`ListQuery`], e.g. `"created_at" DESC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sort {
+    /// The allowlisted column.
+    pub column: &'static str,
+    /// Whether to sort descending.
+    pub descending: bool,
+}
+
+/// The filters and sorts of a request, as parsed by [`ListSpec::parse`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListQuery {
+    /// The filters, in the order they were requested.
+    pub filters: Vec<Filter>,
+    /// The sorts, in order of precedence.
+    pub sorts: Vec<Sort>,
+}
+
+impl ListSpec {
+    /// No filterable or sortable columns, yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow filtering by `column`, with values of `field_type`.
+    pub fn filter(mut self, column: &'static str, field_type: FieldType) -> Self {
+        self.filters.push((column, field_type));
+        self
+    }
+
+    /// Allow sorting by `column`.
+    pub fn sort(mut self, column: &'static str) -> Self {
+        self.sorts.push(column);
+        self
+    }
+
+    /// Sort by `column` when no sort is requested, e.g. `"-created_at"` to sort descending.
+    ///
+    /// Added after any previous default sorts, e.g. to break ties.
+    pub fn default_sort(mut self, column: &'static str) -> Self {
+        self.default_sort.push(Sort {
+            column: column.trim_start_matches('-'),
+            descending: column.starts_with('-'),
+        });
+        self
+    }
+
+    /// The filters and sorts requested via the `filter` and `sort` query parameters.
+    pub fn parse<State>(&self, req: &Request<State>) -> tide::Result<ListQuery> {
+        let params = match Param::parse(req.url().query_pairs()) {
+            Param::Map(params) => params,
+            _ => Vec::new(),
+        };
+
+        let mut list = ListQuery::default();
+        for (name, param) in params {
+            match name.as_str() {
+                "filter" => self.parse_filters(param, &mut list.filters)?,
+                "sort" => list.sorts = self.parse_sorts(param)?,
+                _ => {}
+            }
+        }
+        if list.sorts.is_empty() {
+            list.sorts = self.default_sort.clone();
+        }
+        Ok(list)
+    }
+
+    fn parse_filters(&self, param: Param, filters: &mut Vec<Filter>) -> tide::Result<()> {
+        let columns = match param {
+            Param::Map(columns) => columns,
+            _ => {
+                return Err(invalid_param(
+                    "filter",
+                    "expected e.g. filter[column]=value",
+                ))
+            }
+        };
+
+        for (column, param) in columns {
+            let path = format!("filter[{}]", column);
+            let (column, field_type) = self
+                .filters
+                .iter()
+                .copied()
+                .find(|(allowed, _)| *allowed == column)
+                .ok_or_else(|| invalid_param(&path, "cannot be filtered by"))?;
+
+            let ops = match param {
+                Param::Map(ops) => ops,
+                value => vec![("eq".to_string(), value)],
+            };
+            for (op, value) in ops {
+                let path = format!("{}[{}]", path, op);
+                let op = Op::parse(&op).ok_or_else(|| invalid_param(&path, "unknown operator"))?;
+                let value = match value {
+                    Param::Value(value) => value,
+                    _ => return Err(invalid_param(&path, "expected a value")),
+                };
+                let values = if op == Op::In {
+                    let values: Vec<&str> =
+                        value.split(',').filter(|value| !value.is_empty()).collect();
+                    if values.is_empty() {
+                        return Err(invalid_param(&path, "expected comma-separated values"));
+                    }
+                    values
+                } else {
+                    vec![value.as_str()]
+                };
+                let values = values
+                    .into_iter()
+                    .map(|value| FilterValue::parse(field_type, value))
+                    .collect::<Result<_, _>>()
+                    .map_err(|message| invalid_param(&path, &message))?;
+                filters.push(Filter { column, op, values });
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_sorts(&self, param: Param) -> tide::Result<Vec<Sort>> {
+        let value = match param {
+            Param::Value(value) => value,
+            _ => return Err(invalid_param("sort", "expected e.g. sort=-column")),
+        };
+
+        value
+            .split(',')
+            .filter(|sort| !sort.is_empty())
+            .map(|sort| {
+                let requested = sort.trim_start_matches('-');
+                let column = self
+                    .sorts
+                    .iter()
+                    .copied()
+                    .find(|allowed| *allowed == requested)
+                    .ok_or_else(|| {
+                        invalid_param("sort", &format!("cannot be sorted by \"{}\"", requested))
+                    })?;
+                Ok(Sort {
+                    column,
+                    descending: sort.starts_with('-'),
+                })
+            })
+            .collect()
+    }
+}
+
+impl ListQuery {
+    /// The filters as SQL conditions, e.g. `"status" = $2 AND "age" >= $3`, with parameters numbered from
+    /// `first_param`. `TRUE` if there are no filters, so that it can always follow an `AND`.
+    pub fn filter_sql(&self, first_param: usize) -> String {
+        if self.filters.is_empty() {
+            return "TRUE".to_string();
+        }
+        self.filters
+            .iter()
+            .enumerate()
+            .map(|(i, filter)| match filter.op {
+                Op::In => format!("{} = ANY(${})", Ident(filter.column), first_param + i),
+                op => format!("{} {} ${}", Ident(filter.column), op.sql(), first_param + i),
+            })
+            .collect::<Vec<_>>()
+            .join(" AND ")
+    }
+
+    /// The sorts as an SQL clause, e.g. `ORDER BY "created_at" DESC`. Empty if there are no sorts.
+    pub fn sort_sql(&self) -> String {
+        if self.sorts.is_empty() {
+            return String::new();
+        }
+        let sorts: Vec<String> = self
+            .sorts
+            .iter()
+            .map(|sort| {
+                let direction = if sort.descending { "DESC" } else { "ASC" };
+                format!("{} {}", Ident(sort.column), direction)
+            })
+            .collect();
+        format!("ORDER BY {}", sorts.join(", "))
+    }
+
+    /// Bind the values of the filters, in the order of [`filter_sql`][Self::filter_sql]'s parameters.
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    pub fn bind<'q, Q: BindFilters<'q>>(&self, mut query: Q) -> Q {
+        macro_rules! bind_all {
+            ($values:expr, $variant:ident) => {
+                query.bind_filter_value(
+                    $values
+                        .iter()
+                        .filter_map(|value| match value {
+                            FilterValue::$variant(value) => Some(value.clone()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            };
+        }
+
+        for filter in &self.filters {
+            query = match (filter.op, filter.values.first()) {
+                (Op::In, Some(FilterValue::Text(_))) | (Op::In, None) => {
+                    bind_all!(filter.values, Text)
+                }
+                (Op::In, Some(FilterValue::Integer(_))) => bind_all!(filter.values, Integer),
+                (Op::In, Some(FilterValue::Float(_))) => bind_all!(filter.values, Float),
+                (Op::In, Some(FilterValue::Bool(_))) => bind_all!(filter.values, Bool),
+                (Op::In, Some(FilterValue::Uuid(_))) => bind_all!(filter.values, Uuid),
+                (Op::In, Some(FilterValue::Timestamp(_))) => bind_all!(filter.values, Timestamp),
+                (_, Some(FilterValue::Text(value))) => query.bind_filter_value(value.clone()),
+                (_, Some(FilterValue::Integer(value))) => query.bind_filter_value(*value),
+                (_, Some(FilterValue::Float(value))) => query.bind_filter_value(*value),
+                (_, Some(FilterValue::Bool(value))) => query.bind_filter_value(*value),
+                (_, Some(FilterValue::Uuid(value))) => query.bind_filter_value(*value),
+                (_, Some(FilterValue::Timestamp(value))) => query.bind_filter_value(*value),
+                (_, None) => query.bind_filter_value(None::<String>),
+            };
+        }
+        query
+    }
+}
+
+/// Queries which the values of a [`ListQuery`] can be bound to.
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub trait BindFilters<'q>: Sized {
+    #[doc(hidden)]
+    fn bind_filter_value<T>(self, value: T) -> Self
+    where
+        T: 'q + Send + sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres>;
+}
+
+#[cfg(feature = "postgres")]
+impl<'q> BindFilters<'q> for sqlx::query::Query<'q, sqlx::Postgres, sqlx::postgres::PgArguments> {
+    fn bind_filter_value<T>(self, value: T) -> Self
+    where
+        T: 'q + Send + sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres>,
+    {
+        self.bind(value)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl<'q, O> BindFilters<'q>
+    for sqlx::query::QueryAs<'q, sqlx::Postgres, O, sqlx::postgres::PgArguments>
+{
+    fn bind_filter_value<T>(self, value: T) -> Self
+    where
+        T: 'q + Send + sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres>,
+    {
+        self.bind(value)
+    }
+}
+
+/// A quoted SQL identifier, e.g. `"created_at"`.
+struct Ident(&'static str);
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.replace('"', "\"\""))
+    }
+}
+
+fn invalid_param(path: &str, message: &str) -> tide::Error {
+    Error::with_code(
+        "invalid_query_parameter",
+        StatusCode::BadRequest,
+        format!("Invalid query parameter \"{}\": {}", path, message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+    use crate::JsonError;
+
+    #[async_std::test]
+    async fn filters_and_sorts_allowlisted_columns() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("users").get(|req: Request<Arc<()>>| async move {
+                let spec = ListSpec::new()
+                    .filter("status", FieldType::Text)
+                    .filter("age", FieldType::Integer)
+                    .sort("created_at")
+                    .sort("name")
+                    .default_sort("-created_at");
+                let list = spec.parse(&req)?;
+                Ok(format!("{} {}", list.filter_sql(2), list.sort_sql()))
+            });
+        })
+        .await?;
+
+        let cases = [
+            ("", r#"TRUE ORDER BY "created_at" DESC"#),
+            (
+                "filter[status]=active&filter[age][gte]=18&filter[age][lt]=65&sort=name,-created_at",
+                r#""status" = $2 AND "age" >= $3 AND "age" < $4 ORDER BY "name" ASC, "created_at" DESC"#,
+            ),
+            (
+                "filter[status][in]=active,invited",
+                r#""status" = ANY($2) ORDER BY "created_at" DESC"#,
+            ),
+        ];
+        for (query, body) in cases {
+            let mut res = client.get(format!("/api/v1/users?{}", query)).await?;
+            assert_eq!(assert_status(&mut res, 200).await, body, "{}", query);
+        }
+
+        let cases = [
+            (r#"filter[password]=x"#, "\"filter[password]\""),
+            (r#"filter[age][gte]=old"#, "\"filter[age][gte]\""),
+            (r#"filter[age][like]=1"#, "\"filter[age][like]\""),
+            (r#"sort=password"#, "\"sort\""),
+            (r#"filter=x"#, "\"filter\""),
+        ];
+        for (query, parameter) in cases {
+            let mut res = client.get(format!("/api/v1/users?{}", query)).await?;
+            let error: JsonError = res.body_json().await?;
+            assert_eq!(error.status, 400, "{}", query);
+            assert_eq!(
+                error.code.as_deref(),
+                Some("invalid_query_parameter"),
+                "{}",
+                query
+            );
+            assert!(
+                error.message.contains(parameter),
+                "{}: {}",
+                query,
+                error.message
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Feature flags, evaluated per request by a pluggable [`FlagProvider`].
+//!
+//! Install [`FlagsMiddleware`] with a provider, then check flags via [`FlagsRequestExt::flag`][crate::prelude::FlagsRequestExt::flag].
+//!
+//! - Each flag is evaluated at most once per request, so a request sees a consistent value even if the provider changes mid-request.
+//! - Unknown flags, and all flags if no `FlagsMiddleware` is installed, are `false`.
+//! - [Overrides][set_override], such as those set via the `/admin/flags` routes, take precedence over the provider.
+//! - Evaluations are recorded as events on the request's tracing span with the `"honeycomb"` feature, or logged at `debug` otherwise.
+//!
+//! ## Built-in providers:
+//! - [`EnvFlags`]: Env variables named `FLAG_{NAME}`, e.g. `FLAG_NEW_CHECKOUT=true` for `"new-checkout"`.
+//! - A [`LiveConfig<HashMap<String, bool>>`][crate::LiveConfig], e.g. loaded via `LiveConfig::json_file("flags.json")`,
+//!   from a JSON object of flag names to values, which can be reloaded at runtime.
+//!
+//! Services such as LaunchDarkly or Unleash can be adapted by implementing [`FlagProvider`] around their SDK's client.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::flags::{EnvFlags, FlagsMiddleware};
+//! use preroll::prelude::*;
+//! use preroll::SetupResult;
+//! use tide::{Request, Route, Server};
+//!
+//! # #[allow(dead_code)]
+//! async fn custom_setup(mut server: Server<Arc<()>>) -> SetupResult<Server<Arc<()>>> {
+//!     server.with(FlagsMiddleware::new(EnvFlags::new()));
+//!     Ok(server)
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server.at("checkout").post(|req: Request<Arc<()>>| async move {
+//!         if req.flag("new-checkout") {
+//!             Ok("New checkout")
+//!         } else {
+//!             Ok("Old checkout")
+//!         }
+//!     });
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+
+use cfg_if::cfg_if;
+use once_cell::sync::Lazy;
+use tide::http;
+use tide::{Middleware, Next, Request};
+
+use crate::LiveConfig;
+
+static OVERRIDES: Lazy<RwLock<HashMap<String, bool>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// A source of feature flag values.
+pub trait FlagProvider: Send + Sync + 'static {
+    /// The value of a flag for a request, or `None` if the flag is unknown to this provider.
+    ///
+    /// The request is available for targeting, e.g. by a user id header.
+    fn evaluate(&self, flag: &str, req: &http::Request) -> Option<bool>;
+}
+
+/// Flags from env variables named `FLAG_{NAME}`, where the name is upper-cased and non-alphanumeric characters become `_`.
+///
+/// Values of `true`, `1`, or `on` are `true`, and `false`, `0`, or `off` are `false`.
+/// The environment is read on every evaluation, so flags loaded from Vault or AWS at startup also apply.
+#[derive(Debug, Default, Clone)]
+pub struct EnvFlags {
+    _priv: (),
+}
+
+impl EnvFlags {
+    /// Create a new instance of `EnvFlags`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self { _priv: () }
+    }
+}
+
+impl FlagProvider for EnvFlags {
+    fn evaluate(&self, flag: &str, _req: &http::Request) -> Option<bool> {
+        let name: String = flag
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let value = env::var(format!("FLAG_{}", name.to_ascii_uppercase())).ok()?;
+
+        match value.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "on" => Some(true),
+            "false" | "0" | "off" => Some(false),
+            _ => {
+                log::warn!("Invalid value for feature flag \"{}\": {:?}", flag, value);
+                None
+            }
+        }
+    }
+}
+
+impl FlagProvider for LiveConfig<HashMap<String, bool>> {
+    fn evaluate(&self, flag: &str, _req: &http::Request) -> Option<bool> {
+        self.get().get(flag).copied()
+    }
+}
+
+/// Override a flag for every request in this process, regardless of the provider, or remove the override if `value` is `None`.
+///
+/// Requests which have already evaluated the flag keep their value.
+pub fn set_override(flag: &str, value: Option<bool>) {
+    let mut overrides = OVERRIDES
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match value {
+        Some(value) => overrides.insert(flag.to_string(), value),
+        None => overrides.remove(flag),
+    };
+}
+
+/// The flags which are currently overridden, via [`set_override`].
+pub fn overrides() -> HashMap<String, bool> {
+    OVERRIDES
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+fn override_value(flag: &str) -> Option<bool> {
+    OVERRIDES
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(flag)
+        .copied()
+}
+
+/// The provider and the flags evaluated so far for one request.
+#[derive(Clone)]
+struct RequestFlags {
+    provider: Arc<dyn FlagProvider>,
+    evaluated: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+/// Make a [`FlagProvider`] available to [`FlagsRequestExt::flag`][crate::prelude::FlagsRequestExt::flag].
+#[derive(Clone)]
+pub struct FlagsMiddleware {
+    provider: Arc<dyn FlagProvider>,
+}
+
+impl FlagsMiddleware {
+    /// Create a new instance of `FlagsMiddleware`, which evaluates flags with `provider`.
+    #[must_use]
+    pub fn new(provider: impl FlagProvider) -> Self {
+        Self {
+            provider: Arc::new(provider),
+        }
+    }
+}
+
+impl fmt::Debug for FlagsMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlagsMiddleware").finish()
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for FlagsMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(RequestFlags {
+            provider: self.provider.clone(),
+            evaluated: Arc::new(Mutex::new(HashMap::new())),
+        });
+        Ok(next.run(req).await)
+    }
+}
+
+/// An extension trait for checking feature flags, via the provider set by [`FlagsMiddleware`].
+pub trait FlagsRequestExt {
+    /// Whether a feature flag is on for this request.
+    ///
+    /// The first evaluation of a flag in a request is reused for the rest of the request.
+    fn flag(&self, flag: &str) -> bool;
+}
+
+impl<State> FlagsRequestExt for Request<State> {
+    fn flag(&self, flag: &str) -> bool {
+        let flags = match self.ext::<RequestFlags>() {
+            Some(flags) => flags,
+            None => return override_value(flag).unwrap_or(false),
+        };
+
+        let mut evaluated = flags
+            .evaluated
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(value) = evaluated.get(flag) {
+            return *value;
+        }
+
+        let value = override_value(flag)
+            .or_else(|| flags.provider.evaluate(flag, self.as_ref()))
+            .unwrap_or(false);
+        evaluated.insert(flag.to_string(), value);
+
+        cfg_if! {
+            if #[cfg(feature = "honeycomb")] {
+                tracing::info!(flag = flag, value = value, "Feature Flag Evaluated");
+            } else {
+                log::debug!("Feature flag \"{}\" evaluated to {}", flag, value);
+            }
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    /// Flips every flag on each evaluation.
+    struct FlippingFlags(AtomicBool);
+
+    impl FlagProvider for FlippingFlags {
+        fn evaluate(&self, _flag: &str, _req: &http::Request) -> Option<bool> {
+            Some(!self.0.fetch_xor(true, Ordering::SeqCst))
+        }
+    }
+
+    #[async_std::test]
+    async fn evaluates_flags_once_per_request() -> TestResult<()> {
+        env::set_var("FLAG_ENV_CHECKOUT", "on");
+
+        let mut server = tide::new();
+        server.with(FlagsMiddleware::new(FlippingFlags(AtomicBool::new(false))));
+        server.at("/flip").get(|req: Request<()>| async move {
+            Ok(format!("{} {}", req.flag("flip"), req.flag("flip")))
+        });
+        let client = surf::Client::with_http_client(server);
+
+        let mut res = client.get("http://localhost/flip").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "true true");
+        let mut res = client.get("http://localhost/flip").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "false false");
+
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.with(FlagsMiddleware::new(EnvFlags::new()));
+            server.at("flags").get(|req: Request<Arc<()>>| async move {
+                Ok(format!(
+                    "{} {}",
+                    req.flag("env-checkout"),
+                    req.flag("missing")
+                ))
+            });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/flags").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "true false");
+        Ok(())
+    }
+}
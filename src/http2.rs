@@ -0,0 +1,242 @@
+//! An HTTP/2-capable listener for Tide servers, for the `"http2"` feature.
+//!
+//! Tide's own listener only speaks HTTP/1.1, so this serves connections with Hyper instead, and bridges each request into the Tide server.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+
+use async_compat::Compat;
+use async_std::net::{TcpListener, TcpStream};
+use color_eyre::eyre::eyre;
+use futures_lite::{AsyncReadExt, StreamExt};
+use futures_util::TryStreamExt;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use tide::http::{self as http_types, headers, Method, StatusCode, Url};
+use tide::Server;
+
+use crate::setup::Result;
+
+/// Runs Hyper's background tasks, such as HTTP/2 stream handling, on async-std.
+#[derive(Debug, Clone, Copy)]
+struct AsyncStdExecutor;
+
+impl<F> hyper::rt::Executor<F> for AsyncStdExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        async_std::task::spawn(future);
+    }
+}
+
+/// Listen on `host:port`, serving both HTTP/1.1 and HTTP/2, forever.
+///
+/// HTTP/2 is served over cleartext (h2c) with prior knowledge, i.e. when the client starts the connection with the HTTP/2 preface,
+/// as load balancers which terminate TLS do.
+pub(crate) async fn listen<State>(
+    server: Server<State>,
+    name: &str,
+    host: &str,
+    port: u16,
+) -> Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind((host, port)).await?;
+    log::info!(
+        "{} listening on http://{} (HTTP/1.1 and HTTP/2)",
+        name,
+        listener.local_addr()?
+    );
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                log::error!("Failed to accept connection: {}", error);
+                continue;
+            }
+        };
+
+        async_std::task::spawn(serve_connection(server.clone(), stream));
+    }
+
+    Ok(())
+}
+
+/// Serve HTTP/1.1 or HTTP/2 on an accepted connection, until it closes.
+pub(crate) async fn serve_connection<State>(server: Server<State>, stream: TcpStream)
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let local_addr = stream.local_addr().ok();
+    let peer_addr = stream.peer_addr().ok();
+
+    let service = service_fn(move |req| handle(server.clone(), req, local_addr, peer_addr));
+    let connection = Http::new()
+        .with_executor(AsyncStdExecutor)
+        .serve_connection(Compat::new(stream), service);
+
+    if let Err(error) = connection.await {
+        log::debug!("Connection error: {}", error);
+    }
+}
+
+/// Respond to a Hyper request with the Tide server. Errors are turned into responses, as a listener would.
+async fn handle<State>(
+    server: Server<State>,
+    req: hyper::Request<hyper::Body>,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+) -> std::result::Result<hyper::Response<hyper::Body>, Infallible>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let mut request = match into_tide_request(req) {
+        Ok(request) => request,
+        Err(error) => {
+            log::debug!("Invalid request: {}", error);
+            return Ok(empty_response(StatusCode::BadRequest));
+        }
+    };
+    request.set_local_addr(local_addr);
+    request.set_peer_addr(peer_addr);
+
+    match server.respond::<_, http_types::Response>(request).await {
+        Ok(response) => Ok(into_hyper_response(response)),
+        Err(error) => {
+            log::error!("Unhandled error: {}", error);
+            Ok(empty_response(error.status()))
+        }
+    }
+}
+
+fn into_tide_request(req: hyper::Request<hyper::Body>) -> Result<http_types::Request> {
+    let (parts, body) = req.into_parts();
+
+    // HTTP/2 requests carry the authority in the uri, HTTP/1.1 requests in the Host header.
+    let authority = match parts.uri.authority() {
+        Some(authority) => authority.to_string(),
+        None => parts
+            .headers
+            .get(hyper::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("localhost")
+            .to_string(),
+    };
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let url = Url::parse(&format!("http://{}{}", authority, path))?;
+    let method: Method = parts.method.as_str().parse().map_err(|e| eyre!("{}", e))?;
+
+    let mut request = http_types::Request::new(method, url);
+    request.set_version(Some(match parts.version {
+        hyper::Version::HTTP_10 => http_types::Version::Http1_0,
+        hyper::Version::HTTP_2 => http_types::Version::Http2_0,
+        _ => http_types::Version::Http1_1,
+    }));
+    for (name, value) in parts.headers.iter() {
+        request.append_header(name.as_str(), value.to_str()?);
+    }
+
+    let length = request
+        .header(headers::CONTENT_LENGTH)
+        .and_then(|length| length.as_str().parse().ok());
+    let body = body.map_err(io::Error::other).into_async_read();
+    request.set_body(http_types::Body::from_reader(body, length));
+
+    Ok(request)
+}
+
+fn into_hyper_response(mut response: http_types::Response) -> hyper::Response<hyper::Body> {
+    let mut builder = hyper::Response::builder().status(u16::from(response.status()));
+    for (name, values) in response.iter() {
+        for value in values.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+    }
+
+    let body = response.take_body();
+    if let Some(length) = body.len() {
+        builder = builder.header(hyper::header::CONTENT_LENGTH, length);
+    }
+
+    // Stream the body, so that e.g. server-sent events are not buffered.
+    let chunks = futures_lite::stream::unfold(Some(body), |body| async move {
+        let mut body = body?;
+        let mut chunk = vec![0; 8 * 1024];
+        match body.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(chunk), Some(body)))
+            }
+            Err(error) => Some((Err(error), None)),
+        }
+    });
+
+    builder
+        .body(hyper::Body::wrap_stream(chunks))
+        .unwrap_or_else(|_| empty_response(StatusCode::InternalServerError))
+}
+
+fn empty_response(status: StatusCode) -> hyper::Response<hyper::Body> {
+    let mut response = hyper::Response::new(hyper::Body::empty());
+    *response.status_mut() = hyper::StatusCode::from_u16(status.into())
+        .unwrap_or(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn bridges_http2_requests() -> Result<()> {
+        let mut server = tide::new();
+        server
+            .at("/echo/:name")
+            .post(|mut req: tide::Request<()>| async move {
+                let body = req.body_string().await?;
+                Ok(format!(
+                    "{} {:?} {}",
+                    req.param("name")?,
+                    req.version(),
+                    body
+                ))
+            });
+
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("http://example.local/echo/h2")
+            .version(hyper::Version::HTTP_2)
+            .body(hyper::Body::from("hello"))?;
+        let res = handle(server.clone(), req, None, None).await?;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers()
+                .get(hyper::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok()),
+            Some("text/plain;charset=utf-8")
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(&body[..], b"h2 Some(Http2_0) hello");
+
+        let req = hyper::Request::builder()
+            .uri("/missing")
+            .header(hyper::header::HOST, "example.local")
+            .body(hyper::Body::empty())?;
+        let res = handle(server, req, None, None).await?;
+        assert_eq!(res.status(), 404);
+        Ok(())
+    }
+}
@@ -58,15 +58,37 @@
 //! ```
 //!
 //! ### List of optional add-on features:
+//! - `"aws-secrets"`: Resolves references to [AWS Secrets Manager][] and [AWS Systems Manager Parameter Store][] at startup.
+//!     - Any env variable with a value of `aws-sm://{secret-id}` is replaced with the secret's string value.
+//!         - `aws-sm://{secret-id}#{key}` selects a key from a JSON secret instead.
+//!     - Any env variable with a value of `aws-ssm://{parameter-name}` is replaced with the parameter's (decrypted) value.
+//!         - Parameter names are usually paths, e.g. `aws-ssm:///my-service/api-key`.
+//!     - Each secret or parameter is fetched once, however many env variables reference it.
+//!     - Env variables `AWS_REGION` (or `AWS_DEFAULT_REGION`), `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+//!       and optionally `AWS_SESSION_TOKEN` and `AWS_ENDPOINT_URL`. Only required if references are present.
+//!     - Startup fails if any reference cannot be resolved.
+//! - `"email"`: Enables [`email`][], for transactional email via SMTP or a provider's HTTP API.
+//!     - Env variable `EMAIL_BACKEND`, one of `smtp`, `postmark`, `sendgrid`, `log`, or `capture`, which sets up [`Resources::emailer`][].
+//!         - Defaults to `log` in development, and `capture` in tests. Required in other environments.
+//!     - Env variable `SMTP_URL` (required with `smtp`), e.g. `smtp://{username}:{password}@{host}:587`.
+//!     - Env variable `EMAIL_API_KEY` (required with `postmark` or `sendgrid`).
+//!     - Env variable `EMAIL_FROM`, the sender of messages which do not set one.
 //! - `"honeycomb"`: Enables tracing to [honeycomb.io].
 //!     - Env variable `HONEYCOMBIO_WRITE_KEY` (required).
 //!     - Env variable `TRACELEVEL`, sets the tracing level filter, defaults to `info`.
 //!     - Writes to a dataset named `{service_name}-{environment}`.
 //!         - `service_name` is from `preroll::main!("service_name", ...)`.
 //!         - `environment` is from `ENVIRONMENT`, or defaults to `"development"`.
-//! - `"lambda-http"`: Changes the HTTP listener to connect to an AWS Lambda execution environment.
-//!     - Is no longer reachable as a regular http server, but accepts http lambda requests as if it were one.
-//!     - Some environment variables, such as `PORT`, are disregarded.
+//! - `"http2"`: Changes the HTTP listener to one which serves both HTTP/1.1 and HTTP/2, via [Hyper][].
+//!     - HTTP/2 is served over cleartext (h2c) to clients which start with the HTTP/2 connection preface ("prior knowledge"),
+//!       such as load balancers which terminate TLS and speak h2 to their upstreams.
+//!     - Has no effect when serving Lambda events, with `"lambda-http"`.
+//! - `"lambda-http"`: Serves requests from AWS Lambda events, such as those of API Gateway and ALBs, when running in a
+//!   Lambda execution environment, so that the same service can be deployed both to Lambda and as a server.
+//!     - A Lambda execution environment is detected by the env variable `AWS_LAMBDA_RUNTIME_API`, which Lambda sets.
+//!       Elsewhere, the service listens as a regular http server.
+//!     - Events are translated to the same Tide requests, so routes and middleware are unchanged.
+//!     - On Lambda, some environment variables, such as `PORT`, `WORKERS`, and `ADMIN_PORT`, are disregarded.
 //!     - If the `"honeycomb"` feature is enabled, trace events are written to stdout, and must be collected via
 //!         a layer provided by Honeycomb. See: https://docs.honeycomb.io/getting-data-in/integrations/aws/aws-lambda/
 //! - `"postgres"`: Enables a postgres connection pool with transactions.
@@ -75,7 +97,35 @@
 //!         - `service_name` is from `preroll::main!("service_name", ...)`.
 //!     - Env variable `PGMAXCONNECTIONS`, default 5 connections.
 //!     - Env variable `PGMAXLIFETIME`, default `30` (minutes).
+//!     - Env variables `PGUSERNAME` and `PGPASSWORD`, which override any credentials in `PGURL`, e.g. when loaded from Vault.
+//!     - Env variable `PGMIGRATIONS`, the migrations directory for the `migrate` command, default `"migrations"`.
 //!     - Enables [`PostgresRequestExt`][prelude::PostgresRequestExt] and [`test_utils::create_client_and_postgres`][].
+//!     - Enables [`Hooks::sqlx_errors`][], to respond to e.g. `RowNotFound` with a `404 Not Found` rather than a `500`.
+//! - `"runtime-tokio"`: Runs setup, requests, and background tasks inside a [Tokio][] runtime's context, for crates
+//!   which only work on Tokio. See [`runtime`][].
+//! - `"service-auth"`: Enables [`auth::ServiceTokens`][], signed tokens authenticating calls between services.
+//!     - Env variable `SERVICE_AUTH_SECRET`, shared by the services, for [`ServiceTokens::from_env`][auth::ServiceTokens::from_env].
+//! - `"sqs"`: Enables [`sqs`][] producers and consumers for [Amazon SQS][].
+//!     - Consumers are added via [`Hooks::sqs_consumer`][], and stop receiving when the service shuts down.
+//!     - Env variables `AWS_REGION` (or `AWS_DEFAULT_REGION`), `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+//!       and optionally `AWS_SESSION_TOKEN` and `AWS_ENDPOINT_URL`, as for `"aws-secrets"`.
+//! - `"storage"`: Enables [`storage`][], a client for S3 and S3-compatible object storage.
+//!     - Env variable `STORAGE_BUCKET`, which sets up [`Resources::storage`][] for that bucket. Nothing is set up if unset.
+//!     - Env variable `STORAGE_ENDPOINT_URL`, for S3-compatible stores such as MinIO. Defaults to `AWS_ENDPOINT_URL`, or S3.
+//!     - Env variables `AWS_REGION` (or `AWS_DEFAULT_REGION`), `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+//!       and optionally `AWS_SESSION_TOKEN`, as for `"aws-secrets"`.
+//!     - The `check` command checks that the bucket is reachable.
+//! - `"vault"`: Loads secrets from [HashiCorp Vault][] into the environment at startup, before state setup.
+//!     - Env variable `VAULT_SECRETS`, a comma-separated list of secret paths, each optionally as `PREFIX=path`.
+//!         - Each key of a secret is set as an upper-cased env variable, after its prefix, e.g. `PG=database/creds/my-service`
+//!           sets `PGUSERNAME` and `PGPASSWORD`.
+//!         - Both KV (version 1 and 2) and dynamic secrets are supported. Secrets override existing env variables.
+//!         - Nothing is loaded if unset.
+//!     - Env variables `VAULT_ADDR` and `VAULT_TOKEN` (required with `VAULT_SECRETS`), and `VAULT_NAMESPACE`.
+//!     - Env variable `VAULT_RENEW_LEASES`, if `true`, keeps renewing the leases of dynamic secrets in the background.
+//! - `"websockets"`: Enables WebSocket support.
+//!     - Enables [`websocket`][] routes, via [`WebSocketRouteExt::ws`][prelude::WebSocketRouteExt::ws] from the prelude.
+//!     - Enables [`test_utils::create_websocket_client`][], a WebSocket test client which connects to a test server on an ephemeral port.
 //!
 //! ### List of other optional features:
 //! - `"panic-on-error"`: Makes the response logger [panic][] on error rather than log.
@@ -84,10 +134,42 @@
 //! ## General Environment Settings
 //! The following environment variables are read during `preroll::main!`:
 //! - `ENVIRONMENT`: If this starts with `prod`, load the production-mode JSON logger, avoid `.env`.
+//!   Parsed into an [`Environment`], along with `stag`ing, `test`, and `dev`elopment.
 //! - `FORCE_DOTENV`: Override production-mode, force-load environment from `.env`.
 //! - `HOST`: Sets the hostname that this service will listen on. Defaults to `"127.0.0.1"`.
 //! - `LOGLEVEL`: Set the logger's level filter, defaults to `info` in production-mode, `debug` in development-mode.
 //! - `PORT`: Sets the port that this service will listen on. Defaults to `8080`.
+//! - `ADMIN_PORT`: If set, starts a second, admin listener on this port, which serves the `/monitor` routes instead
+//!   of the main listener, along with any [admin routes][Hooks::admin_routes].
+//! - `ADMIN_HOST`: Sets the hostname that the admin listener will listen on. Defaults to `HOST`.
+//! - `ADMIN_TOKEN`: If set, enables the built-in `/admin` routes, behind `Authorization: Bearer {ADMIN_TOKEN}`.
+//!   These are served by the admin listener if `ADMIN_PORT` is set, or by the main listener otherwise.
+//!   They include a config dump with secrets redacted, log level control, [feature flag][crate::flags] overrides,
+//!   and [cache flushes][Hooks::cache_flush].
+//! - `ADMIN_USERNAME` and `ADMIN_PASSWORD`: If set, instead of `ADMIN_TOKEN`, the `/admin` routes require basic auth.
+//! - `SERVICE_VERSION`: The version in the [service metadata][ServiceMetadata]. Defaults to the service crate's version.
+//! - `CLIENT_TIMEOUT`: The timeout, in seconds, of [outgoing clients][crate::client]. Defaults to `30`.
+//! - `SERVICE_{NAME}_URL`: Sets up a named client for a downstream service, with `SERVICE_{NAME}_TIMEOUT` and `SERVICE_{NAME}_RETRIES`.
+//!   See [`services`][crate::services].
+//! - `INSTANCE_ID`: The instance in the [service metadata][ServiceMetadata]. Defaults to the hostname.
+//! - `WORKERS`: The number of acceptors for the server, each with its own socket on `PORT` via `SO_REUSEPORT`,
+//!   so that the kernel balances connections between them. Defaults to `1`. Unix only.
+//!     - Each worker's connection count is reported in `/monitor/status`.
+//!
+//! ## Command-line Arguments
+//! The binary produced by `preroll::main!` accepts the following arguments, which take precedence over the environment:
+//! - `--port <PORT>`: Overrides `PORT`.
+//! - `--host <HOST>`: Overrides `HOST`.
+//! - `--config <FILE>`: Loads environment variables from a `.env`-format file. Variables which are already set take precedence.
+//! - `--log-level <LEVEL>`: Overrides `LOGLEVEL`.
+//! - `-h`, `--help`: Prints usage and exits.
+//!
+//! It also accepts a subcommand as the first argument, which defaults to `serve`:
+//! - `serve`: Runs the server.
+//! - `migrate`: Runs pending [SQLx][] migrations from `PGMIGRATIONS` and exits. Requires the `"postgres"` feature.
+//! - `routes`: Prints the routes mounted by `preroll::main!` and exits. Routes added by `custom_setup` are not listed.
+//! - `check`: Runs setup up to, but not including, hooks and listening, then validates `HOST` and `PORT`
+//!   and connectivity to dependencies such as Postgres, and exits.
 //!
 //! ## Note:
 //!
@@ -98,7 +180,13 @@
 //! [`preroll::prelude::*;`]: https://docs.rs/preroll/0.8.0/preroll/prelude/index.html
 //! [`JsonError`]: https://docs.rs/preroll/0.8.0/preroll/struct.JsonError.html
 //! [async-std]: https://async.rs/
+//! [AWS Secrets Manager]: https://aws.amazon.com/secrets-manager/
+//! [AWS Systems Manager Parameter Store]: https://docs.aws.amazon.com/systems-manager/latest/userguide/systems-manager-parameter-store.html
+//! [Amazon SQS]: https://aws.amazon.com/sqs/
+//! [HashiCorp Vault]: https://www.vaultproject.io/
 //! [honeycomb.io]: https://www.honeycomb.io/
+//! [Hyper]: https://hyper.rs/
+//! [Tokio]: https://tokio.rs/
 //! [SQLx]: https://github.com/launchbadge/sqlx#sqlx
 //! [Surf]: https://github.com/http-rs/surf#surf
 //! [Test utils]: https://docs.rs/preroll/0.8.0/preroll/test_utils/index.html
@@ -144,7 +232,27 @@
 #[cfg(all(not(debug_assertions), feature = "panic-on-error"))]
 compile_error!("The \"panic-on-error\" feature must not be used in production, and is not available with `--release`.");
 
+#[cfg(any(feature = "aws-secrets", feature = "sqs", feature = "storage"))]
+mod aws;
+#[cfg(feature = "aws-secrets")]
+mod aws_secrets;
+mod buffers;
+mod cli;
+mod context;
+mod environment;
+mod error;
+#[cfg(feature = "http2")]
+mod http2;
+mod live_config;
+mod metadata;
+mod redact;
+mod route_group;
 mod routes_variadic;
+mod scheduler;
+mod shutdown;
+#[cfg(feature = "vault")]
+mod vault;
+mod workers;
 
 pub(crate) mod builtins;
 pub(crate) mod logging;
@@ -153,14 +261,71 @@ pub(crate) mod middleware;
 #[doc(hidden)]
 pub mod setup;
 
+pub mod auth;
+pub mod cache;
+pub mod canary;
+pub mod chaos;
+pub mod client;
+pub mod csp;
+pub mod download;
+#[cfg(feature = "email")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "email")))]
+pub mod email;
+pub mod extract;
+pub mod filter;
+pub mod flags;
+pub mod multipart;
+pub mod openapi;
+pub mod pagination;
 pub mod prelude;
+pub mod proxy;
+pub mod quota;
+pub mod reply;
+pub mod runtime;
+pub mod security;
+pub mod services;
+pub mod shadow;
+pub mod sse;
+pub mod streaming;
 pub mod test_utils;
 pub mod utils;
 
+#[cfg(feature = "sqs")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "sqs")))]
+pub mod sqs;
+#[cfg(feature = "storage")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "storage")))]
+pub mod storage;
+#[cfg(feature = "websockets")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "websockets")))]
+pub mod websocket;
+
 /// The format of error responses from preroll's error handling middleware.
-pub use middleware::json_error::JsonError;
+pub use middleware::json_error::{
+    ErrorDebug, ErrorFormat, ErrorReport, ErrorReporter, ErrorRequest, JsonError, ProblemDetails,
+};
+
+pub use middleware::catch_panic::{PanicReport, PanicRequest};
+
+pub use middleware::logger::LogLevels;
+
+pub use middleware::requestid::{IdGenerator, IdScheme};
+
+pub use routes_variadic::{ApiVersioning, VariadicRoutes};
 
-pub use routes_variadic::VariadicRoutes;
+pub use builtins::static_files::StaticFiles;
+pub use context::RequestContext;
+pub use environment::Environment;
+pub use error::{Error, FieldError, ValidationErrors};
+pub use middleware::extension_types::{Principal, RequestId, Tenant};
+
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub use error::map_sqlx_error;
+pub use live_config::LiveConfig;
+pub use metadata::{service_metadata, ServiceMetadata};
+pub use scheduler::{Schedule, Task};
+pub use setup::{Hooks, Resources};
 
 /// The result type which is expected from functions passed to `preroll::main!`.
 ///
@@ -189,6 +354,10 @@ pub type SetupResult<T> = setup::Result<T>;
 /// This function must be `async` and must return a `preroll::SetupResult`.
 /// It is expected that setup could be anything and may need to await or error.
 ///
+/// The function may optionally take the [`Resources`][crate::Resources] which preroll has already set up,
+/// as in **`async fn setup_state(resources: preroll::Resources) -> preroll::SetupResult<State>`**,
+/// e.g. to load configuration rows or warm lookups from Postgres at boot.
+///
 /// See [`tide::Server::with_state()`][] for more on Tide server state.
 ///
 /// ## `custom_setup` (optional) (advanced)
@@ -212,8 +381,18 @@ pub type SetupResult<T> = setup::Result<T>;
 ///
 /// For example, `preroll::main!("my-service", my_routes)` will have `my_routes` mounted at `/api/v1`.
 ///
+/// Other prefixes, such as none at all or date-based versions, can be set via [`VariadicRoutes::versioning`][crate::VariadicRoutes::versioning]
+/// with an [`ApiVersioning`][crate::ApiVersioning] strategy.
+///
 /// See [`tide::Server::at()`][] for more on Tide server routing.
 ///
+/// ## `hooks_setup` (optional)
+/// Async hooks which run before the server starts listening, and when the service shuts down.
+///
+/// A **`fn setup_hooks() -> preroll::Hooks<State>`**, where `State` is the type returned from `setup_state`.
+/// See [`Hooks`][crate::Hooks] for details, including scheduled background [`Task`][crate::Task]s.
+/// Requires `custom_setup` to also be specified.
+///
 /// # Basic Example
 ///
 /// This will respond with `"Hello World!"` when a GET request is made to `$HOST:$PORT/api/v1/hello-world`.
@@ -314,6 +493,70 @@ pub type SetupResult<T> = setup::Result<T>;
 /// # }
 /// ```
 ///
+/// # Hooks Example
+/// With state setup which uses preroll's [`Resources`][crate::Resources].
+///
+/// ```no_run
+/// # #[cfg(not(feature = "custom_middleware"))]
+/// # {
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// use preroll::{Hooks, Resources, Schedule, SetupResult, Task};
+/// use tide::{Route, Server};
+///
+/// # #[allow(dead_code)]
+/// pub struct AppState {
+///     greeting: &'static str,
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn setup_app_state(resources: Resources) -> SetupResult<AppState> {
+///     // E.g. with the "postgres" feature, load lookups via `resources.pg_pool`.
+///     Ok(AppState {
+///         greeting: "Hello World!",
+///     })
+/// }
+///
+/// # #[allow(dead_code)]
+/// pub async fn setup_custom(server: Server<Arc<AppState>>) -> SetupResult<Server<Arc<AppState>>> {
+///     Ok(server)
+/// }
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(_server: Route<'_, Arc<AppState>>) {}
+///
+/// # #[allow(dead_code)]
+/// fn setup_hooks() -> Hooks<AppState> {
+///     Hooks::new()
+///         .before_start(|state: Arc<AppState>, _resources: Resources| async move {
+///             log::info!("Registering, greeting with: {}", state.greeting);
+///             Ok(())
+///         })
+///         .on_shutdown(|_state, _resources| async move {
+///             log::info!("Deregistering");
+///             Ok(())
+///         })
+///         .task(Task::new(
+///             "heartbeat",
+///             Schedule::every(Duration::from_secs(30)),
+///             |_state, _resources| async move {
+///                 log::info!("Still here");
+///                 Ok(())
+///             },
+///         ))
+/// }
+///
+/// preroll::main!(
+///     "hello-world",
+///     setup_app_state,
+///     setup_custom,
+///     setup_routes,
+///     setup_hooks
+/// );
+/// # }
+/// ```
+///
 /// [`tide::Server::at()`]: https://docs.rs/tide/0.15.0/tide/struct.Server.html#method.at
 /// [`tide::Server::with_state()`]: https://docs.rs/tide/0.15.0/tide/struct.Server.html#method.with_state
 /// [unit `()`]: https://doc.rust-lang.org/std/primitive.unit.html
@@ -344,10 +587,29 @@ macro_rules! main {
     // preroll::main!("service-name", state_setup_function, custom_setup_function, routes_setup_function(s));
     ($service_name:tt, $state_setup:tt, $custom_setup:tt, $routes_fns:tt) => {
         fn main() -> preroll::setup::Result<()> {
+            preroll::setup::set_default_version(env!("CARGO_PKG_VERSION"));
+
             let fut =
                 preroll::setup::setup($service_name, $state_setup, $custom_setup, $routes_fns);
 
             preroll::setup::block_on(fut)
         }
     };
+
+    // preroll::main!("service-name", state_setup_function, custom_setup_function, routes_setup_function(s), hooks_setup_function);
+    ($service_name:tt, $state_setup:tt, $custom_setup:tt, $routes_fns:tt, $hooks_setup:tt) => {
+        fn main() -> preroll::setup::Result<()> {
+            preroll::setup::set_default_version(env!("CARGO_PKG_VERSION"));
+
+            let fut = preroll::setup::setup_with_hooks(
+                $service_name,
+                $state_setup,
+                $custom_setup,
+                $routes_fns,
+                $hooks_setup,
+            );
+
+            preroll::setup::block_on(fut)
+        }
+    };
 }
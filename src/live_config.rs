@@ -0,0 +1,278 @@
+//! Configuration which is reloaded while the service runs, such as tunables read from a file or a remote source.
+
+use std::any::type_name;
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+
+use color_eyre::eyre::WrapErr;
+use serde::de::DeserializeOwned;
+
+use crate::scheduler::{Schedule, Task};
+use crate::setup::Result;
+
+type LoadFn<T> = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<T>> + Send>> + Send + Sync>;
+
+type ListenerFn<T> = Box<dyn Fn(&T) + Send + Sync>;
+
+struct Inner<T> {
+    current: RwLock<Arc<T>>,
+    load_fn: LoadFn<T>,
+    listeners: Mutex<Vec<ListenerFn<T>>>,
+}
+
+/// A handle to the current snapshot of some configuration, which can be reloaded at runtime, e.g. in the server state.
+///
+/// Readers get the snapshot which was current when they asked, as an `Arc<T>`, and are never blocked by a reload.
+/// Clones share the same configuration.
+///
+/// - Reload on a schedule via [`reload_task`][LiveConfig::reload_task], or on demand via [`reload`][LiveConfig::reload],
+///   e.g. from an [admin route][crate::Hooks::admin_routes].
+/// - Listeners added via [`on_change`][LiveConfig::on_change] are called with each new snapshot which differs from the last.
+/// - A reload which fails, e.g. because the file is invalid, is an error, and the previous snapshot stays current.
+///
+/// `SIGHUP` does not trigger a reload: like `SIGINT` and `SIGTERM`, it shuts the service down, via [`Hooks::on_shutdown`][crate::Hooks::on_shutdown].
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// use preroll::{Hooks, LiveConfig, Schedule, SetupResult};
+/// use serde::Deserialize;
+/// use tide::{Request, Route, Server};
+///
+/// # #[allow(dead_code)]
+/// #[derive(Debug, PartialEq, Deserialize)]
+/// struct Tunables {
+///     max_page_size: usize,
+/// }
+///
+/// # #[allow(dead_code)]
+/// struct AppState {
+///     tunables: LiveConfig<Tunables>,
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn setup_app_state() -> SetupResult<AppState> {
+///     let tunables = LiveConfig::json_file("tunables.json").await?;
+///     tunables.on_change(|tunables: &Tunables| log::info!("Tunables changed: {:?}", tunables));
+///
+///     Ok(AppState { tunables })
+/// }
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<AppState>>) {
+///     server
+///         .at("page-size")
+///         .get(|req: Request<Arc<AppState>>| async move {
+///             Ok(req.state().tunables.get().max_page_size.to_string())
+///         });
+/// }
+///
+/// # #[allow(dead_code)]
+/// fn setup_hooks() -> Hooks<AppState> {
+///     Hooks::new().task(LiveConfig::reload_task(
+///         "reload_tunables",
+///         Schedule::every(Duration::from_secs(30)),
+///         |state: &AppState| &state.tunables,
+///     ))
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn custom_setup(server: Server<Arc<AppState>>) -> SetupResult<Server<Arc<AppState>>> {
+///     Ok(server)
+/// }
+///
+/// preroll::main!("live-config", setup_app_state, custom_setup, setup_routes, setup_hooks);
+/// ```
+pub struct LiveConfig<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> LiveConfig<T>
+where
+    T: PartialEq + Send + Sync + 'static,
+{
+    /// Load the configuration by calling `load_fn`, which is called again on every reload, e.g. to poll a remote source.
+    pub async fn load<LoadFnOnce, LoadFuture>(load_fn: LoadFnOnce) -> Result<Self>
+    where
+        LoadFnOnce: Fn() -> LoadFuture + Send + Sync + 'static,
+        LoadFuture: Future<Output = Result<T>> + Send + 'static,
+    {
+        let load_fn: LoadFn<T> = Arc::new(move || Box::pin(load_fn()));
+        let current = load_fn().await?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                current: RwLock::new(Arc::new(current)),
+                load_fn,
+                listeners: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// Load the configuration from a JSON file, which is read again on every reload.
+    pub async fn json_file(path: impl Into<PathBuf>) -> Result<Self>
+    where
+        T: DeserializeOwned,
+    {
+        let path = path.into();
+        Self::load(move || {
+            let path = path.clone();
+            async move {
+                let json = async_std::fs::read_to_string(&path)
+                    .await
+                    .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+                serde_json::from_str(&json)
+                    .wrap_err_with(|| format!("Invalid configuration in {}", path.display()))
+            }
+        })
+        .await
+    }
+
+    /// The current snapshot.
+    pub fn get(&self) -> Arc<T> {
+        self.inner
+            .current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Add a listener, which is called with the new snapshot after each reload which changes it.
+    pub fn on_change(&self, listener: impl Fn(&T) + Send + Sync + 'static) {
+        self.inner
+            .listeners
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(Box::new(listener));
+    }
+
+    /// Load the configuration again, returning whether it changed.
+    pub async fn reload(&self) -> Result<bool> {
+        let reloaded = Arc::new((self.inner.load_fn)().await?);
+
+        {
+            let mut current = self
+                .inner
+                .current
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if **current == *reloaded {
+                return Ok(false);
+            }
+            *current = reloaded.clone();
+        }
+
+        log::info!("Reloaded {}", type_name::<T>());
+        let listeners = self
+            .inner
+            .listeners
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for listener in listeners.iter() {
+            listener(&reloaded);
+        }
+
+        Ok(true)
+    }
+
+    /// A scheduled [`Task`] which reloads the configuration found in the server state by `config_fn`.
+    ///
+    /// Failed reloads are logged and counted in `/monitor/status`, like any other task.
+    pub fn reload_task<State>(
+        name: &'static str,
+        schedule: Schedule,
+        config_fn: fn(&State) -> &Self,
+    ) -> Task<State>
+    where
+        State: Send + Sync + 'static,
+    {
+        Task::new(name, schedule, move |state: Arc<State>, _| async move {
+            config_fn(&state).reload().await.map(|_| ())
+        })
+    }
+}
+
+impl<T> Clone for LiveConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for LiveConfig<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LiveConfig")
+            .field(
+                "current",
+                &self
+                    .inner
+                    .current
+                    .read()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            )
+            .field(
+                "listeners",
+                &self
+                    .inner
+                    .listeners
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .len(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Tunables {
+        max_page_size: usize,
+    }
+
+    #[async_std::test]
+    async fn reloads_changed_files() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("preroll-config-{}.json", std::process::id()));
+        fs::write(&path, r#"{ "max_page_size": 50 }"#)?;
+
+        let config: LiveConfig<Tunables> = LiveConfig::json_file(&path).await?;
+        let changes = Arc::new(AtomicUsize::new(0));
+        let counter = changes.clone();
+        config.on_change(move |tunables| {
+            counter.store(tunables.max_page_size, Ordering::SeqCst);
+        });
+        let snapshot = config.get();
+
+        assert!(!config.reload().await?);
+
+        fs::write(&path, r#"{ "max_page_size": 100 }"#)?;
+        assert!(config.reload().await?);
+        assert_eq!(config.get().max_page_size, 100);
+        assert_eq!(snapshot.max_page_size, 50);
+        assert_eq!(changes.load(Ordering::SeqCst), 100);
+
+        fs::write(&path, "{ not json")?;
+        assert!(config.reload().await.is_err());
+        assert_eq!(config.get().max_page_size, 100);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -3,6 +3,8 @@ use std::{io, process};
 
 use log::kv;
 
+use crate::metadata::service_metadata;
+use crate::redact::with_redacted;
 use crate::utils::HOSTNAME;
 
 // Modified from the json_env_logger crate
@@ -24,7 +26,7 @@ where
     )?;
     write!(f, ",\"pid\":{}", process::id())?;
     write!(f, ",\"message\":")?;
-    write_json_str(f, &record.args().to_string())?;
+    with_redacted(record.args(), |message| write_json_str(f, message))?;
 
     let mut visitor = Visitor { writer: f };
     record
@@ -34,6 +36,16 @@ where
 
     write!(f, ",\"target\":\"{}\"", target)?;
     write!(f, ",\"hostname\":\"{}\"", *HOSTNAME)?;
+
+    let metadata = service_metadata();
+    write!(f, ",\"service\":")?;
+    write_json_str(f, &metadata.service)?;
+    write!(f, ",\"version\":")?;
+    write_json_str(f, &metadata.version)?;
+    write!(f, ",\"environment\":")?;
+    write_json_str(f, &metadata.environment)?;
+    write!(f, ",\"instance\":")?;
+    write_json_str(f, &metadata.instance)?;
     write!(
         f,
         ",\"time\":\"{}\"",
@@ -50,7 +62,7 @@ where
             key: kv::Key<'kvs>,
             val: kv::Value<'kvs>,
         ) -> Result<(), kv::Error> {
-            write!(self.writer, ",\"{}\":\"{}\"", key, val)?;
+            with_redacted(val, |val| write!(self.writer, ",\"{}\":\"{}\"", key, val))?;
             Ok(())
         }
     }
@@ -83,7 +95,9 @@ mod tests {
         log_format_json(&mut buf, &record)?;
         let output = std::str::from_utf8(&buf)?;
         println!("{}", output);
-        assert!(serde_json::from_str::<serde_json::Value>(output).is_ok());
+        let json: serde_json::Value = serde_json::from_str(output)?;
+        assert_eq!(json["service"], service_metadata().service.as_str());
+        assert_eq!(json["instance"], service_metadata().instance.as_str());
         Ok(())
     }
 
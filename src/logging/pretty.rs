@@ -6,6 +6,8 @@ use std::{fmt, io};
 use env_logger::fmt::{Color, Formatter, Style, StyledValue};
 use log::{kv, Level};
 
+use crate::redact::with_redacted;
+
 // Modified from the pretty_env_logger crate
 pub fn log_format_pretty(f: &mut Formatter, record: &log::Record<'_>) -> io::Result<()> {
     let target = record.target();
@@ -25,7 +27,9 @@ pub fn log_format_pretty(f: &mut Formatter, record: &log::Record<'_>) -> io::Res
         width: max_width,
     });
 
-    write!(f, "{} {} | {}", level, target, record.args(),)?;
+    with_redacted(record.args(), |message| {
+        write!(f, "{} {} | {}", level, target, message)
+    })?;
     format_kv_pairs(f, record).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
     writeln!(f)
 }
@@ -78,7 +82,7 @@ fn format_kv_pairs(f: &mut Formatter, record: &log::Record<'_>) -> Result<(), kv
             let mut style = self.f.style();
             let key = style.set_bold(true).value(key);
 
-            write!(self.f, "\n  {} {}", key, val)?;
+            with_redacted(val, |val| write!(self.f, "\n  {} {}", key, val))?;
             Ok(())
         }
     }
@@ -0,0 +1,61 @@
+//! Metadata which identifies this instance of the service, stamped onto every JSON log line and request span.
+
+use std::env;
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::utils::HOSTNAME;
+
+static METADATA: OnceCell<ServiceMetadata> = OnceCell::new();
+static VERSION: OnceCell<&'static str> = OnceCell::new();
+
+/// The name, version, environment, and instance of the running service, as set up by `preroll::main!`.
+///
+/// - `service`: From `preroll::main!("service_name", ...)`.
+/// - `version`: From `SERVICE_VERSION`, or defaults to the service crate's version.
+/// - `environment`: From `ENVIRONMENT`, or defaults to `"development"`.
+/// - `instance`: From `INSTANCE_ID`, or defaults to the hostname, which is the pod name in Kubernetes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct ServiceMetadata {
+    pub service: String,
+    pub version: String,
+    pub environment: String,
+    pub instance: String,
+}
+
+impl ServiceMetadata {
+    fn from_env(service_name: &str) -> Self {
+        Self {
+            service: service_name.to_string(),
+            version: env::var("SERVICE_VERSION")
+                .unwrap_or_else(|_| VERSION.get().copied().unwrap_or("unknown").to_string()),
+            environment: env::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string()),
+            instance: env::var("INSTANCE_ID").unwrap_or_else(|_| HOSTNAME.clone()),
+        }
+    }
+}
+
+/// The metadata of the running service.
+///
+/// Before `preroll::main!` has set up the service, such as in tests, the service name is `"unknown"`.
+pub fn service_metadata() -> &'static ServiceMetadata {
+    METADATA.get_or_init(|| ServiceMetadata::from_env("unknown"))
+}
+
+/// Set the service's metadata from its name and the environment. Only the first call has an effect.
+pub(crate) fn init_service_metadata(service_name: &str) {
+    if METADATA
+        .set(ServiceMetadata::from_env(service_name))
+        .is_err()
+    {
+        log::warn!("Service metadata was already set up");
+    }
+}
+
+/// Set the default version, from the service crate's `CARGO_PKG_VERSION`, as done by `preroll::main!`.
+#[doc(hidden)]
+pub fn set_default_version(version: &'static str) {
+    VERSION.set(version).ok();
+}
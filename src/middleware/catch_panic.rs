@@ -0,0 +1,246 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Once};
+use std::task::{Context, Poll};
+
+use futures_lite::FutureExt;
+use once_cell::sync::OnceCell;
+use tide::http::Method;
+use tide::{Middleware, Next, Request, Result, StatusCode};
+
+use super::extension_types::RequestId;
+use crate::redact::redact;
+
+/// A handler for panics, as set via [`Hooks::on_panic`][crate::Hooks::on_panic].
+pub(crate) type PanicFn = Arc<dyn Fn(&PanicReport) + Send + Sync>;
+
+static PANIC_HANDLER: OnceCell<PanicFn> = OnceCell::new();
+static INSTALL_PANIC_HOOK: Once = Once::new();
+
+thread_local! {
+    /// Whether a request handler is being polled on this thread.
+    static IN_REQUEST: Cell<bool> = const { Cell::new(false) };
+    /// The latest panic from a request handler on this thread, for the middleware to pick up once it has unwound.
+    static REQUEST_PANIC: RefCell<Option<PanicReport>> = const { RefCell::new(None) };
+}
+
+/// A panic, as passed to the [`Hooks::on_panic`][crate::Hooks::on_panic] handler.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PanicReport {
+    /// The panic message, if the panic payload was a string.
+    pub message: String,
+    /// The source location of the panic, as `file:line:column`.
+    pub location: Option<String>,
+    /// The backtrace of the panicking thread, which is always captured, regardless of `RUST_BACKTRACE`.
+    pub backtrace: String,
+    /// The request which was being handled, if the panic happened in a request handler.
+    pub request: Option<PanicRequest>,
+}
+
+/// The request which was being handled when a [`PanicReport`]'s panic happened.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PanicRequest {
+    /// The request's method.
+    pub method: Method,
+    /// The request's path.
+    pub path: String,
+    /// The id assigned to the request, as in its `X-Request-Id` response header. See [`IdScheme`][crate::IdScheme].
+    pub request_id: String,
+}
+
+/// Set the handler which is called with every panic, including those outside of request handlers.
+pub(crate) fn set_panic_handler(handler: PanicFn) {
+    install_panic_hook();
+    if PANIC_HANDLER.set(handler).is_err() {
+        log::warn!("A panic handler was already set, and was not replaced");
+    }
+}
+
+/// Install a process-wide panic hook, which records the details of each panic before the previous hook runs.
+///
+/// Panics in request handlers are left for [`CatchPanicMiddleware`], which adds the request to the report.
+fn install_panic_hook() {
+    INSTALL_PANIC_HOOK.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let report = PanicReport {
+                message: payload_message(info.payload()),
+                location: info.location().map(ToString::to_string),
+                backtrace: Backtrace::force_capture().to_string(),
+                request: None,
+            };
+
+            if IN_REQUEST.with(Cell::get) {
+                REQUEST_PANIC.with(|panic| *panic.borrow_mut() = Some(report));
+            } else if let Some(handler) = PANIC_HANDLER.get() {
+                handler(&report);
+            }
+
+            previous_hook(info);
+        }));
+    });
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => redact(message).into_owned(),
+        (_, Some(message)) => redact(message).into_owned(),
+        _ => "(non-string panic payload)".to_string(),
+    }
+}
+
+/// Marks this thread as handling a request while the inner future is polled.
+struct InRequest<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for InRequest<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        IN_REQUEST.with(|in_request| in_request.set(true));
+        let poll = self.0.as_mut().poll(cx);
+        IN_REQUEST.with(|in_request| in_request.set(false));
+        poll
+    }
+}
+
+/// Respond to panics in request handlers with a `500 Internal Server Error`, rather than dropping the connection,
+/// and report them to the [`Hooks::on_panic`][crate::Hooks::on_panic] handler, if any.
+///
+/// Must be installed after `JsonErrorMiddleware`, so that the response is formatted as a [`JsonError`][crate::JsonError].
+#[derive(Debug, Default, Clone)]
+pub struct CatchPanicMiddleware {
+    _priv: (),
+}
+
+impl CatchPanicMiddleware {
+    /// Create a new instance of `CatchPanicMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        install_panic_hook();
+        Self { _priv: () }
+    }
+
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> Result {
+        let request = PanicRequest {
+            method: req.method(),
+            path: req.url().path().to_string(),
+            request_id: req
+                .ext::<RequestId>()
+                .map(|id| id.as_str().to_string())
+                .unwrap_or_default(),
+        };
+
+        let payload = match AssertUnwindSafe(InRequest(Box::pin(next.run(req))))
+            .catch_unwind()
+            .await
+        {
+            Ok(res) => return Ok(res),
+            Err(payload) => payload,
+        };
+        IN_REQUEST.with(|in_request| in_request.set(false));
+
+        let mut report = REQUEST_PANIC
+            .with(|panic| panic.borrow_mut().take())
+            .unwrap_or_else(|| PanicReport {
+                message: payload_message(&*payload),
+                location: None,
+                backtrace: String::new(),
+                request: None,
+            });
+        report.request = Some(request);
+
+        if let Some(handler) = PANIC_HANDLER.get() {
+            handler(&report);
+        }
+
+        Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            format!(
+                "Panicked at {}: {}",
+                report.location.as_deref().unwrap_or("(unknown location)"),
+                report.message
+            ),
+        ))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CatchPanicMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
+        self.handle(req, next).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::test_utils::{self, TestClientOptions, TestResult};
+    use crate::JsonError;
+
+    #[async_std::test]
+    async fn reports_panics_and_responds_500() -> TestResult<()> {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reported = reports.clone();
+        set_panic_handler(Arc::new(move |report: &PanicReport| {
+            reported
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(report.clone());
+        }));
+
+        let options = TestClientOptions::new().with(CatchPanicMiddleware::new());
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: tide::Route<'_, Arc<()>>| {
+                server.at("panic").get(|_| async {
+                    if true {
+                        panic!("Handler panicked");
+                    }
+                    Ok("Unreachable")
+                });
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.get("/api/v1/panic").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 500);
+
+        // Other tests in this process may panic too, outside of requests.
+        let reports = reports.lock().unwrap_or_else(|e| e.into_inner());
+        let report = reports
+            .iter()
+            .find(|report| report.request.is_some())
+            .ok_or_else(|| tide::http::format_err!("No request panic reported"))?;
+        assert_eq!(report.message, "Handler panicked");
+        assert!(report
+            .location
+            .as_deref()
+            .unwrap_or_default()
+            .contains("catch_panic.rs"));
+        assert!(!report.backtrace.is_empty());
+        let request = report
+            .request
+            .as_ref()
+            .ok_or_else(|| tide::http::format_err!("No request"))?;
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/api/v1/panic");
+        Ok(())
+    }
+}
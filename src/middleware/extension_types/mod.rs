@@ -1,5 +1,9 @@
 mod correlation_id;
+mod principal;
 mod request_id;
+mod tenant;
 
 pub use correlation_id::CorrelationId;
+pub use principal::Principal;
 pub use request_id::RequestId;
+pub use tenant::Tenant;
@@ -0,0 +1,39 @@
+use std::fmt::{self, Display};
+
+/// The authenticated caller of a request, as inserted into its extensions by authentication middleware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Principal {
+    /// The id of the user or service, e.g. the `sub` of a token.
+    pub id: String,
+    /// What the caller is allowed to do, e.g. `"users:write"`.
+    pub scopes: Vec<String>,
+}
+
+impl Principal {
+    /// A caller with `id`, and no scopes.
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            scopes: Vec::new(),
+        }
+    }
+
+    /// The caller with `scopes` as well.
+    #[must_use]
+    pub fn with_scopes<S: Into<String>>(mut self, scopes: impl IntoIterator<Item = S>) -> Self {
+        self.scopes.extend(scopes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Whether the caller has `scope`.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+impl Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
@@ -6,10 +6,20 @@ use serde::de::{Error as DeError, Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+/// The longest text of a request id, that of a hyphenated UUID.
+const MAX_LEN: usize = 36;
+
+/// The Crockford base 32 alphabet of ULIDs.
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The id of a request, as 128 bits and as text, e.g. a UUID or a ULID.
+///
+/// The text is kept inline, so that ids are cheap to create and clone.
+#[derive(Clone)]
 pub struct RequestId {
-    id: Uuid,
-    string_id: String,
+    id: u128,
+    text: [u8; MAX_LEN],
+    len: u8,
 }
 
 impl RequestId {
@@ -19,43 +29,102 @@ impl RequestId {
         Uuid::new_v4().into()
     }
 
+    /// An id of `id` as 32 lowercase hex digits, e.g. `"4bf92f3577b34da6a3ce929d0e0e4736"`.
+    pub fn from_hex(id: u128) -> Self {
+        Self::with_text(id, |text| {
+            for (i, byte) in text[..32].iter_mut().enumerate() {
+                *byte = b"0123456789abcdef"[(id >> (4 * (31 - i)) & 0xf) as usize];
+            }
+            32
+        })
+    }
+
+    /// An id of `id` as a 26 character ULID, e.g. `"01ARZ3NDEKTSV4RRFFQ69G5FAV"`.
+    pub fn from_ulid(id: u128) -> Self {
+        Self::with_text(id, |text| {
+            for (i, byte) in text[..26].iter_mut().enumerate() {
+                *byte = ULID_ALPHABET[(id >> (5 * (25 - i)) & 0x1f) as usize];
+            }
+            26
+        })
+    }
+
     pub fn as_str(&self) -> &str {
-        &self.string_id
+        std::str::from_utf8(&self.text[..usize::from(self.len)]).unwrap_or_default()
     }
 
-    #[cfg(feature = "honeycomb")]
-    #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
     pub fn as_u128(&self) -> u128 {
-        self.id.as_u128()
+        self.id
+    }
+
+    /// An id of `id`, with the text written by `write`, which returns its length.
+    fn with_text(id: u128, write: impl FnOnce(&mut [u8; MAX_LEN]) -> usize) -> Self {
+        let mut text = [0; MAX_LEN];
+        let len = write(&mut text);
+        Self {
+            id,
+            text,
+            len: len as u8,
+        }
+    }
+}
+
+impl fmt::Debug for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RequestId").field(&self.as_str()).finish()
     }
 }
 
 impl Display for RequestId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.id)
+        f.write_str(self.as_str())
     }
 }
 
 impl From<Uuid> for RequestId {
     fn from(uuid: Uuid) -> Self {
-        let buf = &mut [0; 36];
-        let human_id = uuid.to_hyphenated().encode_lower(buf);
-        Self {
-            id: uuid,
-            string_id: human_id.to_string(),
-        }
+        Self::with_text(uuid.as_u128(), |text| {
+            uuid.to_hyphenated().encode_lower(text).len()
+        })
     }
 }
 
+/// Parses a UUID, hyphenated or as 32 hex digits, or a ULID, keeping the text as it was.
 impl FromStr for RequestId {
     type Err = uuid::Error;
 
     fn from_str(string: &str) -> Result<Self, uuid::Error> {
-        Ok(Self {
-            id: Uuid::parse_str(string)?,
-            string_id: string.to_string(),
-        })
+        let id = match decode_ulid(string) {
+            Some(id) => id,
+            None => Uuid::parse_str(string)?.as_u128(),
+        };
+        if string.len() > MAX_LEN {
+            return Ok(Uuid::from_u128(id).into());
+        }
+        Ok(Self::with_text(id, |text| {
+            text[..string.len()].copy_from_slice(string.as_bytes());
+            string.len()
+        }))
+    }
+}
+
+/// The 128 bits of a ULID, in either case.
+fn decode_ulid(string: &str) -> Option<u128> {
+    if string.len() != 26 {
+        return None;
+    }
+    let mut id: u128 = 0;
+    for (i, byte) in string.bytes().enumerate() {
+        let digit = ULID_ALPHABET
+            .iter()
+            .position(|c| *c == byte.to_ascii_uppercase())?;
+        // The first digit only has 3 bits of the 128.
+        if i == 0 && digit > 7 {
+            return None;
+        }
+        id = id << 5 | digit as u128;
     }
+    Some(id)
 }
 
 struct RequestIdVisitor;
@@ -64,7 +133,7 @@ impl<'de> Visitor<'de> for RequestIdVisitor {
     type Value = RequestId;
 
     fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(formatter, "a UUID &str")
+        write!(formatter, "a UUID or ULID &str")
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
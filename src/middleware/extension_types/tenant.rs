@@ -0,0 +1,23 @@
+use std::fmt::{self, Display};
+
+/// The tenant a request is for, as inserted into its extensions by middleware, e.g. from a header or a subdomain.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tenant {
+    id: String,
+}
+
+impl Tenant {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self { id: id.into() }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use tide::http::{self, StatusCode};
+use tide::{Middleware, Next, Request, Result};
+
+/// A custom handler for requests which match no route, as set via [`Hooks::not_found`][crate::Hooks::not_found]
+/// or [`Hooks::method_not_allowed`][crate::Hooks::method_not_allowed].
+pub(crate) type FallbackFn = Arc<dyn Fn(&http::Request) -> Result + Send + Sync>;
+
+/// Replace the router's bare `404 Not Found` and `405 Method Not Allowed` responses with those of custom handlers.
+///
+/// Bare responses, without a body or an error, from route handlers are replaced too.
+///
+/// Must be installed after `JsonErrorMiddleware`, so that errors from the handlers are formatted as a [`JsonError`][crate::JsonError].
+#[derive(Clone)]
+pub(crate) struct FallbackMiddleware {
+    not_found: Option<FallbackFn>,
+    method_not_allowed: Option<FallbackFn>,
+}
+
+impl FallbackMiddleware {
+    /// Create a new instance of `FallbackMiddleware`, if there is at least one handler.
+    pub(crate) fn new(
+        not_found: Option<FallbackFn>,
+        method_not_allowed: Option<FallbackFn>,
+    ) -> Option<Self> {
+        if not_found.is_none() && method_not_allowed.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            not_found,
+            method_not_allowed,
+        })
+    }
+
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> Result {
+        // The body is not cloned, and is only consumed by the route handler, if any.
+        let fallback_req = AsRef::<http::Request>::as_ref(&req).clone();
+
+        let res = next.run(req).await;
+
+        // The router responds to unrouted requests with an empty body and no error.
+        // Route handlers which respond with an error or a body are left alone.
+        if res.error().is_some() || res.is_empty() != Some(true) {
+            return Ok(res);
+        }
+
+        let handler = match res.status() {
+            StatusCode::NotFound => &self.not_found,
+            StatusCode::MethodNotAllowed => &self.method_not_allowed,
+            _ => &None,
+        };
+        match handler {
+            Some(handler) => handler(&fallback_req),
+            None => Ok(res),
+        }
+    }
+}
+
+impl std::fmt::Debug for FallbackMiddleware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FallbackMiddleware")
+            .field("not_found", &self.not_found.is_some())
+            .field("method_not_allowed", &self.method_not_allowed.is_some())
+            .finish()
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for FallbackMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
+        self.handle(req, next).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, assert_json_error, TestClientOptions, TestResult};
+    use crate::JsonError;
+
+    #[async_std::test]
+    async fn replaces_router_fallbacks() -> TestResult<()> {
+        let not_found: FallbackFn = Arc::new(|req: &http::Request| {
+            Err(tide::Error::from_str(
+                StatusCode::NotFound,
+                format!(
+                    "No route for {}. Did you mean /api/v1/users?",
+                    req.url().path()
+                ),
+            ))
+        });
+        let options = TestClientOptions::new().with(FallbackMiddleware {
+            not_found: Some(not_found),
+            method_not_allowed: None,
+        });
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: tide::Route<'_, Arc<()>>| {
+                server.at("users").get(|_| async { Ok("[]") });
+                server.at("users/:id").get(|_| async {
+                    Err::<String, _>(tide::Error::from_str(404, "No such user"))
+                });
+            },
+            options,
+        )
+        .await?;
+
+        let res = client.get("/api/v1/uesrs").await?;
+        assert_json_error(
+            res,
+            404,
+            "No route for /api/v1/uesrs. Did you mean /api/v1/users?",
+        )
+        .await;
+
+        // Errors from route handlers are left alone.
+        let res = client.get("/api/v1/users/1").await?;
+        assert_json_error(res, 404, "No such user").await;
+
+        // Without a handler, the router's response is kept.
+        let mut res = client.post("/api/v1/users").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 405);
+        Ok(())
+    }
+}
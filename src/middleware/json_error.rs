@@ -1,6 +1,19 @@
-use super::extension_types::{CorrelationId, RequestId};
+use std::backtrace::BacktraceStatus;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use once_cell::sync::OnceCell;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
-use tide::{Body, Middleware, Next, Request, Result};
+use serde_json::Value;
+use tide::http::{mime, Method};
+use tide::{Middleware, Next, Request, Response, Result, StatusCode};
+
+use super::extension_types::{CorrelationId, RequestId};
+use crate::buffers::json_body;
+use crate::redact::redact;
+use crate::{Environment, FieldError, ValidationErrors};
 
 #[cfg(feature = "honeycomb")]
 use tracing_honeycomb::TraceId;
@@ -8,16 +21,166 @@ use tracing_honeycomb::TraceId;
 #[cfg(feature = "test")]
 use uuid::Uuid;
 
+/// The format set via [`Hooks::error_format`][crate::Hooks::error_format], for middleware without a format of their own.
+static ERROR_FORMAT: OnceCell<ErrorFormat> = OnceCell::new();
+
+/// The base URL set via [`Hooks::error_docs_url`][crate::Hooks::error_docs_url], for middleware without one of their own.
+static ERROR_DOCS_URL: OnceCell<String> = OnceCell::new();
+
+/// The characters of error codes which are percent-encoded in documentation links.
+const DOCS_URL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.');
+
+/// The reporter set via [`Hooks::error_reporter`][crate::Hooks::error_reporter], for middleware without a reporter of their own.
+static ERROR_REPORTER: OnceCell<Arc<dyn ErrorReporter>> = OnceCell::new();
+
+/// A mapping of database errors to responses, as set via [`Hooks::sqlx_errors`][crate::Hooks::sqlx_errors].
+#[cfg(feature = "postgres")]
+pub(crate) type SqlxErrorFn = Arc<dyn Fn(&sqlx::Error) -> Option<tide::Error> + Send + Sync>;
+
+/// The mapping set via [`Hooks::sqlx_errors`][crate::Hooks::sqlx_errors], for middleware without a mapping of their own.
+#[cfg(feature = "postgres")]
+static SQLX_ERRORS: OnceCell<SqlxErrorFn> = OnceCell::new();
+
 /// Transfrom Errors (`Result::Err`) into JSON responses.
 ///
 /// Special care is taken when handling non-4XX errors to not expose internal error messages.
-#[derive(Debug, Default, Clone)]
+#[derive(Default, Clone)]
 pub struct JsonErrorMiddleware {
-    _priv: (),
+    format: Option<ErrorFormat>,
+    docs_url: Option<String>,
+    reporter: Option<Arc<dyn ErrorReporter>>,
+    #[cfg(feature = "postgres")]
+    sqlx_errors: Option<SqlxErrorFn>,
+}
+
+impl fmt::Debug for JsonErrorMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("JsonErrorMiddleware");
+        debug
+            .field("format", &self.format)
+            .field("docs_url", &self.docs_url)
+            .field("reporter", &self.reporter.is_some());
+        #[cfg(feature = "postgres")]
+        debug.field("sqlx_errors", &self.sqlx_errors.is_some());
+        debug.finish()
+    }
 }
 
 struct JsonErrorMiddlewareHasBeenRun;
 
+/// The format of error response bodies.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorFormat {
+    /// A [`JsonError`], as `application/json`. The default.
+    #[default]
+    Json,
+    /// A [`ProblemDetails`] object, as `application/problem+json`, per [RFC 7807](https://tools.ietf.org/html/rfc7807).
+    ProblemDetails,
+}
+
+/// Set the format of errors from every [`JsonErrorMiddleware`] without a format of its own.
+pub(crate) fn set_error_format(format: ErrorFormat) {
+    if ERROR_FORMAT.set(format).is_err() {
+        log::warn!("An error format was already set, and was not replaced");
+    }
+}
+
+/// Set the base URL of documentation links for every [`JsonErrorMiddleware`] without one of its own.
+pub(crate) fn set_error_docs_url(url: String) {
+    if ERROR_DOCS_URL.set(url).is_err() {
+        log::warn!("An error documentation URL was already set, and was not replaced");
+    }
+}
+
+/// Set the reporter of `5XX` errors from every [`JsonErrorMiddleware`] without a reporter of its own.
+pub(crate) fn set_error_reporter(reporter: Arc<dyn ErrorReporter>) {
+    if ERROR_REPORTER.set(reporter).is_err() {
+        log::warn!("An error reporter was already set, and was not replaced");
+    }
+}
+
+/// Set the mapping of database errors for every [`JsonErrorMiddleware`] without a mapping of its own.
+#[cfg(feature = "postgres")]
+pub(crate) fn set_sqlx_errors(map: SqlxErrorFn) {
+    if SQLX_ERRORS.set(map).is_err() {
+        log::warn!("A mapping of sqlx errors was already set, and was not replaced");
+    }
+}
+
+/// A destination for reports of `5XX` errors, such as Sentry, Slack, or PagerDuty, as set via
+/// [`Hooks::error_reporter`][crate::Hooks::error_reporter].
+///
+/// Reports are sent in the background once the response is ready, so that slow or failing reporters do not affect
+/// responses.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::{ErrorReport, ErrorReporter, Hooks};
+///
+/// #[derive(Debug)]
+/// struct SlackReporter {
+///     webhook_url: String,
+/// }
+///
+/// #[tide::utils::async_trait]
+/// impl ErrorReporter for SlackReporter {
+///     async fn report(&self, report: ErrorReport) {
+///         let text = format!(
+///             "{} {} failed with {} (correlation_id={}): {}",
+///             report.request.method, report.request.path, report.status, report.correlation_id, report.message
+///         );
+///         let body = serde_json::json!({ "text": text });
+///         if let Err(error) = surf::post(&self.webhook_url).body(body).await {
+///             log::warn!("Reporting to Slack failed: {}", error);
+///         }
+///     }
+/// }
+///
+/// # #[allow(dead_code)]
+/// fn setup_hooks() -> Hooks<()> {
+///     Hooks::new().error_reporter(SlackReporter {
+///         webhook_url: "https://hooks.slack.com/services/...".to_string(),
+///     })
+/// }
+/// ```
+#[tide::utils::async_trait]
+pub trait ErrorReporter: Send + Sync + 'static {
+    /// Report a `5XX` error.
+    async fn report(&self, report: ErrorReport);
+}
+
+/// A `5XX` error, as passed to an [`ErrorReporter`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ErrorReport {
+    /// The status of the response.
+    pub status: StatusCode,
+    /// The message of the error, which is not in the response.
+    pub message: String,
+    /// The messages of the error's [`source`][std::error::Error::source]s, in turn.
+    pub sources: Vec<String>,
+    /// The type name of the error, if known.
+    pub error_type: Option<String>,
+    /// The UUID v4 assigned to the error response, as in its `X-Correlation-Id` header.
+    pub correlation_id: String,
+    /// The request which failed.
+    pub request: ErrorRequest,
+}
+
+/// The request which failed with an [`ErrorReport`]'s error.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ErrorRequest {
+    /// The request's method.
+    pub method: Method,
+    /// The request's path.
+    pub path: String,
+    /// The id assigned to the request, as in its `X-Request-Id` response header. See [`IdScheme`][crate::IdScheme].
+    pub request_id: String,
+}
+
 /// The structure of an error as formatted by preroll's error handling middleware.
 ///
 /// A service using preroll will always respond with a JSON body in this format if an internal or client error occurs.
@@ -28,6 +191,8 @@ struct JsonErrorMiddlewareHasBeenRun;
 ///   "status": 422,
 ///   "title": "Unprocessable Entity",
 ///   "message": "missing field \"address\"",
+///   "code": "invalid_address",
+///   "details": null,
 ///   "request_id": "00000000-0000-0000-0000-000000000000"
 ///   "correlation_id": null,
 /// }
@@ -45,7 +210,24 @@ pub struct JsonError {
     ///
     /// If the original error context is missing, this field will be `"(no additional context)"`.
     pub message: String,
-    /// The UUID v4 assigned to the request, possibly from an incoming header.
+    /// The machine-readable code of a [`preroll::Error`][crate::Error], e.g. `"user_not_found"`.
+    #[serde(default)]
+    pub code: Option<String>,
+    /// A link to the documentation of the [`code`][Self::code], if a base URL is set via
+    /// [`Hooks::error_docs_url`][crate::Hooks::error_docs_url].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub docs_url: Option<String>,
+    /// The details of a [`preroll::Error`][crate::Error], e.g. `{ "remaining": 0 }`.
+    #[serde(default)]
+    pub details: Option<Value>,
+    /// The number of seconds after which to retry, as in the `Retry-After` header, for errors such as
+    /// [`Error::rate_limited_until`][crate::Error::rate_limited_until].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// The problems with each field of the request for [`ValidationErrors`][crate::ValidationErrors], if any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// The id assigned to the request, possibly from an incoming header. See [`IdScheme`][crate::IdScheme].
     pub request_id: RequestId,
     /// The service-unique UUID v4 assigned to the error response for 5XX internal server errors.
     pub correlation_id: Option<String>,
@@ -53,13 +235,238 @@ pub struct JsonError {
     #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
     /// If the `honeycomb` feature is enabled, this will be the honeycomb trace id associated with this request.
     pub honeycomb_trace_id: Option<String>,
+    /// The source chain and backtrace of the error, which are never included in [`Production`][Environment::Production].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug: Option<ErrorDebug>,
+}
+
+/// An error as formatted by preroll's error handling middleware with [`ErrorFormat::ProblemDetails`],
+/// per [RFC 7807](https://tools.ietf.org/html/rfc7807).
+///
+/// The fields of a [`JsonError`] which RFC 7807 does not define are included as extension members.
+///
+/// An example of the structure as it would be in JSON:
+/// ```text
+/// {
+///   "type": "about:blank",
+///   "title": "Unprocessable Entity",
+///   "status": 422,
+///   "detail": "missing field \"address\"",
+///   "instance": "/api/v1/users",
+///   "code": "invalid_address",
+///   "details": null,
+///   "request_id": "00000000-0000-0000-0000-000000000000",
+///   "correlation_id": null
+/// }
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProblemDetails {
+    /// A link to the documentation of the [`code`][Self::code], as for [`JsonError::docs_url`], or else `"about:blank"`,
+    /// meaning that the status says it all.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// The 'canonical reason' of the http status code, as for [`JsonError::title`].
+    pub title: String,
+    /// The http status code.
+    pub status: u16,
+    /// The same as [`JsonError::message`].
+    pub detail: String,
+    /// The path of the request which failed.
+    pub instance: String,
+    /// The same as [`JsonError::code`].
+    #[serde(default)]
+    pub code: Option<String>,
+    /// The same as [`JsonError::details`].
+    #[serde(default)]
+    pub details: Option<Value>,
+    /// The same as [`JsonError::retry_after`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// The same as [`JsonError::fields`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// The id assigned to the request, possibly from an incoming header. See [`IdScheme`][crate::IdScheme].
+    pub request_id: RequestId,
+    /// The service-unique UUID v4 assigned to the error response for 5XX internal server errors.
+    pub correlation_id: Option<String>,
+    #[cfg(feature = "honeycomb")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
+    /// If the `honeycomb` feature is enabled, this will be the honeycomb trace id associated with this request.
+    pub honeycomb_trace_id: Option<String>,
+    /// The source chain and backtrace of the error, which are never included in [`Production`][Environment::Production].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug: Option<ErrorDebug>,
+}
+
+/// The internals of an error, for debugging outside of [`Production`][Environment::Production].
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorDebug {
+    /// The message of the error, followed by that of each of its [`source`][std::error::Error::source]s in turn.
+    pub chain: Vec<String>,
+    /// The backtrace of where the error was created, if captured, i.e. with `RUST_BACKTRACE=1` set.
+    pub backtrace: Option<String>,
+}
+
+impl ErrorDebug {
+    fn new(error: &tide::Error) -> Self {
+        let error: &anyhow::Error = error.as_ref();
+        let backtrace = error.backtrace();
+        Self {
+            chain: error
+                .chain()
+                .map(|error| redact(&error.to_string()).into_owned())
+                .collect(),
+            backtrace: match backtrace.status() {
+                BacktraceStatus::Captured => Some(backtrace.to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl ProblemDetails {
+    fn new(error: JsonError, instance: String) -> Self {
+        Self {
+            problem_type: error.docs_url.unwrap_or_else(|| "about:blank".to_string()),
+            title: error.title,
+            status: error.status,
+            detail: error.message,
+            instance,
+            code: error.code,
+            details: error.details,
+            retry_after: error.retry_after,
+            fields: error.fields,
+            request_id: error.request_id,
+            correlation_id: error.correlation_id,
+            #[cfg(feature = "honeycomb")]
+            honeycomb_trace_id: error.honeycomb_trace_id,
+            debug: error.debug,
+        }
+    }
 }
 
 impl JsonErrorMiddleware {
     /// Create a new instance of `JsonErrorMiddleware`.
     #[must_use]
     pub fn new() -> Self {
-        Self { _priv: () }
+        Self {
+            format: None,
+            docs_url: None,
+            reporter: None,
+            #[cfg(feature = "postgres")]
+            sqlx_errors: None,
+        }
+    }
+
+    /// Format errors as `format`, rather than as set via [`Hooks::error_format`][crate::Hooks::error_format].
+    #[must_use]
+    pub fn format(mut self, format: ErrorFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Link error codes to their documentation under `url`, rather than as set via
+    /// [`Hooks::error_docs_url`][crate::Hooks::error_docs_url].
+    #[must_use]
+    pub fn docs_url(mut self, url: impl Into<String>) -> Self {
+        self.docs_url = Some(url.into());
+        self
+    }
+
+    /// Report `5XX` errors to `reporter`, rather than as set via [`Hooks::error_reporter`][crate::Hooks::error_reporter].
+    #[must_use]
+    pub fn reporter(mut self, reporter: Arc<dyn ErrorReporter>) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    /// Map database errors to responses with `map`, rather than as set via [`Hooks::sqlx_errors`][crate::Hooks::sqlx_errors].
+    #[cfg(feature = "postgres")]
+    #[must_use]
+    pub fn sqlx_errors(mut self, map: SqlxErrorFn) -> Self {
+        self.sqlx_errors = Some(map);
+        self
+    }
+
+    /// Replace a database error with the error it is mapped to, if any.
+    #[cfg(feature = "postgres")]
+    fn map_sqlx_error(&self, res: &mut Response) {
+        let map = match self.sqlx_errors.as_ref().or_else(|| SQLX_ERRORS.get()) {
+            Some(map) => map,
+            None => return,
+        };
+
+        let mapped = res
+            .error()
+            .and_then(|error| error.downcast_ref::<sqlx::Error>())
+            .and_then(|error| map(error));
+        if let Some(error) = mapped {
+            res.set_status(error.status());
+            res.set_error(error);
+        }
+    }
+
+    /// Report a `5XX` error in the background, if there is a reporter.
+    fn report(&self, res: &Response, correlation_id: &CorrelationId, request: ErrorRequest) {
+        let reporter = match self.reporter.as_ref().or_else(|| ERROR_REPORTER.get()) {
+            Some(reporter) => reporter.clone(),
+            None => return,
+        };
+
+        let (message, sources, error_type) = match res.error() {
+            Some(error) => {
+                let error_type = error.type_name().map(str::to_string);
+                let error: &anyhow::Error = error.as_ref();
+                (
+                    redact(&error.to_string()).into_owned(),
+                    error
+                        .chain()
+                        .skip(1)
+                        .map(|error| redact(&error.to_string()).into_owned())
+                        .collect(),
+                    error_type,
+                )
+            }
+            None => ("(no additional context)".to_string(), Vec::new(), None),
+        };
+        let report = ErrorReport {
+            status: res.status(),
+            message,
+            sources,
+            error_type,
+            correlation_id: correlation_id.to_string(),
+            request,
+        };
+        async_std::task::spawn(async move { reporter.report(report).await });
+    }
+
+    /// Set the response body to an error, in this middleware's format.
+    fn set_body(&self, res: &mut Response, mut error: JsonError, instance: String) -> Result<()> {
+        let format = self
+            .format
+            .or_else(|| ERROR_FORMAT.get().copied())
+            .unwrap_or_default();
+
+        let docs_url = self.docs_url.as_ref().or_else(|| ERROR_DOCS_URL.get());
+        if let (Some(docs_url), Some(code)) = (docs_url, &error.code) {
+            error.docs_url = Some(format!(
+                "{}/{}",
+                docs_url.trim_end_matches('/'),
+                utf8_percent_encode(code, DOCS_URL_ENCODE_SET)
+            ));
+        }
+
+        match format {
+            ErrorFormat::Json => {
+                res.set_body(json_body(&error)?);
+                res.set_content_type(mime::JSON);
+            }
+            ErrorFormat::ProblemDetails => {
+                res.set_body(json_body(&ProblemDetails::new(error, instance))?);
+                res.set_content_type("application/problem+json");
+            }
+        }
+        Ok(())
     }
 
     /// Log a request and a response.
@@ -81,8 +488,56 @@ impl JsonErrorMiddleware {
         #[cfg(feature = "honeycomb")]
         let honeycomb_trace_id = req.ext::<TraceId>().cloned();
 
+        let instance = req.url().path().to_string();
+        let request = ErrorRequest {
+            method: req.method(),
+            path: instance.clone(),
+            request_id: request_id.as_str().to_string(),
+        };
         let mut res = next.run(req).await;
+
+        #[cfg(feature = "postgres")]
+        self.map_sqlx_error(&mut res);
+
+        let fields = match res
+            .error()
+            .and_then(|error| error.downcast_ref::<ValidationErrors>())
+        {
+            Some(errors) => errors.fields().to_vec(),
+            None => Vec::new(),
+        };
+        if !fields.is_empty() {
+            res.set_status(StatusCode::UnprocessableEntity);
+        }
         let status = res.status();
+        let debug = match res.error() {
+            Some(error) if !Environment::current().is_production() => Some(ErrorDebug::new(error)),
+            _ => None,
+        };
+        let (code, details, retry_after) = match res.error().and_then(crate::Error::of) {
+            Some(error) => (
+                error.code().map(str::to_string),
+                error.details().cloned(),
+                error.retry_after().cloned(),
+            ),
+            None => (None, None, None),
+        };
+        if let Some(retry_after) = &retry_after {
+            retry_after.apply(&mut res);
+        }
+        let retry_after = retry_after.map(|retry_after| {
+            // Durations are sent as seconds, and times as dates.
+            match retry_after.value().as_str().parse() {
+                Ok(seconds) => seconds,
+                Err(_) => {
+                    let duration = retry_after
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default();
+                    // Rounded up, so that clients do not retry early.
+                    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+                }
+            }
+        });
 
         if status.is_server_error() {
             #[cfg(not(feature = "test"))]
@@ -93,15 +548,22 @@ impl JsonErrorMiddleware {
             let body = JsonError {
                 title: status.canonical_reason().to_string(),
                 message: format!("Internal Server Error (correlation_id={})", correlation_id),
+                code,
+                docs_url: None,
+                details,
+                retry_after,
+                fields: Vec::new(),
                 status: status as u16,
                 request_id,
                 correlation_id: Some(correlation_id.to_string()),
                 #[cfg(feature = "honeycomb")]
                 honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                debug,
             };
-            res.set_body(Body::from_json(&body)?);
+            self.set_body(&mut res, body, instance)?;
 
             res.insert_header("X-Correlation-Id", correlation_id.as_str());
+            self.report(&res, &correlation_id, request);
 
             // Set the Correlation Id on the Response so we can use it from the LogMiddleware.
             res.insert_ext(correlation_id);
@@ -126,25 +588,37 @@ impl JsonErrorMiddleware {
             if let Some(error) = res.error() {
                 let body = JsonError {
                     title: status.canonical_reason().to_string(),
-                    message: format!("{:?}", error),
+                    message: redact(&error.to_string()).into_owned(),
+                    code,
+                    docs_url: None,
+                    details,
+                    retry_after,
+                    fields,
                     status: status as u16,
                     request_id,
                     correlation_id: None,
                     #[cfg(feature = "honeycomb")]
                     honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                    debug,
                 };
-                res.set_body(Body::from_json(&body)?);
+                self.set_body(&mut res, body, instance)?;
             } else {
                 let body = JsonError {
                     title: status.canonical_reason().to_string(),
                     message: "(no additional context)".to_string(),
+                    code: None,
+                    docs_url: None,
+                    details: None,
+                    retry_after: None,
+                    fields: Vec::new(),
                     status: status as u16,
                     request_id,
                     correlation_id: None,
                     #[cfg(feature = "honeycomb")]
                     honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                    debug: None,
                 };
-                res.set_body(Body::from_json(&body)?);
+                self.set_body(&mut res, body, instance)?;
             }
 
             return Ok(res);
@@ -160,3 +634,274 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for JsonErrorMiddle
         self.handle(req, next).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_std::channel::{self, Sender};
+    use async_std::io;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::{self, TestClientOptions, TestResult};
+
+    #[async_std::test]
+    async fn formats_problem_details() -> TestResult<()> {
+        let options = TestClientOptions::new().error_format(ErrorFormat::ProblemDetails);
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: tide::Route<'_, Arc<()>>| {
+                server.at("users/:id").get(|_| async {
+                    Err::<String, _>(tide::Error::from_str(404, "No such user"))
+                });
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.get("/api/v1/users/1").await?;
+        assert_eq!(res.status(), 404);
+        assert_eq!(
+            res.content_type().map(|mime| mime.essence().to_string()),
+            Some("application/problem+json".to_string())
+        );
+
+        let problem: ProblemDetails = res.body_json().await?;
+        assert_eq!(problem.problem_type, "about:blank");
+        assert_eq!(problem.title, "Not Found");
+        assert_eq!(problem.status, 404);
+        assert_eq!(problem.detail, "No such user");
+        assert_eq!(problem.instance, "/api/v1/users/1");
+        assert_eq!(problem.correlation_id, None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn includes_error_codes() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("users/:id").get(|_| async {
+                Err::<String, _>(crate::Error::with_code(
+                    "user_not_found",
+                    404,
+                    "No such user",
+                ))
+            });
+            server.at("crash").get(|_| async {
+                Err::<String, _>(crate::Error::with_code("database_down", 503, "Oh no"))
+            });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/users/1").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 404);
+        assert_eq!(error.message, "No such user");
+        assert_eq!(error.code.as_deref(), Some("user_not_found"));
+
+        // Codes are kept for server errors, while their messages are not.
+        let mut res = client.get("/api/v1/crash").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 503);
+        assert_eq!(error.code.as_deref(), Some("database_down"));
+        assert!(!error.message.contains("Oh no"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn links_error_docs() -> TestResult<()> {
+        let setup_routes = |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("users/:id").get(|_| async {
+                Err::<String, _>(crate::Error::with_code(
+                    "user_not_found",
+                    404,
+                    "No such user",
+                ))
+            });
+        };
+
+        let options = TestClientOptions::new().error_docs_url("https://docs.example.com/errors/");
+        let client = test_utils::create_client_with_options((), setup_routes, options).await?;
+        let mut res = client.get("/api/v1/users/1").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(
+            error.docs_url.as_deref(),
+            Some("https://docs.example.com/errors/user_not_found")
+        );
+
+        let options = TestClientOptions::new()
+            .error_docs_url("https://docs.example.com/errors")
+            .error_format(ErrorFormat::ProblemDetails);
+        let client = test_utils::create_client_with_options((), setup_routes, options).await?;
+        let mut res = client.get("/api/v1/users/1").await?;
+        let problem: ProblemDetails = res.body_json().await?;
+        assert_eq!(
+            problem.problem_type,
+            "https://docs.example.com/errors/user_not_found"
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn includes_error_details() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("users").post(|_| async {
+                Err::<String, _>(crate::Error::with_details(
+                    409,
+                    "A user with that email already exists",
+                    json!({ "user_id": 7 }),
+                ))
+            });
+        })
+        .await?;
+
+        let mut res = client.post("/api/v1/users").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 409);
+        assert_eq!(error.message, "A user with that email already exists");
+        assert_eq!(error.code, None);
+        assert_eq!(error.details, Some(json!({ "user_id": 7 })));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn lists_validation_errors() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("users").post(|_| async {
+                let mut errors = ValidationErrors::new();
+                errors.add("name", "required", "A name is required");
+                errors.add("address.city", "too_long", "Too long");
+                // Via `?`, which would otherwise be a 500.
+                Err::<String, _>(errors)?;
+                Ok("")
+            });
+        })
+        .await?;
+
+        let mut res = client.post("/api/v1/users").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 422);
+        assert_eq!(
+            error.message,
+            "Invalid fields: name: A name is required; address.city: Too long"
+        );
+        assert_eq!(error.fields.len(), 2);
+        assert_eq!(error.fields[1].path, "address.city");
+        assert_eq!(error.fields[1].code, "too_long");
+        assert_eq!(error.fields[1].message, "Too long");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn sets_retry_after() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("maintenance").get(|_| async {
+                Err::<String, _>(crate::Error::service_unavailable_for(Duration::from_secs(
+                    30,
+                )))
+            });
+            server.at("throttled").get(|_| async {
+                let until = SystemTime::now() + Duration::from_secs(60);
+                Err::<String, _>(crate::Error::rate_limited_until(until))
+            });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/maintenance").await?;
+        assert_eq!(
+            res.header("Retry-After").map(|value| value.as_str()),
+            Some("30")
+        );
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 503);
+        assert_eq!(error.code.as_deref(), Some("service_unavailable"));
+        assert_eq!(error.retry_after, Some(30));
+
+        let mut res = client.get("/api/v1/throttled").await?;
+        let retry_after = res
+            .header("Retry-After")
+            .map(|value| value.as_str().to_string());
+        assert!(retry_after.is_some_and(|value| value.ends_with(" GMT")));
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 429);
+        assert_eq!(error.code.as_deref(), Some("rate_limited"));
+        assert!(error
+            .retry_after
+            .is_some_and(|seconds| (59..=60).contains(&seconds)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn includes_source_chain_outside_production() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("upload").post(|_| async {
+                let error = std::io::Error::other("disk full");
+                let error = anyhow::Error::new(error).context("Saving the upload failed");
+                Err::<String, _>(tide::Error::new(500, error))
+            });
+        })
+        .await?;
+
+        let mut res = client.post("/api/v1/upload").await?;
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.status, 500);
+        let debug = error.debug.map(|debug| debug.chain);
+        assert_eq!(
+            debug,
+            Some(vec![
+                "Saving the upload failed".to_string(),
+                "disk full".to_string()
+            ])
+        );
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct ChannelReporter(Sender<ErrorReport>);
+
+    #[tide::utils::async_trait]
+    impl ErrorReporter for ChannelReporter {
+        async fn report(&self, report: ErrorReport) {
+            let _ = self.0.send(report).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn reports_server_errors() -> TestResult<()> {
+        let (sender, receiver) = channel::unbounded();
+        let options = TestClientOptions::new().error_reporter(ChannelReporter(sender));
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: tide::Route<'_, Arc<()>>| {
+                server
+                    .at("missing")
+                    .get(|_| async { Err::<String, _>(tide::Error::from_str(404, "Not here")) });
+                server
+                    .at("crash")
+                    .get(|_| async { Err::<String, _>(tide::Error::from_str(500, "Oh no")) });
+            },
+            options,
+        )
+        .await?;
+
+        client.get("/api/v1/missing").await?;
+        let res = client.get("/api/v1/crash").await?;
+
+        // Client errors are not reported.
+        let report = io::timeout(Duration::from_secs(5), async {
+            receiver.recv().await.map_err(io::Error::other)
+        })
+        .await?;
+        assert_eq!(report.status, StatusCode::InternalServerError);
+        assert_eq!(report.message, "Oh no");
+        assert_eq!(report.request.method, Method::Get);
+        assert_eq!(report.request.path, "/api/v1/crash");
+        assert_eq!(
+            res.header("X-Correlation-Id").map(|id| id.as_str()),
+            Some(report.correlation_id.as_str())
+        );
+        assert!(receiver.is_empty());
+        Ok(())
+    }
+}
@@ -1,12 +1,32 @@
-use kv_log_macro::{error, info, trace, warn};
+use std::cell::RefCell;
+use std::ops::Range;
+
+use kv_log_macro::{error, log, trace};
+use log::kv::Value;
+use log::Level;
+use once_cell::sync::OnceCell;
 use tide::http::headers::{REFERER, USER_AGENT};
-use tide::{Middleware, Next, Request, Result};
+use tide::{Middleware, Next, Request, Result, StatusCode};
 
 #[cfg(feature = "honeycomb")]
 use tracing_honeycomb::TraceId;
 
 use super::extension_types::{CorrelationId, RequestId};
 
+/// The levels set via [`Hooks::log_levels`][crate::Hooks::log_levels].
+static LOG_LEVELS: OnceCell<LogLevels> = OnceCell::new();
+
+/// The most buffers for [`RequestFields`] kept for reuse on each thread.
+const MAX_BUFFERS: usize = 64;
+
+/// The largest buffer for [`RequestFields`] kept for reuse, in bytes.
+const MAX_BUFFER_CAPACITY: usize = 4096;
+
+thread_local! {
+    /// Buffers for [`RequestFields`], kept for reuse by the requests handled on this thread.
+    static BUFFERS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
 /// Log all outgoing responses.
 #[derive(Debug, Default, Clone)]
 pub struct LogMiddleware {
@@ -15,6 +35,121 @@ pub struct LogMiddleware {
 
 struct LogMiddlewareHasBeenRun;
 
+/// The levels [`LogMiddleware`] logs responses at, by status, e.g. to log expected `404 Not Found`s at `Debug` rather
+/// than `Warn`.
+///
+/// Statuses which are not set are logged at `Info`, or `Warn` for `4XX` client errors, or `Error` for `5XX` server errors.
+///
+/// Set for every route via [`Hooks::log_levels`][crate::Hooks::log_levels], or for some routes by adding `LogLevels` to
+/// them as middleware, which takes precedence.
+///
+/// ## Example:
+///
+/// ```
+/// use log::Level;
+/// use preroll::{Hooks, LogLevels};
+/// use tide::StatusCode;
+///
+/// # #[allow(dead_code)]
+/// fn setup_hooks() -> Hooks<()> {
+///     Hooks::new().log_levels(
+///         LogLevels::new()
+///             .status(StatusCode::NotFound, Level::Debug)
+///             .status(StatusCode::UnprocessableEntity, Level::Info),
+///     )
+/// }
+///
+/// # #[allow(dead_code)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     // Health checks are expected to fail while the service is starting up.
+///     server
+///         .at("health")
+///         .with(LogLevels::new().server_errors(Level::Info))
+///         .get(|_| async { Ok("ok") });
+/// }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct LogLevels {
+    statuses: Vec<(StatusCode, Level)>,
+    client_errors: Option<Level>,
+    server_errors: Option<Level>,
+}
+
+impl LogLevels {
+    /// No levels, which leaves every status at its default level.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Log responses with `status` at `level`.
+    #[must_use]
+    pub fn status(mut self, status: StatusCode, level: Level) -> Self {
+        self.statuses.push((status, level));
+        self
+    }
+
+    /// Log `4XX` client error responses at `level`, except for those set via [`status`][Self::status].
+    #[must_use]
+    pub fn client_errors(mut self, level: Level) -> Self {
+        self.client_errors = Some(level);
+        self
+    }
+
+    /// Log `5XX` server error responses at `level`, except for those set via [`status`][Self::status].
+    #[must_use]
+    pub fn server_errors(mut self, level: Level) -> Self {
+        self.server_errors = Some(level);
+        self
+    }
+
+    fn get(&self, status: StatusCode) -> Option<Level> {
+        let level = self
+            .statuses
+            .iter()
+            .rev()
+            .find(|(s, _)| *s == status)
+            .map(|(_, level)| *level);
+        if status.is_client_error() {
+            level.or(self.client_errors)
+        } else if status.is_server_error() {
+            level.or(self.server_errors)
+        } else {
+            level
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for LogLevels {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
+        let mut res = next.run(req).await;
+        res.insert_ext(self.clone());
+        Ok(res)
+    }
+}
+
+/// Set the levels of every [`LogMiddleware`].
+pub(crate) fn set_log_levels(levels: LogLevels) {
+    if LOG_LEVELS.set(levels).is_err() {
+        log::warn!("Log levels were already set, and were not replaced");
+    }
+}
+
+/// The level to log a response at, from its route's [`LogLevels`], or else the global levels, or else the default.
+fn level(res: &tide::Response, status: StatusCode) -> Level {
+    res.ext::<LogLevels>()
+        .and_then(|levels| levels.get(status))
+        .or_else(|| LOG_LEVELS.get().and_then(|levels| levels.get(status)))
+        .unwrap_or(if status.is_server_error() {
+            Level::Error
+        } else if status.is_client_error() {
+            Level::Warn
+        } else {
+            Level::Info
+        })
+}
+
 impl LogMiddleware {
     /// Create a new instance of `LogMiddleware`.
     #[must_use]
@@ -43,27 +178,15 @@ impl LogMiddleware {
         #[cfg(not(feature = "honeycomb"))]
         let honeycomb_trace_id = Some("disabled");
 
-        let path = req.url().path().to_owned();
         let method = req.method();
-        // TODO(Jeremiah): Do we need to check the Forwarded header for the origin IP?
-        let ip = req.peer_addr().unwrap_or("(no Peer Address)").to_string();
-        let referer = req
-            .header(REFERER)
-            .map(|hvs| hvs.last().as_str())
-            .unwrap_or("(no Referer)")
-            .to_string();
-        let user_agent = req
-            .header(USER_AGENT)
-            .map(|hvs| hvs.last().as_str())
-            .unwrap_or("(no User-Agent)")
-            .to_string();
+        let fields = RequestFields::new(&req);
 
         trace!("Incoming Request", {
             method: method.as_ref(),
-            path: path,
-            ip: ip,
-            referer: referer,
-            user_agent: user_agent,
+            path: fields.path(),
+            ip: fields.ip(),
+            referer: fields.referer(),
+            user_agent: fields.user_agent(),
             body_size: req.len(),
             request_id: request_id,
         });
@@ -71,6 +194,7 @@ impl LogMiddleware {
         let start = std::time::Instant::now();
         let res = next.run(req).await;
         let status = res.status();
+        let level = level(&res, status);
 
         #[cfg(feature = "panic-on-error")]
         #[allow(clippy::unwrap_used)]
@@ -78,89 +202,229 @@ impl LogMiddleware {
             Err::<(), &tide::Error>(error).unwrap();
         }
 
-        if let Some(correlation_id) = res.ext::<CorrelationId>() {
+        let correlation_id = res.ext::<CorrelationId>();
+        if correlation_id.is_none() && status.is_server_error() {
+            // Programmer error, always expect there to be JsonErrorMiddleware,
+            // which will catch internal server errors first and assign them a correlation id.
+            error!("Internal Error -- JsonErrorMiddleware must be installed after LogMiddleware");
+            return Ok(res);
+        }
+        if !log::log_enabled!(level) {
+            return Ok(res);
+        }
+
+        // These are only formatted by the logger, rather than into strings of their own.
+        let elapsed = start.elapsed();
+        let elapsed = Value::from_debug(&elapsed);
+        let honeycomb_trace_id = honeycomb_trace_id.as_ref().map(Value::from_display);
+
+        if let Some(correlation_id) = correlation_id {
             if let Some(error) = res.error() {
-                error!("Internal Error", {
+                log!(level, "Internal Error", {
                     status: status as u16,
                     method: method.as_ref(),
-                    path: path,
-                    ip: ip,
-                    referer: referer,
-                    user_agent: user_agent,
-                    message: format!("{:?}", error),
+                    path: fields.path(),
+                    ip: fields.ip(),
+                    referer: fields.referer(),
+                    user_agent: fields.user_agent(),
+                    message: Value::from_debug(error),
                     error_type: error.type_name(),
+                    error_code: crate::Error::of(error).and_then(crate::Error::code),
                     correlation_id: correlation_id,
                     request_id: request_id,
-                    honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
+                    honeycomb_trace_id: honeycomb_trace_id,
+                    elapsed: elapsed,
                 });
             } else {
-                error!("Internal Error", {
+                log!(level, "Internal Error", {
                     status: status as u16,
                     method: method.as_ref(),
-                    path: path,
-                    ip: ip,
-                    referer: referer,
-                    user_agent: user_agent,
+                    path: fields.path(),
+                    ip: fields.ip(),
+                    referer: fields.referer(),
+                    user_agent: fields.user_agent(),
                     correlation_id: correlation_id,
                     request_id: request_id,
-                    honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
+                    honeycomb_trace_id: honeycomb_trace_id,
+                    elapsed: elapsed,
                 });
             }
-        } else if status.is_server_error() {
-            // Programmer error, always expect there to be JsonErrorMiddleware,
-            // which will catch internal server errors first and assign them a correlation id.
-            error!("Internal Error -- JsonErrorMiddleware must be installed after LogMiddleware");
         } else if status.is_client_error() {
             if let Some(error) = res.error() {
-                warn!("Client Error: {}", status.canonical_reason(), {
+                log!(level, "Client Error: {}", status.canonical_reason(), {
                     status: status as u16,
                     method: method.as_ref(),
-                    path: path,
-                    ip: ip,
-                    referer: referer,
-                    user_agent: user_agent,
-                    message: format!("{:?}", error),
+                    path: fields.path(),
+                    ip: fields.ip(),
+                    referer: fields.referer(),
+                    user_agent: fields.user_agent(),
+                    message: Value::from_debug(error),
                     error_type: error.type_name(),
+                    error_code: crate::Error::of(error).and_then(crate::Error::code),
                     request_id: request_id,
-                    honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
+                    honeycomb_trace_id: honeycomb_trace_id,
+                    elapsed: elapsed,
                 });
             } else {
-                warn!("Client Error: {}", status.canonical_reason(), {
+                log!(level, "Client Error: {}", status.canonical_reason(), {
                     status: status as u16,
                     method: method.as_ref(),
-                    path: path,
-                    ip: ip,
-                    referer: referer,
-                    user_agent: user_agent,
+                    path: fields.path(),
+                    ip: fields.ip(),
+                    referer: fields.referer(),
+                    user_agent: fields.user_agent(),
                     request_id: request_id,
-                    honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
+                    honeycomb_trace_id: honeycomb_trace_id,
+                    elapsed: elapsed,
                 });
             }
         } else {
-            info!("{}", status.canonical_reason(), {
+            log!(level, "{}", status.canonical_reason(), {
                 status: status as u16,
                 method: method.as_ref(),
-                path: path,
-                ip: ip,
-                referer: referer,
-                user_agent: user_agent,
+                path: fields.path(),
+                ip: fields.ip(),
+                referer: fields.referer(),
+                user_agent: fields.user_agent(),
                 body_size: res.len(),
                 request_id: request_id,
-                honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                elapsed: format!("{:?}", start.elapsed()),
+                honeycomb_trace_id: honeycomb_trace_id,
+                elapsed: elapsed,
             });
         }
         Ok(res)
     }
 }
 
+/// The fields of a request which are logged with its response, kept in one buffer, which is reused by later requests.
+struct RequestFields {
+    buffer: String,
+    path: Range<usize>,
+    ip: Range<usize>,
+    referer: Range<usize>,
+    user_agent: Range<usize>,
+}
+
+impl RequestFields {
+    fn new<State>(req: &Request<State>) -> Self {
+        let mut buffer = BUFFERS
+            .with(|buffers| buffers.borrow_mut().pop())
+            .unwrap_or_default();
+        let mut push = |field: &str| {
+            let start = buffer.len();
+            buffer.push_str(field);
+            start..buffer.len()
+        };
+
+        // TODO(Jeremiah): Do we need to check the Forwarded header for the origin IP?
+        let path = push(req.url().path());
+        let ip = push(req.peer_addr().unwrap_or("(no Peer Address)"));
+        let referer = push(
+            req.header(REFERER)
+                .map_or("(no Referer)", |hvs| hvs.last().as_str()),
+        );
+        let user_agent = push(
+            req.header(USER_AGENT)
+                .map_or("(no User-Agent)", |hvs| hvs.last().as_str()),
+        );
+
+        Self {
+            buffer,
+            path,
+            ip,
+            referer,
+            user_agent,
+        }
+    }
+
+    fn path(&self) -> &str {
+        &self.buffer[self.path.clone()]
+    }
+
+    fn ip(&self) -> &str {
+        &self.buffer[self.ip.clone()]
+    }
+
+    fn referer(&self) -> &str {
+        &self.buffer[self.referer.clone()]
+    }
+
+    fn user_agent(&self) -> &str {
+        &self.buffer[self.user_agent.clone()]
+    }
+}
+
+impl Drop for RequestFields {
+    fn drop(&mut self) {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        // Unusually large buffers are freed, rather than kept around for every request.
+        if buffer.capacity() > MAX_BUFFER_CAPACITY {
+            return;
+        }
+        buffer.clear();
+        BUFFERS.with(|buffers| {
+            let mut buffers = buffers.borrow_mut();
+            if buffers.len() < MAX_BUFFERS {
+                buffers.push(buffer);
+            }
+        });
+    }
+}
+
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for LogMiddleware {
     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
         self.log(req, next).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, capture_logs, LogMatcher, TestResult};
+
+    #[async_std::test]
+    async fn logs_at_route_levels() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server
+                .at("users/:id")
+                .with(LogLevels::new().status(StatusCode::NotFound, Level::Debug))
+                .get(|_| async { Err::<String, _>(tide::Error::from_str(404, "No such user")) });
+            server
+                .at("health")
+                .with(LogLevels::new().server_errors(Level::Info))
+                .get(|_| async { Err::<String, _>(tide::Error::from_str(503, "Starting")) });
+        })
+        .await?;
+
+        let logs = capture_logs(|| async {
+            let _ = client.get("/api/v1/users/1").await;
+            let _ = client.get("/api/v1/health").await;
+            let _ = client.get("/api/v1/missing").await;
+        })
+        .await;
+
+        logs.assert_logged(
+            &LogMatcher::new()
+                .level(Level::Debug)
+                .message_contains("Client Error")
+                .field("path", "/api/v1/users/1"),
+        );
+        logs.assert_logged(
+            &LogMatcher::new()
+                .level(Level::Info)
+                .message_contains("Internal Error")
+                .field("status", 503),
+        );
+        // Other routes keep the default levels.
+        logs.assert_logged(
+            &LogMatcher::new()
+                .level(Level::Warn)
+                .message_contains("Client Error")
+                .field("path", "/api/v1/missing"),
+        );
+        Ok(())
+    }
+}
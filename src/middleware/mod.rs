@@ -1,10 +1,14 @@
 use cfg_if::cfg_if;
 
+pub mod catch_panic;
 pub mod extension_types;
+pub mod fallback;
 pub mod json_error;
 pub mod logger;
 pub mod requestid;
 
+pub use catch_panic::CatchPanicMiddleware;
+pub(crate) use fallback::FallbackMiddleware;
 pub use json_error::JsonErrorMiddleware;
 pub use logger::LogMiddleware;
 pub use requestid::RequestIdMiddleware;
@@ -1,11 +1,132 @@
-use tide::{Middleware, Next, Request};
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[cfg(feature = "test")]
+use once_cell::sync::OnceCell;
+use tide::{Middleware, Next, Request};
 use uuid::Uuid;
 
 use super::extension_types::RequestId;
+use crate::utils::random;
+
+static ID_GENERATOR: OnceCell<Box<dyn IdGenerator>> = OnceCell::new();
+
+thread_local! {
+    /// The id of the request whose handler is being polled on this thread, for outgoing requests to propagate.
+    static CURRENT_REQUEST_ID: RefCell<Option<RequestId>> = const { RefCell::new(None) };
+}
+
+/// Generates the ids of requests which arrive without a valid `X-Request-Id` header.
+///
+/// See [`IdScheme`] for the built-in generators, which are set via
+/// [`Hooks::id_generator`][crate::setup::Hooks::id_generator].
+pub trait IdGenerator: Send + Sync + 'static {
+    /// A new request id.
+    fn generate(&self) -> RequestId;
+}
+
+/// The built-in [`IdGenerator`]s.
+///
+/// The time-sortable schemes, [`IdScheme::UuidV7`] and [`IdScheme::Ulid`], order ids by the millisecond they were
+/// generated in, which keeps logs and database rows keyed by them in roughly the order requests arrived.
+///
+/// Other than for [`IdScheme::UuidV4`], the random bits are from a fast, non-cryptographic generator, seeded per
+/// thread, so ids must not be relied on as secrets.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::setup::Hooks;
+/// use preroll::IdScheme;
+///
+/// # #[allow(dead_code)]
+/// fn hooks() -> Hooks<()> {
+///     // E.g. `X-Request-Id: 0190b6e2-4c5f-7a3b-9d2e-6f1a8c3b5d7e`.
+///     Hooks::new().id_generator(IdScheme::UuidV7)
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum IdScheme {
+    /// Random UUIDs, e.g. `"b1e9a5c4-3f2d-4e8a-9c7b-6d5f4a3e2b1c"`.
+    #[default]
+    UuidV4,
+    /// Time-sortable UUIDs, e.g. `"0190b6e2-4c5f-7a3b-9d2e-6f1a8c3b5d7e"`.
+    UuidV7,
+    /// Time-sortable ULIDs, e.g. `"01J2VE4K2Z7MX9T6QF5C8B3D1E"`.
+    Ulid,
+    /// 128 random bits as 32 hex digits, e.g. `"4bf92f3577b34da6a3ce929d0e0e4736"`.
+    Hex,
+}
+
+impl IdGenerator for IdScheme {
+    fn generate(&self) -> RequestId {
+        match self {
+            Self::UuidV4 => Uuid::new_v4().into(),
+            Self::UuidV7 => {
+                let id = timestamped(random());
+                // The version, 7, and the RFC 4122 variant.
+                let id = id & !(0xf << 76) | 0x7 << 76;
+                let id = id & !(0b11 << 62) | 0b10 << 62;
+                Uuid::from_u128(id).into()
+            }
+            Self::Ulid => RequestId::from_ulid(timestamped(random())),
+            Self::Hex => RequestId::from_hex(random()),
+        }
+    }
+}
+
+pub(crate) fn set_id_generator(generator: Box<dyn IdGenerator>) {
+    if ID_GENERATOR.set(generator).is_err() {
+        log::warn!("The request id generator was already set, and was not replaced");
+    }
+}
+
+/// A new request id, from the generator set via [`Hooks`][crate::setup::Hooks], or else a random UUID.
+#[cfg(not(feature = "test"))]
+fn generate_id() -> RequestId {
+    match ID_GENERATOR.get() {
+        Some(generator) => generator.generate(),
+        None => IdScheme::default().generate(),
+    }
+}
+
+/// `random` with its first 48 bits replaced by the current Unix time in milliseconds.
+fn timestamped(random: u128) -> u128 {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis());
+    (millis & 0xffff_ffff_ffff) << 80 | random & ((1 << 80) - 1)
+}
+
+/// The id of the request being handled, if called from within a request handler.
+///
+/// Only available synchronously within the handler's task, not from tasks which it spawns.
+pub(crate) fn current_request_id() -> Option<RequestId> {
+    CURRENT_REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// Sets the current request id on this thread while the inner future is polled.
+struct WithRequestId<F> {
+    request_id: RequestId,
+    inner: Pin<Box<F>>,
+}
 
-/// Attach a RequestId UUID to every request.
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let previous =
+            CURRENT_REQUEST_ID.with(|id| id.borrow_mut().replace(self.request_id.clone()));
+        let poll = self.inner.as_mut().poll(cx);
+        CURRENT_REQUEST_ID.with(|id| *id.borrow_mut() = previous);
+        poll
+    }
+}
+
+/// Attach a RequestId to every request, from its `X-Request-Id` header or else a new one.
 #[derive(Debug, Default, Clone)]
 pub struct RequestIdMiddleware {
     _priv: (),
@@ -18,7 +139,7 @@ impl RequestIdMiddleware {
         Self { _priv: () }
     }
 
-    /// Attach a UUID to every request.
+    /// Attach a RequestId to every request.
     async fn handle<'a, State: Clone + Send + Sync + 'static>(
         &'a self,
         mut req: Request<State>,
@@ -35,11 +156,11 @@ impl RequestIdMiddleware {
                 Ok(id) => id,
                 Err(e) => {
                     log::warn!("Invalid X-Request-Id: \"{}\" - Error: {}", header, e);
-                    RequestId::new()
+                    generate_id()
                 }
             };
         } else {
-            request_id = RequestId::new();
+            request_id = generate_id();
         }
         #[cfg(feature = "test")]
         {
@@ -48,7 +169,11 @@ impl RequestIdMiddleware {
 
         req.set_ext(request_id.clone());
 
-        let mut res = next.run(req).await;
+        let mut res = WithRequestId {
+            request_id: request_id.clone(),
+            inner: Box::pin(next.run(req)),
+        }
+        .await;
 
         res.insert_header("X-Request-Id", request_id.as_str());
 
@@ -62,3 +187,42 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequestIdMiddle
         self.handle(req, next).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_ids() -> Result<(), uuid::Error> {
+        let v4 = IdScheme::UuidV4.generate();
+        assert_eq!(Uuid::parse_str(v4.as_str())?.get_version_num(), 4);
+
+        let v7 = IdScheme::UuidV7.generate();
+        let uuid = Uuid::parse_str(v7.as_str())?;
+        assert_eq!(uuid.get_version_num(), 7);
+        assert_eq!(uuid.get_variant(), Some(uuid::Variant::RFC4122));
+
+        let ulid = IdScheme::Ulid.generate();
+        assert_eq!(ulid.as_str().len(), 26);
+        let hex = IdScheme::Hex.generate();
+        assert_eq!(hex.as_str().len(), 32);
+
+        for id in [v4, v7, ulid, hex] {
+            let parsed: RequestId = id.as_str().parse()?;
+            assert_eq!(parsed.as_str(), id.as_str());
+        }
+        assert_eq!(
+            "01ARZ3NDEKTSV4RRFFQ69G5FAV".parse::<RequestId>()?.as_str(),
+            "01ARZ3NDEKTSV4RRFFQ69G5FAV"
+        );
+        assert!("81ARZ3NDEKTSV4RRFFQ69G5FAV".parse::<RequestId>().is_err());
+
+        // Ids from later milliseconds sort after earlier ones.
+        for scheme in [IdScheme::UuidV7, IdScheme::Ulid] {
+            let first = scheme.generate();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            assert!(first.as_str() < scheme.generate().as_str(), "{:?}", scheme);
+        }
+        Ok(())
+    }
+}
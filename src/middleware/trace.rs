@@ -4,6 +4,7 @@ use tracing_honeycomb::{register_dist_tracing_root, SpanId, TraceId};
 
 use super::extension_types::RequestId;
 use super::honeycomb::propagation::{Propagation, PROPAGATION_HTTP_HEADER};
+use crate::metadata::service_metadata;
 
 /// Set up tracing for every request.
 #[derive(Debug, Default, Clone)]
@@ -18,8 +19,16 @@ impl TraceMiddleware {
         Self { _priv: () }
     }
 
-    /// Set up tracing for every request.
-    #[instrument(skip(req, next))]
+    /// Set up tracing for every request, with the service metadata on the request's span.
+    #[instrument(
+        skip(req, next),
+        fields(
+            service.name = service_metadata().service.as_str(),
+            service.version = service_metadata().version.as_str(),
+            service.environment = service_metadata().environment.as_str(),
+            service.instance = service_metadata().instance.as_str(),
+        )
+    )]
     async fn handle<'a, State: Clone + Send + Sync + 'static>(
         &'a self,
         mut req: Request<State>,
@@ -102,6 +111,11 @@ impl TraceMiddleware {
                 .map(|v| v.to_string())
                 .as_deref()
                 .unwrap_or("chunked"),
+            error.code = res
+                .error()
+                .and_then(crate::Error::of)
+                .and_then(crate::Error::code)
+                .unwrap_or(""),
             "HTTP Response Info"
         );
 
@@ -0,0 +1,605 @@
+//! Streaming `multipart/form-data` request bodies, via
+//! [`MultipartRequestExt::multipart`][crate::prelude::MultipartRequestExt::multipart].
+//!
+//! Fields are read in order, one at a time, without buffering the whole body:
+//! - Bodies over [`max_size`][Multipart::max_size], or fields over [`max_field_size`][Multipart::max_field_size],
+//!   are a `413 Payload Too Large` error.
+//! - Requests which are not `multipart/form-data`, or fields rejected by
+//!   [`ensure_content_type`][Field::ensure_content_type], are a `415 Unsupported Media Type` error.
+//! - Malformed bodies are a `400 Bad Request` error.
+//!
+//! File uploads can be [spooled][Field::spool] to a temporary file, which is deleted when dropped unless it is
+//! [persisted][SpooledFile::persist].
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use tide::Request;
+//!
+//! # #[allow(dead_code)]
+//! async fn upload_avatar(mut req: Request<Arc<()>>) -> tide::Result<String> {
+//!     let mut multipart = req.multipart()?.max_field_size(2 * 1024 * 1024);
+//!
+//!     let mut caption = String::new();
+//!     while let Some(mut field) = multipart.next_field().await? {
+//!         let name = field.name().to_string();
+//!         match name.as_str() {
+//!             "caption" => caption = field.text().await?,
+//!             "avatar" => {
+//!                 field.ensure_content_type(&["image/png", "image/jpeg"])?;
+//!                 let file = field.spool().await?;
+//!                 file.persist("avatars/latest").await?;
+//!             }
+//!             _ => {}
+//!         }
+//!     }
+//!     Ok(caption)
+//! }
+//! ```
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::fs::{self, OpenOptions};
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use tide::{Body, Request, StatusCode};
+
+/// The default limit on the size of a whole multipart body: 16 MiB.
+pub const DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// The limit on the size of a field's headers.
+const MAX_HEADERS_SIZE: usize = 8 * 1024;
+
+/// How much of the body is read at a time.
+const READ_SIZE: usize = 8 * 1024;
+
+/// For unique spooled file names.
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+/// An extension trait for reading `multipart/form-data` request bodies.
+pub trait MultipartRequestExt {
+    /// Take the request body as a [`Multipart`] reader, or a `415 Unsupported Media Type` error if it is not
+    /// `multipart/form-data`.
+    fn multipart(&mut self) -> tide::Result<Multipart>;
+}
+
+impl<State> MultipartRequestExt for Request<State> {
+    fn multipart(&mut self) -> tide::Result<Multipart> {
+        let boundary = self
+            .content_type()
+            .filter(|mime| mime.essence() == "multipart/form-data")
+            .and_then(|mime| mime.param("boundary").map(|boundary| boundary.to_string()))
+            .ok_or_else(|| {
+                tide::Error::from_str(
+                    StatusCode::UnsupportedMediaType,
+                    "Expected a multipart/form-data body with a boundary",
+                )
+            })?;
+
+        Ok(Multipart::new(self.take_body(), &boundary))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    /// Skipping the preamble, or the rest of a field, up to the next delimiter.
+    Skipping,
+    /// Just past a delimiter, before a field's headers or the closing `--`.
+    Delimited,
+    /// Reading a field's data.
+    Field,
+    /// Past the closing delimiter.
+    Done,
+}
+
+/// A `multipart/form-data` body, read one [`Field`] at a time.
+pub struct Multipart {
+    body: Body,
+    buf: Vec<u8>,
+    delimiter: Vec<u8>,
+    state: ReadState,
+    size: usize,
+    field_size: usize,
+    max_size: usize,
+    max_field_size: usize,
+}
+
+impl fmt::Debug for Multipart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multipart")
+            .field("state", &self.state)
+            .field("size", &self.size)
+            .field("max_size", &self.max_size)
+            .field("max_field_size", &self.max_field_size)
+            .finish()
+    }
+}
+
+impl Multipart {
+    fn new(body: Body, boundary: &str) -> Self {
+        Self {
+            body,
+            // The first delimiter need not follow a line break, so pretend there is one.
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: ReadState::Skipping,
+            size: 0,
+            field_size: 0,
+            max_size: DEFAULT_MAX_SIZE,
+            max_field_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    /// Limit the size of the whole body, in bytes. Defaults to [`DEFAULT_MAX_SIZE`].
+    #[must_use]
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Limit the size of each field's data, in bytes. Defaults to [`DEFAULT_MAX_SIZE`].
+    #[must_use]
+    pub fn max_field_size(mut self, max_field_size: usize) -> Self {
+        self.max_field_size = max_field_size;
+        self
+    }
+
+    /// The next field, or `None` after the last one. Any unread data of the previous field is skipped.
+    pub async fn next_field(&mut self) -> tide::Result<Option<Field<'_>>> {
+        loop {
+            match self.state {
+                ReadState::Done => return Ok(None),
+                ReadState::Skipping | ReadState::Field => {
+                    while self.read_data().await?.is_some() {}
+                }
+                ReadState::Delimited => {
+                    self.fill_to(2).await?;
+                    if self.buf.starts_with(b"--") {
+                        self.state = ReadState::Done;
+                        return Ok(None);
+                    }
+
+                    // Skip any transport padding after the delimiter, then parse the headers.
+                    let line_end = self.fill_until(b"\r\n").await?;
+                    self.buf.drain(..line_end + 2);
+                    let headers = if self.buf.starts_with(b"\r\n") {
+                        self.buf.drain(..2);
+                        String::new()
+                    } else {
+                        let headers_end = self.fill_until(b"\r\n\r\n").await?;
+                        let headers: Vec<u8> = self.buf.drain(..headers_end + 4).collect();
+                        String::from_utf8_lossy(&headers).into_owned()
+                    };
+
+                    self.state = ReadState::Field;
+                    self.field_size = 0;
+                    return Field::new(self, &headers).map(Some);
+                }
+            }
+        }
+    }
+
+    /// The next chunk of data before a delimiter, or `None` once the delimiter has been reached.
+    async fn read_data(&mut self) -> tide::Result<Option<Vec<u8>>> {
+        if self.state == ReadState::Delimited || self.state == ReadState::Done {
+            return Ok(None);
+        }
+
+        loop {
+            if let Some(index) = find(&self.buf, &self.delimiter) {
+                if index == 0 {
+                    self.buf.drain(..self.delimiter.len());
+                    self.state = ReadState::Delimited;
+                    return Ok(None);
+                }
+                return self.take_data(index).map(Some);
+            }
+
+            // Anything which could be the start of a delimiter must wait for more of the body.
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return self.take_data(safe).map(Some);
+            }
+            if !self.fill().await? {
+                return Err(malformed(
+                    "Multipart body ended before its closing boundary",
+                ));
+            }
+        }
+    }
+
+    fn take_data(&mut self, len: usize) -> tide::Result<Vec<u8>> {
+        let data: Vec<u8> = self.buf.drain(..len).collect();
+        if self.state == ReadState::Field {
+            self.field_size += data.len();
+            if self.field_size > self.max_field_size {
+                return Err(too_large(format!(
+                    "Multipart field is larger than {} bytes",
+                    self.max_field_size
+                )));
+            }
+        }
+        Ok(data)
+    }
+
+    /// Read more of the body into the buffer, returning `false` at the end of the body.
+    async fn fill(&mut self) -> tide::Result<bool> {
+        let mut chunk = [0; READ_SIZE];
+        let read = self.body.read(&mut chunk).await?;
+        self.size += read;
+        if self.size > self.max_size {
+            return Err(too_large(format!(
+                "Multipart body is larger than {} bytes",
+                self.max_size
+            )));
+        }
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    async fn fill_to(&mut self, len: usize) -> tide::Result<()> {
+        while self.buf.len() < len {
+            if !self.fill().await? {
+                return Err(malformed("Multipart body ended unexpectedly"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill the buffer until it contains `needle`, returning its index.
+    async fn fill_until(&mut self, needle: &[u8]) -> tide::Result<usize> {
+        loop {
+            if let Some(index) = find(&self.buf, needle) {
+                return Ok(index);
+            }
+            if self.buf.len() > MAX_HEADERS_SIZE {
+                return Err(malformed("Multipart field headers are too large"));
+            }
+            if !self.fill().await? {
+                return Err(malformed("Multipart body ended unexpectedly"));
+            }
+        }
+    }
+}
+
+/// A field of a [`Multipart`] body, whose data is read by the methods which consume it.
+#[derive(Debug)]
+pub struct Field<'a> {
+    multipart: &'a mut Multipart,
+    name: String,
+    file_name: Option<String>,
+    content_type: Option<String>,
+}
+
+impl<'a> Field<'a> {
+    fn new(multipart: &'a mut Multipart, headers: &str) -> tide::Result<Self> {
+        let mut name = None;
+        let mut file_name = None;
+        let mut content_type = None;
+
+        for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+            let (header, value) = line
+                .split_once(':')
+                .ok_or_else(|| malformed("Malformed multipart field header"))?;
+            let value = value.trim();
+            if header.eq_ignore_ascii_case("Content-Disposition") {
+                for (param, value) in disposition_params(value) {
+                    if param.eq_ignore_ascii_case("name") {
+                        name = Some(value);
+                    } else if param.eq_ignore_ascii_case("filename") {
+                        file_name = Some(value);
+                    }
+                }
+            } else if header.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.to_string());
+            }
+        }
+
+        Ok(Self {
+            multipart,
+            name: name.ok_or_else(|| malformed("Multipart field has no name"))?,
+            file_name,
+            content_type,
+        })
+    }
+
+    /// The field's name, from its `Content-Disposition`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The uploaded file's name, if this field is a file.
+    ///
+    /// This is chosen by the client, and must not be used as a path without sanitizing it.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// The field's `Content-Type`, if it has one.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Fail with a `415 Unsupported Media Type` unless the field's `Content-Type` is one of `allowed`.
+    ///
+    /// Parameters such as `charset` are ignored.
+    pub fn ensure_content_type(&self, allowed: &[&str]) -> tide::Result<()> {
+        let essence = self
+            .content_type()
+            .and_then(|content_type| content_type.split(';').next())
+            .map(str::trim);
+        match essence {
+            Some(essence) if allowed.iter().any(|a| a.eq_ignore_ascii_case(essence)) => Ok(()),
+            _ => Err(tide::Error::from_str(
+                StatusCode::UnsupportedMediaType,
+                format!(
+                    "Field \"{}\" must be one of: {}",
+                    self.name,
+                    allowed.join(", ")
+                ),
+            )),
+        }
+    }
+
+    /// The next chunk of the field's data, or `None` at its end.
+    pub async fn chunk(&mut self) -> tide::Result<Option<Vec<u8>>> {
+        self.multipart.read_data().await
+    }
+
+    /// All of the field's data.
+    pub async fn bytes(&mut self) -> tide::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    /// All of the field's data as text, or a `400 Bad Request` if it is not UTF-8.
+    pub async fn text(&mut self) -> tide::Result<String> {
+        String::from_utf8(self.bytes().await?).map_err(|_| {
+            malformed(format!(
+                "Multipart field \"{}\" is not valid UTF-8",
+                self.name
+            ))
+        })
+    }
+
+    /// Write the field's data to a new file in the system's temporary directory.
+    pub async fn spool(&mut self) -> tide::Result<SpooledFile> {
+        self.spool_in(std::env::temp_dir()).await
+    }
+
+    /// Write the field's data to a new file in `dir`.
+    pub async fn spool_in(&mut self, dir: impl AsRef<Path>) -> tide::Result<SpooledFile> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos())
+            .unwrap_or_default();
+        let path = dir.as_ref().join(format!(
+            "preroll-upload-{}-{}-{}",
+            process::id(),
+            nanos,
+            SPOOLED.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        // From here on, the file is removed if anything fails.
+        let mut spooled = SpooledFile {
+            path: Some(path),
+            size: 0,
+            file_name: self.file_name.clone(),
+            content_type: self.content_type.clone(),
+        };
+
+        while let Some(chunk) = self.chunk().await? {
+            file.write_all(&chunk).await?;
+            spooled.size += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(spooled)
+    }
+}
+
+/// A field's data, written to a temporary file which is removed when this is dropped.
+#[derive(Debug)]
+pub struct SpooledFile {
+    path: Option<PathBuf>,
+    size: u64,
+    file_name: Option<String>,
+    content_type: Option<String>,
+}
+
+impl SpooledFile {
+    /// The temporary file's path.
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap_or_else(|| Path::new(""))
+    }
+
+    /// The size of the file, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The uploaded file's name, as given by the client. See [`Field::file_name`].
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// The field's `Content-Type`, if it had one.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Move the file to `to`, so that it is kept.
+    pub async fn persist(mut self, to: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let to = to.as_ref().to_path_buf();
+        let from = self.path.take().unwrap_or_default();
+        if fs::rename(&from, &to).await.is_err() {
+            // Renaming fails across filesystems.
+            let copied = fs::copy(&from, &to).await;
+            fs::remove_file(&from).await.ok();
+            copied?;
+        }
+        Ok(to)
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// The index of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The `key=value` parameters of a `Content-Disposition`, with quotes removed.
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = value.split_once(';').map(|(_, rest)| rest).unwrap_or("");
+
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().to_string();
+        let after = after.trim_start();
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                let remaining = &quoted[end..];
+                (
+                    value,
+                    remaining.split_once(';').map(|(_, r)| r).unwrap_or(""),
+                )
+            }
+            None => match after.split_once(';') {
+                Some((value, remaining)) => (value.trim().to_string(), remaining),
+                None => (after.trim().to_string(), ""),
+            },
+        };
+        params.push((key, value));
+        rest = remaining;
+    }
+
+    params
+}
+
+fn malformed(message: impl Into<String>) -> tide::Error {
+    tide::Error::from_str(StatusCode::BadRequest, message.into())
+}
+
+fn too_large(message: String) -> tide::Error {
+    tide::Error::from_str(StatusCode::PayloadTooLarge, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    const BODY: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"caption\"\r\n\
+        \r\n\
+        A \"quoted\"\r\ncaption\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        \r\n\
+        --XyZ is not a delimiter without a line break\r\n\
+        --XyZ--\r\n";
+
+    fn setup_routes(mut server: Route<'_, Arc<()>>) {
+        server
+            .at("upload")
+            .post(|mut req: Request<Arc<()>>| async move {
+                let mut multipart = req.multipart()?.max_field_size(64);
+                let mut summary = Vec::new();
+                while let Some(mut field) = multipart.next_field().await? {
+                    if field.file_name().is_some() {
+                        field.ensure_content_type(&["text/plain"])?;
+                        let file = field.spool().await?;
+                        let text = fs::read_to_string(file.path()).await?;
+                        summary.push(format!("{:?}: {}", file.file_name(), text));
+                    } else {
+                        let name = field.name().to_string();
+                        summary.push(format!("{}: {}", name, field.text().await?));
+                    }
+                }
+                Ok(summary.join("\n"))
+            });
+    }
+
+    #[async_std::test]
+    async fn reads_multipart_fields() -> TestResult<()> {
+        let client = test_utils::create_client((), setup_routes).await?;
+
+        let mut res = client
+            .post("/api/v1/upload")
+            .content_type("multipart/form-data; boundary=XyZ")
+            .body(BODY)
+            .await?;
+        assert_eq!(
+            assert_status(&mut res, 200).await,
+            "caption: A \"quoted\"\r\ncaption\n\
+             Some(\"a \\\"b\\\".txt\"): --XyZ is not a delimiter without a line break"
+        );
+
+        let res = client
+            .post("/api/v1/upload")
+            .content_type("multipart/form-data; boundary=XyZ")
+            .body(BODY.replace("caption\r\n--", &format!("{}\r\n--", "x".repeat(100))))
+            .await?;
+        assert_eq!(res.status(), 413);
+
+        let res = client
+            .post("/api/v1/upload")
+            .content_type("multipart/form-data; boundary=XyZ")
+            .body(BODY.replace("text/plain", "image/png"))
+            .await?;
+        assert_eq!(res.status(), 415);
+
+        let res = client
+            .post("/api/v1/upload")
+            .content_type("multipart/form-data; boundary=XyZ")
+            .body(BODY.replace("--XyZ--", "--Abc--"))
+            .await?;
+        assert_eq!(res.status(), 400);
+
+        let res = client.post("/api/v1/upload").body("{}").await?;
+        assert_eq!(res.status(), 415);
+        Ok(())
+    }
+}
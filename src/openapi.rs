@@ -0,0 +1,682 @@
+//! OpenAPI 3 specifications, generated from the routes as they are mounted.
+//!
+//! Routes mounted via [`OpenApiRouteExt::documented`][crate::prelude::OpenApiRouteExt::documented] register an
+//! [`Operation`] describing them at the same time, so the specification always matches the mounted routes, including
+//! their path parameters. With [`Hooks::openapi`][crate::Hooks::openapi], the specification is served at
+//! `/openapi.json`.
+//!
+//! Outside of production, interactive docs for the specification are also served at `/docs`, via
+//! [Swagger UI](https://swagger.io/tools/swagger-ui/) or [Redoc](https://redocly.com/redoc/), as set by
+//! [`OpenApi::docs_ui`]. Their assets are loaded from a CDN, so need no setup. With [`OpenApi::docs_admin_auth`],
+//! the docs and the specification require the same credentials as the built-in `/admin` routes.
+//!
+//! Request and response bodies are described by their [`Schema`]. Every operation also has a `default` response of
+//! [`JsonError`][crate::JsonError], which is included in the specification's components.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::openapi::{Operation, Schema};
+//! use preroll::prelude::*;
+//! use serde::{Deserialize, Serialize};
+//! use serde_json::{json, Value};
+//! use tide::http::Method;
+//! use tide::{Request, Route};
+//!
+//! #[derive(Deserialize, Serialize)]
+//! struct User {
+//!     id: u64,
+//!     name: String,
+//! }
+//!
+//! impl Schema for User {
+//!     fn schema() -> Value {
+//!         json!({
+//!             "type": "object",
+//!             "required": ["id", "name"],
+//!             "properties": {
+//!                 "id": u64::schema(),
+//!                 "name": String::schema(),
+//!             },
+//!         })
+//!     }
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn get_user(_req: Request<Arc<()>>) -> tide::Result {
+//!     Ok(tide::Body::from_json(&User { id: 7, name: "Ada".to_string() })?.into())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     // Documented as `GET /api/v1/users/{id}`, with an `id` path parameter.
+//!     server.at("users/:id").documented(
+//!         Method::Get,
+//!         Operation::new("Get a user")
+//!             .tag("users")
+//!             .response::<User>(200, "The user"),
+//!         get_user,
+//!     );
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+use tide::http::{mime, Method};
+use tide::{Body, Endpoint, Response, Route, Server, StatusCode};
+
+use crate::builtins::admin::{AdminAuth, AdminAuthMiddleware};
+use crate::context::WithMatchedRoute;
+use crate::pagination::Page;
+use crate::{Environment, JsonError};
+
+/// The documented operations, by path and then by lowercase method.
+static OPERATIONS: Lazy<Mutex<BTreeMap<String, BTreeMap<String, Operation>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The description of the API as a whole, for [`Hooks::openapi`][crate::Hooks::openapi].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenApi {
+    title: String,
+    version: String,
+    description: Option<String>,
+    docs_path: String,
+    docs_ui: DocsUi,
+    docs_admin_auth: bool,
+}
+
+/// The interactive docs served for an [`OpenApi`] specification outside of production.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum DocsUi {
+    /// [Swagger UI](https://swagger.io/tools/swagger-ui/), which can send requests to the API.
+    #[default]
+    SwaggerUi,
+    /// [Redoc](https://redocly.com/redoc/), which is read-only.
+    Redoc,
+    /// No docs, only the specification.
+    None,
+}
+
+impl OpenApi {
+    /// An API called `title`, at `version`, e.g. `"1.4.0"`.
+    pub fn new(title: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            version: version.into(),
+            description: None,
+            docs_path: "/docs".to_string(),
+            docs_ui: DocsUi::default(),
+            docs_admin_auth: false,
+        }
+    }
+
+    /// Serve the interactive docs at `path`, rather than at `/docs`.
+    #[must_use]
+    pub fn docs_path(mut self, path: impl Into<String>) -> Self {
+        self.docs_path = path.into();
+        self
+    }
+
+    /// Serve `ui` as the interactive docs, rather than [`DocsUi::SwaggerUi`].
+    #[must_use]
+    pub fn docs_ui(mut self, ui: DocsUi) -> Self {
+        self.docs_ui = ui;
+        self
+    }
+
+    /// Require the `/admin` credentials, from `ADMIN_TOKEN`, or `ADMIN_USERNAME` and `ADMIN_PASSWORD`, for the docs and
+    /// the specification.
+    ///
+    /// Browsers only send these along with the docs' own requests for the specification when they are basic auth.
+    /// Without any credentials set, neither the docs nor the specification are served.
+    #[must_use]
+    pub fn docs_admin_auth(mut self) -> Self {
+        self.docs_admin_auth = true;
+        self
+    }
+
+    /// Describe the API, in CommonMark.
+    #[must_use]
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// The specification of every documented route, as OpenAPI 3 JSON.
+    pub fn spec(&self) -> Value {
+        let mut info = json!({ "title": self.title, "version": self.version });
+        if let Some(description) = &self.description {
+            info["description"] = json!(description);
+        }
+
+        let operations = OPERATIONS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let paths: Map<String, Value> = operations
+            .iter()
+            .map(|(path, methods)| {
+                let methods: Map<String, Value> = methods
+                    .iter()
+                    .map(|(method, operation)| (method.clone(), operation.spec(path)))
+                    .collect();
+                (path.clone(), Value::Object(methods))
+            })
+            .collect();
+
+        json!({
+            "openapi": "3.0.3",
+            "info": info,
+            "paths": paths,
+            "components": {
+                "schemas": {
+                    "JsonError": json_error_schema(),
+                },
+            },
+        })
+    }
+}
+
+/// The description of a route, as registered via
+/// [`OpenApiRouteExt::documented`][crate::prelude::OpenApiRouteExt::documented].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Operation {
+    summary: String,
+    description: Option<String>,
+    operation_id: Option<String>,
+    tags: Vec<String>,
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+    responses: BTreeMap<u16, Value>,
+}
+
+impl Operation {
+    /// An operation which does what `summary` says, e.g. `"Get a user"`.
+    pub fn new(summary: impl Into<String>) -> Self {
+        Self {
+            summary: summary.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Describe the operation at more length, in CommonMark.
+    #[must_use]
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// A unique id for the operation, e.g. `"getUser"`, as used by client generators.
+    #[must_use]
+    pub fn operation_id(mut self, id: impl Into<String>) -> Self {
+        self.operation_id = Some(id.into());
+        self
+    }
+
+    /// Group the operation under `tag`, e.g. `"users"`.
+    #[must_use]
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// An optional query parameter of type `T`.
+    #[must_use]
+    pub fn query_param<T: Schema>(mut self, name: &str, description: &str) -> Self {
+        self.parameters.push(json!({
+            "name": name,
+            "in": "query",
+            "description": description,
+            "schema": T::schema(),
+        }));
+        self
+    }
+
+    /// A path parameter of type `T`. Path parameters which are not described are documented as strings.
+    #[must_use]
+    pub fn path_param<T: Schema>(mut self, name: &str, description: &str) -> Self {
+        self.parameters.push(json!({
+            "name": name,
+            "in": "path",
+            "required": true,
+            "description": description,
+            "schema": T::schema(),
+        }));
+        self
+    }
+
+    /// A JSON request body of type `T`.
+    #[must_use]
+    pub fn request_body<T: Schema>(mut self) -> Self {
+        self.request_body = Some(json!({
+            "required": true,
+            "content": { "application/json": { "schema": T::schema() } },
+        }));
+        self
+    }
+
+    /// A JSON response of type `T`, with `status`.
+    #[must_use]
+    pub fn response<T: Schema>(mut self, status: u16, description: &str) -> Self {
+        self.responses.insert(
+            status,
+            json!({
+                "description": description,
+                "content": { "application/json": { "schema": T::schema() } },
+            }),
+        );
+        self
+    }
+
+    /// A response without a body, with `status`, e.g. `204 No Content`.
+    #[must_use]
+    pub fn empty_response(mut self, status: u16, description: &str) -> Self {
+        self.responses
+            .insert(status, json!({ "description": description }));
+        self
+    }
+
+    /// The specification of the operation at `path`, e.g. `/api/v1/users/{id}`.
+    fn spec(&self, path: &str) -> Value {
+        let mut operation = json!({ "summary": self.summary });
+        if let Some(description) = &self.description {
+            operation["description"] = json!(description);
+        }
+        if let Some(id) = &self.operation_id {
+            operation["operationId"] = json!(id);
+        }
+        if !self.tags.is_empty() {
+            operation["tags"] = json!(self.tags);
+        }
+
+        let mut parameters = self.parameters.clone();
+        for name in path_params(path) {
+            let described = self
+                .parameters
+                .iter()
+                .any(|param| param["in"] == "path" && param["name"] == name);
+            if !described {
+                parameters.push(json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": String::schema(),
+                }));
+            }
+        }
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+
+        if let Some(body) = &self.request_body {
+            operation["requestBody"] = body.clone();
+        }
+
+        let mut responses: Map<String, Value> = self
+            .responses
+            .iter()
+            .map(|(status, response)| (status.to_string(), response.clone()))
+            .collect();
+        if responses.is_empty() {
+            responses.insert("200".to_string(), json!({ "description": "OK" }));
+        }
+        responses.insert(
+            "default".to_string(),
+            json!({
+                "description": "An error",
+                "content": { "application/json": { "schema": JsonError::schema() } },
+            }),
+        );
+        operation["responses"] = Value::Object(responses);
+
+        operation
+    }
+}
+
+/// Extension methods for mounting documented routes.
+pub trait OpenApiRouteExt<State: Clone + Send + Sync + 'static> {
+    /// Mount `endpoint` for `method`, and register `operation` as its description in the OpenAPI specification.
+    fn documented(
+        &mut self,
+        method: Method,
+        operation: Operation,
+        endpoint: impl Endpoint<State>,
+    ) -> &mut Self;
+}
+
+impl<'a, State> OpenApiRouteExt<State> for Route<'a, State>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    fn documented(
+        &mut self,
+        method: Method,
+        operation: Operation,
+        endpoint: impl Endpoint<State>,
+    ) -> &mut Self {
+        OPERATIONS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(openapi_path(self.path()))
+            .or_default()
+            .insert(method.to_string().to_lowercase(), operation);
+        let route = Arc::from(self.path());
+        self.method(method, WithMatchedRoute { route, endpoint })
+    }
+}
+
+/// The JSON Schema of a type, for describing request and response bodies.
+///
+/// Implemented for common types, and for types of your own by returning e.g.
+/// `json!({ "type": "object", "properties": { ... } })`.
+pub trait Schema {
+    /// The JSON Schema of the type, as of OpenAPI 3.0.
+    fn schema() -> Value;
+}
+
+macro_rules! impl_schema {
+    ($($ty:ty => $schema:expr,)*) => {$(
+        impl Schema for $ty {
+            fn schema() -> Value {
+                $schema
+            }
+        }
+    )*};
+}
+
+impl_schema! {
+    String => json!({ "type": "string" }),
+    &str => json!({ "type": "string" }),
+    bool => json!({ "type": "boolean" }),
+    i32 => json!({ "type": "integer", "format": "int32" }),
+    i64 => json!({ "type": "integer", "format": "int64" }),
+    u32 => json!({ "type": "integer", "format": "int32", "minimum": 0 }),
+    u64 => json!({ "type": "integer", "format": "int64", "minimum": 0 }),
+    f32 => json!({ "type": "number", "format": "float" }),
+    f64 => json!({ "type": "number", "format": "double" }),
+    uuid::Uuid => json!({ "type": "string", "format": "uuid" }),
+    chrono::DateTime<chrono::Utc> => json!({ "type": "string", "format": "date-time" }),
+    Value => json!({}),
+    JsonError => json!({ "$ref": "#/components/schemas/JsonError" }),
+}
+
+impl<T: Schema> Schema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: Schema> Schema for Option<T> {
+    fn schema() -> Value {
+        let mut schema = T::schema();
+        if let Value::Object(schema) = &mut schema {
+            schema.insert("nullable".to_string(), json!(true));
+        }
+        schema
+    }
+}
+
+impl<T: Schema> Schema for Page<T> {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["items", "limit"],
+            "properties": {
+                "items": Vec::<T>::schema(),
+                "limit": u32::schema(),
+                "offset": u64::schema(),
+                "total": u64::schema(),
+                "next_cursor": String::schema(),
+            },
+        })
+    }
+}
+
+/// The schema of [`JsonError`], as included in the specification's components.
+fn json_error_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["status", "title", "message"],
+        "properties": {
+            "status": { "type": "integer" },
+            "title": String::schema(),
+            "message": String::schema(),
+            "code": Option::<String>::schema(),
+            "docs_url": String::schema(),
+            "details": Value::schema(),
+            "fields": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["path", "message", "code"],
+                    "properties": {
+                        "path": String::schema(),
+                        "message": String::schema(),
+                        "code": String::schema(),
+                    },
+                },
+            },
+            "retry_after": u64::schema(),
+            "request_id": Option::<String>::schema(),
+            "correlation_id": Option::<String>::schema(),
+        },
+    })
+}
+
+/// The OpenAPI form of a Tide path, e.g. `/api/v1/users/{id}` for `/api/v1/users/:id`.
+fn openapi_path(path: &str) -> String {
+    let segments: Vec<String> = path
+        .trim_matches('/')
+        .split('/')
+        .map(
+            |segment| match segment.strip_prefix(|c| c == ':' || c == '*') {
+                Some(name) if !name.is_empty() => format!("{{{}}}", name),
+                _ => segment.to_string(),
+            },
+        )
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// The names of the parameters of an OpenAPI path.
+fn path_params(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter_map(|segment| {
+        segment
+            .strip_prefix('{')
+            .and_then(|segment| segment.strip_suffix('}'))
+    })
+}
+
+/// Serve the specification of `openapi` at `/openapi.json`, and its docs outside of production, behind `admin_auth`
+/// if it requires it.
+pub(crate) fn setup_openapi<State>(
+    server: &mut Server<Arc<State>>,
+    openapi: OpenApi,
+    admin_auth: Option<AdminAuth>,
+) where
+    State: Send + Sync + 'static,
+{
+    let auth = match (openapi.docs_admin_auth, admin_auth) {
+        (false, _) => None,
+        (true, Some(auth)) => Some(AdminAuthMiddleware::new(auth)),
+        (true, None) => {
+            log::warn!(
+                "OpenAPI docs require admin auth, and are not served. Set ADMIN_TOKEN, or ADMIN_USERNAME and ADMIN_PASSWORD, to enable them."
+            );
+            return;
+        }
+    };
+    let openapi = Arc::new(openapi);
+
+    let mut spec_route = server.at("/openapi.json");
+    if let Some(auth) = auth.clone() {
+        spec_route.with(auth);
+    }
+    let spec_openapi = openapi.clone();
+    spec_route.get(move |_| {
+        let openapi = spec_openapi.clone();
+        async move { Body::from_json(&openapi.spec()) }
+    });
+
+    if Environment::current().is_production() {
+        return;
+    }
+    let html = match docs_html(&openapi) {
+        Some(html) => html,
+        None => return,
+    };
+    let mut docs_route = server.at(&openapi.docs_path);
+    if let Some(auth) = auth {
+        docs_route.with(auth);
+    }
+    docs_route.get(move |_| {
+        let html = html.clone();
+        async move {
+            Ok(Response::builder(StatusCode::Ok)
+                .body(html)
+                .content_type(mime::HTML)
+                .build())
+        }
+    });
+}
+
+/// The page of the interactive docs, if there are any.
+fn docs_html(openapi: &OpenApi) -> Option<String> {
+    let title = escape_html(&openapi.title);
+    let page = match openapi.docs_ui {
+        DocsUi::SwaggerUi => format!(
+            r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{}</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({{ url: "/openapi.json", dom_id: "#swagger-ui" }});</script>
+</body>
+</html>
+"##,
+            title
+        ),
+        DocsUi::Redoc => format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{}</title>
+</head>
+<body>
+<redoc spec-url="/openapi.json"></redoc>
+<script src="https://cdn.redoc.ly/redoc/latest/bundles/redoc.standalone.js"></script>
+</body>
+</html>
+"#,
+            title
+        ),
+        DocsUi::None => return None,
+    };
+    Some(page)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::Request;
+
+    use super::*;
+    use crate::test_utils::{self, TestClientOptions, TestResult};
+
+    #[test]
+    fn converts_paths() {
+        assert_eq!(openapi_path("/api/v1/users/:id"), "/api/v1/users/{id}");
+        assert_eq!(openapi_path("files/*path"), "/files/{path}");
+        assert_eq!(
+            path_params("/orgs/{org_id}/users/{id}").collect::<Vec<_>>(),
+            ["org_id", "id"]
+        );
+    }
+
+    #[async_std::test]
+    async fn serves_documented_routes() -> TestResult<()> {
+        let openapi = OpenApi::new("Test & Co", "1.0.0")
+            .docs_path("/api-docs")
+            .docs_ui(DocsUi::Redoc);
+        let options = TestClientOptions::new().openapi(openapi);
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: Route<'_, Arc<()>>| {
+                server.at("widgets/:id").documented(
+                    Method::Get,
+                    Operation::new("Get a widget")
+                        .tag("widgets")
+                        .path_param::<u64>("id", "The widget's id")
+                        .response::<Vec<String>>(200, "The widget's parts"),
+                    |_: Request<Arc<()>>| async { Ok("[]") },
+                );
+                server.at("widgets/:id/parts/:part").documented(
+                    Method::Delete,
+                    Operation::new("Remove a part").empty_response(204, "Removed"),
+                    |_: Request<Arc<()>>| async { Ok("") },
+                );
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.get("/api/v1/widgets/7").await?;
+        assert_eq!(res.body_string().await?, "[]");
+
+        let spec: Value = client.get("/openapi.json").recv_json().await?;
+        assert_eq!(spec["info"]["title"], "Test & Co");
+        assert!(spec["components"]["schemas"]["JsonError"].is_object());
+
+        let get = &spec["paths"]["/api/v1/widgets/{id}"]["get"];
+        assert_eq!(get["summary"], "Get a widget");
+        assert_eq!(get["parameters"][0]["schema"]["format"], "int64");
+        assert_eq!(
+            get["responses"]["200"]["content"]["application/json"]["schema"]["type"],
+            "array"
+        );
+        assert_eq!(
+            get["responses"]["default"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/JsonError"
+        );
+
+        let delete = &spec["paths"]["/api/v1/widgets/{id}/parts/{part}"]["delete"];
+        let names: Vec<&Value> = delete["parameters"]
+            .as_array()
+            .map(|params| params.iter().map(|param| &param["name"]).collect())
+            .unwrap_or_default();
+        assert_eq!(names, [&json!("id"), &json!("part")]);
+        assert_eq!(delete["responses"]["204"]["description"], "Removed");
+
+        let mut res = client.get("/api-docs").await?;
+        assert_eq!(
+            res.content_type()
+                .map(|mime| mime.essence().to_string())
+                .as_deref(),
+            Some("text/html")
+        );
+        let html = res.body_string().await?;
+        assert!(html.contains("<title>Test &amp; Co</title>"));
+        assert!(html.contains(r#"<redoc spec-url="/openapi.json">"#));
+        assert_eq!(client.get("/docs").await?.status(), 404);
+        Ok(())
+    }
+}
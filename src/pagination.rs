@@ -0,0 +1,301 @@
+//! Paginated list responses, with paging metadata and RFC 5988 `Link` headers.
+//!
+//! A [`Pagination`] is read from the `limit` and either the `offset` or the `cursor` query parameters, via
+//! [`req.pagination()`][crate::prelude::ExtractRequestExt::pagination]:
+//! - The `limit` defaults to [`DEFAULT_PAGE_SIZE`], and may be at most [`MAX_PAGE_SIZE`], or else is a `400 Bad Request`
+//!   error with the code `"invalid_query_parameter"`.
+//! - An `offset` and a `cursor` may not be combined.
+//!
+//! A [`Page`] of items is then responded with as JSON, along with `Link` headers to the `next`, `prev`, `first`, and
+//! `last` pages as they are known. The links are relative to the host, and keep any other query parameters.
+//!
+//! ## Example:
+//!
+//! ```
+//! use preroll::pagination::Page;
+//! use preroll::prelude::*;
+//! use serde::Serialize;
+//! use tide::Request;
+//!
+//! #[derive(Serialize)]
+//! struct User {
+//!     id: u64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn list_users(req: Request<()>) -> tide::Result {
+//!     // E.g. `?limit=20&offset=40`.
+//!     let pagination = req.pagination()?;
+//!     let total = 95;
+//!     let users = (pagination.offset..total.min(pagination.offset + pagination.limit as u64))
+//!         .map(|id| User { id })
+//!         .collect();
+//!
+//!     // `{"items": [...], "limit": 20, "offset": 40, "total": 95}`, with a `Link` header like
+//!     // `</api/v1/users?limit=20&offset=60>; rel="next", </api/v1/users?limit=20&offset=20>; rel="prev", ...`.
+//!     Page::with_offset(users, &pagination, Some(total)).into_response(&req)
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use tide::http::url::{Position, Url};
+use tide::{Request, Response, StatusCode};
+
+use crate::buffers::json_body;
+use crate::prelude::ExtractRequestExt;
+use crate::Error;
+
+/// The number of items in a page, unless the `limit` query parameter says otherwise.
+pub const DEFAULT_PAGE_SIZE: u32 = 20;
+
+/// The most items in a page, by default.
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// Which page of items was requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pagination {
+    /// The most items to respond with.
+    pub limit: u32,
+    /// How many items to skip. Always `0` when paging by cursor.
+    pub offset: u64,
+    /// Where the previous page left off, when paging by cursor.
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PaginationParams {
+    limit: Option<u32>,
+    offset: Option<u64>,
+    cursor: Option<String>,
+}
+
+impl Pagination {
+    /// The pagination of a request, with up to `max_limit` items in a page.
+    pub(crate) fn from_request<State>(
+        req: &Request<State>,
+        default_limit: u32,
+        max_limit: u32,
+    ) -> tide::Result<Self>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let params: PaginationParams = req.query_typed()?;
+
+        let limit = params.limit.unwrap_or(default_limit);
+        if limit == 0 || limit > max_limit {
+            return Err(Error::with_code(
+                "invalid_query_parameter",
+                StatusCode::BadRequest,
+                format!(
+                    "Invalid query parameter \"limit\": must be between 1 and {}",
+                    max_limit
+                ),
+            ));
+        }
+        if params.offset.is_some() && params.cursor.is_some() {
+            return Err(Error::with_code(
+                "invalid_query_parameter",
+                StatusCode::BadRequest,
+                "Invalid query parameter \"offset\": cannot be combined with \"cursor\"",
+            ));
+        }
+
+        Ok(Self {
+            limit,
+            offset: params.offset.unwrap_or_default(),
+            cursor: params.cursor,
+        })
+    }
+}
+
+/// A page of items, with its paging metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Page<T> {
+    /// The items in the page.
+    pub items: Vec<T>,
+    /// The most items in a page.
+    pub limit: u32,
+    /// How many items were skipped, when paging by offset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// How many items there are in all, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    /// Where the next page starts, when paging by cursor and there are more items.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T: Serialize> Page<T> {
+    /// A page of items when paging by offset, with the `total` number of items if known.
+    ///
+    /// Without a total, there is assumed to be a next page whenever this one is full.
+    pub fn with_offset(items: Vec<T>, pagination: &Pagination, total: Option<u64>) -> Self {
+        Self {
+            items,
+            limit: pagination.limit,
+            offset: Some(pagination.offset),
+            total,
+            next_cursor: None,
+        }
+    }
+
+    /// A page of items when paging by cursor, with the cursor of the next page if there are more items.
+    pub fn with_cursor(
+        items: Vec<T>,
+        pagination: &Pagination,
+        next_cursor: Option<String>,
+    ) -> Self {
+        Self {
+            items,
+            limit: pagination.limit,
+            offset: None,
+            total: None,
+            next_cursor,
+        }
+    }
+
+    /// A JSON response of the page, with `Link` headers relative to the request's URL.
+    pub fn into_response<State>(self, req: &Request<State>) -> tide::Result {
+        let links = self.links(req.url());
+
+        let mut res = Response::new(StatusCode::Ok);
+        res.set_body(json_body(&self)?);
+        if !links.is_empty() {
+            res.insert_header("Link", links.join(", "));
+        }
+        Ok(res)
+    }
+
+    fn links(&self, url: &Url) -> Vec<String> {
+        let limit = self.limit.to_string();
+        let link = |rel: &str, param: (&str, String)| {
+            let mut url = url.clone();
+            let pairs: Vec<(String, String)> = url
+                .query_pairs()
+                .filter(|(key, _)| !matches!(key.as_ref(), "limit" | "offset" | "cursor"))
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect();
+            url.query_pairs_mut()
+                .clear()
+                .extend_pairs(pairs)
+                .append_pair("limit", &limit)
+                .append_pair(param.0, &param.1);
+            format!("<{}>; rel=\"{}\"", &url[Position::BeforePath..], rel)
+        };
+
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => {
+                return self
+                    .next_cursor
+                    .iter()
+                    .map(|cursor| link("next", ("cursor", cursor.clone())))
+                    .collect();
+            }
+        };
+
+        let limit = u64::from(self.limit);
+        let end = offset + self.items.len() as u64;
+        let has_next = match self.total {
+            Some(total) => end < total,
+            None => self.items.len() as u64 >= limit,
+        };
+
+        let mut links = Vec::new();
+        if has_next {
+            links.push(link("next", ("offset", end.to_string())));
+        }
+        if offset > 0 {
+            let prev = offset.saturating_sub(limit);
+            links.push(link("prev", ("offset", prev.to_string())));
+            links.push(link("first", ("offset", "0".to_string())));
+        }
+        if let Some(total) = self.total.filter(|total| *total > 0) {
+            let last = (total - 1) / limit * limit;
+            links.push(link("last", ("offset", last.to_string())));
+        }
+        links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, TestResult};
+    use crate::JsonError;
+
+    #[async_std::test]
+    async fn pages_with_links() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: tide::Route<'_, Arc<()>>| {
+            server
+                .at("numbers")
+                .get(|req: Request<Arc<()>>| async move {
+                    let pagination = req.pagination_with(10, 50)?;
+                    let total = 25;
+                    match pagination.cursor.as_deref() {
+                        Some(cursor) => {
+                            let start: u64 = cursor.parse()?;
+                            let end = total.min(start + pagination.limit as u64);
+                            let next = Some(end.to_string()).filter(|_| end < total);
+                            Page::with_cursor((start..end).collect(), &pagination, next)
+                                .into_response(&req)
+                        }
+                        None => {
+                            let start = pagination.offset;
+                            let end = total.min(start + pagination.limit as u64);
+                            Page::with_offset((start..end).collect(), &pagination, Some(total))
+                                .into_response(&req)
+                        }
+                    }
+                });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/numbers?offset=10&q=a%20b").await?;
+        assert_eq!(
+            res.header("Link").map(|links| links.as_str()),
+            Some(concat!(
+                r#"</api/v1/numbers?q=a+b&limit=10&offset=20>; rel="next", "#,
+                r#"</api/v1/numbers?q=a+b&limit=10&offset=0>; rel="prev", "#,
+                r#"</api/v1/numbers?q=a+b&limit=10&offset=0>; rel="first", "#,
+                r#"</api/v1/numbers?q=a+b&limit=10&offset=20>; rel="last""#,
+            ))
+        );
+        let page: Page<u64> = res.body_json().await?;
+        assert_eq!(page.items, (10..20).collect::<Vec<_>>());
+        assert_eq!((page.offset, page.total), (Some(10), Some(25)));
+
+        let mut res = client.get("/api/v1/numbers?limit=20&cursor=20").await?;
+        assert!(res.header("Link").is_none());
+        let page: Page<u64> = res.body_json().await?;
+        assert_eq!(page.items, (20..25).collect::<Vec<_>>());
+        assert_eq!(page.next_cursor, None);
+
+        let res = client.get("/api/v1/numbers?limit=20&cursor=0").await?;
+        assert_eq!(
+            res.header("Link").map(|links| links.as_str()),
+            Some(r#"</api/v1/numbers?limit=20&cursor=20>; rel="next""#)
+        );
+
+        for query in ["limit=51", "limit=0", "offset=1&cursor=1"] {
+            let mut res = client.get(format!("/api/v1/numbers?{}", query)).await?;
+            let error: JsonError = res.body_json().await?;
+            assert_eq!(error.status, 400, "{}", query);
+            assert_eq!(
+                error.code.as_deref(),
+                Some("invalid_query_parameter"),
+                "{}",
+                query
+            );
+        }
+
+        let mut res = client.get("/api/v1/numbers").await?;
+        assert_eq!(res.status(), 200);
+        let page: Page<u64> = res.body_json().await?;
+        assert_eq!((page.limit, page.items.len()), (10, 10));
+        Ok(())
+    }
+}
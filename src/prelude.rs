@@ -1,5 +1,23 @@
 //! Auto-import of all preroll extension traits.
 
+pub use crate::auth::GuardRouteExt;
+pub use crate::builtins::static_files::StaticFilesExt;
+pub use crate::cache::CacheRouteExt;
+pub use crate::context::ContextRequestExt;
+pub use crate::csp::CspRequestExt;
+pub use crate::environment::EnvironmentRequestExt;
+pub use crate::extract::ExtractRequestExt;
+pub use crate::flags::FlagsRequestExt;
+pub use crate::multipart::MultipartRequestExt;
+pub use crate::openapi::OpenApiRouteExt;
+pub use crate::proxy::ProxyRouteExt;
+pub use crate::route_group::RouteGroupExt;
+pub use crate::services::ServiceClientRequestExt;
+
 #[cfg(feature = "postgres")]
 #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
 pub use crate::middleware::postgres::PostgresRequestExt;
+
+#[cfg(feature = "websockets")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "websockets")))]
+pub use crate::websocket::WebSocketRouteExt;
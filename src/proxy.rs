@@ -0,0 +1,269 @@
+//! Reverse proxying of routes to another service, via [`ProxyRouteExt::proxy`][crate::prelude::ProxyRouteExt::proxy],
+//! e.g. to migrate a legacy service's routes over one at a time.
+//!
+//! Requests are forwarded to the same path below the upstream url, with their method, query, headers, and body.
+//! Bodies are streamed both ways, rather than buffered.
+//!
+//! - The `Host` header is the upstream's, and the original is kept in `X-Forwarded-Host`. The client's IP is added to
+//!   `X-Forwarded-For`, and the scheme is set in `X-Forwarded-Proto`.
+//! - Hop-by-hop headers, such as `Connection` and `Transfer-Encoding`, are not forwarded in either direction.
+//! - Upstream requests carry the `X-Request-Id` of the original request, and are logged and counted like those of a
+//!   [`preroll::client`][crate::client].
+//! - Upstream responses are returned as they are, even errors. Requests which get no response are answered with a
+//!   `502 Bad Gateway`, or a `504 Gateway Timeout` after `CLIENT_TIMEOUT` seconds, as JSON errors.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use tide::Route;
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server.at("users").get(|_| async { Ok("[]") });
+//!
+//!     // Everything else under /api/v1/legacy is still served by the old service, e.g. /api/v1/legacy/orders/7 by
+//!     // http://old-service.internal/api/v1/legacy/orders/7.
+//!     server.at("legacy/*").proxy("http://old-service.internal/");
+//! }
+//! ```
+
+use std::time::Duration;
+
+use async_std::future::{timeout, TimeoutError};
+use surf::{Client, Config, Url};
+use tide::http::headers::{
+    HeaderName, HeaderValue, CONNECTION, HOST, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+};
+use tide::http::{self, Headers};
+use tide::{Endpoint, Request, Response, Route, StatusCode};
+
+use crate::client;
+use crate::setup::Result;
+
+/// Headers which only apply to a single connection, so are not forwarded.
+const HOP_BY_HOP: &[&str] = &[
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+/// An extension trait for proxying routes to another service.
+pub trait ProxyRouteExt {
+    /// Forward requests for this route to the same path below `upstream`, as described in the [module docs][self].
+    ///
+    /// Panics if `upstream` is not an absolute url, or if `CLIENT_TIMEOUT` is not a number of seconds.
+    fn proxy(&mut self, upstream: &str) -> &mut Self;
+}
+
+impl<'a, State> ProxyRouteExt for Route<'a, State>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    fn proxy(&mut self, upstream: &str) -> &mut Self {
+        let proxy = Proxy::new(upstream)
+            .unwrap_or_else(|error| panic!("Invalid proxy to \"{}\": {:?}", upstream, error));
+        self.all(proxy)
+    }
+}
+
+/// An endpoint which forwards requests to an upstream service.
+#[derive(Debug, Clone)]
+struct Proxy {
+    client: Client,
+    upstream: Url,
+    timeout: Duration,
+}
+
+impl Proxy {
+    fn new(upstream: &str) -> Result<Self> {
+        Self::with_config(Config::new(), upstream, client::default_timeout()?)
+    }
+
+    fn with_config(config: Config, upstream: &str, timeout: Duration) -> Result<Self> {
+        // Bodies are streamed, so requests cannot be retried.
+        let client = client::with_options(config, upstream, timeout, 0)?;
+        Ok(Self {
+            client,
+            upstream: Url::parse(upstream)?,
+            timeout,
+        })
+    }
+
+    /// The upstream request for `req`.
+    fn upstream_request<State>(&self, req: Request<State>) -> surf::Request {
+        let client_ip = req.peer_addr().map(|addr| {
+            addr.rsplit_once(':')
+                .map_or(addr, |(ip, _)| ip)
+                .trim_matches(|c| c == '[' || c == ']')
+                .to_string()
+        });
+        let mut req: http::Request = req.into();
+
+        let mut url = self.upstream.clone();
+        url.set_path(&format!(
+            "{}{}",
+            self.upstream.path().trim_end_matches('/'),
+            req.url().path()
+        ));
+        url.set_query(req.url().query());
+
+        let forwarded_for = match (req.header("X-Forwarded-For"), client_ip) {
+            (Some(existing), Some(ip)) => Some(format!("{}, {}", existing.last(), ip)),
+            (Some(existing), None) => Some(existing.last().to_string()),
+            (None, ip) => ip,
+        };
+        if let Some(forwarded_for) = forwarded_for {
+            req.insert_header("X-Forwarded-For", forwarded_for);
+        }
+        if req.header("X-Forwarded-Host").is_none() {
+            if let Some(host) = req.header(HOST).map(|host| host.last().to_string()) {
+                req.insert_header("X-Forwarded-Host", host);
+            }
+        }
+        if req.header("X-Forwarded-Proto").is_none() {
+            let scheme = req.url().scheme().to_string();
+            req.insert_header("X-Forwarded-Proto", scheme);
+        }
+        // The upstream's `Host` is set from its url.
+        req.remove_header(HOST);
+        remove_hop_by_hop(&mut req);
+
+        *req.url_mut() = url;
+        req.into()
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State> Endpoint<State> for Proxy
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let upstream_req = self.upstream_request(req);
+        let url = upstream_req.url().clone();
+
+        let result = timeout(self.timeout, self.client.send(upstream_req)).await;
+        let mut res: http::Response = match result {
+            Ok(Ok(res)) => res.into(),
+            Ok(Err(error)) if error.downcast_ref::<TimeoutError>().is_none() => {
+                log::warn!("Proxying to {} failed: {}", url, error);
+                return Err(crate::Error::with_code(
+                    "bad_gateway",
+                    StatusCode::BadGateway,
+                    "The upstream service is unavailable",
+                ));
+            }
+            Ok(Err(_)) | Err(_) => {
+                log::warn!("Proxying to {} timed out after {:?}", url, self.timeout);
+                return Err(crate::Error::with_code(
+                    "gateway_timeout",
+                    StatusCode::GatewayTimeout,
+                    "The upstream service did not respond in time",
+                ));
+            }
+        };
+
+        remove_hop_by_hop(&mut res);
+        Ok(Response::from(res))
+    }
+}
+
+/// Remove hop-by-hop headers, including those named by `Connection`.
+fn remove_hop_by_hop(headers: &mut impl AsMut<Headers>) {
+    let headers = headers.as_mut();
+    let named: Vec<HeaderName> = headers
+        .get(CONNECTION)
+        .map(|values| {
+            values
+                .iter()
+                .flat_map(|value: &HeaderValue| value.as_str().split(','))
+                .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes().to_vec()).ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for name in named {
+        headers.remove(name);
+    }
+    for name in [CONNECTION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE] {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn proxies_requests() -> TestResult<()> {
+        let mut upstream = tide::new();
+        upstream.at("*").all(|mut req: Request<()>| async move {
+            if req.url().path().ends_with("/slow") {
+                async_std::task::sleep(Duration::from_secs(1)).await;
+            }
+            let header = |name| {
+                req.header(name)
+                    .map(|values| values.last().to_string())
+                    .unwrap_or_default()
+            };
+            let echo = format!(
+                "{} {} {} {} {} {}",
+                req.method(),
+                req.url(),
+                header("X-Forwarded-Host"),
+                header("X-Forwarded-Proto"),
+                header("Keep-Alive"),
+                header("X-Request-Id").len(),
+            );
+            let body = req.body_string().await?;
+            Ok(Response::builder(201)
+                .header("X-Upstream", "old")
+                .header("Keep-Alive", "timeout=5")
+                .body(format!("{} {}", echo, body))
+                .build())
+        });
+        let proxy = Proxy::with_config(
+            Config::new().set_http_client(upstream),
+            "http://old.internal/base/",
+            Duration::from_millis(100),
+        )
+        .map_err(|error| tide::Error::from_str(500, error.to_string()))?;
+
+        let client = test_utils::create_client((), move |mut server: Route<'_, Arc<()>>| {
+            server.at("legacy/*").all(proxy.clone());
+        })
+        .await?;
+
+        let mut res = client
+            .post("/api/v1/legacy/orders?page=2")
+            .header("Host", "api.example.com")
+            .header("Keep-Alive", "timeout=5")
+            .body_string("{}".to_string())
+            .await?;
+        assert_eq!(
+            res.header("X-Upstream").map(|v| v.last().as_str()),
+            Some("old")
+        );
+        assert!(res.header("Keep-Alive").is_none());
+        assert_eq!(
+            assert_status(&mut res, 201).await,
+            "POST http://old.internal/base/api/v1/legacy/orders?page=2 api.example.com http  36 {}"
+        );
+
+        let mut res = client.get("/api/v1/legacy/slow").await?;
+        assert!(assert_status(&mut res, 504)
+            .await
+            .contains("gateway_timeout"));
+        Ok(())
+    }
+}
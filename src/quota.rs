@@ -0,0 +1,678 @@
+//! Usage quotas per tenant or API key, e.g. for plans with a number of requests per month, enforced with
+//! `429 Too Many Requests` errors and reported for billing.
+//!
+//! [`Quotas`] counts the requests and bytes of each key per UTC day or month, via [`Quotas::middleware`], which
+//! derives the key from each request, such as its [`Tenant`][crate::Tenant].
+//!
+//! - Requests for keys over any of their quotas get an [`Error::quota_exceeded_until`][crate::Error::quota_exceeded_until]
+//!   error, with the quota and its usage as details, and a `Retry-After` of when the quota's period ends.
+//! - Bytes are those of request and response bodies of known length, so streamed responses only count their requests.
+//! - Requests are counted before they are handled, so that concurrent requests cannot overshoot a cap, and uncounted
+//!   again if they are rejected, so that refused requests are not billed.
+//! - Quotas with the same period share the usage of that period, which each request is counted in once.
+//! - Usage is kept in memory by default, which is per process. Use [`PostgresStore`] with the `"postgres"` feature, or
+//!   implement [`QuotaStore`], to share it between processes, and keep it across restarts.
+//! - [`Quotas::usage`] and [`Quotas::usage_endpoint`] report the usage of a key in each of its quotas' current
+//!   periods, e.g. for a billing service to collect.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::quota::{Quota, Quotas};
+//! use preroll::Tenant;
+//! use tide::{Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     let quotas = Quotas::new()
+//!         .quota(Quota::daily().requests(10_000))
+//!         .quota(Quota::monthly().bytes(10 * 1024 * 1024 * 1024))
+//!         // Enterprise tenants only have a monthly request quota.
+//!         .quotas_for(|tenant| {
+//!             tenant
+//!                 .starts_with("enterprise-")
+//!                 .then(|| vec![Quota::monthly().requests(10_000_000)])
+//!         });
+//!
+//!     server
+//!         .at("reports")
+//!         .with(quotas.middleware(|req: &Request<Arc<()>>| {
+//!             req.ext::<Tenant>().map(|tenant| tenant.to_string())
+//!         }))
+//!         .get(|_| async { Ok("[]") });
+//!
+//!     // For billing, e.g. `GET /api/v1/usage/acme`, which should only be reachable by the billing service.
+//!     server.at("usage/:tenant").get(quotas.usage_endpoint("tenant"));
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use cfg_if::cfg_if;
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use tide::{Endpoint, Middleware, Next, Request};
+
+use crate::buffers::json_body;
+
+cfg_if! {
+    if #[cfg(feature = "postgres")] {
+        use std::convert::TryFrom;
+
+        use sqlx::postgres::PgPool;
+    }
+}
+
+/// The period of a [`Quota`], in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Period {
+    /// Each day, from midnight.
+    Daily,
+    /// Each month, from midnight on the first.
+    Monthly,
+}
+
+impl Period {
+    /// The lowercase name of the period, e.g. `"daily"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Daily => "daily",
+            Self::Monthly => "monthly",
+        }
+    }
+
+    /// The start and end of the period containing `time`.
+    pub fn bounds(self, time: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = time.date_naive();
+        let (start, end) = match self {
+            Self::Daily => (today, today.succ_opt()),
+            Self::Monthly => {
+                let start = today.with_day(1).unwrap_or(today);
+                (start, start.checked_add_months(Months::new(1)))
+            }
+        };
+        let midnight = |date: NaiveDate| date.and_time(NaiveTime::MIN).and_utc();
+        (midnight(start), midnight(end.unwrap_or(NaiveDate::MAX)))
+    }
+}
+
+/// A cap on the requests and bytes of a key in each [`Period`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    period: Period,
+    requests: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl Quota {
+    /// A quota for each UTC day, which caps nothing until [`requests`][Self::requests] or [`bytes`][Self::bytes] are set.
+    pub fn daily() -> Self {
+        Self::new(Period::Daily)
+    }
+
+    /// A quota for each UTC month, which caps nothing until [`requests`][Self::requests] or [`bytes`][Self::bytes] are
+    /// set.
+    pub fn monthly() -> Self {
+        Self::new(Period::Monthly)
+    }
+
+    /// A quota for each `period`.
+    pub fn new(period: Period) -> Self {
+        Self {
+            period,
+            requests: None,
+            bytes: None,
+        }
+    }
+
+    /// The most requests in each period.
+    #[must_use]
+    pub fn requests(mut self, requests: u64) -> Self {
+        self.requests = Some(requests);
+        self
+    }
+
+    /// The most bytes of request and response bodies in each period.
+    #[must_use]
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    fn is_exceeded_by(&self, usage: Usage) -> bool {
+        self.requests.is_some_and(|max| usage.requests >= max)
+            || self.bytes.is_some_and(|max| usage.bytes >= max)
+    }
+}
+
+/// Requests and bytes counted against a quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    /// The number of requests.
+    pub requests: u64,
+    /// The bytes of request and response bodies.
+    pub bytes: u64,
+}
+
+/// The usage of a key in the current period of one of its quotas, as reported by [`Quotas::usage`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct QuotaUsage {
+    /// The period of the quota.
+    pub period: Period,
+    /// When the current period started.
+    pub start: DateTime<Utc>,
+    /// When the current period ends, and its usage is reset.
+    pub end: DateTime<Utc>,
+    /// The usage so far in the current period.
+    pub usage: Usage,
+    /// The most requests in each period, if capped.
+    pub max_requests: Option<u64>,
+    /// The most bytes in each period, if capped.
+    pub max_bytes: Option<u64>,
+}
+
+/// Where [`Quotas`] keep usage, e.g. in memory or in Postgres.
+///
+/// Usage is kept per key, period, and start of the period, so that each period starts from nothing.
+#[tide::utils::async_trait]
+pub trait QuotaStore: Send + Sync + 'static {
+    /// Add `usage` to that of `key` in the `period` starting at `start`, and return the total.
+    async fn add(
+        &self,
+        key: &str,
+        period: Period,
+        start: DateTime<Utc>,
+        usage: Usage,
+    ) -> tide::Result<Usage>;
+
+    /// Subtract `usage` from that of `key` in the `period` starting at `start`, e.g. for a request which was added
+    /// and then rejected, without going below zero.
+    async fn subtract(
+        &self,
+        key: &str,
+        period: Period,
+        start: DateTime<Utc>,
+        usage: Usage,
+    ) -> tide::Result<()>;
+
+    /// The usage of `key` in the `period` starting at `start`.
+    async fn get(&self, key: &str, period: Period, start: DateTime<Utc>) -> tide::Result<Usage>;
+}
+
+/// The start of the current period of each key and period, and the usage so far.
+type UsageByKey = HashMap<(String, Period), (DateTime<Utc>, Usage)>;
+
+/// A [`QuotaStore`] in the memory of this process, which only keeps the current period of each key.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    usage: Mutex<UsageByKey>,
+}
+
+impl MemoryStore {
+    /// An empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn usage(&self) -> std::sync::MutexGuard<'_, UsageByKey> {
+        self.usage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[tide::utils::async_trait]
+impl QuotaStore for MemoryStore {
+    async fn add(
+        &self,
+        key: &str,
+        period: Period,
+        start: DateTime<Utc>,
+        usage: Usage,
+    ) -> tide::Result<Usage> {
+        let mut all = self.usage();
+        let (current_start, total) = all
+            .entry((key.to_string(), period))
+            .or_insert((start, Usage::default()));
+        if *current_start != start {
+            *current_start = start;
+            *total = Usage::default();
+        }
+        total.requests = total.requests.saturating_add(usage.requests);
+        total.bytes = total.bytes.saturating_add(usage.bytes);
+        Ok(*total)
+    }
+
+    async fn subtract(
+        &self,
+        key: &str,
+        period: Period,
+        start: DateTime<Utc>,
+        usage: Usage,
+    ) -> tide::Result<()> {
+        if let Some((current_start, total)) = self.usage().get_mut(&(key.to_string(), period)) {
+            if *current_start == start {
+                total.requests = total.requests.saturating_sub(usage.requests);
+                total.bytes = total.bytes.saturating_sub(usage.bytes);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, period: Period, start: DateTime<Utc>) -> tide::Result<Usage> {
+        Ok(self
+            .usage()
+            .get(&(key.to_string(), period))
+            .filter(|(current_start, _)| *current_start == start)
+            .map(|(_, usage)| *usage)
+            .unwrap_or_default())
+    }
+}
+
+/// A [`QuotaStore`] in a Postgres table, which is shared between processes and kept across restarts.
+///
+/// The table must already exist, e.g. via a migration of:
+///
+/// ```sql
+/// CREATE TABLE quota_usage (
+///     key TEXT NOT NULL,
+///     period TEXT NOT NULL,
+///     period_start TIMESTAMPTZ NOT NULL,
+///     requests BIGINT NOT NULL,
+///     bytes BIGINT NOT NULL,
+///     PRIMARY KEY (key, period, period_start)
+/// );
+/// ```
+///
+/// Past periods are kept, for billing, until they are deleted.
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresStore {
+    /// A store in the `quota_usage` table of `pool`'s database.
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[tide::utils::async_trait]
+impl QuotaStore for PostgresStore {
+    async fn add(
+        &self,
+        key: &str,
+        period: Period,
+        start: DateTime<Utc>,
+        usage: Usage,
+    ) -> tide::Result<Usage> {
+        let (requests, bytes): (i64, i64) = sqlx::query_as(
+            "INSERT INTO quota_usage (key, period, period_start, requests, bytes)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (key, period, period_start) DO UPDATE
+            SET requests = quota_usage.requests + EXCLUDED.requests,
+                bytes = quota_usage.bytes + EXCLUDED.bytes
+            RETURNING requests, bytes",
+        )
+        .bind(key)
+        .bind(period.as_str())
+        .bind(start)
+        .bind(i64::try_from(usage.requests).unwrap_or(i64::MAX))
+        .bind(i64::try_from(usage.bytes).unwrap_or(i64::MAX))
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Usage {
+            requests: u64::try_from(requests).unwrap_or_default(),
+            bytes: u64::try_from(bytes).unwrap_or_default(),
+        })
+    }
+
+    async fn subtract(
+        &self,
+        key: &str,
+        period: Period,
+        start: DateTime<Utc>,
+        usage: Usage,
+    ) -> tide::Result<()> {
+        sqlx::query(
+            "UPDATE quota_usage
+            SET requests = GREATEST(requests - $4, 0),
+                bytes = GREATEST(bytes - $5, 0)
+            WHERE key = $1 AND period = $2 AND period_start = $3",
+        )
+        .bind(key)
+        .bind(period.as_str())
+        .bind(start)
+        .bind(i64::try_from(usage.requests).unwrap_or(i64::MAX))
+        .bind(i64::try_from(usage.bytes).unwrap_or(i64::MAX))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, period: Period, start: DateTime<Utc>) -> tide::Result<Usage> {
+        let row: Option<(i64, i64)> = sqlx::query_as(
+            "SELECT requests, bytes FROM quota_usage
+            WHERE key = $1 AND period = $2 AND period_start = $3",
+        )
+        .bind(key)
+        .bind(period.as_str())
+        .bind(start)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row
+            .map(|(requests, bytes)| Usage {
+                requests: u64::try_from(requests).unwrap_or_default(),
+                bytes: u64::try_from(bytes).unwrap_or_default(),
+            })
+            .unwrap_or_default())
+    }
+}
+
+type QuotasFn = dyn Fn(&str) -> Option<Vec<Quota>> + Send + Sync;
+
+/// The quotas of keys and their usage, as described in the [module docs][self].
+///
+/// Clones share their usage, as do the middleware and endpoints created from them.
+#[derive(Clone)]
+pub struct Quotas {
+    store: Arc<dyn QuotaStore>,
+    quotas: Vec<Quota>,
+    quotas_for: Option<Arc<QuotasFn>>,
+}
+
+impl Quotas {
+    /// Quotas with usage kept in memory, which cap nothing until a [`quota`][Self::quota] is added.
+    pub fn new() -> Self {
+        Self::with_store(MemoryStore::new())
+    }
+
+    /// Quotas with usage kept in `store`.
+    pub fn with_store(store: impl QuotaStore) -> Self {
+        Self {
+            store: Arc::new(store),
+            quotas: Vec::new(),
+            quotas_for: None,
+        }
+    }
+
+    /// Add a quota for every key, except those with quotas of their own via [`quotas_for`][Self::quotas_for].
+    #[must_use]
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.quotas.push(quota);
+        self
+    }
+
+    /// Set the quotas of some keys, e.g. by their plan, instead of those added via [`quota`][Self::quota]. Keys for
+    /// which `quotas_fn` returns `None` have those instead.
+    #[must_use]
+    pub fn quotas_for<QuotasFn>(mut self, quotas_fn: QuotasFn) -> Self
+    where
+        QuotasFn: Fn(&str) -> Option<Vec<Quota>> + Send + Sync + 'static,
+    {
+        self.quotas_for = Some(Arc::new(quotas_fn));
+        self
+    }
+
+    /// The usage of `key` in the current period of each of its quotas.
+    pub async fn usage(&self, key: &str) -> tide::Result<Vec<QuotaUsage>> {
+        let now = Utc::now();
+        let mut usages = Vec::new();
+        for quota in self.quotas_of(key) {
+            let (start, end) = quota.period.bounds(now);
+            usages.push(QuotaUsage {
+                period: quota.period,
+                start,
+                end,
+                usage: self.store.get(key, quota.period, start).await?,
+                max_requests: quota.requests,
+                max_bytes: quota.bytes,
+            });
+        }
+        Ok(usages)
+    }
+
+    /// Add `usage` to that of `key`, in the current period of each of its quotas.
+    pub async fn record(&self, key: &str, usage: Usage) -> tide::Result<()> {
+        let now = Utc::now();
+        for period in periods(&self.quotas_of(key)) {
+            let (start, _) = period.bounds(now);
+            self.store.add(key, period, start, usage).await?;
+        }
+        Ok(())
+    }
+
+    /// Middleware enforcing and counting the quotas of the key `key` derives from each request, e.g. its tenant.
+    ///
+    /// Requests without a key are let through uncounted.
+    pub fn middleware<F>(&self, key: F) -> QuotaMiddleware<F> {
+        QuotaMiddleware {
+            quotas: self.clone(),
+            key,
+        }
+    }
+
+    /// An endpoint responding with the [`usage`][Self::usage] of the key in the route parameter `param`, as JSON.
+    pub fn usage_endpoint(&self, param: &'static str) -> UsageEndpoint {
+        UsageEndpoint {
+            quotas: self.clone(),
+            param,
+        }
+    }
+
+    fn quotas_of(&self, key: &str) -> Vec<Quota> {
+        self.quotas_for
+            .as_ref()
+            .and_then(|quotas_for| quotas_for(key))
+            .unwrap_or_else(|| self.quotas.clone())
+    }
+}
+
+/// The distinct periods of `quotas`, which usage is counted in once each.
+fn periods(quotas: &[Quota]) -> Vec<Period> {
+    let mut periods = Vec::new();
+    for quota in quotas {
+        if !periods.contains(&quota.period) {
+            periods.push(quota.period);
+        }
+    }
+    periods
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Quotas {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Quotas")
+            .field("quotas", &self.quotas)
+            .field("quotas_for", &self.quotas_for.is_some())
+            .finish()
+    }
+}
+
+/// Middleware which enforces and counts quotas, via [`Quotas::middleware`].
+#[derive(Debug)]
+pub struct QuotaMiddleware<F> {
+    quotas: Quotas,
+    key: F,
+}
+
+#[tide::utils::async_trait]
+impl<State, F> Middleware<State> for QuotaMiddleware<F>
+where
+    State: Clone + Send + Sync + 'static,
+    F: Fn(&Request<State>) -> Option<String> + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let key = match (self.key)(&req) {
+            Some(key) => key,
+            None => return Ok(next.run(req).await),
+        };
+
+        // The request is counted before it is handled, so that concurrent requests cannot all pass a cap which only
+        // one more fits under, and uncounted if it is rejected.
+        let request = Usage {
+            requests: 1,
+            bytes: req.len().unwrap_or_default() as u64,
+        };
+        let now = Utc::now();
+        let quotas = self.quotas.quotas_of(&key);
+        let mut counted = Vec::new();
+        for period in periods(&quotas) {
+            let (start, end) = period.bounds(now);
+            let usage = self.quotas.store.add(&key, period, start, request).await?;
+            counted.push((period, start));
+            let before = Usage {
+                requests: usage.requests.saturating_sub(request.requests),
+                bytes: usage.bytes.saturating_sub(request.bytes),
+            };
+            let exceeded = quotas
+                .iter()
+                .find(|quota| quota.period == period && quota.is_exceeded_by(before));
+            if let Some(quota) = exceeded {
+                for (period, start) in counted {
+                    self.quotas
+                        .store
+                        .subtract(&key, period, start, request)
+                        .await?;
+                }
+                let details = json!({
+                    "period": quota.period,
+                    "max_requests": quota.requests,
+                    "max_bytes": quota.bytes,
+                    "usage": before,
+                });
+                return Err(crate::Error::quota_exceeded_until(end, details));
+            }
+        }
+
+        let res = next.run(req).await;
+        let response_bytes = res.len().unwrap_or_default() as u64;
+        if response_bytes > 0 {
+            let usage = Usage {
+                requests: 0,
+                bytes: response_bytes,
+            };
+            self.quotas.record(&key, usage).await?;
+        }
+        Ok(res)
+    }
+}
+
+/// An endpoint which responds with the usage of a key, via [`Quotas::usage_endpoint`].
+#[derive(Debug)]
+pub struct UsageEndpoint {
+    quotas: Quotas,
+    param: &'static str,
+}
+
+#[tide::utils::async_trait]
+impl<State> Endpoint<State> for UsageEndpoint
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let usage = self.quotas.usage(req.param(self.param)?).await?;
+        Ok(json_body(&usage)?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[test]
+    fn bounds_periods() -> Result<(), chrono::ParseError> {
+        let time: DateTime<Utc> = "2024-12-31T15:30:00Z".parse()?;
+        assert_eq!(
+            Period::Daily.bounds(time),
+            (
+                "2024-12-31T00:00:00Z".parse()?,
+                "2025-01-01T00:00:00Z".parse()?
+            )
+        );
+        assert_eq!(
+            Period::Monthly.bounds(time),
+            (
+                "2024-12-01T00:00:00Z".parse()?,
+                "2025-01-01T00:00:00Z".parse()?
+            )
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn enforces_quotas() -> TestResult<()> {
+        let quotas = Quotas::new()
+            .quota(Quota::daily().requests(2))
+            .quota(Quota::daily().bytes(1_000))
+            .quotas_for(|key| (key == "unlimited").then(Vec::new));
+
+        let client = test_utils::create_client((), move |mut server: Route<'_, Arc<()>>| {
+            server
+                .at("reports")
+                .with(quotas.middleware(|req: &Request<Arc<()>>| {
+                    req.header("X-Tenant")
+                        .map(|tenant| tenant.last().to_string())
+                }))
+                .get(|_| async { Ok("[]") });
+            server
+                .at("usage/:tenant")
+                .get(quotas.usage_endpoint("tenant"));
+        })
+        .await?;
+
+        for _ in 0..2 {
+            let mut res = client
+                .get("/api/v1/reports")
+                .header("X-Tenant", "acme")
+                .await?;
+            assert_status(&mut res, 200).await;
+        }
+        let mut res = client
+            .get("/api/v1/reports")
+            .header("X-Tenant", "acme")
+            .await?;
+        assert!(res.header("Retry-After").is_some());
+        assert!(assert_status(&mut res, 429)
+            .await
+            .contains("quota_exceeded"));
+
+        for _ in 0..3 {
+            let mut res = client
+                .get("/api/v1/reports")
+                .header("X-Tenant", "unlimited")
+                .await?;
+            assert_status(&mut res, 200).await;
+        }
+
+        let mut res = client.get("/api/v1/usage/acme").await?;
+        let usage: serde_json::Value = serde_json::from_str(&assert_status(&mut res, 200).await)?;
+        assert_eq!(usage[0]["period"], "daily");
+        // The rejected request is not counted, and each request is counted once for both daily quotas.
+        assert_eq!(usage[0]["usage"], json!({ "requests": 2, "bytes": 4 }));
+        assert_eq!(usage[1]["usage"], usage[0]["usage"]);
+        assert_eq!(usage[0]["max_requests"], 2);
+        Ok(())
+    }
+}
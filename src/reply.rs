@@ -0,0 +1,126 @@
+//! Responses for common REST outcomes, with their status and headers set.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use preroll::reply;
+//! use serde::{Deserialize, Serialize};
+//! use tide::Request;
+//!
+//! #[derive(Deserialize, Serialize)]
+//! struct User {
+//!     id: u64,
+//!     name: String,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn create_user(mut req: Request<()>) -> tide::Result {
+//!     let user: User = req.body_json().await?;
+//!     // `201 Created`, with a `Location: /api/v1/users/7` header.
+//!     reply::created(format!("/api/v1/users/{}", user.id), &user)
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn get_countries(_req: Request<()>) -> tide::Result {
+//!     // `200 OK`, with a `Cache-Control: public, max-age=86400` header.
+//!     reply::json_with_cache(&["NZ", "US"], Duration::from_secs(24 * 60 * 60))
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn delete_user(_req: Request<()>) -> tide::Result {
+//!     Ok(reply::no_content())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn export_users(_req: Request<()>) -> tide::Result {
+//!     // `202 Accepted`, with `{"job_id": "export-7"}`.
+//!     Ok(reply::accepted("export-7"))
+//! }
+//! ```
+
+use std::fmt::Display;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+use tide::http::cache::{CacheControl, CacheDirective};
+use tide::http::headers;
+use tide::{Response, StatusCode};
+
+use crate::buffers::json_body;
+
+/// A `201 Created` response with the created resource as JSON, and its URL as the `Location` header.
+pub fn created<T: Serialize>(location: impl AsRef<str>, body: &T) -> tide::Result {
+    let mut res = json(StatusCode::Created, body)?;
+    res.insert_header(headers::LOCATION, location.as_ref());
+    Ok(res)
+}
+
+/// A `204 No Content` response.
+pub fn no_content() -> Response {
+    Response::new(StatusCode::NoContent)
+}
+
+/// A `202 Accepted` response for work which continues in the background, with the id of the job as JSON, e.g.
+/// `{"job_id": "export-7"}`.
+pub fn accepted(job_id: impl Display) -> Response {
+    let mut res = Response::new(StatusCode::Accepted);
+    res.set_body(json!({ "job_id": job_id.to_string() }));
+    res
+}
+
+/// A `200 OK` response with a JSON body, which clients and caches may reuse for `ttl`.
+///
+/// A `ttl` of zero has clients revalidate the response every time instead.
+pub fn json_with_cache<T: Serialize>(body: &T, ttl: Duration) -> tide::Result {
+    let mut res = json(StatusCode::Ok, body)?;
+    let mut cache_control = CacheControl::new();
+    if ttl.as_secs() == 0 {
+        cache_control.push(CacheDirective::NoCache);
+    } else {
+        cache_control.push(CacheDirective::Public);
+        cache_control.push(CacheDirective::MaxAge(ttl));
+    }
+    cache_control.apply(&mut res);
+    Ok(res)
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> tide::Result {
+    let mut res = Response::new(status);
+    res.set_body(json_body(body)?);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::mime;
+
+    use super::*;
+
+    #[test]
+    fn sets_statuses_and_headers() -> tide::Result<()> {
+        let res = created("/api/v1/users/7", &json!({ "id": 7 }))?;
+        assert_eq!(res.status(), StatusCode::Created);
+        assert_eq!(res["Location"], "/api/v1/users/7");
+        assert_eq!(res.content_type(), Some(mime::JSON));
+
+        assert_eq!(no_content().status(), StatusCode::NoContent);
+        assert_eq!(accepted(7).status(), StatusCode::Accepted);
+
+        let res = json_with_cache(&["NZ"], Duration::from_secs(60))?;
+        assert_eq!(res["Cache-Control"], "public, max-age=60");
+        let res = json_with_cache(&["NZ"], Duration::ZERO)?;
+        assert_eq!(res["Cache-Control"], "no-cache");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn accepted_has_job_id() -> tide::Result<()> {
+        let mut res: tide::http::Response = accepted("export-7").into();
+        let body: serde_json::Value = res.body_json().await?;
+        assert_eq!(body, json!({ "job_id": "export-7" }));
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use tide::Route;
+
+/// Extension methods for grouping routes under a path, with middleware which applies only to that group.
+///
+/// Middleware added to a group applies to the group's routes, and to those of groups nested within it,
+/// but not to the rest of the server. Requests under the group's path which match none of its routes are
+/// not passed through the group's middleware.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use preroll::prelude::*;
+/// use tide::{Middleware, Next, Request, Route, StatusCode};
+///
+/// /// Only allow requests from other services, which set `X-Internal-Token`.
+/// # #[allow(dead_code)]
+/// struct InternalOnly;
+///
+/// #[tide::utils::async_trait]
+/// impl<State: Clone + Send + Sync + 'static> Middleware<State> for InternalOnly {
+///     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+///         match req.header("X-Internal-Token") {
+///             Some(_) => Ok(next.run(req).await),
+///             None => Err(tide::Error::from_str(StatusCode::Forbidden, "Internal only")),
+///         }
+///     }
+/// }
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server.at("users").get(|_| async { Ok("[]") });
+///
+///     // Serves /api/v1/internal/jobs, only to requests which pass InternalOnly.
+///     server.group("/internal", |internal| {
+///         internal.with(InternalOnly);
+///         internal.at("jobs").get(|_| async { Ok("[]") });
+///     });
+/// }
+/// ```
+pub trait RouteGroupExt<State> {
+    /// Set up a group of routes at `path`, via `group_fn`, which may add middleware to the group with `Route::with`.
+    fn group<GroupFn>(&mut self, path: &str, group_fn: GroupFn)
+    where
+        GroupFn: FnOnce(&mut Route<'_, State>);
+}
+
+impl<'a, State> RouteGroupExt<State> for Route<'a, State>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    fn group<GroupFn>(&mut self, path: &str, group_fn: GroupFn)
+    where
+        GroupFn: FnOnce(&mut Route<'_, State>),
+    {
+        // Tide routes copy their parent's middleware, so middleware added to the group stays within it.
+        group_fn(&mut self.at(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::utils::After;
+    use tide::Response;
+
+    use super::*;
+    use crate::test_utils::{self, TestResult};
+
+    fn tag(tag: &'static str) -> After<impl Fn(Response) -> std::future::Ready<tide::Result>> {
+        After(move |mut res: Response| {
+            let tags = res
+                .header("X-Tags")
+                .map(|tags| format!("{},{}", tags.last(), tag))
+                .unwrap_or_else(|| tag.to_string());
+            res.insert_header("X-Tags", tags);
+            std::future::ready(Ok(res))
+        })
+    }
+
+    #[async_std::test]
+    async fn scopes_middleware_to_groups() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: Route<'_, Arc<()>>| {
+            server.at("public").get(|_| async { Ok("") });
+            server.group("/internal", |internal| {
+                internal.with(tag("internal"));
+                internal.at("jobs").get(|_| async { Ok("") });
+                internal.group("admin", |admin| {
+                    admin.with(tag("admin"));
+                    admin.at("users").get(|_| async { Ok("") });
+                });
+                internal.at("health").get(|_| async { Ok("") });
+            });
+        })
+        .await?;
+
+        let tags = |res: surf::Response| res.header("X-Tags").map(|tags| tags.last().to_string());
+
+        assert_eq!(tags(client.get("/api/v1/public").await?), None);
+        assert_eq!(
+            tags(client.get("/api/v1/internal/jobs").await?).as_deref(),
+            Some("internal")
+        );
+        assert_eq!(
+            tags(client.get("/api/v1/internal/admin/users").await?).as_deref(),
+            Some("admin,internal")
+        );
+        assert_eq!(
+            tags(client.get("/api/v1/internal/health").await?).as_deref(),
+            Some("internal")
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,12 @@
+use std::any::type_name;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::Arc;
 
-use tide::Route;
+use color_eyre::eyre::eyre;
+use tide::{Route, Server};
+
+use crate::setup::Result;
 
 /// [Variadic-argument][] route versioning is implemented via this struct for [`From<T>`][] with Single-argument, Tuple, and Vec types.
 ///
@@ -20,6 +24,8 @@ use tide::Route;
 ///     - Will become `/api/v{N}` where N is the index + 1.
 ///     - E.g. `vec![Box::new(routes_v1), Box::new(routes_v2)]`
 ///
+/// The prefixes can be changed from `/api/v{N}` via [`VariadicRoutes::versioning`].
+///
 /// [`From<T>`]: https://doc.rust-lang.org/std/convert/trait.From.html
 /// [Tuple]: https://doc.rust-lang.org/std/primitive.tuple.html
 /// [Variadic-argument]: https://en.wikipedia.org/wiki/Variadic_function
@@ -32,6 +38,123 @@ where
 {
     _phantom_state: PhantomData<*const State>,
     pub routes: Vec<Box<dyn for<'r> Fn(Route<'r, Arc<State>>)>>,
+    /// The type names of the routes functions, for display.
+    pub(crate) names: Vec<&'static str>,
+    versioning: ApiVersioning,
+}
+
+/// How routes functions are prefixed when they are mounted, as set via [`VariadicRoutes::versioning`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ApiVersioning {
+    /// `/api/v{N}`, where `{N}` is the position of the routes function, starting at `1`. The default.
+    Numbered,
+    /// No prefix, so that routes are mounted at the root. Only one routes function is allowed.
+    Unprefixed,
+    /// `/api/{date}`, with one `YYYY-MM-DD` date per routes function, in order, e.g. `/api/2024-06-01`.
+    Dated(Vec<&'static str>),
+    /// Any prefixes, with one per routes function, in order, e.g. `"/v1"` or `"/internal/api"`.
+    Prefixed(Vec<&'static str>),
+}
+
+impl<State> VariadicRoutes<State>
+where
+    State: Send + Sync + 'static,
+{
+    /// Mount the routes functions with prefixes from `versioning`, rather than `/api/v{N}`.
+    ///
+    /// As `preroll::main!` takes single tokens, wrap this in a block there.
+    ///
+    /// ## Example:
+    ///
+    /// ```no_run
+    /// # #[cfg(not(feature = "custom_middleware"))]
+    /// # {
+    /// use std::sync::Arc;
+    ///
+    /// use preroll::{ApiVersioning, VariadicRoutes};
+    /// use tide::Route;
+    ///
+    /// # #[allow(dead_code)]
+    /// fn routes_2023(mut server: Route<'_, Arc<()>>) {
+    ///     server.at("users").get(|_| async { Ok("[]") });
+    /// }
+    ///
+    /// # #[allow(dead_code)]
+    /// fn routes_2024(mut server: Route<'_, Arc<()>>) {
+    ///     server.at("accounts").get(|_| async { Ok("[]") });
+    /// }
+    ///
+    /// # #[allow(dead_code)]
+    /// async fn setup_state() -> preroll::SetupResult<()> {
+    ///     Ok(())
+    /// }
+    ///
+    /// // Mounted at /api/2023-01-01 and /api/2024-06-01.
+    /// preroll::main!("dated-service", setup_state, {
+    ///     VariadicRoutes::from((routes_2023, routes_2024))
+    ///         .versioning(ApiVersioning::Dated(vec!["2023-01-01", "2024-06-01"]))
+    /// });
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn versioning(mut self, versioning: ApiVersioning) -> Self {
+        self.versioning = versioning;
+        self
+    }
+
+    /// The prefix of each routes function, in order.
+    pub(crate) fn prefixes(&self) -> Result<Vec<String>> {
+        let count = self.routes.len();
+        let prefixes: Vec<String> = match &self.versioning {
+            ApiVersioning::Numbered => {
+                return Ok((1..=count).map(|n| format!("/api/v{}", n)).collect())
+            }
+            ApiVersioning::Unprefixed if count > 1 => {
+                return Err(eyre!(
+                    "Unprefixed routes allow only one routes function, but {} were given",
+                    count
+                ))
+            }
+            ApiVersioning::Unprefixed => return Ok(vec![String::new(); count]),
+            ApiVersioning::Dated(dates) => dates
+                .iter()
+                .map(|date| {
+                    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                        .map(|_| format!("/api/{}", date))
+                        .map_err(|_| eyre!("API version \"{}\" is not a YYYY-MM-DD date", date))
+                })
+                .collect::<Result<_>>()?,
+            ApiVersioning::Prefixed(prefixes) => prefixes
+                .iter()
+                .map(|prefix| format!("/{}", prefix.trim_matches('/')))
+                .collect(),
+        };
+
+        if prefixes.len() != count {
+            return Err(eyre!(
+                "{} API versions were given for {} routes functions",
+                prefixes.len(),
+                count
+            ));
+        }
+
+        Ok(prefixes)
+    }
+
+    /// Mount each routes function on `server` at its prefix.
+    pub(crate) fn mount(self, server: &mut Server<Arc<State>>) -> Result<()> {
+        let prefixes = self.prefixes()?;
+        for (prefix, routes_fn) in prefixes.iter().zip(self.routes) {
+            if prefix.is_empty() {
+                routes_fn(server.at("/"));
+            } else {
+                routes_fn(server.at(prefix));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl<State, RoutesFn> From<RoutesFn> for VariadicRoutes<State>
@@ -42,7 +165,9 @@ where
     fn from(routes: RoutesFn) -> Self {
         VariadicRoutes {
             _phantom_state: PhantomData,
+            versioning: ApiVersioning::Numbered,
             routes: vec![Box::new(routes)],
+            names: vec![type_name::<RoutesFn>()],
         }
     }
 }
@@ -56,7 +181,9 @@ where
     fn from(routes: (RoutesFn,)) -> Self {
         VariadicRoutes {
             _phantom_state: PhantomData,
+            versioning: ApiVersioning::Numbered,
             routes: vec![Box::new(routes.0)],
+            names: vec![type_name::<RoutesFn>()],
         }
     }
 }
@@ -70,7 +197,9 @@ where
     fn from(routes: (RoutesFn1, RoutesFn2)) -> Self {
         VariadicRoutes {
             _phantom_state: PhantomData,
+            versioning: ApiVersioning::Numbered,
             routes: vec![Box::new(routes.0), Box::new(routes.1)],
+            names: vec![type_name::<RoutesFn1>(), type_name::<RoutesFn2>()],
         }
     }
 }
@@ -86,7 +215,13 @@ where
     fn from(routes: (RoutesFn1, RoutesFn2, RoutesFn3)) -> Self {
         VariadicRoutes {
             _phantom_state: PhantomData,
+            versioning: ApiVersioning::Numbered,
             routes: vec![Box::new(routes.0), Box::new(routes.1), Box::new(routes.2)],
+            names: vec![
+                type_name::<RoutesFn1>(),
+                type_name::<RoutesFn2>(),
+                type_name::<RoutesFn3>(),
+            ],
         }
     }
 }
@@ -103,12 +238,19 @@ where
     fn from(routes: (RoutesFn1, RoutesFn2, RoutesFn3, RoutesFn4)) -> Self {
         VariadicRoutes {
             _phantom_state: PhantomData,
+            versioning: ApiVersioning::Numbered,
             routes: vec![
                 Box::new(routes.0),
                 Box::new(routes.1),
                 Box::new(routes.2),
                 Box::new(routes.3),
             ],
+            names: vec![
+                type_name::<RoutesFn1>(),
+                type_name::<RoutesFn2>(),
+                type_name::<RoutesFn3>(),
+                type_name::<RoutesFn4>(),
+            ],
         }
     }
 }
@@ -122,6 +264,8 @@ where
     fn from(routes: Vec<Box<dyn for<'r> Fn(Route<'r, Arc<State>>)>>) -> Self {
         VariadicRoutes {
             _phantom_state: PhantomData,
+            versioning: ApiVersioning::Numbered,
+            names: vec!["(boxed routes function)"; routes.len()],
             routes,
         }
     }
@@ -0,0 +1,112 @@
+//! The async runtime which services run on.
+//!
+//! Services run on [async-std][], as Tide does. With the `"runtime-tokio"` feature, everything preroll runs is also
+//! inside the context of a [Tokio][] runtime, so that crates which only work on Tokio, such as `tonic`, `rdkafka`,
+//! and the AWS SDK, can be used directly, without compat shims:
+//! - The setup functions of [`preroll::main!`][crate::main], via [`block_on`].
+//! - Every request, through all middleware and handlers.
+//! - Tasks spawned via [`spawn`], such as the [scheduled tasks][crate::Hooks::task] and queue consumers of hooks.
+//!
+//! Tokio's context is that of the current Tokio runtime, if there is one, or else of a single-threaded runtime
+//! started on demand on a background thread, via [async-compat][]. Futures are still polled by async-std, so work
+//! that `tokio::spawn`s its own tasks, such as a `tonic` channel, runs those tasks on Tokio's thread.
+//!
+//! Without the feature, these are the same as their async-std equivalents.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! # #[allow(dead_code)]
+//! fn start_polling() {
+//!     preroll::runtime::spawn(async {
+//!         loop {
+//!             // With "runtime-tokio", this could be e.g. `tokio::time::sleep`, or an rdkafka consumer.
+//!             async_std::task::sleep(Duration::from_secs(60)).await;
+//!         }
+//!     });
+//! }
+//! ```
+//!
+//! [async-std]: https://docs.rs/async-std
+//! [Tokio]: https://docs.rs/tokio
+//! [async-compat]: https://docs.rs/async-compat
+
+use std::future::Future;
+
+use async_std::task::JoinHandle;
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(feature = "runtime-tokio")] {
+        use async_compat::Compat;
+        use tide::{Middleware, Next, Request};
+    }
+}
+
+/// Run `future` to completion on the current thread, blocking until it finishes.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    cfg_if! {
+        if #[cfg(feature = "runtime-tokio")] {
+            async_std::task::block_on(Compat::new(future))
+        } else {
+            async_std::task::block_on(future)
+        }
+    }
+}
+
+/// Spawn `future` as a background task.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    cfg_if! {
+        if #[cfg(feature = "runtime-tokio")] {
+            async_std::task::spawn(Compat::new(future))
+        } else {
+            async_std::task::spawn(future)
+        }
+    }
+}
+
+/// Handle requests inside Tokio's context, for the `"runtime-tokio"` feature.
+#[cfg(feature = "runtime-tokio")]
+#[derive(Debug, Default)]
+pub(crate) struct TokioContextMiddleware;
+
+#[cfg(feature = "runtime-tokio")]
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TokioContextMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        Ok(Compat::new(next.run(req)).await)
+    }
+}
+
+#[cfg(all(test, feature = "runtime-tokio"))]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn runs_in_tokio_context() -> TestResult<()> {
+        let in_context = || tokio::runtime::Handle::try_current().is_ok().to_string();
+        assert_eq!(block_on(async { in_context() }), "true");
+        assert_eq!(spawn(async move { in_context() }).await, "true");
+
+        let client = test_utils::create_client((), move |mut server: Route<'_, Arc<()>>| {
+            server
+                .at("tokio")
+                .get(move |_| async move { Ok(in_context()) });
+        })
+        .await?;
+        let mut res = client.get("/api/v1/tokio").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "true");
+        Ok(())
+    }
+}
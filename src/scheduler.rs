@@ -0,0 +1,299 @@
+//! Scheduled background tasks, which `preroll::main!` starts alongside the server.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::setup::{Resources, Result};
+
+type TaskFn<State> = Arc<
+    dyn Fn(Arc<State>, Resources) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync,
+>;
+
+static STATS: Lazy<Mutex<BTreeMap<&'static str, TaskStats>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// When a [`Task`] runs.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// At a fixed interval, starting one interval after the server starts.
+    Every(Duration),
+    /// On a cron schedule, in UTC.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Run at a fixed interval.
+    pub fn every(interval: Duration) -> Self {
+        Schedule::Every(interval)
+    }
+
+    /// Run on a cron schedule, in UTC, with fields for `sec min hour day-of-month month day-of-week [year]`.
+    ///
+    /// ## Example:
+    ///
+    /// ```
+    /// use preroll::Schedule;
+    ///
+    /// // At the start of every fifth minute.
+    /// let schedule = Schedule::cron("0 */5 * * * *").unwrap();
+    ///
+    /// assert!(Schedule::cron("every five minutes").is_err());
+    /// ```
+    pub fn cron(expression: &str) -> Result<Self> {
+        let schedule = cron::Schedule::from_str(expression)
+            .map_err(|e| eyre!("Invalid cron expression \"{}\": {}", expression, e))?;
+        Ok(Schedule::Cron(Box::new(schedule)))
+    }
+
+    /// How long to wait, from now, until the next run. `None` if the schedule has no more runs.
+    fn until_next(&self) -> Option<Duration> {
+        match self {
+            Schedule::Every(interval) => Some(*interval),
+            Schedule::Cron(schedule) => schedule
+                .upcoming(Utc)
+                .next()
+                .map(|next| (next - Utc::now()).to_std().unwrap_or_default()),
+        }
+    }
+}
+
+/// A named async task which runs on a [`Schedule`], added via [`Hooks::task`][crate::Hooks::task].
+///
+/// Runs of a task never overlap: the next run is scheduled only once the current one has finished,
+/// and runs which would have started in the meantime are skipped.
+///
+/// With the `"postgres"` feature, a task can also be restricted to one instance of the service at a time
+/// via [`advisory_lock`][Task::advisory_lock].
+///
+/// Each task's run counts and last error are reported in `/monitor/status`.
+pub struct Task<State> {
+    pub(crate) name: &'static str,
+    schedule: Schedule,
+    task_fn: TaskFn<State>,
+    advisory_lock: bool,
+}
+
+impl<State> Task<State>
+where
+    State: Send + Sync + 'static,
+{
+    /// A task which calls `task_fn` with the server state and [`Resources`] on each run.
+    ///
+    /// Errors are logged and counted, and do not stop future runs.
+    pub fn new<TaskFnOnce, TaskFuture>(
+        name: &'static str,
+        schedule: Schedule,
+        task_fn: TaskFnOnce,
+    ) -> Self
+    where
+        TaskFnOnce: Fn(Arc<State>, Resources) -> TaskFuture + Send + Sync + 'static,
+        TaskFuture: Future<Output = Result<()>> + Send + 'static,
+    {
+        Self {
+            name,
+            schedule,
+            task_fn: Arc::new(move |state, resources| Box::pin(task_fn(state, resources))),
+            advisory_lock: false,
+        }
+    }
+
+    /// Only run if a Postgres advisory lock for this task's name can be taken, so that when several instances of
+    /// the service are running, each scheduled run happens on at most one of them. Runs which do not get the lock are skipped.
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn advisory_lock(mut self, advisory_lock: bool) -> Self {
+        self.advisory_lock = advisory_lock;
+        self
+    }
+
+    /// Run the task on its schedule, forever.
+    pub(crate) async fn run(self, state: Arc<State>, resources: Resources) {
+        update_stats(self.name, |_| {});
+
+        while let Some(delay) = self.schedule.until_next() {
+            async_std::task::sleep(delay).await;
+
+            let start = Instant::now();
+            let result = match self.run_once(state.clone(), resources.clone()).await {
+                Ok(true) => Ok(()),
+                Ok(false) => {
+                    log::debug!("Scheduled task {} skipped, lock is held", self.name);
+                    update_stats(self.name, |stats| stats.skipped += 1);
+                    continue;
+                }
+                Err(error) => Err(error),
+            };
+            let duration = start.elapsed();
+
+            match result {
+                Ok(()) => {
+                    log::debug!("Scheduled task {} succeeded in {:?}", self.name, duration);
+                    update_stats(self.name, |stats| {
+                        stats.successes += 1;
+                        stats.last_duration = Some(duration.as_secs_f64());
+                    });
+                }
+                Err(error) => {
+                    log::error!(
+                        "Scheduled task {} failed in {:?}: {:?}",
+                        self.name,
+                        duration,
+                        error
+                    );
+                    update_stats(self.name, |stats| {
+                        stats.failures += 1;
+                        stats.last_duration = Some(duration.as_secs_f64());
+                        stats.last_error = Some(error.to_string());
+                    });
+                }
+            }
+        }
+
+        log::info!("Scheduled task {} has no more runs", self.name);
+    }
+
+    /// Run once, returning `false` if skipped because another instance holds the lock.
+    #[cfg(feature = "postgres")]
+    async fn run_once(&self, state: Arc<State>, resources: Resources) -> Result<bool> {
+        if !self.advisory_lock {
+            (self.task_fn)(state, resources).await?;
+            return Ok(true);
+        }
+
+        // The lock is held by a transaction, so that it is released when the transaction ends, however it ends, rather
+        // than staying with a pooled connection if unlocking fails or the task panics.
+        let mut tx = resources.pg_pool.begin().await?;
+        let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_xact_lock($1)")
+            .bind(lock_key(self.name))
+            .fetch_one(&mut tx)
+            .await?;
+        if !locked {
+            return Ok(false);
+        }
+
+        let result = (self.task_fn)(state, resources).await;
+
+        // The task's own result matters more; a transaction which fails to commit is rolled back when dropped.
+        if let Err(error) = tx.commit().await {
+            log::warn!(
+                "Releasing the lock of scheduled task {} failed: {}",
+                self.name,
+                error
+            );
+        }
+
+        result.map(|_| true)
+    }
+
+    /// Run once.
+    #[cfg(not(feature = "postgres"))]
+    async fn run_once(&self, state: Arc<State>, resources: Resources) -> Result<bool> {
+        (self.task_fn)(state, resources).await?;
+        Ok(true)
+    }
+}
+
+impl<State> fmt::Debug for Task<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Task")
+            .field("name", &self.name)
+            .field("schedule", &self.schedule)
+            .field("advisory_lock", &self.advisory_lock)
+            .finish()
+    }
+}
+
+/// A stable advisory lock key for a task name, the same on every instance (FNV-1a).
+#[cfg(feature = "postgres")]
+fn lock_key(name: &str) -> i64 {
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    i64::from_ne_bytes(hash.to_ne_bytes())
+}
+
+/// The run counts of a scheduled task, as reported in `/monitor/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct TaskStats {
+    successes: u64,
+    failures: u64,
+    skipped: u64,
+    /// Seconds.
+    last_duration: Option<f64>,
+    last_error: Option<String>,
+}
+
+fn update_stats(name: &'static str, update: impl FnOnce(&mut TaskStats)) {
+    let mut stats = STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    update(stats.entry(name).or_default());
+}
+
+/// The run counts of all started tasks, by name.
+pub(crate) fn task_stats() -> BTreeMap<&'static str, TaskStats> {
+    STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "postgres"))]
+    #[async_std::test]
+    async fn runs_tasks_and_counts_failures() -> Result<()> {
+        let task = Task::new(
+            "counts_failures",
+            Schedule::every(Duration::from_millis(10)),
+            |state: Arc<Mutex<u32>>, _| async move {
+                let mut runs = state.lock().unwrap_or_else(|e| e.into_inner());
+                *runs += 1;
+                if *runs == 2 {
+                    return Err(eyre!("second run"));
+                }
+                Ok(())
+            },
+        );
+        let state = Arc::new(Mutex::new(0));
+
+        async_std::future::timeout(
+            Duration::from_millis(100),
+            task.run(
+                state,
+                Resources {
+                    environment: crate::Environment::Test,
+                    services: Default::default(),
+                    #[cfg(feature = "email")]
+                    emailer: crate::email::Emailer::capture().0,
+                    #[cfg(feature = "storage")]
+                    storage: None,
+                },
+            ),
+        )
+        .await
+        .ok();
+
+        let stats = task_stats();
+        let stats = stats
+            .get("counts_failures")
+            .ok_or_else(|| eyre!("No stats"))?;
+        assert!(stats.successes >= 2);
+        assert_eq!(stats.failures, 1);
+        assert_eq!(stats.last_error.as_deref(), Some("second run"));
+        Ok(())
+    }
+}
@@ -0,0 +1,488 @@
+//! Protection for authentication endpoints against credential stuffing and password guessing.
+//!
+//! An [`AttemptTracker`] counts the consecutive failed attempts of a key, such as a username or a client IP, and locks
+//! the key out once there are too many, for twice as long after each further failure.
+//!
+//! - Handlers call [`AttemptTracker::record_failure`] and [`AttemptTracker::record_success`] themselves for keys only
+//!   they know, such as the username in a login form.
+//! - [`AttemptTracker::middleware`] does so for keys derived from the request, such as the client IP, recording `401`
+//!   and `403` responses as failures, and rejecting requests for locked out keys with a `429 Too Many Requests`
+//!   [`Error::rate_limited_until`][crate::Error::rate_limited_until].
+//! - Attempts are kept in memory by default, which is per process. Implement [`AttemptStore`] to share them between
+//!   processes, e.g. in Redis or Postgres.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use preroll::security::AttemptTracker;
+//! use tide::{Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     let tracker = AttemptTracker::new().max_failures(5);
+//!     let by_username = tracker.clone();
+//!
+//!     server
+//!         .at("login/:username")
+//!         .with(tracker.middleware(|req: &Request<Arc<()>>| {
+//!             req.context().client_ip.map(|ip| format!("ip:{}", ip))
+//!         }))
+//!         .post(move |req| login(by_username.clone(), req));
+//! }
+//!
+//! async fn login(tracker: AttemptTracker, req: Request<Arc<()>>) -> tide::Result<String> {
+//!     let key = format!("user:{}", req.param("username")?);
+//!     if let Some(until) = tracker.locked_until(&key).await? {
+//!         return Err(preroll::Error::rate_limited_until(until));
+//!     }
+//!     if !check_password(&req) {
+//!         tracker.record_failure(&key).await?;
+//!         return Err(preroll::Error::with_code(
+//!             "invalid_credentials",
+//!             tide::StatusCode::Unauthorized,
+//!             "Invalid username or password",
+//!         ));
+//!     }
+//!     tracker.record_success(&key).await?;
+//!     Ok("Welcome back".to_string())
+//! }
+//! # fn check_password(_req: &Request<Arc<()>>) -> bool { true }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tide::{Middleware, Next, Request, StatusCode};
+
+/// Where an [`AttemptTracker`] keeps attempts, e.g. in memory or in Redis.
+///
+/// Each method is a single operation on one key, so that a shared store can implement it atomically, e.g. via Redis'
+/// `INCR` and `PEXPIRE`.
+#[tide::utils::async_trait]
+pub trait AttemptStore: Send + Sync + 'static {
+    /// Add a failure for `key` and return how many there are now, forgetting them once there has been none for `window`.
+    async fn increment(&self, key: &str, window: Duration) -> tide::Result<u32>;
+
+    /// Remove a failure added by `increment`, e.g. for an attempt which turned out to be neither a failure nor a
+    /// success.
+    async fn decrement(&self, key: &str) -> tide::Result<()>;
+
+    /// Lock `key` out until `until`.
+    async fn lock(&self, key: &str, until: SystemTime) -> tide::Result<()>;
+
+    /// When `key` is locked out until, if it is.
+    async fn locked_until(&self, key: &str) -> tide::Result<Option<SystemTime>>;
+
+    /// Forget the failures and any lockout of `key`.
+    async fn clear(&self, key: &str) -> tide::Result<()>;
+}
+
+/// An [`AttemptStore`] in the memory of this process.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    attempts: Mutex<HashMap<String, Attempts>>,
+    /// When expired keys were last swept out of `attempts`.
+    swept: Mutex<Option<SystemTime>>,
+}
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    last_failure: SystemTime,
+    locked_until: Option<SystemTime>,
+}
+
+impl MemoryStore {
+    /// An empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn attempts(&self) -> std::sync::MutexGuard<'_, HashMap<String, Attempts>> {
+        self.attempts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Forget keys which have expired, at most once per `window`, so that the map only holds recent failures without
+    /// being scanned on every failure.
+    fn sweep(&self, attempts: &mut HashMap<String, Attempts>, now: SystemTime, window: Duration) {
+        let mut swept = self
+            .swept
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if swept.is_some_and(|swept| swept + window > now) {
+            return;
+        }
+        *swept = Some(now);
+        attempts.retain(|_, attempts| !attempts.expired(now, window));
+    }
+}
+
+#[tide::utils::async_trait]
+impl AttemptStore for MemoryStore {
+    async fn increment(&self, key: &str, window: Duration) -> tide::Result<u32> {
+        let now = SystemTime::now();
+        let mut attempts = self.attempts();
+        self.sweep(&mut attempts, now, window);
+        let attempts = attempts.entry(key.to_string()).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        // The key may have expired since the last sweep.
+        if attempts.expired(now, window) {
+            attempts.failures = 0;
+            attempts.locked_until = None;
+        }
+        attempts.failures = attempts.failures.saturating_add(1);
+        attempts.last_failure = now;
+        Ok(attempts.failures)
+    }
+
+    async fn decrement(&self, key: &str) -> tide::Result<()> {
+        if let Some(attempts) = self.attempts().get_mut(key) {
+            attempts.failures = attempts.failures.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    async fn lock(&self, key: &str, until: SystemTime) -> tide::Result<()> {
+        if let Some(attempts) = self.attempts().get_mut(key) {
+            attempts.locked_until = Some(until);
+        }
+        Ok(())
+    }
+
+    async fn locked_until(&self, key: &str) -> tide::Result<Option<SystemTime>> {
+        let now = SystemTime::now();
+        Ok(self
+            .attempts()
+            .get(key)
+            .and_then(|attempts| attempts.locked_until)
+            .filter(|until| *until > now))
+    }
+
+    async fn clear(&self, key: &str) -> tide::Result<()> {
+        self.attempts().remove(key);
+        Ok(())
+    }
+}
+
+impl Attempts {
+    fn expired(&self, now: SystemTime, window: Duration) -> bool {
+        let forgotten = self.last_failure + window;
+        forgotten < now && self.locked_until.is_none_or(|until| until < now)
+    }
+}
+
+/// Counts failed attempts per key, and locks keys out after too many.
+///
+/// After [`max_failures`][Self::max_failures] consecutive failures a key is locked out for
+/// [`lockout`][Self::lockout], doubling with each further failure up to [`max_lockout`][Self::max_lockout].
+/// Failures are forgotten after a success, or once there has been none for [`window`][Self::window].
+///
+/// Clones share their attempts.
+#[derive(Clone)]
+pub struct AttemptTracker {
+    store: Arc<dyn AttemptStore>,
+    max_failures: u32,
+    lockout: Duration,
+    max_lockout: Duration,
+    window: Duration,
+}
+
+impl AttemptTracker {
+    /// A tracker keeping attempts in memory, which locks keys out for 30 seconds after 5 failures, up to 15 minutes.
+    pub fn new() -> Self {
+        Self::with_store(MemoryStore::new())
+    }
+
+    /// A tracker keeping attempts in `store`.
+    pub fn with_store(store: impl AttemptStore) -> Self {
+        Self {
+            store: Arc::new(store),
+            max_failures: 5,
+            lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(15 * 60),
+            window: Duration::from_secs(15 * 60),
+        }
+    }
+
+    /// How many consecutive failures lock a key out.
+    #[must_use]
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /// How long a key is first locked out for.
+    #[must_use]
+    pub fn lockout(mut self, lockout: Duration) -> Self {
+        self.lockout = lockout;
+        self
+    }
+
+    /// The longest a key is locked out for, however many times it fails.
+    #[must_use]
+    pub fn max_lockout(mut self, max_lockout: Duration) -> Self {
+        self.max_lockout = max_lockout;
+        self
+    }
+
+    /// How long failures are remembered for without another.
+    #[must_use]
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Record a failed attempt for `key`, returning when it is locked out until, if this locked it out.
+    pub async fn record_failure(&self, key: &str) -> tide::Result<Option<SystemTime>> {
+        let failures = self.store.increment(key, self.window).await?;
+        self.lock_after(key, failures).await
+    }
+
+    /// Lock `key` out if `failures` consecutive failures call for it, returning until when.
+    async fn lock_after(&self, key: &str, failures: u32) -> tide::Result<Option<SystemTime>> {
+        match self.lockout_for(failures) {
+            Some(lockout) => {
+                let until = SystemTime::now() + lockout;
+                self.store.lock(key, until).await?;
+                log::warn!(
+                    "Locked out {} for {:?} after {} failed attempts",
+                    key,
+                    lockout,
+                    failures
+                );
+                Ok(Some(until))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Record a successful attempt for `key`, forgetting its failures.
+    pub async fn record_success(&self, key: &str) -> tide::Result<()> {
+        self.store.clear(key).await
+    }
+
+    /// Whether `key` is locked out now.
+    pub async fn is_locked(&self, key: &str) -> tide::Result<bool> {
+        Ok(self.locked_until(key).await?.is_some())
+    }
+
+    /// When `key` is locked out until, if it is now.
+    pub async fn locked_until(&self, key: &str) -> tide::Result<Option<SystemTime>> {
+        self.store.locked_until(key).await
+    }
+
+    /// Middleware tracking the attempts of the key `key` derives from each request, e.g. the client IP.
+    ///
+    /// Requests for locked out keys are rejected before reaching the handler. `401` and `403` responses are recorded
+    /// as failures, and `2XX` responses as successes. Requests without a key are let through untracked.
+    ///
+    /// Each attempt is counted as a failure before it reaches the handler, so that concurrent attempts cannot get
+    /// past [`max_failures`][Self::max_failures], and uncounted if its response is neither a failure nor a success.
+    pub fn middleware<F>(&self, key: F) -> AttemptMiddleware<F> {
+        AttemptMiddleware {
+            tracker: self.clone(),
+            key,
+        }
+    }
+
+    /// How long `failures` consecutive failures lock a key out for, if at all.
+    fn lockout_for(&self, failures: u32) -> Option<Duration> {
+        let doublings = failures.checked_sub(self.max_failures)?;
+        let lockout = self
+            .lockout
+            .checked_mul(2_u32.checked_pow(doublings).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_lockout);
+        Some(lockout.min(self.max_lockout))
+    }
+}
+
+impl Default for AttemptTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for AttemptTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttemptTracker")
+            .field("max_failures", &self.max_failures)
+            .field("lockout", &self.lockout)
+            .field("max_lockout", &self.max_lockout)
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+/// Middleware which locks out keys derived from requests, via [`AttemptTracker::middleware`].
+#[derive(Debug)]
+pub struct AttemptMiddleware<F> {
+    tracker: AttemptTracker,
+    key: F,
+}
+
+#[tide::utils::async_trait]
+impl<State, F> Middleware<State> for AttemptMiddleware<F>
+where
+    State: Clone + Send + Sync + 'static,
+    F: Fn(&Request<State>) -> Option<String> + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let key = match (self.key)(&req) {
+            Some(key) => key,
+            None => return Ok(next.run(req).await),
+        };
+        if let Some(until) = self.tracker.locked_until(&key).await? {
+            return Err(crate::Error::rate_limited_until(until));
+        }
+
+        // The attempt is counted before it is handled, so that attempts in flight at once are counted against each
+        // other.
+        let tracker = &self.tracker;
+        let failures = tracker.store.increment(&key, tracker.window).await?;
+        if failures > tracker.max_failures {
+            if let Some(until) = tracker.lock_after(&key, failures).await? {
+                return Err(crate::Error::rate_limited_until(until));
+            }
+        }
+
+        let res = next.run(req).await;
+        match res.status() {
+            StatusCode::Unauthorized | StatusCode::Forbidden => {
+                tracker.lock_after(&key, failures).await?;
+            }
+            status if status.is_success() => tracker.record_success(&key).await?,
+            _ => tracker.store.decrement(&key).await?,
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    #[async_std::test]
+    async fn locks_out_after_failures() -> TestResult<()> {
+        let tracker = AttemptTracker::new()
+            .max_failures(3)
+            .lockout(Duration::from_secs(10))
+            .max_lockout(Duration::from_secs(25));
+        assert_eq!(tracker.lockout_for(2), None);
+        assert_eq!(tracker.lockout_for(3), Some(Duration::from_secs(10)));
+        assert_eq!(tracker.lockout_for(4), Some(Duration::from_secs(20)));
+        assert_eq!(tracker.lockout_for(5), Some(Duration::from_secs(25)));
+        assert_eq!(tracker.lockout_for(u32::MAX), Some(Duration::from_secs(25)));
+
+        let guarded = tracker.clone();
+        let client = test_utils::create_client((), move |mut server: tide::Route<'_, Arc<()>>| {
+            server
+                .at("login")
+                .with(guarded.middleware(|req: &Request<Arc<()>>| {
+                    req.header("X-User").map(|user| user.last().to_string())
+                }))
+                .post(|req: Request<Arc<()>>| async move {
+                    match req
+                        .header("X-Password")
+                        .map(|password| password.last().as_str())
+                    {
+                        Some("hunter2") => Ok("welcome"),
+                        _ => Err(crate::Error::with_code(
+                            "invalid_credentials",
+                            StatusCode::Unauthorized,
+                            "Invalid password",
+                        )),
+                    }
+                });
+        })
+        .await?;
+
+        for _ in 0..2 {
+            let mut res = client.post("/api/v1/login").header("X-User", "ada").await?;
+            assert_status(&mut res, 401).await;
+        }
+        // A success forgets earlier failures.
+        let mut res = client
+            .post("/api/v1/login")
+            .header("X-User", "ada")
+            .header("X-Password", "hunter2")
+            .await?;
+        assert_status(&mut res, 200).await;
+
+        for _ in 0..3 {
+            let mut res = client.post("/api/v1/login").header("X-User", "ada").await?;
+            assert_status(&mut res, 401).await;
+        }
+        assert!(tracker.is_locked("ada").await?);
+        assert!(!tracker.is_locked("grace").await?);
+
+        // Even the right password is rejected while locked out.
+        let mut res = client
+            .post("/api/v1/login")
+            .header("X-User", "ada")
+            .header("X-Password", "hunter2")
+            .await?;
+        assert!(res.header("Retry-After").is_some());
+        assert!(assert_status(&mut res, 429).await.contains("rate_limited"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn counts_concurrent_attempts() -> TestResult<()> {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let tracker = AttemptTracker::new().max_failures(3);
+        let client = test_utils::create_client(
+            attempts.clone(),
+            move |mut server: tide::Route<'_, Arc<Arc<AtomicUsize>>>| {
+                server
+                    .at("login")
+                    .with(tracker.middleware(|_: &Request<_>| Some("ada".to_string())))
+                    .post(|req: Request<Arc<Arc<AtomicUsize>>>| async move {
+                        req.state().fetch_add(1, Ordering::SeqCst);
+                        async_std::task::sleep(Duration::from_millis(50)).await;
+                        Ok(tide::Response::new(StatusCode::Unauthorized))
+                    });
+            },
+        )
+        .await?;
+
+        let guesses: Vec<_> = (0..6)
+            .map(|_| {
+                let client = client.clone();
+                async_std::task::spawn(async move { client.post("/api/v1/login").await })
+            })
+            .collect();
+        for guess in guesses {
+            let status = guess.await?.status();
+            assert!(status == StatusCode::Unauthorized || status == StatusCode::TooManyRequests);
+        }
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn forgets_expired_failures() -> TestResult<()> {
+        let store = MemoryStore::new();
+        let window = Duration::from_millis(20);
+        assert_eq!(store.increment("ada", window).await?, 1);
+        assert_eq!(store.increment("ada", window).await?, 2);
+        store.increment("grace", window).await?;
+
+        async_std::task::sleep(window * 2).await;
+        assert_eq!(store.increment("ada", window).await?, 1);
+        assert!(!store.attempts().contains_key("grace"));
+        Ok(())
+    }
+}
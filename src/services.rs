@@ -0,0 +1,205 @@
+//! Named clients for downstream services, configured from the environment.
+//!
+//! For each `SERVICE_{NAME}_URL`, `preroll::main!` sets up a [client][crate::client] with that base url, which request handlers
+//! get via [`ServiceClientRequestExt::service_client`][crate::prelude::ServiceClientRequestExt::service_client]:
+//! - `SERVICE_{NAME}_URL`: The base url, e.g. `SERVICE_BILLING_URL=http://billing.internal/api/v1/` for `"billing"`.
+//! - `SERVICE_{NAME}_TIMEOUT`: The timeout in seconds. Defaults to `CLIENT_TIMEOUT`.
+//! - `SERVICE_{NAME}_RETRIES`: How many times to retry `GET`, `HEAD`, and `OPTIONS` requests which fail or get a `5xx`. Defaults to `0`.
+//!
+//! Names are matched case-insensitively, with `-` and `_` interchangeable, so `SERVICE_USER_ACCOUNTS_URL` is `"user-accounts"`.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use tide::Request;
+//!
+//! # #[allow(dead_code)]
+//! async fn get_invoices(req: Request<Arc<()>>) -> tide::Result<String> {
+//!     let billing = req.service_client("billing")?;
+//!     Ok(billing.get("invoices").recv_string().await?)
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::time::Duration;
+
+use color_eyre::eyre::WrapErr;
+use surf::{Client, Config};
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::client;
+use crate::setup::Result;
+
+/// The clients for downstream services, by normalized name.
+#[derive(Clone, Default)]
+pub struct ServiceClients {
+    clients: BTreeMap<String, Client>,
+}
+
+impl ServiceClients {
+    /// Set up a client for every `SERVICE_{NAME}_URL` in the environment.
+    pub(crate) fn from_env() -> Result<Self> {
+        let mut clients = BTreeMap::new();
+        for (key, base_url) in env::vars() {
+            let name = match key
+                .strip_prefix("SERVICE_")
+                .and_then(|key| key.strip_suffix("_URL"))
+            {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => continue,
+            };
+
+            let timeout = match env::var(format!("SERVICE_{}_TIMEOUT", name)) {
+                Ok(timeout) => Duration::from_secs(timeout.parse().wrap_err_with(|| {
+                    format!("SERVICE_{}_TIMEOUT must be a number of seconds", name)
+                })?),
+                Err(_) => client::default_timeout()?,
+            };
+            let retries: u32 = env::var(format!("SERVICE_{}_RETRIES", name))
+                .map(|v| v.parse())
+                .unwrap_or(Ok(0))
+                .wrap_err_with(|| format!("SERVICE_{}_RETRIES must be a number", name))?;
+
+            let client = client::with_options(Config::new(), &base_url, timeout, retries)
+                .wrap_err_with(|| format!("Invalid SERVICE_{}_URL", name))?;
+            clients.insert(normalize(&name), client);
+        }
+
+        Ok(Self { clients })
+    }
+
+    /// The client for a downstream service, if it is configured.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Client> {
+        self.clients.get(&normalize(name))
+    }
+
+    /// The names of the configured downstream services, normalized to `SCREAMING_SNAKE_CASE`.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.clients.keys().map(String::as_str)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+impl fmt::Debug for ServiceClients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.clients.keys()).finish()
+    }
+}
+
+/// A service name as it appears in `SERVICE_{NAME}_URL`.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Make the [`ServiceClients`] available to [`ServiceClientRequestExt::service_client`].
+#[derive(Debug, Clone)]
+pub(crate) struct ServiceClientsMiddleware {
+    services: ServiceClients,
+}
+
+impl ServiceClientsMiddleware {
+    pub(crate) fn new(services: ServiceClients) -> Self {
+        Self { services }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ServiceClientsMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(self.services.clone());
+        Ok(next.run(req).await)
+    }
+}
+
+/// An extension trait for getting the clients for downstream services, as configured by `SERVICE_{NAME}_URL`.
+pub trait ServiceClientRequestExt {
+    /// The client for a downstream service, or a `500 Internal Server Error` if it is not configured.
+    fn service_client(&self, name: &str) -> tide::Result<Client>;
+}
+
+impl<State> ServiceClientRequestExt for Request<State> {
+    fn service_client(&self, name: &str) -> tide::Result<Client> {
+        self.ext::<ServiceClients>()
+            .and_then(|services| services.get(name))
+            .cloned()
+            .ok_or_else(|| {
+                tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!(
+                        "No downstream service \"{}\". Set SERVICE_{}_URL to configure it.",
+                        name,
+                        normalize(name)
+                    ),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestClientOptions, TestResult};
+
+    #[async_std::test]
+    async fn gets_clients_by_name() -> TestResult<()> {
+        env::set_var("SERVICE_TEST_LEDGER_URL", "http://ledger.internal/api/v1/");
+        env::set_var("SERVICE_TEST_LEDGER_RETRIES", "2");
+        let services = ServiceClients::from_env()
+            .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+        assert!(services.names().any(|name| name == "TEST_LEDGER"));
+
+        let options = TestClientOptions::new().with(ServiceClientsMiddleware::new(services));
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: Route<'_, Arc<()>>| {
+                server.at("ledger").get(|req: Request<Arc<()>>| async move {
+                    let ledger = req.service_client("test-ledger")?;
+                    Ok(ledger
+                        .config()
+                        .base_url
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .unwrap_or_default())
+                });
+                server
+                    .at("missing")
+                    .get(|req: Request<Arc<()>>| async move {
+                        req.service_client("missing")?;
+                        Ok("")
+                    });
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.get("/api/v1/ledger").await?;
+        assert_eq!(
+            assert_status(&mut res, 200).await,
+            "http://ledger.internal/api/v1/"
+        );
+        let res = client.get("/api/v1/missing").await?;
+        assert_eq!(res.status(), 500);
+        Ok(())
+    }
+}
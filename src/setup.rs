@@ -4,15 +4,23 @@
 //! Prefer using `preroll::main!` whenever possible.
 
 use std::env;
+use std::ffi::OsString;
+use std::fmt;
 use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 
 use cfg_if::cfg_if;
-use tide::{Request, Server};
+use futures_lite::FutureExt;
+use tide::{http, Request, Route, Server};
 
-pub use async_std::task::block_on;
+pub use crate::runtime::block_on;
 
+pub use crate::metadata::set_default_version;
+
+use crate::builtins::admin::{setup_admin, AdminAuth, CacheFlushFn};
 use crate::builtins::monitor::setup_monitor;
+use crate::cli::{self, Args, Command};
 
 cfg_if! {
     if #[cfg(feature = "honeycomb")] {
@@ -27,9 +35,11 @@ cfg_if! {
 
 cfg_if! {
     if #[cfg(feature = "postgres")] {
+        use std::path::Path;
         use std::time::Duration;
 
-        use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+        use sqlx::migrate::Migrator;
+        use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
         use sqlx::ConnectOptions;
 
         use crate::middleware::PostgresMiddleware;
@@ -39,14 +49,31 @@ cfg_if! {
 cfg_if! {
     if #[cfg(feature = "lambda-http")] {
         use tide_lambda_listener::LambdaListener;
-    } else {
+    }
+}
+
+cfg_if! {
+    if #[cfg(not(feature = "http2"))] {
         use tide::listener::Listener;
     }
 }
 
 use crate::logging::{log_format_json, log_format_pretty};
-use crate::middleware::{JsonErrorMiddleware, LogMiddleware, RequestIdMiddleware};
+use crate::middleware::catch_panic::{self, PanicFn};
+use crate::middleware::fallback::FallbackFn;
+use crate::middleware::json_error::{self, ErrorReporter};
+use crate::middleware::logger::{self, LogLevels};
+use crate::middleware::requestid::{self, IdGenerator};
+use crate::middleware::{
+    CatchPanicMiddleware, FallbackMiddleware, JsonErrorMiddleware, LogMiddleware,
+    RequestIdMiddleware,
+};
+use crate::openapi::{setup_openapi, OpenApi};
+use crate::redact;
+use crate::scheduler::Task;
+use crate::services::{ServiceClients, ServiceClientsMiddleware};
 use crate::VariadicRoutes;
+use crate::{Environment, ErrorFormat, PanicReport};
 
 /// The result type which is expected from functions passed to `preroll::main!`,
 /// and used in the return of `setup`'s functions.
@@ -54,7 +81,415 @@ use crate::VariadicRoutes;
 /// This is a `color_eyre::eyre::Result<T>`.
 pub type Result<T> = color_eyre::eyre::Result<T>;
 
-pub async fn setup<AppState, StateFn, StateFnFuture, ServerFn, ServerFnFuture>(
+type HookFn<State> =
+    Box<dyn FnOnce(Arc<State>, Resources) -> Pin<Box<dyn Future<Output = Result<()>>>>>;
+
+type AdminRoutesFn<State> = Box<dyn for<'r> Fn(Route<'r, Arc<State>>)>;
+
+/// The admin server, with the host and port to listen on.
+type AdminServer<State> = (Server<Arc<State>>, String, u16);
+
+/// Async hooks which run around the lifetime of the server, as set via `preroll::main!`.
+///
+/// Each hook receives the server state and the [`Resources`] set up by preroll, such as the Postgres pool.
+///
+/// - `before_start` hooks run in order after the routes are set up, and before the server starts listening.
+///   An error from any of them aborts startup.
+/// - `on_shutdown` hooks run in order when the process receives `SIGINT` or `SIGTERM`, or if the server stops.
+///   Errors are logged, and the remaining hooks still run.
+/// - Scheduled [`Task`]s start after the `before_start` hooks, and run in the background for the life of the process.
+/// - [`SqsConsumer`][crate::sqs::SqsConsumer]s start after the `before_start` hooks, and stop receiving when the
+///   service shuts down, before the `on_shutdown` hooks run.
+/// - Admin routes are served only by the admin listener, which is enabled by `ADMIN_PORT`.
+/// - Cache flushes are run via the built-in `/admin/cache` routes, which are enabled by `ADMIN_TOKEN`, or `ADMIN_USERNAME` and `ADMIN_PASSWORD`.
+/// - `not_found` and `method_not_allowed` handlers replace the responses to requests which match no route.
+/// - The `on_panic` handler is called with every panic, before the `500 Internal Server Error` response for panics in request handlers.
+/// - The `error_format` applies to every error response, including those from the built-in routes.
+/// - The `error_docs_url` links the code of every error response to its documentation.
+/// - The `error_reporter` is sent every `5XX` error response in the background, after the response is ready.
+/// - The `log_levels` apply to every response, except for routes with [`LogLevels`] of their own.
+/// - Tokens starting with a `secret_prefix` are masked in error responses, reports, and logs.
+/// - The `id_generator` generates the ids of requests which arrive without an `X-Request-Id` header.
+/// - The `openapi` specification of every documented route is served at `/openapi.json`, with interactive docs at `/docs`
+///   outside of production.
+pub struct Hooks<State> {
+    before_start: Vec<HookFn<State>>,
+    on_shutdown: Vec<HookFn<State>>,
+    tasks: Vec<Task<State>>,
+    #[cfg(feature = "sqs")]
+    sqs_consumers: Vec<crate::sqs::SqsConsumer<State>>,
+    admin_routes: Vec<AdminRoutesFn<State>>,
+    cache_flushes: Vec<(&'static str, CacheFlushFn<State>)>,
+    not_found: Option<FallbackFn>,
+    method_not_allowed: Option<FallbackFn>,
+    on_panic: Option<PanicFn>,
+    error_format: Option<ErrorFormat>,
+    error_docs_url: Option<String>,
+    error_reporter: Option<Arc<dyn ErrorReporter>>,
+    log_levels: Option<LogLevels>,
+    secret_prefixes: Vec<String>,
+    id_generator: Option<Box<dyn IdGenerator>>,
+    openapi: Option<OpenApi>,
+    #[cfg(feature = "postgres")]
+    sqlx_errors: Option<json_error::SqlxErrorFn>,
+}
+
+impl<State> Hooks<State>
+where
+    State: Send + Sync + 'static,
+{
+    /// No hooks.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a hook to run before the server starts listening, e.g. to warm caches or register with service discovery.
+    #[must_use]
+    pub fn before_start<HookFnOnce, HookFuture>(mut self, hook: HookFnOnce) -> Self
+    where
+        HookFnOnce: FnOnce(Arc<State>, Resources) -> HookFuture + 'static,
+        HookFuture: Future<Output = Result<()>> + 'static,
+    {
+        self.before_start.push(Box::new(move |state, resources| {
+            Box::pin(hook(state, resources))
+        }));
+        self
+    }
+
+    /// Add a hook to run when the service shuts down, e.g. to deregister from service discovery or flush buffers.
+    #[must_use]
+    pub fn on_shutdown<HookFnOnce, HookFuture>(mut self, hook: HookFnOnce) -> Self
+    where
+        HookFnOnce: FnOnce(Arc<State>, Resources) -> HookFuture + 'static,
+        HookFuture: Future<Output = Result<()>> + 'static,
+    {
+        self.on_shutdown.push(Box::new(move |state, resources| {
+            Box::pin(hook(state, resources))
+        }));
+        self
+    }
+
+    /// Add a task to run in the background on a schedule, e.g. to prune expired rows or refresh a cache.
+    #[must_use]
+    pub fn task(mut self, task: Task<State>) -> Self {
+        self.tasks.push(task);
+        self
+    }
+
+    /// Add a consumer of an SQS queue, which handles messages in the background until the service shuts down.
+    #[cfg(feature = "sqs")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "sqs")))]
+    #[must_use]
+    pub fn sqs_consumer(mut self, consumer: crate::sqs::SqsConsumer<State>) -> Self {
+        self.sqs_consumers.push(consumer);
+        self
+    }
+
+    /// Add routes to the admin listener, alongside the `/monitor` routes, e.g. for debugging or cache control endpoints
+    /// which must never be exposed publicly.
+    ///
+    /// Startup fails if admin routes are added but `ADMIN_PORT` is not set, rather than serving them on the public listener.
+    #[must_use]
+    pub fn admin_routes<RoutesFn>(mut self, routes_fn: RoutesFn) -> Self
+    where
+        RoutesFn: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
+    {
+        self.admin_routes.push(Box::new(routes_fn));
+        self
+    }
+
+    /// Add a named cache flush, which is run via `POST /admin/cache/flush` or `POST /admin/cache/{name}/flush`,
+    /// e.g. to clear an in-memory cache in the state after a bad deploy of upstream data.
+    #[must_use]
+    pub fn cache_flush<FlushFn>(mut self, name: &'static str, flush_fn: FlushFn) -> Self
+    where
+        FlushFn: Fn(&State) + Send + Sync + 'static,
+    {
+        self.cache_flushes.push((name, Arc::new(flush_fn)));
+        self
+    }
+
+    /// Set the handler for requests which match no route, e.g. to suggest the closest matching route.
+    ///
+    /// Errors from the handler are formatted as a [`JsonError`][crate::JsonError], like any other error.
+    /// Route handlers which respond with a `404 Not Found` without a body or an error are also passed to this handler.
+    ///
+    /// ## Example:
+    ///
+    /// ```
+    /// use preroll::Hooks;
+    /// use tide::http::{Request, StatusCode};
+    ///
+    /// # #[allow(dead_code)]
+    /// fn setup_hooks() -> Hooks<()> {
+    ///     Hooks::new().not_found(|req: &Request| {
+    ///         let hint = if req.url().path().starts_with("/api/v1/user") {
+    ///             " Did you mean /api/v1/users?"
+    ///         } else {
+    ///             ""
+    ///         };
+    ///         Err(tide::Error::from_str(
+    ///             StatusCode::NotFound,
+    ///             format!("No route for {}.{}", req.url().path(), hint),
+    ///         ))
+    ///     })
+    /// }
+    /// ```
+    #[must_use]
+    pub fn not_found<HandlerFn>(mut self, handler: HandlerFn) -> Self
+    where
+        HandlerFn: Fn(&http::Request) -> tide::Result + Send + Sync + 'static,
+    {
+        self.not_found = Some(Arc::new(handler));
+        self
+    }
+
+    /// Set the handler for requests which match a route, but not any of its methods.
+    ///
+    /// Errors from the handler are formatted as a [`JsonError`][crate::JsonError], like any other error.
+    #[must_use]
+    pub fn method_not_allowed<HandlerFn>(mut self, handler: HandlerFn) -> Self
+    where
+        HandlerFn: Fn(&http::Request) -> tide::Result + Send + Sync + 'static,
+    {
+        self.method_not_allowed = Some(Arc::new(handler));
+        self
+    }
+
+    /// Set the handler for panics, e.g. to page someone or file a ticket, with the panic message, backtrace, and request.
+    ///
+    /// This is called for panics anywhere in the process once the server is set up, such as in scheduled tasks,
+    /// in which case the report has no request. Panics in request handlers are responded to with a `500 Internal Server Error`
+    /// after the handler returns.
+    ///
+    /// ## Example:
+    ///
+    /// ```
+    /// use preroll::{Hooks, PanicReport};
+    ///
+    /// # #[allow(dead_code)]
+    /// fn setup_hooks() -> Hooks<()> {
+    ///     Hooks::new().on_panic(|report: &PanicReport| {
+    ///         let path = report.request.as_ref().map(|req| req.path.as_str());
+    ///         log::error!("Panic in {:?}: {}\n{}", path, report.message, report.backtrace);
+    ///     })
+    /// }
+    /// ```
+    #[must_use]
+    pub fn on_panic<HandlerFn>(mut self, handler: HandlerFn) -> Self
+    where
+        HandlerFn: Fn(&PanicReport) + Send + Sync + 'static,
+    {
+        self.on_panic = Some(Arc::new(handler));
+        self
+    }
+
+    /// Set the format of error responses, e.g. [`ErrorFormat::ProblemDetails`] for `application/problem+json`.
+    /// Defaults to [`ErrorFormat::Json`].
+    #[must_use]
+    pub fn error_format(mut self, format: ErrorFormat) -> Self {
+        self.error_format = Some(format);
+        self
+    }
+
+    /// Link the code of every error response to its documentation under `url`, as [`JsonError::docs_url`][crate::JsonError::docs_url]
+    /// and as the [`ProblemDetails`][crate::ProblemDetails] `type`.
+    ///
+    /// E.g. with `"https://docs.example.com/errors"`, the code `"user_not_found"` links to
+    /// `"https://docs.example.com/errors/user_not_found"`.
+    #[must_use]
+    pub fn error_docs_url(mut self, url: impl Into<String>) -> Self {
+        self.error_docs_url = Some(url.into());
+        self
+    }
+
+    /// Report every `5XX` error response to `reporter`, e.g. to send them to Sentry, Slack, or PagerDuty.
+    ///
+    /// See [`ErrorReporter`][crate::ErrorReporter] for an example.
+    #[must_use]
+    pub fn error_reporter(mut self, reporter: impl ErrorReporter) -> Self {
+        self.error_reporter = Some(Arc::new(reporter));
+        self
+    }
+
+    /// Set the levels responses are logged at, by status, e.g. to log expected `404 Not Found`s at `Debug`.
+    ///
+    /// See [`LogLevels`] for an example, and to set levels for some routes only.
+    #[must_use]
+    pub fn log_levels(mut self, levels: LogLevels) -> Self {
+        self.log_levels = Some(levels);
+        self
+    }
+
+    /// Mask tokens starting with `prefix`, e.g. `"sk_live_"`, in error responses, error reports, and logs.
+    ///
+    /// Passwords in URLs, `Bearer` and `Basic` credentials, the values of fields like `password=` or `"api_key":`, and
+    /// the values of env variables with names like `*_SECRET` or `*_TOKEN` are always masked.
+    #[must_use]
+    pub fn secret_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.secret_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Generate the ids of requests which arrive without an `X-Request-Id` header via `generator`, rather than as
+    /// random UUIDs, e.g. as time-sortable [`IdScheme::UuidV7`][crate::IdScheme::UuidV7]s.
+    #[must_use]
+    pub fn id_generator(mut self, generator: impl IdGenerator) -> Self {
+        self.id_generator = Some(Box::new(generator));
+        self
+    }
+
+    /// Serve the OpenAPI specification of the routes mounted via
+    /// [`OpenApiRouteExt::documented`][crate::prelude::OpenApiRouteExt::documented] at `/openapi.json`, and
+    /// interactive docs for it at `/docs` outside of production.
+    ///
+    /// See [`preroll::openapi`][crate::openapi] for an example.
+    #[must_use]
+    pub fn openapi(mut self, openapi: OpenApi) -> Self {
+        self.openapi = Some(openapi);
+        self
+    }
+
+    /// Respond to database errors as mapped by `map`, e.g. [`map_sqlx_error`][crate::map_sqlx_error], rather than with a
+    /// `500 Internal Server Error`.
+    ///
+    /// Errors which `map` returns `None` for are left as they are.
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn sqlx_errors(
+        mut self,
+        map: impl Fn(&sqlx::Error) -> Option<tide::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.sqlx_errors = Some(Arc::new(map));
+        self
+    }
+}
+
+impl<State> Default for Hooks<State> {
+    fn default() -> Self {
+        Self {
+            before_start: Vec::new(),
+            on_shutdown: Vec::new(),
+            tasks: Vec::new(),
+            #[cfg(feature = "sqs")]
+            sqs_consumers: Vec::new(),
+            admin_routes: Vec::new(),
+            cache_flushes: Vec::new(),
+            not_found: None,
+            method_not_allowed: None,
+            on_panic: None,
+            error_format: None,
+            error_docs_url: None,
+            error_reporter: None,
+            log_levels: None,
+            secret_prefixes: Vec::new(),
+            id_generator: None,
+            openapi: None,
+            #[cfg(feature = "postgres")]
+            sqlx_errors: None,
+        }
+    }
+}
+
+impl<State> fmt::Debug for Hooks<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Hooks");
+        debug
+            .field("before_start", &self.before_start.len())
+            .field("on_shutdown", &self.on_shutdown.len())
+            .field(
+                "tasks",
+                &self.tasks.iter().map(|task| task.name).collect::<Vec<_>>(),
+            );
+        #[cfg(feature = "sqs")]
+        debug.field("sqs_consumers", &self.sqs_consumers);
+        debug
+            .field("admin_routes", &self.admin_routes.len())
+            .field(
+                "cache_flushes",
+                &self
+                    .cache_flushes
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("not_found", &self.not_found.is_some())
+            .field("method_not_allowed", &self.method_not_allowed.is_some())
+            .field("on_panic", &self.on_panic.is_some())
+            .field("error_format", &self.error_format)
+            .field("error_docs_url", &self.error_docs_url)
+            .field("error_reporter", &self.error_reporter.is_some())
+            .field("log_levels", &self.log_levels)
+            .field("secret_prefixes", &self.secret_prefixes.len())
+            .field("id_generator", &self.id_generator.is_some())
+            .field("openapi", &self.openapi);
+        #[cfg(feature = "postgres")]
+        debug.field("sqlx_errors", &self.sqlx_errors.is_some());
+        debug.finish()
+    }
+}
+
+/// Shared clients and pools which preroll sets up for its enabled features, such as the Postgres connection pool.
+///
+/// These are set up before the server state, and are handed to the state setup function (if it takes them) and to [`Hooks`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Resources {
+    /// The environment the service is running in, from `ENVIRONMENT`.
+    pub environment: Environment,
+    /// The clients for downstream services, from `SERVICE_{NAME}_URL`.
+    pub services: ServiceClients,
+    /// The connection pool used by [`PostgresMiddleware`][crate::middleware::PostgresMiddleware].
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    pub pg_pool: PgPool,
+    /// The emailer for the backend in `EMAIL_BACKEND`.
+    #[cfg(feature = "email")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "email")))]
+    pub emailer: crate::email::Emailer,
+    /// The object storage client for the bucket in `STORAGE_BUCKET`, if it is set.
+    #[cfg(feature = "storage")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "storage")))]
+    pub storage: Option<crate::storage::Storage>,
+}
+
+/// A state setup function for `preroll::main!`, which may optionally take the [`Resources`] set up by preroll.
+///
+/// This is implemented for both `async fn() -> Result<State>` and `async fn(Resources) -> Result<State>`.
+/// `Args` only distinguishes the two, and is always inferred.
+pub trait StateSetup<State, Args> {
+    type Future: Future<Output = Result<State>>;
+
+    fn setup_state(&self, resources: Resources) -> Self::Future;
+}
+
+impl<State, StateFn, StateFnFuture> StateSetup<State, ()> for StateFn
+where
+    StateFn: Fn() -> StateFnFuture,
+    StateFnFuture: Future<Output = Result<State>>,
+{
+    type Future = StateFnFuture;
+
+    fn setup_state(&self, _resources: Resources) -> Self::Future {
+        self()
+    }
+}
+
+impl<State, StateFn, StateFnFuture> StateSetup<State, (Resources,)> for StateFn
+where
+    StateFn: Fn(Resources) -> StateFnFuture,
+    StateFnFuture: Future<Output = Result<State>>,
+{
+    type Future = StateFnFuture;
+
+    fn setup_state(&self, resources: Resources) -> Self::Future {
+        self(resources)
+    }
+}
+
+pub async fn setup<AppState, StateFn, StateArgs, ServerFn, ServerFnFuture>(
     service_name: &'static str,
     state_setup: StateFn,
     server_setup: ServerFn,
@@ -62,34 +497,286 @@ pub async fn setup<AppState, StateFn, StateFnFuture, ServerFn, ServerFnFuture>(
 ) -> Result<()>
 where
     AppState: Send + Sync + 'static,
-    StateFn: Fn() -> StateFnFuture,
-    StateFnFuture: Future<Output = Result<AppState>>,
+    StateFn: StateSetup<AppState, StateArgs>,
     ServerFn: Fn(Server<Arc<AppState>>) -> ServerFnFuture,
     ServerFnFuture: Future<Output = Result<Server<Arc<AppState>>>>,
 {
+    setup_with_hooks(
+        service_name,
+        state_setup,
+        server_setup,
+        routes_setups,
+        Hooks::new,
+    )
+    .await
+}
+
+pub async fn setup_with_hooks<AppState, StateFn, StateArgs, ServerFn, ServerFnFuture, HooksFn>(
+    service_name: &'static str,
+    state_setup: StateFn,
+    server_setup: ServerFn,
+    routes_setups: impl Into<VariadicRoutes<AppState>>,
+    hooks_setup: HooksFn,
+) -> Result<()>
+where
+    AppState: Send + Sync + 'static,
+    StateFn: StateSetup<AppState, StateArgs>,
+    ServerFn: Fn(Server<Arc<AppState>>) -> ServerFnFuture,
+    ServerFnFuture: Future<Output = Result<Server<Arc<AppState>>>>,
+    HooksFn: Fn() -> Hooks<AppState>,
+{
+    let args = Args::from_env()?;
+    if args.help {
+        #[allow(clippy::print_stdout)]
+        {
+            println!("{}", cli::usage(service_name));
+        }
+        return Ok(());
+    }
+
+    let routes_setups = routes_setups.into();
+    if args.command == Command::Routes {
+        let routes_table: Vec<_> = routes_setups
+            .prefixes()?
+            .into_iter()
+            .zip(routes_setups.names.iter().copied())
+            .collect();
+        #[allow(clippy::print_stdout)]
+        {
+            println!("{}", cli::route_table(&routes_table, admin_enabled()));
+        }
+        return Ok(());
+    }
+
+    args.apply()?;
+
     initial_setup(service_name)?;
 
-    let state = state_setup().await?;
+    #[cfg(feature = "vault")]
+    crate::vault::load_secrets().await?;
 
-    let (mut base_server, server) = setup_server(service_name, state).await?;
+    #[cfg(feature = "aws-secrets")]
+    crate::aws_secrets::resolve_references().await?;
 
-    let mut server = server_setup(server).await?;
+    redact::load_env_secrets();
 
-    let mut version = 1;
-    for routes_fn in routes_setups.into().routes {
-        routes_fn(server.at(&format!("/api/v{}", version)));
-        version += 1;
+    let resources = setup_resources(service_name).await?;
+
+    if args.command == Command::Migrate {
+        return run_migrations(&resources).await;
     }
 
+    let state = state_setup.setup_state(resources.clone()).await?;
+
+    let (mut base_server, server) = setup_server_with_resources(service_name, state, &resources);
+
+    let mut server = server_setup(server).await?;
+
+    routes_setups.mount(&mut server)?;
+
     #[cfg(debug_assertions)]
     server.at("/internal-error").get(get_internal_error);
 
+    if args.command == Command::Check {
+        return check(&resources).await;
+    }
+
+    let state = server.state().clone();
+    let hooks = hooks_setup();
+
+    if let Some(handler) = hooks.on_panic {
+        catch_panic::set_panic_handler(handler);
+    }
+
+    if let Some(format) = hooks.error_format {
+        json_error::set_error_format(format);
+    }
+
+    if let Some(url) = hooks.error_docs_url {
+        json_error::set_error_docs_url(url);
+    }
+
+    if let Some(reporter) = hooks.error_reporter {
+        json_error::set_error_reporter(reporter);
+    }
+
+    if let Some(levels) = hooks.log_levels {
+        logger::set_log_levels(levels);
+    }
+
+    for prefix in hooks.secret_prefixes {
+        redact::add_secret_prefix(prefix);
+    }
+
+    if let Some(generator) = hooks.id_generator {
+        requestid::set_id_generator(generator);
+    }
+
+    #[cfg(feature = "postgres")]
+    if let Some(map) = hooks.sqlx_errors {
+        json_error::set_sqlx_errors(map);
+    }
+
+    if let Some(openapi) = hooks.openapi {
+        setup_openapi(&mut base_server, openapi, AdminAuth::from_env()?);
+    }
+
+    for hook in hooks.before_start {
+        hook(state.clone(), resources.clone()).await?;
+    }
+
+    for task in hooks.tasks {
+        crate::runtime::spawn(task.run(state.clone(), resources.clone()));
+    }
+
+    #[cfg(feature = "sqs")]
+    let sqs_consumers: Vec<_> = hooks
+        .sqs_consumers
+        .into_iter()
+        .map(|consumer| crate::runtime::spawn(consumer.run(state.clone(), resources.clone())))
+        .collect();
+
+    let mut admin_server = setup_admin_server(service_name, &state, hooks.admin_routes)?;
+
+    // The /admin routes are served by the admin listener if there is one, and only ever behind auth.
+    match (AdminAuth::from_env()?, &mut admin_server) {
+        (Some(auth), Some((admin_server, _, _))) => {
+            setup_admin(admin_server.at("/admin"), auth, hooks.cache_flushes)
+        }
+        (Some(auth), None) => setup_admin(server.at("/admin"), auth, hooks.cache_flushes),
+        (None, _) if !hooks.cache_flushes.is_empty() => log::warn!(
+            "Cache flushes were set up, but the /admin routes were not. Set ADMIN_TOKEN, or ADMIN_USERNAME and ADMIN_PASSWORD, to enable them."
+        ),
+        (None, _) => {}
+    }
+
+    if let Some(fallback) = FallbackMiddleware::new(hooks.not_found, hooks.method_not_allowed) {
+        server.with(fallback);
+    }
+
     base_server.at("/").nest(server);
-    start_server(base_server).await?;
 
+    let result = serve_until_shutdown(
+        start_servers(base_server, admin_server),
+        shutdown_signal()?,
+        crate::shutdown::start,
+        #[cfg(feature = "sqs")]
+        sqs_consumers,
+    )
+    .await;
+
+    for hook in hooks.on_shutdown {
+        if let Err(error) = hook(state.clone(), resources.clone()).await {
+            log::error!("Shutdown hook failed: {:?}", error);
+        }
+    }
+
+    result
+}
+
+/// Run pending migrations from the directory in `PGMIGRATIONS`, for the `migrate` command.
+#[cfg(feature = "postgres")]
+pub async fn run_migrations(resources: &Resources) -> Result<()> {
+    let migrations = env::var("PGMIGRATIONS").unwrap_or_else(|_| "migrations".to_string());
+
+    let migrator = Migrator::new(Path::new(&migrations)).await?;
+    migrator.run(&resources.pg_pool).await?;
+
+    log::info!("Migrations from \"{}\" are up to date", migrations);
     Ok(())
 }
 
+/// Run pending migrations, for the `migrate` command.
+///
+/// Always an error without the `"postgres"` feature, as there is nothing to migrate.
+#[cfg(not(feature = "postgres"))]
+pub async fn run_migrations(_resources: &Resources) -> Result<()> {
+    Err(color_eyre::eyre::eyre!(
+        "The migrate command requires preroll's \"postgres\" feature."
+    ))
+}
+
+/// Validate the listener configuration and connectivity to dependencies, for the `check` command.
+///
+/// By this point the state, server, and routes have already been set up successfully.
+pub async fn check(resources: &Resources) -> Result<()> {
+    if running_on_lambda() {
+        log::info!("Check: would serve AWS Lambda events");
+    } else {
+        let (host, port) = listen_address()?;
+        let workers = crate::workers::workers()?;
+        log::info!(
+            "Check: would listen on {}:{} with {} workers",
+            host,
+            port,
+            workers
+        );
+
+        if let Some((host, port)) = admin_listen_address()? {
+            log::info!("Check: admin server would listen on {}:{}", host, port);
+        }
+    }
+
+    for name in resources.services.names() {
+        log::info!("Check: downstream service {} is configured", name);
+    }
+
+    #[cfg(feature = "postgres")]
+    {
+        sqlx::query("SELECT 1").execute(&resources.pg_pool).await?;
+        log::info!("Check: postgres is reachable");
+    }
+
+    #[cfg(feature = "storage")]
+    if let Some(storage) = &resources.storage {
+        storage.check().await?;
+        log::info!("Check: storage bucket {} is reachable", storage.bucket());
+    }
+
+    log::info!("Check passed");
+    Ok(())
+}
+
+/// Serve until `servers` stop or `signal` resolves, then wind down: `start_shutdown`, and wait for WebSocket
+/// connections to close, SQS consumers to finish their messages, and emails to be sent.
+async fn serve_until_shutdown(
+    servers: impl Future<Output = Result<()>>,
+    signal: impl Future<Output = Result<()>>,
+    start_shutdown: fn(),
+    #[cfg(feature = "sqs")] sqs_consumers: Vec<async_std::task::JoinHandle<()>>,
+) -> Result<()> {
+    let result = servers.race(signal).await;
+
+    start_shutdown();
+
+    #[cfg(feature = "websockets")]
+    crate::websocket::drain().await;
+
+    #[cfg(feature = "sqs")]
+    crate::sqs::drain(sqs_consumers).await;
+
+    #[cfg(feature = "email")]
+    crate::email::drain().await;
+
+    result
+}
+
+/// Resolves once the process receives `SIGINT` or `SIGTERM`.
+///
+/// Installs a process-wide signal handler, and so must only be called once.
+fn shutdown_signal() -> Result<impl Future<Output = Result<()>>> {
+    let (sender, receiver) = async_std::channel::bounded(1);
+    ctrlc::set_handler(move || {
+        sender.try_send(()).ok();
+    })?;
+
+    Ok(async move {
+        receiver.recv().await.ok();
+        log::info!("Shutdown signal received");
+        Ok(())
+    })
+}
+
 #[cfg(debug_assertions)]
 async fn get_internal_error<AppState>(_req: Request<Arc<AppState>>) -> tide::Result<&'static str>
 where
@@ -113,8 +800,10 @@ pub fn initial_setup(service_name: &'static str) -> Result<()> {
 
     let environment = env::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string());
 
+    crate::metadata::init_service_metadata(service_name);
+
     // Logging
-    if environment.starts_with("prod") {
+    if Environment::current().is_production() {
         // Production
         log_level = env::var("LOGLEVEL")
             .map(|v| v.parse().expect("LOGLEVEL must be a valid log level."))
@@ -122,7 +811,7 @@ pub fn initial_setup(service_name: &'static str) -> Result<()> {
 
         env_logger::builder()
             .format(log_format_json)
-            .filter_level(log_level)
+            .filter_level(log::LevelFilter::Trace)
             .write_style(env_logger::WriteStyle::Never)
             .try_init()?;
     } else {
@@ -135,10 +824,13 @@ pub fn initial_setup(service_name: &'static str) -> Result<()> {
 
         env_logger::builder()
             .format(log_format_pretty)
-            .filter_level(log_level)
+            .filter_level(log::LevelFilter::Trace)
             .try_init()?;
     }
 
+    // The level is enforced by the `log` crate rather than by env_logger, so that /admin/log-level can raise it at runtime.
+    log::set_max_level(log_level);
+
     log::info!("Logger started - level: {}", log_level);
 
     // Tracing (Honeycomb)
@@ -222,76 +914,308 @@ pub fn initial_setup(service_name: &'static str) -> Result<()> {
 }
 
 #[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+pub async fn setup_resources(service_name: &'static str) -> Result<Resources> {
+    // Postgres
+    #[cfg(feature = "postgres")]
+    let pg_pool = {
+        let max_connections: u32 = env::var("PGMAXCONNECTIONS")
+            .map(|v| v.parse())
+            .unwrap_or(Ok(5))?;
+        let max_lifetime: u64 = env::var("PGMAXLIFETIME")
+            .map(|v| v.parse())
+            .unwrap_or(Ok(30 /* 30 mins */))?;
+
+        let pgurl =
+            env::var("PGURL").unwrap_or_else(|_| format!("postgres://localhost/{}", service_name));
+
+        let mut connect_opts: PgConnectOptions = pgurl.parse()?;
+        if let Ok(username) = env::var("PGUSERNAME") {
+            connect_opts = connect_opts.username(&username);
+        }
+        if let Ok(password) = env::var("PGPASSWORD") {
+            connect_opts = connect_opts.password(&password);
+        }
+        connect_opts.log_statements(log::LevelFilter::Debug);
+
+        PgPoolOptions::new()
+            .max_connections(max_connections)
+            .max_lifetime(Duration::from_secs(max_lifetime * 60 /* to seconds */))
+            .connect_with(connect_opts)
+            .await?
+    };
+
+    Ok(Resources {
+        environment: Environment::current(),
+        services: ServiceClients::from_env()?,
+        #[cfg(feature = "postgres")]
+        pg_pool,
+        #[cfg(feature = "email")]
+        emailer: crate::email::Emailer::from_env(Environment::current())?,
+        #[cfg(feature = "storage")]
+        storage: crate::storage::Storage::from_env()?,
+    })
+}
+
 pub async fn setup_server<State>(
     service_name: &'static str,
     state: State,
 ) -> Result<(Server<Arc<()>>, Server<Arc<State>>)>
+where
+    State: Send + Sync + 'static,
+{
+    let resources = setup_resources(service_name).await?;
+
+    Ok(setup_server_with_resources(service_name, state, &resources))
+}
+
+pub fn setup_server_with_resources<State>(
+    service_name: &'static str,
+    state: State,
+    resources: &Resources,
+) -> (Server<Arc<()>>, Server<Arc<State>>)
 where
     State: Send + Sync + 'static,
 {
     let mut base_server = tide::with_state(Arc::new(()));
 
-    // Set handlers for /monitor/ping, etc.
+    // Set handlers for /monitor/ping, etc., unless they are served by the admin listener instead.
     //
     // These are intentionally excluded from logging/tracing middleware.
-    setup_monitor(service_name, &mut base_server);
+    if !admin_enabled() {
+        setup_monitor(service_name, &mut base_server);
+    }
 
     let mut server = tide::with_state(Arc::new(state));
+    #[cfg(feature = "runtime-tokio")]
+    server.with(crate::runtime::TokioContextMiddleware);
     server.with(RequestIdMiddleware::new());
     server.with(LogMiddleware::new());
     server.with(JsonErrorMiddleware::new());
+    server.with(CatchPanicMiddleware::new());
+
+    if !resources.services.is_empty() {
+        server.with(ServiceClientsMiddleware::new(resources.services.clone()));
+    }
 
     #[cfg(feature = "honeycomb")]
     server.with(TraceMiddleware::new());
 
-    // Postgres
     #[cfg(feature = "postgres")]
-    {
-        let max_connections: u32 = env::var("PGMAXCONNECTIONS")
-            .map(|v| v.parse())
-            .unwrap_or(Ok(5))?;
-        let max_lifetime: u64 = env::var("PGMAXLIFETIME")
-            .map(|v| v.parse())
-            .unwrap_or(Ok(30 /* 30 mins */))?;
-
-        let pgurl =
-            env::var("PGURL").unwrap_or_else(|_| format!("postgres://localhost/{}", service_name));
+    server.with(PostgresMiddleware::from(resources.pg_pool.clone()));
 
-        let mut connect_opts: PgConnectOptions = pgurl.parse()?;
-        connect_opts.log_statements(log::LevelFilter::Debug);
+    (base_server, server)
+}
 
-        let pg_pool = PgPoolOptions::new()
-            .max_connections(max_connections)
-            .max_lifetime(Duration::from_secs(max_lifetime * 60 /* to seconds */))
-            .connect_with(connect_opts)
-            .await?;
+pub async fn start_server<State>(server: Server<Arc<State>>) -> Result<()>
+where
+    State: Send + Sync + 'static,
+{
+    #[cfg(feature = "lambda-http")]
+    if running_on_lambda() {
+        server.listen(LambdaListener::new()).await?;
+        return Ok(());
+    }
 
-        server.with(PostgresMiddleware::from(pg_pool));
+    let (host, port) = listen_address()?;
+    match crate::workers::workers()? {
+        1 => listen(server, "Server", &host, port).await?,
+        workers => crate::workers::listen(server, "Server", &host, port, workers).await?,
     }
 
-    Ok((base_server, server))
+    // Essentially "never".
+    Ok(())
 }
 
-pub async fn start_server<State>(server: Server<Arc<State>>) -> Result<()>
+/// Start the server, and the admin server if there is one.
+async fn start_servers<State>(
+    server: Server<Arc<()>>,
+    admin_server: Option<AdminServer<State>>,
+) -> Result<()>
 where
     State: Send + Sync + 'static,
 {
-    #[cfg(feature = "lambda-http")]
+    match admin_server {
+        Some((admin_server, host, port)) => {
+            start_server(server)
+                .race(listen(admin_server, "Admin server", &host, port))
+                .await
+        }
+        None => start_server(server).await,
+    }
+}
+
+async fn listen<State>(server: Server<Arc<State>>, name: &str, host: &str, port: u16) -> Result<()>
+where
+    State: Send + Sync + 'static,
+{
+    #[cfg(feature = "http2")]
     {
-        server.listen(LambdaListener::new()).await?;
+        crate::http2::listen(server, name, host, port).await?;
     }
-    #[cfg(not(feature = "lambda-http"))]
+    #[cfg(not(feature = "http2"))]
     {
-        let port: u16 = env::var("PORT").map(|v| v.parse()).unwrap_or(Ok(8080))?;
-        let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-
-        let mut listener = server.bind((host.as_str(), port)).await?;
+        let mut listener = server.bind((host, port)).await?;
         for info in listener.info().iter() {
-            log::info!("Server listening on {}", info);
+            log::info!("{} listening on {}", name, info);
         }
         listener.accept().await?;
     }
 
-    // Essentially "never".
     Ok(())
 }
+
+/// Set up the admin server, with the `/monitor` routes and any admin routes, if the admin listener is enabled.
+fn setup_admin_server<State>(
+    service_name: &'static str,
+    state: &Arc<State>,
+    admin_routes: Vec<AdminRoutesFn<State>>,
+) -> Result<Option<AdminServer<State>>>
+where
+    State: Send + Sync + 'static,
+{
+    let (host, port) = match admin_listen_address()? {
+        Some(address) => address,
+        None if admin_routes.is_empty() => return Ok(None),
+        None => {
+            return Err(color_eyre::eyre::eyre!(
+                "Admin routes were set up, but there is no admin listener. Set ADMIN_PORT to enable it."
+            ))
+        }
+    };
+
+    let mut admin_server = tide::with_state(state.clone());
+    setup_monitor(service_name, &mut admin_server);
+    for routes_fn in admin_routes {
+        routes_fn(admin_server.at("/"));
+    }
+
+    Ok(Some((admin_server, host, port)))
+}
+
+/// Whether the built-in `/monitor` routes are served by a separate admin listener, rather than the server.
+pub(crate) fn admin_enabled() -> bool {
+    !running_on_lambda() && env::var("ADMIN_PORT").is_ok()
+}
+
+/// Whether requests are served from AWS Lambda events, with the `"lambda-http"` feature in a Lambda execution
+/// environment, rather than by listening on a port.
+pub(crate) fn running_on_lambda() -> bool {
+    on_lambda(env::var_os("AWS_LAMBDA_RUNTIME_API"))
+}
+
+/// Whether requests are served from AWS Lambda events, given the value of `AWS_LAMBDA_RUNTIME_API`.
+fn on_lambda(runtime_api: Option<OsString>) -> bool {
+    cfg!(feature = "lambda-http") && runtime_api.is_some()
+}
+
+/// The host and port for the admin listener, from `ADMIN_HOST` and `ADMIN_PORT`, if enabled.
+///
+/// `ADMIN_HOST` defaults to `HOST`.
+fn admin_listen_address() -> Result<Option<(String, u16)>> {
+    use color_eyre::eyre::WrapErr;
+
+    if !admin_enabled() {
+        return Ok(None);
+    }
+
+    let port: u16 = env::var("ADMIN_PORT")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(9090))
+        .wrap_err("ADMIN_PORT must be a valid port number")?;
+    let host = env::var("ADMIN_HOST")
+        .or_else(|_| env::var("HOST"))
+        .unwrap_or_else(|_| "127.0.0.1".to_string());
+
+    Ok(Some((host, port)))
+}
+
+/// The host and port to listen on, from `HOST` and `PORT`.
+fn listen_address() -> Result<(String, u16)> {
+    use color_eyre::eyre::WrapErr;
+
+    let port: u16 = env::var("PORT")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(8080))
+        .wrap_err("PORT must be a valid port number")?;
+    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    static SHUTDOWN_STARTED: AtomicBool = AtomicBool::new(false);
+
+    fn start_shutdown() {
+        SHUTDOWN_STARTED.store(true, Ordering::SeqCst);
+    }
+
+    /// Captures messages after a delay, as if sending them to a slow server.
+    #[cfg(feature = "email")]
+    #[derive(Debug, Default)]
+    struct SlowMailer(crate::email::CaptureMailer);
+
+    #[cfg(feature = "email")]
+    #[tide::utils::async_trait]
+    impl crate::email::Mailer for SlowMailer {
+        async fn send(&self, email: &crate::email::Email) -> tide::Result<()> {
+            async_std::task::sleep(Duration::from_millis(50)).await;
+            self.0.send(email).await
+        }
+    }
+
+    #[test]
+    fn detects_lambda_runtime() {
+        assert!(!on_lambda(None));
+        assert_eq!(
+            on_lambda(Some("127.0.0.1:9001".into())),
+            cfg!(feature = "lambda-http")
+        );
+    }
+
+    #[async_std::test]
+    async fn drains_on_shutdown_signal() -> Result<()> {
+        // A consumer still handling a message when the signal arrives.
+        #[cfg(feature = "sqs")]
+        let handled = Arc::new(AtomicBool::new(false));
+        #[cfg(feature = "sqs")]
+        let consumer = {
+            let handled = handled.clone();
+            async_std::task::spawn(async move {
+                async_std::task::sleep(Duration::from_millis(50)).await;
+                handled.store(true, Ordering::SeqCst);
+            })
+        };
+
+        // An email still being sent when the signal arrives.
+        #[cfg(feature = "email")]
+        let captured = {
+            let mailer = SlowMailer::default();
+            let captured = mailer.0.clone();
+            let emailer = crate::email::Emailer::new(mailer).default_from("hello@example.com");
+            emailer.send_later(crate::email::Email::new("ada@example.com", "Hi").text("Hi"));
+            captured
+        };
+
+        let shutdown = serve_until_shutdown(
+            std::future::pending(),
+            async { Ok(()) },
+            start_shutdown,
+            #[cfg(feature = "sqs")]
+            vec![consumer],
+        );
+        async_std::future::timeout(Duration::from_secs(5), shutdown).await??;
+
+        assert!(SHUTDOWN_STARTED.load(Ordering::SeqCst));
+        #[cfg(feature = "sqs")]
+        assert!(handled.load(Ordering::SeqCst));
+        #[cfg(feature = "email")]
+        assert_eq!(captured.messages().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! Mirroring of live traffic to a shadow deployment, such as a new version of the service, to validate it against real
+//! requests before it takes any.
+//!
+//! [`ShadowMiddleware`] copies a sample of requests, with their method, path, query, headers, and body, to the shadow
+//! target in the background, once the request has been handled. Shadow responses are ignored, and never delay or
+//! affect the real response.
+//!
+//! - Shadow requests carry an `X-Shadow: true` header, and the `X-Request-Id` of the original request.
+//! - Only requests with a body of a known length up to [`max_body_size`][ShadowMiddleware::max_body_size] are mirrored,
+//!   as the body has to be buffered to send it twice.
+//! - At most [`max_in_flight`][ShadowMiddleware::max_in_flight] shadow requests are sent at once. Requests beyond that
+//!   are not mirrored, so that a slow shadow cannot build up a backlog.
+//! - Shadow requests are made via a [`preroll::client`][crate::client], so they are counted under the shadow's host in
+//!   `/monitor/status`.
+//!
+//! Shadow targets receive writes too, so they should use their own datastores.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::shadow::ShadowMiddleware;
+//! use preroll::SetupResult;
+//! use tide::Route;
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) -> SetupResult<()> {
+//!     // Mirror 5% of searches to the new search service.
+//!     let shadow = ShadowMiddleware::new("http://search-v2.internal/")?.sample(0.05);
+//!     server.at("search").with(shadow).get(|_| async { Ok("results") });
+//!     Ok(())
+//! }
+//! ```
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_std::task;
+use surf::http::headers::{CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use surf::{Client, Config, Url};
+use tide::{Middleware, Next, Request};
+
+use crate::client;
+use crate::setup::Result;
+use crate::utils::random_fraction;
+
+/// Middleware which mirrors a sample of requests to a shadow target, as described in the [module docs][self].
+#[derive(Debug, Clone)]
+pub struct ShadowMiddleware {
+    client: Client,
+    target: Url,
+    sample: f64,
+    max_body_size: usize,
+    max_in_flight: usize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ShadowMiddleware {
+    /// Mirror every request to the same path on `target`, e.g. `http://search-v2.internal/`.
+    ///
+    /// Shadow requests time out after `CLIENT_TIMEOUT` seconds, like other outgoing requests, and are not retried.
+    pub fn new(target: &str) -> Result<Self> {
+        Self::with_config(Config::new(), target)
+    }
+
+    fn with_config(config: Config, target: &str) -> Result<Self> {
+        let client = client::with_options(config, target, client::default_timeout()?, 0)?;
+        Ok(Self {
+            client,
+            target: Url::parse(target)?,
+            sample: 1.0,
+            max_body_size: 1024 * 1024,
+            max_in_flight: 64,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// The fraction of requests to mirror, from `0.0` to `1.0`. Defaults to `1.0`, every request.
+    #[must_use]
+    pub fn sample(mut self, sample: f64) -> Self {
+        self.sample = sample.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The largest request body to mirror, in bytes. Defaults to 1 MiB.
+    #[must_use]
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// The most shadow requests to send at once. Defaults to `64`.
+    #[must_use]
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Whether to mirror the next request, as a fraction `sample` of all of them.
+    fn sampled(&self) -> bool {
+        random_fraction() < self.sample
+    }
+
+    /// The shadow of `req`, if it should be mirrored, with a copy of its buffered body.
+    async fn shadow<State>(&self, req: &mut Request<State>) -> Option<surf::Request> {
+        if !self.sampled() {
+            return None;
+        }
+        let len = match req.len() {
+            Some(len) => len,
+            // Requests without a body have no length, unlike chunked ones.
+            None if req.header(TRANSFER_ENCODING).is_none() => 0,
+            None => return None,
+        };
+        if len > self.max_body_size {
+            return None;
+        }
+
+        let body = if len > 0 {
+            let body = req.body_bytes().await.ok()?;
+            req.set_body(body.clone());
+            Some(body)
+        } else {
+            None
+        };
+
+        let mut url = self.target.clone();
+        url.set_path(req.url().path());
+        url.set_query(req.url().query());
+
+        let mut shadow = surf::Request::new(req.method(), url);
+        for (name, values) in req.iter() {
+            if ![HOST, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING].contains(name) {
+                for value in values {
+                    shadow.append_header(name, value.clone());
+                }
+            }
+        }
+        shadow.insert_header("X-Shadow", "true");
+        if let Some(body) = body {
+            shadow.set_body(body);
+        }
+        Some(shadow)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ShadowMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let shadow = self.shadow(&mut req).await;
+        let res = next.run(req).await;
+
+        if let Some(shadow) = shadow {
+            if self.in_flight.fetch_add(1, Ordering::SeqCst) >= self.max_in_flight {
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                log::debug!(
+                    "Not mirroring {}, too many shadow requests in flight",
+                    shadow.url()
+                );
+            } else {
+                let client = self.client.clone();
+                let in_flight = self.in_flight.clone();
+                task::spawn(async move {
+                    // Failures are already logged and counted by the client.
+                    let _ = client.send(shadow).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestClientOptions, TestResult};
+
+    #[async_std::test]
+    async fn mirrors_requests() -> TestResult<()> {
+        let mirrored = Arc::new(Mutex::new(Vec::new()));
+        let mut target = tide::with_state(mirrored.clone());
+        target
+            .at("*")
+            .all(|mut req: Request<Arc<Mutex<Vec<String>>>>| async move {
+                let body = req.body_string().await?;
+                let shadow = req.header("X-Shadow").map(|value| value.last().to_string());
+                req.state()
+                    .lock()
+                    .map_err(|_| tide::Error::from_str(500, "poisoned"))?
+                    .push(format!(
+                        "{} {} {} {:?}",
+                        req.method(),
+                        req.url(),
+                        body,
+                        shadow
+                    ));
+                // Shadow responses are ignored.
+                Ok(tide::Response::new(500))
+            });
+        let shadow = ShadowMiddleware::with_config(
+            Config::new().set_http_client(target),
+            "http://shadow.internal/",
+        )
+        .map_err(|error| tide::Error::from_str(500, error.to_string()))?;
+
+        let options = TestClientOptions::new().with(shadow);
+        let client = test_utils::create_client_with_options(
+            (),
+            |mut server: Route<'_, Arc<()>>| {
+                server
+                    .at("users")
+                    .post(|mut req: Request<Arc<()>>| async move {
+                        Ok(format!("created {}", req.body_string().await?))
+                    });
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client
+            .post("/api/v1/users?notify=false")
+            .body_string("ada".to_string())
+            .await?;
+        assert_eq!(assert_status(&mut res, 200).await, "created ada");
+
+        for _ in 0..100 {
+            if mirrored.lock().is_ok_and(|mirrored| !mirrored.is_empty()) {
+                break;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *mirrored
+                .lock()
+                .map_err(|_| tide::Error::from_str(500, "poisoned"))?,
+            ["POST http://shadow.internal/api/v1/users?notify=false ada Some(\"true\")"]
+        );
+        Ok(())
+    }
+}
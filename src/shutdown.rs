@@ -0,0 +1,25 @@
+//! Process-wide notice that the server is shutting down, for long-lived work such as WebSocket connections and
+//! queue consumers to wind down.
+
+use async_std::channel::{self, Receiver, Sender};
+use once_cell::sync::Lazy;
+
+/// Closed when shutdown starts. Nothing is ever sent.
+static SHUTDOWN: Lazy<(Sender<()>, Receiver<()>)> = Lazy::new(|| channel::bounded(1));
+
+/// Start shutting down, waking everything waiting in [`started`].
+pub(crate) fn start() {
+    SHUTDOWN.0.close();
+}
+
+/// Whether shutdown has started.
+#[cfg_attr(not(feature = "sqs"), allow(dead_code))]
+pub(crate) fn is_started() -> bool {
+    SHUTDOWN.0.is_closed()
+}
+
+/// Resolves once shutdown has started.
+#[cfg_attr(not(any(feature = "sqs", feature = "websockets")), allow(dead_code))]
+pub(crate) async fn started() {
+    SHUTDOWN.1.recv().await.ok();
+}
@@ -0,0 +1,576 @@
+//! Sending to and consuming from Amazon SQS queues, for the `"sqs"` feature.
+//!
+//! [`SqsClient`] is configured from the same environment variables as `"aws-secrets"`, and sends messages.
+//! [`SqsConsumer`]s are added via [`Hooks::sqs_consumer`][crate::Hooks::sqs_consumer], and started by `preroll::main!`:
+//! - Messages are long-polled in batches, and each message of a batch is handled concurrently.
+//! - While a message is being handled, its visibility timeout is extended, so that slow handlers do not lead to
+//!   the message being redelivered to another consumer.
+//! - Messages whose handler succeeds are deleted in a batch. Those whose handler fails are left to be redelivered
+//!   once their visibility timeout expires, or moved to the queue's dead-letter queue by SQS.
+//! - When the service shuts down, consumers stop receiving, and handlers of received messages get up to
+//!   [`SHUTDOWN_TIMEOUT`] to finish.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::sqs::{SqsClient, SqsConsumer, SqsMessage};
+//! use preroll::{Hooks, Resources, SetupResult};
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct OrderPlaced {
+//!     order_id: u64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn on_order_placed(_state: Arc<()>, _resources: Resources, message: SqsMessage) -> SetupResult<()> {
+//!     let event: OrderPlaced = message.json()?;
+//!     log::info!("Order {} was placed", event.order_id);
+//!     Ok(())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_hooks() -> SetupResult<Hooks<()>> {
+//!     let client = SqsClient::from_env()?;
+//!     let queue_url = std::env::var("ORDERS_QUEUE_URL")?;
+//!
+//!     Ok(Hooks::new().sqs_consumer(SqsConsumer::new(client, queue_url, on_order_placed)))
+//! }
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::task::JoinHandle;
+use cfg_if::cfg_if;
+use color_eyre::eyre::{eyre, WrapErr};
+use futures_lite::FutureExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::aws::Aws;
+use crate::setup::{Resources, Result};
+use crate::shutdown;
+
+cfg_if! {
+    if #[cfg(feature = "honeycomb")] {
+        use tracing_futures::Instrument;
+    }
+}
+
+/// How long messages are hidden from other consumers once received, by default.
+pub const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long shutdown waits for the handlers of received messages to finish.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest wait for messages which SQS allows.
+const MAX_WAIT_TIME: Duration = Duration::from_secs(20);
+
+/// The longest delay between retries of a failed receive.
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(60);
+
+type HandlerFn<State> = Arc<
+    dyn Fn(Arc<State>, Resources, SqsMessage) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// A client for SQS, which signs requests with the credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+/// and optionally `AWS_SESSION_TOKEN`, in `AWS_REGION`.
+///
+/// `AWS_ENDPOINT_URL` overrides the endpoint, e.g. for a local SQS emulator.
+#[derive(Clone)]
+pub struct SqsClient {
+    aws: Arc<Aws>,
+}
+
+impl SqsClient {
+    /// A client configured from the environment.
+    pub fn from_env() -> Result<Self> {
+        let aws = Aws::from_env().wrap_err("AWS credentials are required for SQS")?;
+        Ok(Self { aws: Arc::new(aws) })
+    }
+
+    /// Send a message to a queue, returning its message id.
+    pub async fn send(&self, queue_url: &str, body: impl Into<String>) -> Result<String> {
+        let response = self
+            .call(
+                "SendMessage",
+                json!({ "QueueUrl": queue_url, "MessageBody": body.into() }),
+            )
+            .await?;
+        response["MessageId"]
+            .as_str()
+            .map(ToString::to_string)
+            .ok_or_else(|| eyre!("SQS SendMessage response has no MessageId"))
+    }
+
+    /// Serialize a value to JSON and send it to a queue, returning its message id.
+    pub async fn send_json(&self, queue_url: &str, message: &impl Serialize) -> Result<String> {
+        self.send(queue_url, serde_json::to_string(message)?).await
+    }
+
+    async fn call(&self, action: &str, body: Value) -> Result<Value> {
+        self.aws
+            .call("sqs", "1.0", &format!("AmazonSQS.{}", action), body)
+            .await
+    }
+
+    async fn receive(
+        &self,
+        queue_url: &str,
+        max_messages: u8,
+        wait_time: Duration,
+        visibility_timeout: Duration,
+    ) -> Result<Vec<SqsMessage>> {
+        let response = self
+            .call(
+                "ReceiveMessage",
+                json!({
+                    "QueueUrl": queue_url,
+                    "MaxNumberOfMessages": max_messages,
+                    "WaitTimeSeconds": wait_time.as_secs(),
+                    "VisibilityTimeout": visibility_timeout.as_secs(),
+                    "MessageSystemAttributeNames": ["ApproximateReceiveCount"],
+                }),
+            )
+            .await?;
+        parse_messages(&response)
+    }
+
+    async fn change_visibility(
+        &self,
+        queue_url: &str,
+        message: &SqsMessage,
+        visibility_timeout: Duration,
+    ) -> Result<()> {
+        self.call(
+            "ChangeMessageVisibility",
+            json!({
+                "QueueUrl": queue_url,
+                "ReceiptHandle": message.receipt_handle,
+                "VisibilityTimeout": visibility_timeout.as_secs(),
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_batch(&self, queue_url: &str, messages: &[SqsMessage]) -> Result<()> {
+        let entries: Vec<Value> = messages
+            .iter()
+            .enumerate()
+            .map(|(index, message)| {
+                json!({ "Id": index.to_string(), "ReceiptHandle": message.receipt_handle })
+            })
+            .collect();
+        let response = self
+            .call(
+                "DeleteMessageBatch",
+                json!({ "QueueUrl": queue_url, "Entries": entries }),
+            )
+            .await?;
+
+        for failed in response["Failed"].as_array().into_iter().flatten() {
+            let message = failed["Id"]
+                .as_str()
+                .and_then(|index| index.parse::<usize>().ok())
+                .and_then(|index| messages.get(index));
+            log::warn!(
+                "Could not delete SQS message {}, it will be redelivered: {}",
+                message.map(|message| message.id.as_str()).unwrap_or("?"),
+                failed["Message"].as_str().unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    /// The number of receives after which SQS moves a message to the queue's dead-letter queue, if it has one.
+    async fn max_receive_count(&self, queue_url: &str) -> Result<Option<u32>> {
+        let response = self
+            .call(
+                "GetQueueAttributes",
+                json!({ "QueueUrl": queue_url, "AttributeNames": ["RedrivePolicy"] }),
+            )
+            .await?;
+        parse_max_receive_count(&response)
+    }
+}
+
+impl fmt::Debug for SqsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqsClient").finish_non_exhaustive()
+    }
+}
+
+/// A message received from a queue.
+#[derive(Debug, Clone)]
+pub struct SqsMessage {
+    id: String,
+    body: String,
+    receive_count: u32,
+    receipt_handle: String,
+}
+
+impl SqsMessage {
+    /// The message id, as returned when it was sent.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The message body.
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// The message body, deserialized from JSON.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_str(&self.body)
+            .wrap_err_with(|| format!("SQS message {} is not valid JSON", self.id))
+    }
+
+    /// How many times the message has been received, including this time.
+    pub fn receive_count(&self) -> u32 {
+        self.receive_count
+    }
+}
+
+/// A consumer of an SQS queue, which calls a handler for every message, added via
+/// [`Hooks::sqs_consumer`][crate::Hooks::sqs_consumer].
+pub struct SqsConsumer<State> {
+    client: SqsClient,
+    queue_url: String,
+    handler: HandlerFn<State>,
+    batch_size: u8,
+    wait_time: Duration,
+    visibility_timeout: Duration,
+}
+
+impl<State> SqsConsumer<State>
+where
+    State: Send + Sync + 'static,
+{
+    /// A consumer of the queue at `queue_url`, which calls `handler` with the server state, [`Resources`], and each message.
+    ///
+    /// Messages are deleted once the handler succeeds. Errors are logged, and leave the message to be redelivered.
+    pub fn new<HandlerFnOnce, HandlerFuture>(
+        client: SqsClient,
+        queue_url: impl Into<String>,
+        handler: HandlerFnOnce,
+    ) -> Self
+    where
+        HandlerFnOnce:
+            Fn(Arc<State>, Resources, SqsMessage) -> HandlerFuture + Send + Sync + 'static,
+        HandlerFuture: Future<Output = Result<()>> + Send + 'static,
+    {
+        Self {
+            client,
+            queue_url: queue_url.into(),
+            handler: Arc::new(move |state, resources, message| {
+                Box::pin(handler(state, resources, message))
+            }),
+            batch_size: 10,
+            wait_time: MAX_WAIT_TIME,
+            visibility_timeout: DEFAULT_VISIBILITY_TIMEOUT,
+        }
+    }
+
+    /// The most messages to receive, and handle concurrently, at once, from 1 to 10. Defaults to 10.
+    #[must_use]
+    pub fn batch_size(mut self, batch_size: u8) -> Self {
+        self.batch_size = batch_size.clamp(1, 10);
+        self
+    }
+
+    /// How long each receive waits for messages, up to 20 seconds. Defaults to 20 seconds.
+    #[must_use]
+    pub fn wait_time(mut self, wait_time: Duration) -> Self {
+        self.wait_time = wait_time.min(MAX_WAIT_TIME);
+        self
+    }
+
+    /// How long received messages are hidden from other consumers, which is extended for as long as they are being
+    /// handled. Defaults to [`DEFAULT_VISIBILITY_TIMEOUT`].
+    #[must_use]
+    pub fn visibility_timeout(mut self, visibility_timeout: Duration) -> Self {
+        self.visibility_timeout = visibility_timeout.max(Duration::from_secs(2));
+        self
+    }
+
+    /// Receive and handle messages until the service shuts down.
+    pub(crate) async fn run(self, state: Arc<State>, resources: Resources) {
+        let consumer = Arc::new(self);
+        let queue_url = consumer.queue_url.as_str();
+
+        let max_receives = match consumer.client.max_receive_count(queue_url).await {
+            Ok(Some(max_receives)) => Some(max_receives),
+            Ok(None) => {
+                log::warn!(
+                    "SQS queue {} has no dead-letter queue, so failing messages are retried forever",
+                    queue_url
+                );
+                None
+            }
+            Err(error) => {
+                log::warn!(
+                    "Could not get the redrive policy of SQS queue {}: {:?}",
+                    queue_url,
+                    error
+                );
+                None
+            }
+        };
+        log::info!("SQS consumer for {} started", queue_url);
+
+        let mut backoff = Duration::from_secs(1);
+        while !shutdown::is_started() {
+            let receive = async {
+                Some(
+                    consumer
+                        .client
+                        .receive(
+                            queue_url,
+                            consumer.batch_size,
+                            consumer.wait_time,
+                            consumer.visibility_timeout,
+                        )
+                        .await,
+                )
+            };
+            let messages = match receive
+                .race(async {
+                    shutdown::started().await;
+                    None
+                })
+                .await
+            {
+                None => break,
+                Some(Ok(messages)) => messages,
+                Some(Err(error)) => {
+                    log::error!(
+                        "Receiving from SQS queue {} failed, retrying in {:?}: {:?}",
+                        queue_url,
+                        backoff,
+                        error
+                    );
+                    async_std::task::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECEIVE_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = Duration::from_secs(1);
+
+            let handles: Vec<_> = messages
+                .into_iter()
+                .map(|message| {
+                    async_std::task::spawn(consumer.clone().handle(
+                        state.clone(),
+                        resources.clone(),
+                        message,
+                        max_receives,
+                    ))
+                })
+                .collect();
+            let mut handled = Vec::with_capacity(handles.len());
+            for handle in handles {
+                handled.extend(handle.await);
+            }
+
+            if !handled.is_empty() {
+                if let Err(error) = consumer.client.delete_batch(queue_url, &handled).await {
+                    log::error!(
+                        "Deleting handled messages from SQS queue {} failed, they will be redelivered: {:?}",
+                        queue_url,
+                        error
+                    );
+                }
+            }
+        }
+
+        log::info!("SQS consumer for {} stopped", queue_url);
+    }
+
+    /// Handle a message, returning it if it succeeded and should be deleted.
+    async fn handle(
+        self: Arc<Self>,
+        state: Arc<State>,
+        resources: Resources,
+        message: SqsMessage,
+        max_receives: Option<u32>,
+    ) -> Option<SqsMessage> {
+        let handling = async {
+            (self.handler)(state, resources, message.clone())
+                .or(self.extend_visibility(&message))
+                .await
+        };
+
+        cfg_if! {
+            if #[cfg(feature = "honeycomb")] {
+                let span = tracing::info_span!(
+                    "SQS Message",
+                    queue_url = self.queue_url.as_str(),
+                    message_id = message.id.as_str(),
+                    receive_count = message.receive_count
+                );
+                let result = handling.instrument(span).await;
+            } else {
+                let result = handling.await;
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                log::debug!("SQS message {} handled", message.id);
+                Some(message)
+            }
+            Err(error) if max_receives.is_some_and(|max| message.receive_count >= max) => {
+                log::error!(
+                    "SQS message {} failed on its last receive, and will be moved to the dead-letter queue: {:?}",
+                    message.id,
+                    error
+                );
+                None
+            }
+            Err(error) => {
+                log::error!(
+                    "SQS message {} failed on receive {}, and will be redelivered: {:?}",
+                    message.id,
+                    message.receive_count,
+                    error
+                );
+                None
+            }
+        }
+    }
+
+    /// Keep extending the visibility timeout of a message, for as long as this is polled.
+    async fn extend_visibility(&self, message: &SqsMessage) -> Result<()> {
+        loop {
+            async_std::task::sleep(self.visibility_timeout / 2).await;
+            if let Err(error) = self
+                .client
+                .change_visibility(&self.queue_url, message, self.visibility_timeout)
+                .await
+            {
+                log::warn!(
+                    "Could not extend the visibility timeout of SQS message {}: {:?}",
+                    message.id,
+                    error
+                );
+            }
+        }
+    }
+}
+
+impl<State> fmt::Debug for SqsConsumer<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqsConsumer")
+            .field("queue_url", &self.queue_url)
+            .field("batch_size", &self.batch_size)
+            .field("wait_time", &self.wait_time)
+            .field("visibility_timeout", &self.visibility_timeout)
+            .finish()
+    }
+}
+
+/// Wait up to [`SHUTDOWN_TIMEOUT`] for consumers to finish handling the messages they have received.
+pub(crate) async fn drain(consumers: Vec<JoinHandle<()>>) {
+    if consumers.is_empty() {
+        return;
+    }
+
+    let all = async {
+        for consumer in consumers {
+            consumer.await;
+        }
+    };
+    if async_std::future::timeout(SHUTDOWN_TIMEOUT, all)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "SQS consumers were still handling messages after {:?}",
+            SHUTDOWN_TIMEOUT
+        );
+    }
+}
+
+fn parse_messages(response: &Value) -> Result<Vec<SqsMessage>> {
+    response["Messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|message| {
+            let field = |name: &str| {
+                message[name]
+                    .as_str()
+                    .map(ToString::to_string)
+                    .ok_or_else(|| eyre!("SQS message has no {}", name))
+            };
+            Ok(SqsMessage {
+                id: field("MessageId")?,
+                body: field("Body")?,
+                receipt_handle: field("ReceiptHandle")?,
+                receive_count: message["Attributes"]["ApproximateReceiveCount"]
+                    .as_str()
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(1),
+            })
+        })
+        .collect()
+}
+
+fn parse_max_receive_count(response: &Value) -> Result<Option<u32>> {
+    let policy = match response["Attributes"]["RedrivePolicy"].as_str() {
+        Some(policy) => policy,
+        None => return Ok(None),
+    };
+    let policy: Value = serde_json::from_str(policy).wrap_err("Invalid SQS RedrivePolicy")?;
+
+    // Documented as a number, but sometimes a string.
+    let max_receives = match &policy["maxReceiveCount"] {
+        Value::Number(max) => max.as_u64(),
+        Value::String(max) => max.parse().ok(),
+        _ => None,
+    };
+    Ok(max_receives.and_then(|max| u32::try_from(max).ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_responses() -> Result<()> {
+        let messages = parse_messages(&json!({
+            "Messages": [{
+                "MessageId": "m-1",
+                "ReceiptHandle": "r-1",
+                "Body": "{\"order_id\": 7}",
+                "Attributes": { "ApproximateReceiveCount": "3" },
+            }]
+        }))?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id(), "m-1");
+        assert_eq!(messages[0].receive_count(), 3);
+        assert_eq!(messages[0].json::<Value>()?["order_id"], 7);
+        assert!(parse_messages(&json!({}))?.is_empty());
+
+        let policy = json!({
+            "Attributes": {
+                "RedrivePolicy": "{\"deadLetterTargetArn\":\"arn:aws:sqs:us-east-1:1:dlq\",\"maxReceiveCount\":5}"
+            }
+        });
+        assert_eq!(parse_max_receive_count(&policy)?, Some(5));
+        assert_eq!(parse_max_receive_count(&json!({ "Attributes": {} }))?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! Server-Sent Events responses from async streams.
+//!
+//! [`stream`] turns a stream of [`Event`]s, or of anything which converts into them, into a `text/event-stream` response:
+//! - A `: keep-alive` comment is sent whenever no event has been sent for 15 seconds, so that proxies do not close idle streams.
+//! - Events with an [`id`][Event::id] let clients reconnect where they left off. The id they last received is sent back in
+//!   the `Last-Event-ID` request header, available via [`last_event_id`].
+//! - The response ends when the stream does, and the stream is dropped as soon as the client disconnects.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use async_std::channel::Receiver;
+//! use preroll::sse::{self, Event};
+//! use serde::Serialize;
+//! use tide::Request;
+//!
+//! #[derive(Serialize)]
+//! struct Price {
+//!     symbol: String,
+//!     cents: u64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! struct AppState {
+//!     prices: Receiver<(u64, Price)>,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn prices(req: Request<Arc<AppState>>) -> tide::Result {
+//!     let events = req.state().prices.clone();
+//!     Ok(sse::stream(futures_lite::StreamExt::map(events, |(seq, price)| {
+//!         Event::json(&price)
+//!             .unwrap_or_else(|_| Event::new(""))
+//!             .name("price")
+//!             .id(seq.to_string())
+//!     })))
+//! }
+//! ```
+
+use std::time::Duration;
+
+use async_std::channel;
+use async_std::future::timeout;
+use futures_lite::{Stream, StreamExt};
+use serde::Serialize;
+use tide::http::{headers, mime};
+use tide::{Request, Response, StatusCode};
+
+use crate::streaming;
+
+/// How long a stream may be idle before a `: keep-alive` comment is sent, by default.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A single Server-Sent Event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    name: Option<String>,
+    data: String,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// An unnamed event (a `message`, to clients) with `data`, which may span multiple lines.
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            name: None,
+            data: data.into(),
+            id: None,
+            retry: None,
+        }
+    }
+
+    /// An unnamed event with `data` serialized as JSON.
+    pub fn json<T: Serialize>(data: &T) -> serde_json::Result<Self> {
+        Ok(Self::new(serde_json::to_string(data)?))
+    }
+
+    /// Set the event's name, which clients listen for with `addEventListener(name, ...)`.
+    #[must_use]
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the event's id, which a reconnecting client sends back as `Last-Event-ID`.
+    #[must_use]
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set how long clients should wait before reconnecting, if the connection is lost.
+    #[must_use]
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// The event in the `text/event-stream` wire format.
+    fn encode(&self) -> Vec<u8> {
+        // Line breaks would end a field early, and are not valid in names or ids.
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+
+        let mut encoded = String::new();
+        if let Some(name) = &self.name {
+            encoded.push_str(&format!("event: {}\n", single_line(name)));
+        }
+        for line in self.data.lines() {
+            encoded.push_str(&format!("data: {}\n", line));
+        }
+        if self.data.is_empty() || self.data.ends_with('\n') {
+            encoded.push_str("data: \n");
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        encoded.push('\n');
+        encoded.into_bytes()
+    }
+}
+
+impl From<String> for Event {
+    fn from(data: String) -> Self {
+        Self::new(data)
+    }
+}
+
+impl From<&str> for Event {
+    fn from(data: &str) -> Self {
+        Self::new(data)
+    }
+}
+
+/// A `text/event-stream` response which sends each event from `events`, with a keep-alive comment every
+/// [`DEFAULT_KEEP_ALIVE`] of inactivity.
+pub fn stream<S, E>(events: S) -> Response
+where
+    S: Stream<Item = E> + Send + Unpin + 'static,
+    E: Into<Event>,
+{
+    stream_with_keep_alive(events, DEFAULT_KEEP_ALIVE)
+}
+
+/// Like [`stream`], with a keep-alive comment after every `keep_alive` of inactivity.
+pub fn stream_with_keep_alive<S, E>(mut events: S, keep_alive: Duration) -> Response
+where
+    S: Stream<Item = E> + Send + Unpin + 'static,
+    E: Into<Event>,
+{
+    let (sender, receiver) = channel::bounded(1);
+
+    async_std::task::spawn(async move {
+        loop {
+            let chunk = match timeout(keep_alive, events.next()).await {
+                Ok(Some(event)) => event.into().encode(),
+                Ok(None) => break,
+                Err(_) => b": keep-alive\n\n".to_vec(),
+            };
+            // The receiver is dropped with the response body when the client disconnects.
+            if sender.send(Ok(chunk)).await.is_err() {
+                log::debug!("Event stream client disconnected");
+                break;
+            }
+        }
+    });
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(streaming::channel_body(receiver));
+    res.set_content_type(mime::SSE);
+    res.insert_header(headers::CACHE_CONTROL, "no-cache");
+    // Stop nginx from buffering the stream.
+    res.insert_header("X-Accel-Buffering", "no");
+    res
+}
+
+/// The id of the last event a reconnecting client received, from the `Last-Event-ID` header.
+pub fn last_event_id<State>(req: &Request<State>) -> Option<&str> {
+    req.header("Last-Event-ID").map(|id| id.last().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_lite::stream;
+    use tide::Route;
+
+    use super::*;
+    use crate::test_utils::{self, collect_sse, TestResult};
+
+    #[async_std::test]
+    async fn streams_events() -> TestResult<()> {
+        let client = test_utils::create_client((), |mut server: Route<'_, Arc<()>>| {
+            server.at("events").get(|req: Request<Arc<()>>| async move {
+                let start: u32 = last_event_id(&req)
+                    .and_then(|id| id.parse().ok())
+                    .unwrap_or(0);
+                let events = stream::iter(start + 1..=3).map(|n| {
+                    Event::new(format!("line {}\nline {}", n, n))
+                        .name("count")
+                        .id(n.to_string())
+                });
+                Ok(stream_with_keep_alive(events, Duration::from_millis(10)))
+            });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/events").await?;
+        let events = collect_sse(&mut res, 3).await;
+        assert_eq!(events[0].event, "count");
+        assert_eq!(events[0].data, "line 1\nline 1");
+        assert_eq!(events[2].id.as_deref(), Some("3"));
+
+        let mut res = client
+            .get("/api/v1/events")
+            .header("Last-Event-ID", "2")
+            .await?;
+        let events = collect_sse(&mut res, 1).await;
+        assert_eq!(events[0].id.as_deref(), Some("3"));
+        Ok(())
+    }
+
+    #[test]
+    fn encodes_events() {
+        let event = Event::new("a\nb")
+            .name("x\ny")
+            .id("1")
+            .retry(Duration::from_secs(2));
+        assert_eq!(
+            String::from_utf8_lossy(&event.encode()),
+            "event: xy\ndata: a\ndata: b\nid: 1\nretry: 2000\n\n"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&Event::new("").encode()),
+            "data: \n\n"
+        );
+    }
+}
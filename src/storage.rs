@@ -0,0 +1,418 @@
+//! S3-compatible object storage, for the `"storage"` feature.
+//!
+//! [`Storage`] is a client for one bucket, which is set up by preroll as [`Resources::storage`][crate::Resources::storage]
+//! when `STORAGE_BUCKET` is set, or via [`Storage::new`] for other buckets.
+//! - Request bodies are streamed to the bucket as they are received, via [`put_request`][Storage::put_request].
+//! - Objects are streamed back as responses via [`respond`][Storage::respond], which passes `Range` and conditional
+//!   request headers through, so that downloads can be resumed and cached.
+//! - Clients can also upload and download objects directly, via [presigned URLs][Storage::presigned_get].
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! use color_eyre::eyre::eyre;
+//! use preroll::storage::Storage;
+//! use preroll::{Resources, SetupResult};
+//! use tide::{Request, Response, StatusCode};
+//!
+//! pub struct AppState {
+//!     storage: Storage,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_app_state(resources: Resources) -> SetupResult<AppState> {
+//!     let storage = resources
+//!         .storage
+//!         .ok_or_else(|| eyre!("STORAGE_BUCKET must be set"))?;
+//!     Ok(AppState { storage })
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn put_avatar(mut req: Request<Arc<AppState>>) -> tide::Result {
+//!     let key = format!("avatars/{}", req.param("id")?);
+//!     let storage = req.state().storage.clone();
+//!     storage.put_request(&key, &mut req).await?;
+//!     Ok(Response::new(StatusCode::Created))
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn get_avatar(req: Request<Arc<AppState>>) -> tide::Result {
+//!     let key = format!("avatars/{}", req.param("id")?);
+//!     req.state().storage.respond(&key, &req).await
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn get_avatar_link(req: Request<Arc<AppState>>) -> tide::Result {
+//!     let key = format!("avatars/{}", req.param("id")?);
+//!     let url = req.state().storage.presigned_get(&key, Duration::from_secs(300))?;
+//!     Ok(Response::builder(StatusCode::SeeOther)
+//!         .header("Location", url.as_str())
+//!         .build())
+//! }
+//! ```
+
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use color_eyre::eyre::{eyre, WrapErr};
+use sha2::{Digest, Sha256};
+use tide::http::{Method, Url};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::aws::{self, Aws, UNSIGNED_PAYLOAD};
+use crate::setup::Result;
+
+/// The largest upload accepted by [`put`][Storage::put] by default, which is also S3's limit for a single upload.
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// The longest expiry of a presigned URL which S3 allows.
+const MAX_PRESIGNED_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Request headers passed through to the bucket by [`respond`][Storage::respond].
+const FORWARDED_REQUEST_HEADERS: &[&str] = &[
+    "if-match",
+    "if-modified-since",
+    "if-none-match",
+    "if-range",
+    "if-unmodified-since",
+    "range",
+];
+
+/// Object headers passed back to the client by [`respond`][Storage::respond].
+const FORWARDED_RESPONSE_HEADERS: &[&str] = &[
+    "accept-ranges",
+    "cache-control",
+    "content-disposition",
+    "content-encoding",
+    "content-range",
+    "content-type",
+    "etag",
+    "last-modified",
+];
+
+/// A client for one bucket of S3, or an S3-compatible store.
+///
+/// Requests are signed with the credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and optionally
+/// `AWS_SESSION_TOKEN`, in `AWS_REGION`. `STORAGE_ENDPOINT_URL`, or else `AWS_ENDPOINT_URL`, replaces the S3 endpoint,
+/// e.g. for MinIO, in which case objects are addressed by path rather than by virtual host.
+#[derive(Clone)]
+pub struct Storage {
+    aws: Arc<Aws>,
+    bucket: String,
+    endpoint: Option<String>,
+    max_upload_size: u64,
+}
+
+impl Storage {
+    /// A client for `bucket`, configured from the environment.
+    pub fn new(bucket: impl Into<String>) -> Result<Self> {
+        let aws = Aws::from_env().wrap_err("AWS credentials are required for storage")?;
+        let endpoint = env::var("STORAGE_ENDPOINT_URL")
+            .ok()
+            .or_else(|| aws.endpoint().map(ToString::to_string));
+
+        Ok(Self {
+            aws: Arc::new(aws),
+            bucket: bucket.into(),
+            endpoint,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+        })
+    }
+
+    /// The client for the bucket in `STORAGE_BUCKET`, if it is set.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        match env::var("STORAGE_BUCKET") {
+            Ok(bucket) => Ok(Some(Self::new(bucket)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The largest upload accepted by [`put`][Storage::put] and [`put_request`][Storage::put_request], in bytes.
+    /// Defaults to [`DEFAULT_MAX_UPLOAD_SIZE`].
+    #[must_use]
+    pub fn max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.max_upload_size = max_upload_size;
+        self
+    }
+
+    /// The bucket's name.
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Upload `body` to `key`, with the body's content type.
+    ///
+    /// The body must have a known length, or this is a `411 Length Required` error. Bodies larger than the
+    /// [`max_upload_size`][Storage::max_upload_size] are a `413 Payload Too Large` error.
+    pub async fn put(&self, key: &str, body: impl Into<Body>) -> tide::Result<()> {
+        let body = body.into();
+        match body.len() {
+            None => {
+                return Err(tide::Error::from_str(
+                    StatusCode::LengthRequired,
+                    "Uploads must have a Content-Length",
+                ))
+            }
+            Some(len) if len as u64 > self.max_upload_size => {
+                return Err(tide::Error::from_str(
+                    StatusCode::PayloadTooLarge,
+                    format!("Uploads must be at most {} bytes", self.max_upload_size),
+                ))
+            }
+            Some(_) => {}
+        }
+
+        let headers = vec![("content-type", body.mime().to_string())];
+        let mut res = self
+            .send(Method::Put, key, headers, body, UNSIGNED_PAYLOAD)
+            .await?;
+        if !res.status().is_success() {
+            return Err(upstream_error("PUT", key, &mut res).await);
+        }
+        Ok(())
+    }
+
+    /// Stream the body of `req` to `key`, with the request's content type.
+    ///
+    /// See [`put`][Storage::put] for the errors.
+    pub async fn put_request<State>(
+        &self,
+        key: &str,
+        req: &mut Request<State>,
+    ) -> tide::Result<()> {
+        let mut body = req.take_body();
+        if let Some(content_type) = req.content_type() {
+            body.set_mime(content_type);
+        }
+        self.put(key, body).await
+    }
+
+    /// Respond to `req` with the object at `key`, streamed from the bucket.
+    ///
+    /// `Range` and conditional headers are passed through, so the response may be a `206 Partial Content`,
+    /// `304 Not Modified`, or `416 Range Not Satisfiable`. A missing object is a `404 Not Found`.
+    pub async fn respond<State>(&self, key: &str, req: &Request<State>) -> tide::Result {
+        let method = match req.method() {
+            Method::Head => Method::Head,
+            _ => Method::Get,
+        };
+        let headers = FORWARDED_REQUEST_HEADERS
+            .iter()
+            .filter_map(|name| {
+                req.header(*name)
+                    .map(|value| (*name, value.last().to_string()))
+            })
+            .collect();
+
+        let mut upstream = self
+            .send(method, key, headers, Body::empty(), &empty_payload_hash())
+            .await?;
+        let status = upstream.status();
+        match status {
+            StatusCode::NotFound => Ok(Response::new(StatusCode::NotFound)),
+            StatusCode::NotModified | StatusCode::RequestedRangeNotSatisfiable => {
+                Ok(forward_response(status, &mut upstream, method))
+            }
+            status if status.is_success() => Ok(forward_response(status, &mut upstream, method)),
+            _ => Err(upstream_error("GET", key, &mut upstream).await),
+        }
+    }
+
+    /// Delete the object at `key`. Deleting a missing object is not an error.
+    pub async fn delete(&self, key: &str) -> tide::Result<()> {
+        let mut res = self
+            .send(
+                Method::Delete,
+                key,
+                Vec::new(),
+                Body::empty(),
+                &empty_payload_hash(),
+            )
+            .await?;
+        if !res.status().is_success() {
+            return Err(upstream_error("DELETE", key, &mut res).await);
+        }
+        Ok(())
+    }
+
+    /// A URL which anyone can download the object at `key` from, until `expires_in` from now (at most 7 days).
+    pub fn presigned_get(&self, key: &str, expires_in: Duration) -> tide::Result<Url> {
+        self.presign(Method::Get, key, expires_in)
+    }
+
+    /// A URL which anyone can upload an object to `key` with, until `expires_in` from now (at most 7 days).
+    pub fn presigned_put(&self, key: &str, expires_in: Duration) -> tide::Result<Url> {
+        self.presign(Method::Put, key, expires_in)
+    }
+
+    /// Check that the bucket exists and the credentials can access it.
+    pub(crate) async fn check(&self) -> Result<()> {
+        let url = bucket_url(self.endpoint.as_deref(), self.aws.region(), &self.bucket)?;
+        let res = self
+            .aws
+            .send(
+                "s3",
+                Method::Head,
+                url,
+                Vec::new(),
+                Body::empty(),
+                &empty_payload_hash(),
+            )
+            .await
+            .map_err(|e| eyre!("Storage bucket \"{}\" is unreachable: {}", self.bucket, e))?;
+        if !res.status().is_success() {
+            return Err(eyre!(
+                "Storage bucket \"{}\" responded with {}",
+                self.bucket,
+                res.status()
+            ));
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        headers: Vec<(&'static str, String)>,
+        body: Body,
+        payload_hash: &str,
+    ) -> tide::Result<surf::Response> {
+        let url = self.object_url(key)?;
+        self.aws
+            .send("s3", method, url, headers, body, payload_hash)
+            .await
+            .map_err(|e| {
+                tide::Error::from_str(
+                    StatusCode::BadGateway,
+                    format!("Storage {} of \"{}\" failed: {}", method, key, e),
+                )
+            })
+    }
+
+    fn presign(&self, method: Method, key: &str, expires_in: Duration) -> tide::Result<Url> {
+        let url = self.object_url(key)?;
+        let expires_in = expires_in.min(MAX_PRESIGNED_EXPIRY);
+        self.aws
+            .presign("s3", method, url, expires_in, Utc::now())
+            .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))
+    }
+
+    fn object_url(&self, key: &str) -> tide::Result<Url> {
+        if key.is_empty() {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                "Storage keys must not be empty",
+            ));
+        }
+        let mut url = bucket_url(self.endpoint.as_deref(), self.aws.region(), &self.bucket)
+            .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))?;
+
+        let encoded_key = key
+            .split('/')
+            .map(aws::uri_encode)
+            .collect::<Vec<_>>()
+            .join("/");
+        let path = format!("{}/{}", url.path().trim_end_matches('/'), encoded_key);
+        url.set_path(&path);
+        Ok(url)
+    }
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Storage")
+            .field("bucket", &self.bucket)
+            .field("endpoint", &self.endpoint)
+            .field("max_upload_size", &self.max_upload_size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The URL of a bucket, by virtual host on S3, or by path on custom endpoints and for bucket names with dots,
+/// which virtual hosts' TLS certificates do not cover.
+fn bucket_url(endpoint: Option<&str>, region: &str, bucket: &str) -> Result<Url> {
+    let url = match endpoint {
+        Some(endpoint) => format!(
+            "{}/{}",
+            endpoint.trim_end_matches('/'),
+            aws::uri_encode(bucket)
+        ),
+        None if bucket.contains('.') => format!("https://s3.{}.amazonaws.com/{}", region, bucket),
+        None => format!("https://{}.s3.{}.amazonaws.com", bucket, region),
+    };
+    url.parse()
+        .wrap_err_with(|| format!("Invalid storage URL \"{}\"", url))
+}
+
+fn empty_payload_hash() -> String {
+    hex::encode(Sha256::digest(b""))
+}
+
+/// A response with the status, headers, and (streamed) body of an object.
+fn forward_response(status: StatusCode, upstream: &mut surf::Response, method: Method) -> Response {
+    let mut res = Response::new(status);
+    for name in FORWARDED_RESPONSE_HEADERS {
+        if let Some(value) = upstream.header(*name) {
+            res.insert_header(*name, value.last().as_str());
+        }
+    }
+    if method == Method::Get && status.is_success() {
+        res.set_body(upstream.take_body());
+    }
+    res
+}
+
+/// A `502 Bad Gateway` error with S3's error message, which is logged.
+async fn upstream_error(action: &str, key: &str, res: &mut surf::Response) -> tide::Error {
+    let body = res.body_string().await.unwrap_or_default();
+    let message = body
+        .split_once("<Message>")
+        .and_then(|(_, rest)| rest.split_once("</Message>"))
+        .map(|(message, _)| message)
+        .unwrap_or_default();
+
+    log::error!(
+        "Storage {} of \"{}\" failed with {}: {}",
+        action,
+        key,
+        res.status(),
+        message
+    );
+    tide::Error::from_str(
+        StatusCode::BadGateway,
+        format!("Storage {} of \"{}\" failed", action, key),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_bucket_urls() -> Result<()> {
+        assert_eq!(
+            bucket_url(None, "us-east-1", "uploads")?.as_str(),
+            "https://uploads.s3.us-east-1.amazonaws.com/"
+        );
+        assert_eq!(
+            bucket_url(None, "eu-west-1", "uploads.example.com")?.as_str(),
+            "https://s3.eu-west-1.amazonaws.com/uploads.example.com"
+        );
+        assert_eq!(
+            bucket_url(Some("http://localhost:9000/"), "us-east-1", "uploads")?.as_str(),
+            "http://localhost:9000/uploads"
+        );
+        assert_eq!(
+            aws::uri_encode("report (final).pdf"),
+            "report%20%28final%29.pdf"
+        );
+        Ok(())
+    }
+}
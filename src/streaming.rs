@@ -0,0 +1,416 @@
+//! Chunked response bodies from async streams and readers, for responses too large to buffer in memory.
+//!
+//! The source is only read as fast as the client receives the response, so a slow client slows the source down
+//! rather than filling up memory. When the client disconnects, the source is dropped, which is where any cleanup,
+//! such as returning a database connection to its pool, happens.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use futures_lite::stream::{self, StreamExt};
+//! use preroll::streaming;
+//! use tide::{Request, Response};
+//!
+//! # #[allow(dead_code)]
+//! async fn export_csv(_req: Request<Arc<()>>) -> tide::Result {
+//!     let rows = stream::iter(0..1_000_000).map(|n| format!("{},{}\n", n, n * n));
+//!
+//!     Ok(Response::builder(200)
+//!         .content_type("text/csv")
+//!         .header("Content-Disposition", "attachment; filename=\"squares.csv\"")
+//!         .body(streaming::stream(rows))
+//!         .build())
+//! }
+//! ```
+//!
+//! ## JSON rows
+//!
+//! Rows which implement `Serialize`, such as from a `sqlx` query, can be streamed as newline-delimited JSON via
+//! [`ndjson`], or as one JSON array via [`json_array`]. Rows are serialized into chunks of up to [`CHUNK_SIZE`] bytes,
+//! and at most one chunk is buffered ahead of the client, however many rows there are.
+//!
+//! With the `postgres` feature, [`fetch`] runs a query on its own connection from the pool, so that its rows can be
+//! streamed after the handler has returned. The query does not take part in the request's transaction from
+//! [`PostgresMiddleware`][crate::middleware::PostgresMiddleware].
+//!
+//! ```no_run
+//! # #[cfg(feature = "postgres")]
+//! # mod example {
+//! use preroll::streaming;
+//! use serde::Serialize;
+//! use sqlx::postgres::PgPool;
+//! use tide::{Request, Response};
+//!
+//! #[derive(Serialize, sqlx::FromRow)]
+//! struct Order {
+//!     id: i64,
+//!     total: i64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn export_orders(req: Request<PgPool>) -> tide::Result {
+//!     let orders = streaming::fetch(req.state().clone(), |conn| {
+//!         sqlx::query_as::<_, Order>("SELECT id, total FROM orders ORDER BY id").fetch(conn)
+//!     });
+//!
+//!     // `{"id":1,"total":250}\n{"id":2,"total":990}\n...`, with `Content-Type: application/x-ndjson`.
+//!     Ok(Response::builder(200).body(streaming::ndjson(orders)).build())
+//! }
+//! # }
+//! ```
+
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::channel::{self, Receiver};
+use futures_lite::io::BufReader;
+use futures_lite::{stream, AsyncRead, Stream, StreamExt};
+use serde::Serialize;
+use tide::http::mime;
+use tide::Body;
+
+#[cfg(feature = "postgres")]
+use sqlx::postgres::{PgConnection, PgPool};
+
+/// The most bytes of rows which [`ndjson`] and [`json_array`] serialize into one chunk.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A chunked body of each chunk from `chunks`, such as `Vec<u8>`, `String`, or `bytes::Bytes`.
+pub fn stream<S, B>(chunks: S) -> Body
+where
+    S: Stream<Item = B> + Send + 'static,
+    B: Into<Vec<u8>> + Send + 'static,
+{
+    try_stream(chunks.map(Ok::<B, io::Error>))
+}
+
+/// A chunked body of each chunk from `chunks`, until the first error.
+///
+/// As the status has already been sent by then, an error aborts the response, so that the client sees an incomplete
+/// body rather than one which looks complete. The error is logged.
+pub fn try_stream<S, B, E>(chunks: S) -> Body
+where
+    S: Stream<Item = Result<B, E>> + Send + 'static,
+    B: Into<Vec<u8>> + Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    let (sender, receiver) = channel::bounded(1);
+
+    async_std::task::spawn(async move {
+        let mut chunks = Box::pin(chunks);
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map(Into::into).map_err(|error| {
+                let error = io::Error::other(error);
+                log::error!("Streaming response failed: {}", error);
+                error
+            });
+            let failed = chunk.is_err();
+            // The receiver is dropped with the response body when the client disconnects.
+            if sender.send(chunk).await.is_err() {
+                log::debug!("Streaming response client disconnected");
+                break;
+            }
+            if failed {
+                break;
+            }
+        }
+    });
+
+    channel_body(receiver)
+}
+
+/// A chunked `application/x-ndjson` body of each row from `rows` as JSON on its own line, until the first error.
+///
+/// Errors abort the response, as with [`try_stream`].
+pub fn ndjson<S, T, E>(rows: S) -> Body
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    T: Serialize + Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    let mut body = try_stream(json_rows(rows, JsonRows::Ndjson));
+    body.set_mime("application/x-ndjson");
+    body
+}
+
+/// A chunked `application/json` body of the rows from `rows` as one JSON array, until the first error.
+///
+/// Errors abort the response, as with [`try_stream`], so that the client sees invalid JSON rather than a shorter array.
+pub fn json_array<S, T, E>(rows: S) -> Body
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    T: Serialize + Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    let mut body = try_stream(json_rows(rows, JsonRows::Array));
+    body.set_mime(mime::JSON);
+    body
+}
+
+/// The rows of `query`, run on a connection of its own from `pool`, as a stream which outlives the handler.
+///
+/// The connection is returned to the pool once every row is read, or when the stream is dropped, e.g. because the
+/// client disconnected.
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub fn fetch<T, F>(
+    pool: PgPool,
+    query: F,
+) -> impl Stream<Item = Result<T, sqlx::Error>> + Send + 'static
+where
+    T: Send + 'static,
+    F: for<'c> FnOnce(
+            &'c mut PgConnection,
+        ) -> Pin<Box<dyn Stream<Item = Result<T, sqlx::Error>> + Send + 'c>>
+        + Send
+        + 'static,
+{
+    let (sender, receiver) = channel::bounded(1);
+
+    async_std::task::spawn(async move {
+        let mut conn = match pool.acquire().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                let _ = sender.send(Err(error)).await;
+                return;
+            }
+        };
+        let mut rows = query(&mut conn);
+        while let Some(row) = rows.next().await {
+            if sender.send(row).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+/// How [`json_rows`] delimits rows.
+#[derive(Debug, Clone, Copy)]
+enum JsonRows {
+    Ndjson,
+    Array,
+}
+
+/// Chunks of `rows` serialized as JSON, each up to about [`CHUNK_SIZE`] bytes.
+fn json_rows<S, T, E>(
+    rows: S,
+    format: JsonRows,
+) -> impl Stream<Item = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send + 'static
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    T: Serialize + Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    let state = (Box::pin(rows), 0_usize, false);
+    stream::unfold(state, move |(mut rows, mut count, done)| async move {
+        if done {
+            return None;
+        }
+
+        let mut chunk = Vec::new();
+        if count == 0 {
+            if let JsonRows::Array = format {
+                chunk.push(b'[');
+            }
+        }
+        while chunk.len() < CHUNK_SIZE {
+            let row = match rows.next().await {
+                Some(Ok(row)) => row,
+                Some(Err(error)) => return Some((Err(error.into()), (rows, count, true))),
+                None => {
+                    if let JsonRows::Array = format {
+                        chunk.push(b']');
+                    }
+                    return Some((Ok(chunk), (rows, count, true)));
+                }
+            };
+            if count > 0 {
+                if let JsonRows::Array = format {
+                    chunk.push(b',');
+                }
+            }
+            if let Err(error) = serde_json::to_writer(&mut chunk, &row) {
+                return Some((Err(error.into()), (rows, count, true)));
+            }
+            if let JsonRows::Ndjson = format {
+                chunk.push(b'\n');
+            }
+            count += 1;
+        }
+        Some((Ok(chunk), (rows, count, false)))
+    })
+}
+
+/// A chunked body read from `reader`.
+pub fn reader(reader: impl AsyncRead + Send + Sync + Unpin + 'static) -> Body {
+    Body::from_reader(BufReader::new(reader), None)
+}
+
+/// A chunked body of the chunks sent to `receiver`, which ends when every sender is dropped.
+pub(crate) fn channel_body(receiver: Receiver<io::Result<Vec<u8>>>) -> Body {
+    reader(ChunkReader {
+        receiver,
+        chunk: Vec::new(),
+        read: 0,
+    })
+}
+
+/// Reads the chunks sent to a channel.
+struct ChunkReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    read: usize,
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.read == self.chunk.len() {
+            match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.chunk = chunk;
+                    self.read = 0;
+                }
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(error)),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.read);
+        let start = self.read;
+        buf[..len].copy_from_slice(&self.chunk[start..start + len]);
+        self.read += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures_lite::stream;
+    use tide::{Request, Route};
+
+    use super::*;
+    use crate::test_utils::{self, assert_status, TestResult};
+
+    /// Records when it is dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[async_std::test]
+    async fn streams_chunks() -> TestResult<()> {
+        let dropped = Arc::new(AtomicBool::new(false));
+
+        let flag = dropped.clone();
+        let client = test_utils::create_client((), move |mut server: Route<'_, Arc<()>>| {
+            server
+                .at("count")
+                .get(|_| async { Ok(stream(stream::iter(1..=3).map(|n| format!("{}\n", n)))) });
+
+            let flag = flag.clone();
+            server.at("forever").get(move |_: Request<Arc<()>>| {
+                let guard = DropFlag(flag.clone());
+                async move {
+                    let chunks = stream::repeat(b"chunk".to_vec()).map(move |chunk| {
+                        let _guard = &guard;
+                        chunk
+                    });
+                    Ok(stream(chunks))
+                }
+            });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/count").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "1\n2\n3\n");
+
+        let mut res = client.get("/api/v1/forever").await?;
+        let mut chunk = [0; 5];
+        futures_lite::AsyncReadExt::read_exact(&mut res, &mut chunk).await?;
+        assert_eq!(&chunk, b"chunk");
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        drop(res);
+        for _ in 0..100 {
+            if dropped.load(Ordering::SeqCst) {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn streams_json_rows() -> TestResult<()> {
+        fn rows(count: u32) -> impl Stream<Item = Result<serde_json::Value, io::Error>> {
+            stream::iter(0..count).map(|id| Ok(serde_json::json!({ "id": id })))
+        }
+
+        let client = test_utils::create_client((), |mut server: Route<'_, Arc<()>>| {
+            server.at("ndjson").get(|_| async { Ok(ndjson(rows(3))) });
+            server
+                .at("array")
+                .get(|_| async { Ok(json_array(rows(3))) });
+            server
+                .at("empty")
+                .get(|_| async { Ok(json_array(rows(0))) });
+            server
+                .at("large")
+                .get(|_| async { Ok(json_array(rows(10_000))) });
+        })
+        .await?;
+
+        let mut res = client.get("/api/v1/ndjson").await?;
+        assert_eq!(
+            res.content_type().map(|mime| mime.essence().to_string()),
+            Some("application/x-ndjson".to_string())
+        );
+        assert_eq!(
+            assert_status(&mut res, 200).await,
+            "{\"id\":0}\n{\"id\":1}\n{\"id\":2}\n"
+        );
+
+        let mut res = client.get("/api/v1/array").await?;
+        assert_eq!(res.content_type(), Some(mime::JSON));
+        assert_eq!(
+            assert_status(&mut res, 200).await,
+            r#"[{"id":0},{"id":1},{"id":2}]"#
+        );
+
+        let mut res = client.get("/api/v1/empty").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "[]");
+
+        let mut res = client.get("/api/v1/large").await?;
+        let large: Vec<serde_json::Value> = res.body_json().await?;
+        assert_eq!(large.len(), 10_000);
+        assert_eq!(large[9_999], serde_json::json!({ "id": 9_999 }));
+
+        let mut chunks = Box::pin(json_rows(rows(10_000), JsonRows::Ndjson));
+        let mut count = 0;
+        while let Some(chunk) = chunks.next().await {
+            assert!(chunk.is_ok_and(|chunk| chunk.len() < CHUNK_SIZE + 32));
+            count += 1;
+        }
+        assert!(count > 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use log::{kv, Level, LevelFilter, Log, Metadata, Record};
+
+use super::init_test_globals;
+
+type Sink = Arc<Mutex<Vec<CapturedLog>>>;
+
+async_std::task_local! {
+    static CAPTURE: RefCell<Option<Sink>> = RefCell::new(None);
+}
+
+/// The global logger used by test_utils.
+///
+/// Forwards to the regular (env_logger) logger, and additionally records every log emitted from a task which is within [`capture_logs`].
+pub(crate) struct TestLogger {
+    inner: env_logger::Logger,
+}
+
+impl TestLogger {
+    /// Install as the global logger.
+    ///
+    /// The global max level is set to `Trace` so that captures see every record, regardless of `LOGLEVEL`.
+    pub(crate) fn init(inner: env_logger::Logger) {
+        if log::set_boxed_logger(Box::new(TestLogger { inner })).is_ok() {
+            log::set_max_level(LevelFilter::Trace);
+        }
+    }
+}
+
+impl Log for TestLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner.enabled(metadata) || current_sink().is_some()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if let Some(sink) = current_sink() {
+            let captured = CapturedLog::from_record(record);
+            sink.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(captured);
+        }
+
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn current_sink() -> Option<Sink> {
+    CAPTURE
+        .try_with(|capture| capture.borrow().clone())
+        .ok()
+        .flatten()
+}
+
+/// A log record captured by [`capture_logs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedLog {
+    /// The level the record was logged at.
+    pub level: Level,
+    /// The target of the record, usually the module path it was logged from.
+    pub target: String,
+    /// The formatted log message.
+    pub message: String,
+    /// The structured key-value fields of the record, formatted as strings.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl CapturedLog {
+    fn from_record(record: &Record<'_>) -> Self {
+        struct Visitor<'f> {
+            fields: &'f mut BTreeMap<String, String>,
+        }
+
+        impl<'kvs, 'f> kv::Visitor<'kvs> for Visitor<'f> {
+            fn visit_pair(
+                &mut self,
+                key: kv::Key<'kvs>,
+                val: kv::Value<'kvs>,
+            ) -> Result<(), kv::Error> {
+                self.fields.insert(key.to_string(), val.to_string());
+                Ok(())
+            }
+        }
+
+        let mut fields = BTreeMap::new();
+        record
+            .key_values()
+            .visit(&mut Visitor {
+                fields: &mut fields,
+            })
+            .ok();
+
+        Self {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            fields,
+        }
+    }
+}
+
+impl fmt::Display for CapturedLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} | {}", self.level, self.target, self.message)?;
+        for (key, value) in &self.fields {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Criteria for matching [`CapturedLog`]s. All specified criteria must match.
+///
+/// ## Example:
+///
+/// ```
+/// use log::Level;
+/// use preroll::test_utils::LogMatcher;
+///
+/// let matcher = LogMatcher::new()
+///     .level(Level::Warn)
+///     .message_contains("Client Error")
+///     .field("status", "404");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LogMatcher {
+    level: Option<Level>,
+    message: Option<String>,
+    fields: Vec<(String, String)>,
+}
+
+impl LogMatcher {
+    /// Create a matcher which matches any record.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match records logged at exactly this level.
+    #[must_use]
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Only match records whose message contains this substring.
+    #[must_use]
+    pub fn message_contains(mut self, substring: impl Into<String>) -> Self {
+        self.message = Some(substring.into());
+        self
+    }
+
+    /// Only match records with a structured field equal to this value, when formatted.
+    #[must_use]
+    pub fn field(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.fields.push((key.into(), value.to_string()));
+        self
+    }
+
+    /// Whether a record satisfies all criteria of this matcher.
+    pub fn matches(&self, log: &CapturedLog) -> bool {
+        self.level.map(|level| log.level == level).unwrap_or(true)
+            && self
+                .message
+                .as_ref()
+                .map(|message| log.message.contains(message.as_str()))
+                .unwrap_or(true)
+            && self
+                .fields
+                .iter()
+                .all(|(key, value)| log.fields.get(key) == Some(value))
+    }
+}
+
+/// The log records emitted during a [`capture_logs`] closure, in order.
+#[derive(Debug, Clone, Default)]
+pub struct CapturedLogs {
+    records: Vec<CapturedLog>,
+}
+
+impl CapturedLogs {
+    /// All captured records, in order.
+    pub fn records(&self) -> &[CapturedLog] {
+        &self.records
+    }
+
+    /// All captured records which match.
+    pub fn matching(&self, matcher: &LogMatcher) -> Vec<&CapturedLog> {
+        self.records
+            .iter()
+            .filter(|log| matcher.matches(log))
+            .collect()
+    }
+
+    /// Assert that at least one record matches, and return the first one that does.
+    ///
+    /// On failure, all captured records are included in the assertion message.
+    pub fn assert_logged(&self, matcher: &LogMatcher) -> &CapturedLog {
+        self.records
+            .iter()
+            .find(|log| matcher.matches(log))
+            .unwrap_or_else(|| {
+                panic!(
+                    "No captured log matched {:?}. Captured logs:\n{}",
+                    matcher, self
+                )
+            })
+    }
+
+    /// Assert that no record matches.
+    ///
+    /// On failure, all captured records are included in the assertion message.
+    pub fn assert_not_logged(&self, matcher: &LogMatcher) {
+        if let Some(log) = self.records.iter().find(|log| matcher.matches(log)) {
+            panic!(
+                "Captured log \"{}\" unexpectedly matched {:?}. Captured logs:\n{}",
+                log, matcher, self
+            );
+        }
+    }
+}
+
+impl fmt::Display for CapturedLogs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for log in &self.records {
+            writeln!(f, "  {}", log)?;
+        }
+        Ok(())
+    }
+}
+
+/// Capture all log records emitted while running a closure's future, for assertions.
+///
+/// Records are captured regardless of `LOGLEVEL`, and are still written to the regular logger according to it.
+///
+/// Only records emitted from the current task are captured, so parallel tests do not see each other's logs.
+/// This includes requests made with a client from [`create_client`][super::create_client], but excludes
+/// servers from [`spawn_server`][super::spawn_server], which run in a separate task.
+///
+/// If some other global logger was installed before test_utils, nothing is captured.
+///
+/// ## Example:
+///
+/// ```
+/// use log::Level;
+/// use preroll::test_utils::{self, capture_logs, LogMatcher, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let logs = capture_logs(|| async {
+///         client.get("/not_found").await.unwrap();
+///     })
+///     .await;
+///
+///     logs.assert_logged(
+///         &LogMatcher::new()
+///             .level(Level::Warn)
+///             .message_contains("Client Error")
+///             .field("status", 404),
+///     );
+///     Ok(())
+/// }
+/// ```
+pub async fn capture_logs<F, Fut>(f: F) -> CapturedLogs
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    init_test_globals();
+
+    let sink: Sink = Arc::new(Mutex::new(Vec::new()));
+    let previous = CAPTURE.with(|capture| capture.replace(Some(sink.clone())));
+
+    f().await;
+
+    CAPTURE.with(|capture| capture.replace(previous));
+
+    let records =
+        std::mem::take(&mut *sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+    CapturedLogs { records }
+}
@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use surf::{Client, StatusCode};
+use tide::{Middleware, Next, Request, Server};
+
+use super::mock_client_with_state;
+use crate::utils::{fraction, wyrand};
+
+/// A degraded-environment profile which applies to every mock client created from it.
+///
+/// Each mocked request is delayed by a latency picked uniformly from a range, and may be failed
+/// (without running the mock) at the configured error rate.
+/// The profile can be switched on and off mid-test with [`set_active`][MockProfile::set_active],
+/// affecting all of its mocks at once.
+///
+/// Random choices use a fixed seed by default, so a test sees the same sequence of delays and failures on every run.
+///
+/// ## Example:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use preroll::test_utils::MockProfile;
+/// use tide::{Server, StatusCode};
+///
+/// fn setup_example_local_org_mocks(mock: &mut Server<()>) {
+///     mock.at("hello-world").get(|_| async { Ok("Hello World!") });
+/// }
+///
+/// #[async_std::main]
+/// async fn main() {
+///     let profile = MockProfile::new()
+///         .latency(Duration::from_millis(1), Duration::from_millis(5))
+///         .error_rate(1.0)
+///         .error_status(StatusCode::ServiceUnavailable);
+///
+///     let client = profile.mock_client("http://api.example_local.org/", setup_example_local_org_mocks);
+///
+///     let res = client.get("http://api.example_local.org/hello-world").await.unwrap();
+///     assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+///
+///     // The dependency recovers.
+///     profile.set_active(false);
+///
+///     let res = client.get("http://api.example_local.org/hello-world").await.unwrap();
+///     assert_eq!(res.status(), StatusCode::Ok);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockProfile {
+    latency: Option<(Duration, Duration)>,
+    error_rate: f64,
+    error_status: StatusCode,
+    // Shared by clones, which are held by the mocks.
+    active: Arc<AtomicBool>,
+    rng: Arc<AtomicU64>,
+}
+
+impl Default for MockProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockProfile {
+    /// A healthy, active profile: no added latency and no errors.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            latency: None,
+            error_rate: 0.0,
+            error_status: StatusCode::InternalServerError,
+            active: Arc::new(AtomicBool::new(true)),
+            rng: Arc::new(AtomicU64::new(0x9E37_79B9_7F4A_7C15)),
+        }
+    }
+
+    /// Delay each mocked request by a duration picked uniformly between `min` and `max`.
+    #[must_use]
+    pub fn latency(mut self, min: Duration, max: Duration) -> Self {
+        self.latency = Some((min.min(max), max.max(min)));
+        self
+    }
+
+    /// Fail this fraction of mocked requests, from `0.0` (never) to `1.0` (always).
+    #[must_use]
+    pub fn error_rate(mut self, error_rate: f64) -> Self {
+        self.error_rate = error_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The status of failed requests. Defaults to `500 Internal Server Error`.
+    #[must_use]
+    pub fn error_status(mut self, error_status: StatusCode) -> Self {
+        self.error_status = error_status;
+        self
+    }
+
+    /// Seed the random choices of latency and failures, for a different but still reproducible sequence.
+    #[must_use]
+    pub fn seed(self, seed: u64) -> Self {
+        self.rng.store(seed, Ordering::SeqCst);
+        self
+    }
+
+    /// Switch the profile on or off for all of its mocks. When inactive, mocks respond normally and immediately.
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::SeqCst);
+    }
+
+    /// Whether the profile is currently applied to its mocks.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Like [`mock_client`][super::mock_client], with this profile applied to every request.
+    pub fn mock_client<MocksFn>(&self, base_url: impl AsRef<str>, setup_mocks_fn: MocksFn) -> Client
+    where
+        MocksFn: Fn(&mut Server<()>),
+    {
+        self.mock_client_with_state(base_url, (), setup_mocks_fn)
+    }
+
+    /// Like [`mock_client_with_state`][super::mock_client_with_state], with this profile applied to every request.
+    pub fn mock_client_with_state<MockState, MocksFn>(
+        &self,
+        base_url: impl AsRef<str>,
+        state: MockState,
+        setup_mocks_fn: MocksFn,
+    ) -> Client
+    where
+        MockState: Clone + Send + Sync + Unpin + 'static,
+        MocksFn: Fn(&mut Server<MockState>),
+    {
+        let profile = self.clone();
+        mock_client_with_state(base_url, state, move |mock: &mut Server<MockState>| {
+            mock.with(profile.clone());
+            setup_mocks_fn(mock);
+        })
+    }
+
+    /// A random number in `[0, 1)`, from the profile's seeded generator.
+    fn next_f64(&self) -> f64 {
+        fraction(wyrand(&self.rng))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for MockProfile {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !self.is_active() {
+            return Ok(next.run(req).await);
+        }
+
+        if let Some((min, max)) = self.latency {
+            let latency = min + (max - min).mul_f64(self.next_f64());
+            async_std::task::sleep(latency).await;
+        }
+
+        if self.error_rate > 0.0 && self.next_f64() < self.error_rate {
+            let mut res = tide::Response::new(self.error_status);
+            res.set_body(format!(
+                "MockProfile injected failure for {} {}",
+                req.method(),
+                req.url().path()
+            ));
+            return Ok(res);
+        }
+
+        Ok(next.run(req).await)
+    }
+}
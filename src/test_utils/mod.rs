@@ -23,26 +23,77 @@
 //!     Ok(())
 //! }
 //! ```
+//!
+//! ## Parallel tests
+//!
+//! `cargo test` runs tests in parallel by default, and test_utils are safe to use that way:
+//! - Process-global state, (loading `.env`, the logger, and the tracing subscriber), is initialized exactly once,
+//!   by whichever test sets up a server first.
+//! - Every test gets its own server, state, and mocks. Nothing is shared between clients.
+//! - `postgres`: Every call to `create_client_and_postgres` uses its own connection and transaction, which is rolled back when dropped.
+//!
+//! Process environment variables are shared by all tests. Avoid setting them from within tests.
 
 #![allow(clippy::unwrap_used)]
 
 use std::convert::TryInto;
 use std::env;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::io;
+use std::sync::{Arc, Once};
 
+use async_std::task::JoinHandle;
 use cfg_if::cfg_if;
+use futures_lite::StreamExt;
 use surf::{Client, StatusCode, Url};
+use tide::listener::{Listener, ToListener};
 use tide::{http, Server};
 
+use crate::builtins::admin::AdminAuth;
 use crate::builtins::monitor::setup_monitor;
 use crate::logging::{log_format_json, log_format_pretty};
 use crate::middleware::json_error::JsonError;
 use crate::middleware::{JsonErrorMiddleware, LogMiddleware, RequestIdMiddleware};
-use crate::VariadicRoutes;
+use crate::openapi::setup_openapi;
+use crate::{Environment, VariadicRoutes};
+
+mod logs;
+mod mock_profile;
+mod options;
+
+pub use logs::{capture_logs, CapturedLog, CapturedLogs, LogMatcher};
+pub use mock_profile::MockProfile;
+pub use options::TestClientOptions;
+
+use logs::TestLogger;
+use options::{CustomMiddleware, FollowRedirects, TestClientMiddleware};
+
+cfg_if! {
+    if #[cfg(feature = "honeycomb")] {
+        use tracing_honeycomb::new_blackhole_telemetry_layer;
+        use tracing_subscriber::layer::SubscriberExt;
+        use tracing_subscriber::Registry;
+
+        use crate::middleware::TraceMiddleware;
+
+        mod spans;
+
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
+        pub use spans::{capture_spans, CapturedEvent, CapturedSpan, CapturedSpans, SpanMatcher};
+
+        use spans::SpanCaptureLayer;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "websockets")] {
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "websockets")))]
+        pub mod websocket;
 
-#[cfg(feature = "honeycomb")]
-use tracing_subscriber::Registry;
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "websockets")))]
+        pub use websocket::{create_websocket_client, TestWebSocket};
+    }
+}
 
 cfg_if! {
     if #[cfg(feature = "postgres")] {
@@ -52,6 +103,11 @@ cfg_if! {
         use tide::{Middleware, Next, Request};
 
         use crate::middleware::postgres::{ConnectionWrap, ConnectionWrapInner};
+
+        mod sql;
+
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+        pub use sql::run_sql_file;
     }
 }
 
@@ -88,15 +144,107 @@ pub async fn create_client<State>(
     state: State,
     setup_routes_fns: impl Into<VariadicRoutes<State>>,
 ) -> TestResult<Client>
+where
+    State: Send + Sync + 'static,
+{
+    create_client_with_options(state, setup_routes_fns, TestClientOptions::new()).await
+}
+
+/// Like [`create_client`], but the client is configured by [`TestClientOptions`],
+/// e.g. with default headers for authentication, so that every test case need not repeat them.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, assert_status, TestClientOptions, TestResult};
+/// use tide::Request;
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("tenant").get(|req: Request<_>| async move {
+///         Ok(req.header("X-Tenant-Id").map(|v| v.as_str().to_string()).unwrap_or_default())
+///     });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let options = TestClientOptions::new()
+///         .header("X-Tenant-Id", "tenant-1")
+///         .base_path("/api/v1");
+///     let client = test_utils::create_client_with_options((), setup_routes, options).await?;
+///
+///     let mut res = client.get("tenant").await?;
+///     assert_eq!(assert_status(&mut res, 200).await, "tenant-1");
+///
+///     let mut res = client.get("tenant").header("X-Tenant-Id", "tenant-2").await?;
+///     assert_eq!(assert_status(&mut res, 200).await, "tenant-2");
+///     Ok(())
+/// }
+/// ```
+pub async fn create_client_with_options<State>(
+    state: State,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+    options: TestClientOptions<State>,
+) -> TestResult<Client>
+where
+    State: Send + Sync + 'static,
+{
+    let server = create_server_with_options(state, setup_routes_fns, &options)?;
+
+    connect_client(server, &options)
+}
+
+/// Creates a test application with routes and mocks set up, and listens on an ephemeral port on `127.0.0.1`.
+///
+/// Hands back the base url of the listening server, and the handle of the task accepting connections.
+/// This is useful for tests which need external tooling to reach the service over a real network connection.
+/// Prefer [`create_client`] whenever possible.
+///
+/// The server is stopped when the handle is [cancelled][JoinHandle::cancel], or when the test process exits.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let (base_url, server) = test_utils::spawn_server((), setup_routes).await?;
+///
+///     let body = surf::get(base_url.join("/monitor/ping")?).recv_string().await?;
+///     assert_eq!(body, "preroll_test_utils");
+///
+///     server.cancel().await;
+///     Ok(())
+/// }
+/// ```
+pub async fn spawn_server<State>(
+    state: State,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+) -> TestResult<(Url, JoinHandle<io::Result<()>>)>
 where
     State: Send + Sync + 'static,
 {
     let server = create_server(state, setup_routes_fns)?;
 
-    let mut client = Client::with_http_client(server);
-    client.set_base_url(Url::parse("http://localhost:8080")?); // Address not actually used.
+    let mut listener = ("127.0.0.1", 0).to_listener()?;
+    listener.bind(server).await?;
 
-    Ok(client)
+    let info = listener.info().into_iter().next().ok_or_else(|| {
+        surf::Error::from_str(
+            StatusCode::InternalServerError,
+            "Test server listener did not report an address",
+        )
+    })?;
+    let base_url = Url::parse(info.connection())?;
+
+    let handle = async_std::task::spawn(async move { listener.accept().await });
+
+    Ok((base_url, handle))
 }
 
 /// Creates a test application with routes and mocks set up,
@@ -110,6 +258,12 @@ where
 /// The `RwLockWriteGuard` returned from `pg_conn.write().await` MUST be [dropped][] before running
 /// the test cases, or else there will be a writer conflict and the test will hang indefinitely.
 ///
+/// ## Parallel tests
+///
+/// Every call opens its own connection and transaction, which is never committed and is rolled back when dropped.
+/// Tests using this function are isolated from each other and can be run with `cargo test`'s default parallelism,
+/// provided the service under test does not commit or open connections of its own.
+///
 /// ## Example:
 ///
 /// ```no_run
@@ -164,8 +318,10 @@ where
         .database("database_test");
     connect_opts.log_statements(log::LevelFilter::Debug);
 
+    // Each test gets its own pool holding exactly one connection, which is only ever used for this test's transaction.
+    // This keeps parallel tests from sharing connections or observing each other's uncommitted data.
     let pg_pool = PgPoolOptions::new()
-        .max_connections(5)
+        .max_connections(1)
         .connect_with(connect_opts)
         .await?;
 
@@ -174,13 +330,77 @@ where
     )));
     server.with(PostgresTestMiddleware(conn_wrap.clone()));
 
-    let mut client = Client::with_http_client(server);
-    client.set_base_url(Url::parse("http://localhost:8080")?); // Address not actually used.
+    let client = connect_client(server, &TestClientOptions::new())?;
 
     Ok((client, conn_wrap))
 }
 
-#[allow(clippy::unnecessary_wraps)]
+/// Create a client which is directly connected to a test server.
+fn connect_client<State>(
+    server: Server<Arc<State>>,
+    options: &TestClientOptions<State>,
+) -> TestResult<Client>
+where
+    State: Send + Sync + 'static,
+{
+    let mut base_url = Url::parse("http://localhost:8080")?; // Address not actually used.
+    if let Some(base_path) = &options.base_path {
+        // A trailing slash is required for relative paths to be resolved beneath the base path.
+        base_url.set_path(&format!("{}/", base_path.trim_end_matches('/')));
+    }
+
+    let mut client = Client::with_http_client(server);
+    client.set_base_url(base_url);
+    client = client.with(TestClientMiddleware::new(options)?);
+    if options.follow_redirects {
+        client = client.with(FollowRedirects::new(3));
+    }
+
+    Ok(client)
+}
+
+/// Initialize process-global state exactly once, no matter how many tests set up servers in parallel.
+///
+/// The environment from `.env` is loaded before the logger, so that `LOGLEVEL` and `ENVIRONMENT` may be set there.
+fn init_test_globals() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        dotenv::dotenv().ok();
+
+        let log_level: log::LevelFilter = env::var("LOGLEVEL")
+            .map(|v| v.parse().expect("LOGLEVEL must be a valid log level."))
+            .unwrap_or(log::LevelFilter::Off);
+
+        let logger = if Environment::current().is_production() {
+            // Like Production
+            env_logger::builder()
+                .format(log_format_json)
+                .filter_level(log_level)
+                .write_style(env_logger::WriteStyle::Never)
+                .build()
+        } else {
+            // Like Development
+            env_logger::builder()
+                .format(log_format_pretty)
+                .filter_level(log_level)
+                .build()
+        };
+
+        // Wrapped so that logs can also be captured for assertions.
+        TestLogger::init(logger);
+
+        #[cfg(feature = "honeycomb")]
+        {
+            let subscriber = Registry::default()
+                // .with(tracing_subscriber::fmt::Layer::default()) // log to stdout
+                .with(new_blackhole_telemetry_layer())
+                .with(SpanCaptureLayer::new()); // retain spans for capture_spans
+            tracing::subscriber::set_global_default(subscriber).ok();
+        }
+    });
+}
+
 pub(crate) fn create_server<State>(
     state: State,
     setup_routes_fns: impl Into<VariadicRoutes<State>>,
@@ -188,49 +408,63 @@ pub(crate) fn create_server<State>(
 where
     State: Send + Sync + 'static,
 {
-    dotenv::dotenv().ok();
-
-    let log_level: log::LevelFilter = env::var("LOGLEVEL")
-        .map(|v| v.parse().expect("LOGLEVEL must be a valid log level."))
-        .unwrap_or(log::LevelFilter::Off);
-
-    let environment = env::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string());
-
-    if environment.starts_with("prod") {
-        // Like Production
-        env_logger::builder()
-            .format(log_format_json)
-            .filter_level(log_level)
-            .write_style(env_logger::WriteStyle::Never)
-            .try_init()
-            .ok();
-    } else {
-        // Like Development
-        env_logger::builder()
-            .format(log_format_pretty)
-            .filter_level(log_level)
-            .try_init()
-            .ok();
-    }
+    create_server_with_options(state, setup_routes_fns, &TestClientOptions::new())
+}
 
-    #[cfg(feature = "honeycomb")]
-    {
-        let subscriber = Registry::default();
-        // .with(tracing_subscriber::fmt::Layer::default()) // log to stdout
-        tracing::subscriber::set_global_default(subscriber).ok();
-    }
+fn create_server_with_options<State>(
+    state: State,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+    options: &TestClientOptions<State>,
+) -> TestResult<Server<Arc<State>>>
+where
+    State: Send + Sync + 'static,
+{
+    init_test_globals();
 
     let mut server = tide::with_state(Arc::new(state));
-    server.with(RequestIdMiddleware::new());
-    server.with(LogMiddleware::new());
-    server.with(JsonErrorMiddleware::new());
+    #[cfg(feature = "runtime-tokio")]
+    server.with(crate::runtime::TokioContextMiddleware);
+    if options.request_id_middleware {
+        server.with(RequestIdMiddleware::new());
+    }
+    if options.log_middleware {
+        server.with(LogMiddleware::new());
+    }
+    if options.json_error_middleware {
+        let mut middleware = JsonErrorMiddleware::new();
+        if let Some(format) = options.error_format {
+            middleware = middleware.format(format);
+        }
+        if let Some(url) = options.error_docs_url.clone() {
+            middleware = middleware.docs_url(url);
+        }
+        if let Some(reporter) = options.error_reporter.clone() {
+            middleware = middleware.reporter(reporter);
+        }
+        #[cfg(feature = "postgres")]
+        if let Some(map) = options.sqlx_errors.clone() {
+            middleware = middleware.sqlx_errors(map);
+        }
+        server.with(middleware);
+    }
+    for middleware in &options.middleware {
+        server.with(CustomMiddleware(middleware.clone()));
+    }
+
+    #[cfg(feature = "honeycomb")]
+    server.with(TraceMiddleware::new());
 
     setup_monitor("preroll_test_utils", &mut server);
 
-    let mut version = 1;
-    for routes_fn in setup_routes_fns.into().routes {
-        routes_fn(server.at(&format!("/api/v{}", version)));
-        version += 1;
+    setup_routes_fns
+        .into()
+        .mount(&mut server)
+        .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+
+    if let Some(openapi) = options.openapi.clone() {
+        let admin_auth =
+            AdminAuth::from_env().map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+        setup_openapi(&mut server, openapi, admin_auth);
     }
 
     Ok(server)
@@ -278,7 +512,61 @@ pub fn mock_client<MocksFn>(base_url: impl AsRef<str>, setup_mocks_fn: MocksFn)
 where
     MocksFn: Fn(&mut Server<()>),
 {
-    let mut mocks_server = tide::new();
+    mock_client_with_state(base_url, (), setup_mocks_fn)
+}
+
+/// Creates a mock client directly connected to a server with caller-provided state, which is setup by the provided function.
+///
+/// The test keeps a clone of the state, so that mocks can record requests or change behavior partway through a test,
+/// e.g. failing the first call and succeeding afterwards.
+///
+/// ## Example:
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// use preroll::test_utils;
+/// use tide::{Request, Server, StatusCode};
+///
+/// type MockState = Arc<AtomicUsize>;
+///
+/// fn setup_flaky_mocks(mock: &mut Server<MockState>) {
+///     mock.at("hello-world").get(|req: Request<MockState>| async move {
+///         match req.state().fetch_add(1, Ordering::SeqCst) {
+///             0 => Ok(tide::Response::new(StatusCode::InternalServerError)),
+///             _ => Ok(tide::Response::from("Hello World!")),
+///         }
+///     });
+/// }
+///
+/// #[async_std::main]
+/// async fn main() {
+///     let calls = MockState::default();
+///     let client = test_utils::mock_client_with_state("http://api.example_local.org/", calls.clone(), setup_flaky_mocks);
+///
+///     let res = client.get("http://api.example_local.org/hello-world").await.unwrap();
+///     assert_eq!(res.status(), StatusCode::InternalServerError);
+///
+///     let response = client
+///         .get("http://api.example_local.org/hello-world")
+///         .recv_string()
+///         .await
+///         .unwrap();
+///     assert_eq!(response, "Hello World!");
+///
+///     assert_eq!(calls.load(Ordering::SeqCst), 2);
+/// }
+/// ```
+pub fn mock_client_with_state<MockState, MocksFn>(
+    base_url: impl AsRef<str>,
+    state: MockState,
+    setup_mocks_fn: MocksFn,
+) -> Client
+where
+    MockState: Clone + Send + Sync + Unpin + 'static,
+    MocksFn: Fn(&mut Server<MockState>),
+{
+    let mut mocks_server = tide::with_state(state);
     setup_mocks_fn(&mut mocks_server);
 
     let mut mock_client = Client::with_http_client(mocks_server);
@@ -318,48 +606,116 @@ where
 #[allow(dead_code)] // Not actually dead code. (??)
 #[track_caller]
 pub async fn assert_json_error<Status>(
-    mut res: impl AsMut<http::Response>,
+    res: impl AsMut<http::Response>,
     status: Status,
     err_msg: &str,
 ) where
     Status: TryInto<StatusCode>,
     Status::Error: Debug,
+{
+    try_assert_json_error(res, status, err_msg)
+        .await
+        .unwrap_or_else(|err| panic!("{}", err));
+}
+
+/// Like [`assert_json_error`], but returns an error describing the first mismatch rather than panicking.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, try_assert_json_error, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///
+///     let mut res = client.get("/not_found").await?;
+///
+///     try_assert_json_error(&mut res, 404, "(no additional context)").await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn try_assert_json_error<Status>(
+    mut res: impl AsMut<http::Response>,
+    status: Status,
+    err_msg: &str,
+) -> TestResult<()>
+where
+    Status: TryInto<StatusCode>,
+    Status::Error: Debug,
 {
     let res = res.as_mut();
 
-    let status: StatusCode = status
-        .try_into()
-        .expect("test must specify valid status code");
+    let status = expected_status(status)?;
 
-    let str_response = res.body_string().await.unwrap();
+    let str_response = res.body_string().await?;
 
     let error: JsonError = serde_json::from_str(&str_response).map_err(|e| {
-        surf::Error::from_str(
-            res.status(),
-            format!("Error, could not parse Response into JsonError! json err: \"{}\", response body: \"{}\"", e, str_response)
-        )
-    }).unwrap();
+        assertion_error(format!(
+            "Error, could not parse Response into JsonError! json err: \"{}\", response body: \"{}\"",
+            e, str_response
+        ))
+    })?;
 
-    assert_eq!(res.status(), status);
-    assert_eq!(&error.title, status.canonical_reason());
-    assert_eq!(error.message, err_msg);
-    assert_eq!(error.status, status as u16);
-    assert_eq!(
-        error.request_id.as_str(),
-        res["X-Request-Id"].last().as_str()
-    );
+    check_eq("Response status", res.status(), status, &str_response)?;
+    check_eq(
+        "JsonError title",
+        error.title.as_str(),
+        status.canonical_reason(),
+        &str_response,
+    )?;
+    check_eq(
+        "JsonError message",
+        error.message.as_str(),
+        err_msg,
+        &str_response,
+    )?;
+    check_eq(
+        "JsonError status",
+        error.status,
+        status as u16,
+        &str_response,
+    )?;
+    check_eq(
+        "JsonError request_id",
+        Some(error.request_id.to_string()),
+        header_str(res.as_ref(), &"X-Request-Id".into()),
+        &str_response,
+    )?;
     if res.status().is_server_error() {
-        assert_eq!(
-            error
-                .correlation_id
-                .expect("Internal server errors must have correlation ids.")
-                .as_str(),
-            res["X-Correlation-Id"].last().as_str()
-        );
+        let correlation_id = error.correlation_id.ok_or_else(|| {
+            assertion_error(format!(
+                "Internal server errors must have correlation ids. Response body: {}",
+                str_response
+            ))
+        })?;
+        check_eq(
+            "JsonError correlation_id",
+            Some(correlation_id.to_string()),
+            header_str(res.as_ref(), &"X-Correlation-Id".into()),
+            &str_response,
+        )?;
     } else {
-        assert_eq!(error.correlation_id, None);
-        assert!(res.header("X-Correlation-Id").is_none());
+        check_eq(
+            "JsonError correlation_id",
+            error.correlation_id.map(|id| id.to_string()),
+            None,
+            &str_response,
+        )?;
+        check_eq(
+            "X-Correlation-Id header",
+            header_str(res.as_ref(), &"X-Correlation-Id".into()),
+            None,
+            &str_response,
+        )?;
     }
+
+    Ok(())
 }
 
 /// Assert that a response has a status code and parse out the body to JSON if possible.
@@ -391,9 +747,52 @@ pub async fn assert_json_error<Status>(
 /// ```
 #[track_caller]
 pub async fn assert_status_json<StructType, Status>(
-    mut res: impl AsMut<http::Response>,
+    res: impl AsMut<http::Response>,
     status: Status,
 ) -> StructType
+where
+    StructType: serde::de::DeserializeOwned,
+    Status: TryInto<StatusCode>,
+    Status::Error: Debug,
+{
+    try_assert_status_json(res, status)
+        .await
+        .unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Like [`assert_status_json`], but returns an error rather than panicking.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, try_assert_status_json, TestResult};
+/// use preroll::JsonError;
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///
+///     let mut res = client.get("/not_found").await?;
+///
+///     let json: JsonError = try_assert_status_json(&mut res, 404).await?;
+///     assert_eq!(&json.title, res.status().canonical_reason());
+///
+///     let mut res = client.get("/not_found").await?;
+///
+///     let result: TestResult<JsonError> = try_assert_status_json(&mut res, 200).await;
+///     assert!(result.is_err());
+///     Ok(())
+/// }
+/// ```
+pub async fn try_assert_status_json<StructType, Status>(
+    mut res: impl AsMut<http::Response>,
+    status: Status,
+) -> TestResult<StructType>
 where
     StructType: serde::de::DeserializeOwned,
     Status: TryInto<StatusCode>,
@@ -401,21 +800,19 @@ where
 {
     let res = res.as_mut();
 
-    let status: StatusCode = status
-        .try_into()
-        .expect("test must specify valid status code");
+    let status = expected_status(status)?;
 
-    let body = res.body_string().await.unwrap();
+    let body = res.body_string().await?;
 
-    assert_eq!(res.status(), status, "Response body: {}", body);
+    check_eq("Response status", res.status(), status, &body)?;
 
-    serde_json::from_str(&body).unwrap_or_else(|err| {
-        panic!(
+    serde_json::from_str(&body).map_err(|err| {
+        assertion_error(format!(
             "Error: \"{}\" Body was not parseable into a {}, body was: \"{}\"",
             err,
             std::any::type_name::<StructType>(),
             body
-        )
+        ))
     })
 }
 
@@ -445,20 +842,490 @@ where
 /// }
 /// ```
 #[track_caller]
-pub async fn assert_status<Status>(mut res: impl AsMut<http::Response>, status: Status) -> String
+pub async fn assert_status<Status>(res: impl AsMut<http::Response>, status: Status) -> String
+where
+    Status: TryInto<StatusCode>,
+    Status::Error: Debug,
+{
+    try_assert_status(res, status)
+        .await
+        .unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Like [`assert_status`], but returns an error rather than panicking.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, try_assert_status, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///
+///     let mut res = client.get("/monitor/ping").await?;
+///
+///     let body = try_assert_status(&mut res, 200).await?;
+///     assert_eq!(body, "preroll_test_utils");
+///     Ok(())
+/// }
+/// ```
+pub async fn try_assert_status<Status>(
+    mut res: impl AsMut<http::Response>,
+    status: Status,
+) -> TestResult<String>
 where
     Status: TryInto<StatusCode>,
     Status::Error: Debug,
 {
     let res = res.as_mut();
 
-    let status: StatusCode = status
+    let status = expected_status(status)?;
+
+    let body = res.body_string().await?;
+
+    check_eq("Response status", res.status(), status, &body)?;
+
+    Ok(body)
+}
+
+/// Assert that a response has a header with the specified value.
+///
+/// If the header was sent multiple times, the values are joined with `", "` before comparison, as per [rfc7230 section 3.2.2](https://tools.ietf.org/html/rfc7230#section-3.2.2).
+///
+/// On failure, all response headers are included in the assertion message.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, assert_header, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let res = client.get("/not_found").await.unwrap();
+///
+///     assert_header(&res, "Content-Type", "application/json");
+///     Ok(())
+/// }
+/// ```
+pub fn assert_header(
+    headers: impl AsRef<http::Headers>,
+    name: impl Into<http::headers::HeaderName>,
+    value: &str,
+) {
+    try_assert_header(headers, name, value).unwrap_or_else(|err| panic!("{}", err));
+}
+
+/// Like [`assert_header`], but returns an error rather than panicking.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, try_assert_header, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///
+///     let res = client.get("/not_found").await?;
+///
+///     try_assert_header(&res, "Content-Type", "application/json")?;
+///     assert!(try_assert_header(&res, "Content-Type", "text/html").is_err());
+///     Ok(())
+/// }
+/// ```
+pub fn try_assert_header(
+    headers: impl AsRef<http::Headers>,
+    name: impl Into<http::headers::HeaderName>,
+    value: &str,
+) -> TestResult<()> {
+    let headers = headers.as_ref();
+    let name = name.into();
+
+    match header_str(headers, &name) {
+        Some(actual) if actual == value => Ok(()),
+        Some(actual) => Err(assertion_error(format!(
+            "Header \"{}\" did not match.\n  expected: {:?}\n    actual: {:?}\nAll headers:\n{}",
+            name,
+            value,
+            actual,
+            format_headers(headers)
+        ))),
+        None => Err(assertion_error(format!(
+            "Header \"{}\" was expected to be \"{}\" but was not present. All headers:\n{}",
+            name,
+            value,
+            format_headers(headers)
+        ))),
+    }
+}
+
+/// Assert that a response has a header, and return its value.
+///
+/// If the header was sent multiple times, the values are joined with `", "`.
+///
+/// On failure, all response headers are included in the assertion message.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, assert_header_present, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let res = client.get("/monitor/ping").await.unwrap();
+///
+///     let request_id = assert_header_present(&res, "X-Request-Id");
+///     assert_eq!(request_id.len(), 36);
+///     Ok(())
+/// }
+/// ```
+pub fn assert_header_present(
+    headers: impl AsRef<http::Headers>,
+    name: impl Into<http::headers::HeaderName>,
+) -> String {
+    try_assert_header_present(headers, name).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Like [`assert_header_present`], but returns an error rather than panicking.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, try_assert_header_present, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///
+///     let res = client.get("/monitor/ping").await?;
+///
+///     let request_id = try_assert_header_present(&res, "X-Request-Id")?;
+///     assert_eq!(request_id.len(), 36);
+///     Ok(())
+/// }
+/// ```
+pub fn try_assert_header_present(
+    headers: impl AsRef<http::Headers>,
+    name: impl Into<http::headers::HeaderName>,
+) -> TestResult<String> {
+    let headers = headers.as_ref();
+    let name = name.into();
+
+    header_str(headers, &name).ok_or_else(|| {
+        assertion_error(format!(
+            "Header \"{}\" was expected but was not present. All headers:\n{}",
+            name,
+            format_headers(headers)
+        ))
+    })
+}
+
+/// Assert that a response does not have a header.
+///
+/// On failure, all response headers are included in the assertion message.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, assert_header_absent, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let res = client.get("/not_found").await.unwrap();
+///
+///     assert_header_absent(&res, "X-Correlation-Id");
+///     Ok(())
+/// }
+/// ```
+pub fn assert_header_absent(
+    headers: impl AsRef<http::Headers>,
+    name: impl Into<http::headers::HeaderName>,
+) {
+    try_assert_header_absent(headers, name).unwrap_or_else(|err| panic!("{}", err));
+}
+
+/// Like [`assert_header_absent`], but returns an error rather than panicking.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, try_assert_header_absent, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///
+///     let res = client.get("/not_found").await?;
+///
+///     try_assert_header_absent(&res, "X-Correlation-Id")?;
+///     Ok(())
+/// }
+/// ```
+pub fn try_assert_header_absent(
+    headers: impl AsRef<http::Headers>,
+    name: impl Into<http::headers::HeaderName>,
+) -> TestResult<()> {
+    let headers = headers.as_ref();
+    let name = name.into();
+
+    match header_str(headers, &name) {
+        Some(actual) => Err(assertion_error(format!(
+            "Header \"{}\" was expected to be absent but was \"{}\". All headers:\n{}",
+            name,
+            actual,
+            format_headers(headers)
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Assert that a response is a redirect with the specified status code and `Location` header.
+///
+/// Test clients do not follow redirects unless configured to with [`TestClientOptions::follow_redirects`].
+///
+/// On failure, all response headers are included in the assertion message.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, assert_redirect, TestResult};
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("account").get(tide::Redirect::new("/login"));
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let res = client.get("/api/v1/account").await.unwrap();
+///
+///     assert_redirect(&res, 302, "/login");
+///     Ok(())
+/// }
+/// ```
+pub fn assert_redirect<Status>(res: impl AsRef<http::Response>, status: Status, location: &str)
+where
+    Status: TryInto<StatusCode>,
+    Status::Error: Debug,
+{
+    try_assert_redirect(res, status, location).unwrap_or_else(|err| panic!("{}", err));
+}
+
+/// Like [`assert_redirect`], but returns an error rather than panicking.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, try_assert_redirect, TestResult};
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("account").get(tide::Redirect::permanent("/login"));
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///
+///     let res = client.get("/api/v1/account").await?;
+///
+///     try_assert_redirect(&res, 308, "/login")?;
+///     assert!(try_assert_redirect(&res, 302, "/login").is_err());
+///     Ok(())
+/// }
+/// ```
+pub fn try_assert_redirect<Status>(
+    res: impl AsRef<http::Response>,
+    status: Status,
+    location: &str,
+) -> TestResult<()>
+where
+    Status: TryInto<StatusCode>,
+    Status::Error: Debug,
+{
+    let res = res.as_ref();
+
+    let status = expected_status(status)?;
+
+    if res.status() != status {
+        return Err(assertion_error(format!(
+            "Redirect status did not match.\n  expected: {:?}\n    actual: {:?}\nAll headers:\n{}",
+            status,
+            res.status(),
+            format_headers(res.as_ref())
+        )));
+    }
+
+    try_assert_header(res, http::headers::LOCATION, location)
+}
+
+/// The error returned from failed `try_assert_*` helpers.
+fn assertion_error(message: String) -> surf::Error {
+    surf::Error::from_str(StatusCode::InternalServerError, message)
+}
+
+fn expected_status<Status>(status: Status) -> TestResult<StatusCode>
+where
+    Status: TryInto<StatusCode>,
+    Status::Error: Debug,
+{
+    status
         .try_into()
-        .expect("test must specify valid status code");
+        .map_err(|err| assertion_error(format!("Test must specify a valid status code: {:?}", err)))
+}
+
+fn check_eq<T>(what: &str, actual: T, expected: T, body: &str) -> TestResult<()>
+where
+    T: PartialEq + Debug,
+{
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(assertion_error(format!(
+            "{} did not match.\n  expected: {:?}\n    actual: {:?}\nResponse body: {}",
+            what, expected, actual, body
+        )))
+    }
+}
+
+fn header_str(headers: &http::Headers, name: &http::headers::HeaderName) -> Option<String> {
+    headers.get(name).map(|values| {
+        values
+            .iter()
+            .map(|value| value.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+fn format_headers(headers: &http::Headers) -> String {
+    let mut lines: Vec<String> = headers
+        .iter()
+        .map(|(name, values)| format!("  {}: {}", name, values))
+        .collect();
+    lines.sort();
+    lines.join("\n")
+}
 
-    let body = res.body_string().await.unwrap();
+/// A single message parsed from a `text/event-stream` response by [`collect_sse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The `id:` field of the event, if one was sent.
+    pub id: Option<String>,
+    /// The `event:` field of the event, which is `"message"` if none was sent.
+    pub event: String,
+    /// The `data:` field(s) of the event, joined by newlines.
+    pub data: String,
+}
 
-    assert_eq!(res.status(), status, "Response body: {}", body);
+/// Read the first `n` events from a Server-Sent Events (`text/event-stream`) response.
+///
+/// Panics if the response is not an event stream, or if the stream ends before `n` events have been received.
+/// Reconnection (`retry:`) instructions are skipped.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, collect_sse, TestResult};
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("events").get(tide::sse::endpoint(|_req, sender| async move {
+///         sender.send("greeting", "Hello", Some("1")).await?;
+///         sender.send("greeting", "World!", Some("2")).await?;
+///         Ok(())
+///     }));
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let mut res = client.get("/api/v1/events").await.unwrap();
+///
+///     let events = collect_sse(&mut res, 2).await;
+///     assert_eq!(events[0].id.as_deref(), Some("1"));
+///     assert_eq!(events[0].event, "greeting");
+///     assert_eq!(events[1].data, "World!");
+///     Ok(())
+/// }
+/// ```
+pub async fn collect_sse(mut res: impl AsMut<http::Response>, n: usize) -> Vec<SseEvent> {
+    let res = res.as_mut();
+
+    let content_type = res.content_type().map(|mime| mime.essence().to_string());
+    assert_eq!(
+        content_type.as_deref(),
+        Some("text/event-stream"),
+        "Response was not an event stream, status: {}",
+        res.status()
+    );
+
+    let mut decoder = async_sse::decode(res.take_body());
+    let mut events = Vec::with_capacity(n);
+
+    while events.len() < n {
+        match decoder.next().await {
+            Some(Ok(async_sse::Event::Message(message))) => events.push(SseEvent {
+                id: message.id().clone(),
+                event: message.name().clone(),
+                data: String::from_utf8_lossy(message.data()).into_owned(),
+            }),
+            Some(Ok(async_sse::Event::Retry(_))) => continue,
+            Some(Err(err)) => panic!(
+                "Error decoding event stream after {} event(s): {}",
+                events.len(),
+                err
+            ),
+            None => panic!(
+                "Event stream ended after {} event(s), expected {}. Events: {:?}",
+                events.len(),
+                n,
+                events
+            ),
+        }
+    }
 
-    body
+    events
 }
@@ -0,0 +1,457 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::future::timeout;
+use surf::http::headers::{self, HeaderName, HeaderValue};
+use surf::http::Method;
+use surf::middleware::{Middleware, Next};
+use surf::{Body, Client, Request, Response, StatusCode};
+
+use super::TestResult;
+use crate::openapi::OpenApi;
+use crate::{ErrorFormat, ErrorReporter};
+
+#[cfg(feature = "postgres")]
+use crate::middleware::json_error::SqlxErrorFn;
+
+/// Configuration for the client returned from [`create_client_with_options`][super::create_client_with_options].
+///
+/// ## Example:
+///
+/// ```
+/// use std::time::Duration;
+/// use preroll::test_utils::TestClientOptions;
+///
+/// let options: TestClientOptions = TestClientOptions::new()
+///     .header("Authorization", "Bearer test-token")
+///     .header("X-Tenant-Id", "tenant-1")
+///     .base_path("/api/v1")
+///     .timeout(Duration::from_secs(2));
+/// ```
+///
+/// The type parameter is the `State` of the test server, for custom middleware added via [`with`][TestClientOptions::with].
+pub struct TestClientOptions<State = ()> {
+    pub(crate) headers: Vec<(HeaderName, String)>,
+    pub(crate) base_path: Option<String>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) follow_redirects: bool,
+    pub(crate) request_id_middleware: bool,
+    pub(crate) log_middleware: bool,
+    pub(crate) json_error_middleware: bool,
+    pub(crate) error_format: Option<ErrorFormat>,
+    pub(crate) error_docs_url: Option<String>,
+    pub(crate) error_reporter: Option<Arc<dyn ErrorReporter>>,
+    pub(crate) openapi: Option<OpenApi>,
+    #[cfg(feature = "postgres")]
+    pub(crate) sqlx_errors: Option<SqlxErrorFn>,
+    pub(crate) middleware: Vec<Arc<dyn tide::Middleware<Arc<State>>>>,
+}
+
+impl<State> Default for TestClientOptions<State> {
+    fn default() -> Self {
+        Self {
+            headers: Vec::new(),
+            base_path: None,
+            timeout: None,
+            follow_redirects: false,
+            request_id_middleware: true,
+            log_middleware: true,
+            json_error_middleware: true,
+            error_format: None,
+            error_docs_url: None,
+            error_reporter: None,
+            openapi: None,
+            #[cfg(feature = "postgres")]
+            sqlx_errors: None,
+            middleware: Vec::new(),
+        }
+    }
+}
+
+impl<State> Clone for TestClientOptions<State> {
+    fn clone(&self) -> Self {
+        Self {
+            headers: self.headers.clone(),
+            base_path: self.base_path.clone(),
+            timeout: self.timeout,
+            follow_redirects: self.follow_redirects,
+            request_id_middleware: self.request_id_middleware,
+            log_middleware: self.log_middleware,
+            json_error_middleware: self.json_error_middleware,
+            error_format: self.error_format,
+            error_docs_url: self.error_docs_url.clone(),
+            error_reporter: self.error_reporter.clone(),
+            openapi: self.openapi.clone(),
+            #[cfg(feature = "postgres")]
+            sqlx_errors: self.sqlx_errors.clone(),
+            middleware: self.middleware.clone(),
+        }
+    }
+}
+
+impl<State: Send + Sync + 'static> fmt::Debug for TestClientOptions<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("TestClientOptions");
+        debug
+            .field("headers", &self.headers)
+            .field("base_path", &self.base_path)
+            .field("timeout", &self.timeout)
+            .field("follow_redirects", &self.follow_redirects)
+            .field("request_id_middleware", &self.request_id_middleware)
+            .field("log_middleware", &self.log_middleware)
+            .field("json_error_middleware", &self.json_error_middleware)
+            .field("error_format", &self.error_format)
+            .field("error_docs_url", &self.error_docs_url)
+            .field("error_reporter", &self.error_reporter.is_some())
+            .field("openapi", &self.openapi);
+        #[cfg(feature = "postgres")]
+        debug.field("sqlx_errors", &self.sqlx_errors.is_some());
+        debug
+            .field(
+                "middleware",
+                &self.middleware.iter().map(|m| m.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<State> TestClientOptions<State>
+where
+    State: Send + Sync + 'static,
+{
+    /// Options for a client which behaves exactly like the one from [`create_client`][super::create_client].
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a header with every request, unless the request already sets a header of the same name.
+    ///
+    /// Invalid header values are reported when the client is created.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<HeaderName>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Resolve request paths relative to this path prefix, e.g. `"/api/v1"`.
+    ///
+    /// Only relative paths are resolved against the prefix: `client.get("users")` requests `/api/v1/users`,
+    /// while `client.get("/monitor/ping")` still requests `/monitor/ping`.
+    #[must_use]
+    pub fn base_path(mut self, base_path: impl Into<String>) -> Self {
+        self.base_path = Some(base_path.into());
+        self
+    }
+
+    /// Fail any request which takes longer than this to respond, with a `408 Request Timeout` error.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Whether to follow redirects (up to 3), rather than return the redirect response. Defaults to `false`.
+    ///
+    /// `301`, `302`, and `303` redirects are followed with a `GET`, and `307` and `308` redirects with the same method
+    /// and body.
+    ///
+    /// Redirect responses can be checked precisely when not followed, with [`assert_redirect`][super::assert_redirect].
+    #[must_use]
+    pub fn follow_redirects(mut self, follow_redirects: bool) -> Self {
+        self.follow_redirects = follow_redirects;
+        self
+    }
+
+    /// Whether the test server includes [`RequestIdMiddleware`][crate::middleware::RequestIdMiddleware]. Defaults to `true`.
+    #[must_use]
+    pub fn request_id_middleware(mut self, enabled: bool) -> Self {
+        self.request_id_middleware = enabled;
+        self
+    }
+
+    /// Whether the test server includes [`LogMiddleware`][crate::middleware::LogMiddleware]. Defaults to `true`.
+    #[must_use]
+    pub fn log_middleware(mut self, enabled: bool) -> Self {
+        self.log_middleware = enabled;
+        self
+    }
+
+    /// Whether the test server includes [`JsonErrorMiddleware`][crate::middleware::JsonErrorMiddleware]. Defaults to `true`.
+    ///
+    /// Disable this to observe raw error responses, or to test a replacement error middleware added via [`with`][Self::with].
+    ///
+    /// ## Example:
+    ///
+    /// ```
+    /// use preroll::test_utils::{self, assert_status, TestClientOptions, TestResult};
+    /// use tide::{Request, StatusCode};
+    ///
+    /// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+    ///     server.at("teapot").get(|_req: Request<_>| async {
+    ///         Err::<String, _>(tide::Error::from_str(StatusCode::ImATeapot, "short and stout"))
+    ///     });
+    /// }
+    ///
+    /// #[async_std::main] // Would be #[async_std::test] instead.
+    /// async fn main() -> TestResult<()> {
+    ///     let options = TestClientOptions::new().json_error_middleware(false);
+    ///     let client = test_utils::create_client_with_options((), setup_routes, options).await?;
+    ///
+    ///     let mut res = client.get("/api/v1/teapot").await?;
+    ///
+    ///     // Tide's own error handling, rather than a JsonError body.
+    ///     let body = assert_status(&mut res, 418).await;
+    ///     assert_eq!(body, "");
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn json_error_middleware(mut self, enabled: bool) -> Self {
+        self.json_error_middleware = enabled;
+        self
+    }
+
+    /// The format of error responses from the test server, like [`Hooks::error_format`][crate::Hooks::error_format].
+    /// Defaults to [`ErrorFormat::Json`].
+    #[must_use]
+    pub fn error_format(mut self, format: ErrorFormat) -> Self {
+        self.error_format = Some(format);
+        self
+    }
+
+    /// Link error codes from the test server to their documentation under `url`, like
+    /// [`Hooks::error_docs_url`][crate::Hooks::error_docs_url].
+    #[must_use]
+    pub fn error_docs_url(mut self, url: impl Into<String>) -> Self {
+        self.error_docs_url = Some(url.into());
+        self
+    }
+
+    /// Report `5XX` errors from the test server to `reporter`, like [`Hooks::error_reporter`][crate::Hooks::error_reporter].
+    #[must_use]
+    pub fn error_reporter(mut self, reporter: impl ErrorReporter) -> Self {
+        self.error_reporter = Some(Arc::new(reporter));
+        self
+    }
+
+    /// Serve the OpenAPI specification of documented routes at `/openapi.json`, and its docs, like
+    /// [`Hooks::openapi`][crate::Hooks::openapi].
+    #[must_use]
+    pub fn openapi(mut self, openapi: OpenApi) -> Self {
+        self.openapi = Some(openapi);
+        self
+    }
+
+    /// Respond to database errors from the test server as mapped by `map`, like [`Hooks::sqlx_errors`][crate::Hooks::sqlx_errors].
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn sqlx_errors(
+        mut self,
+        map: impl Fn(&sqlx::Error) -> Option<tide::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.sqlx_errors = Some(Arc::new(map));
+        self
+    }
+
+    /// Add a custom middleware to the test server, after whichever of the default middleware are enabled.
+    ///
+    /// Middleware are run in the order they are added.
+    #[must_use]
+    pub fn with<M>(mut self, middleware: M) -> Self
+    where
+        M: tide::Middleware<Arc<State>>,
+    {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+}
+
+/// A custom middleware from [`TestClientOptions::with`].
+pub(crate) struct CustomMiddleware<State>(pub(crate) Arc<dyn tide::Middleware<State>>);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for CustomMiddleware<State> {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        self.0.handle(req, next).await
+    }
+
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+}
+
+/// Applies default headers and the request timeout from [`TestClientOptions`].
+#[derive(Debug)]
+pub(crate) struct TestClientMiddleware {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    timeout: Option<Duration>,
+}
+
+impl TestClientMiddleware {
+    pub(crate) fn new<State>(options: &TestClientOptions<State>) -> TestResult<Self> {
+        let headers = options
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value: HeaderValue = value.parse().map_err(|_| {
+                    surf::Error::from_str(
+                        StatusCode::InternalServerError,
+                        format!("Invalid value for default header \"{}\": {:?}", name, value),
+                    )
+                })?;
+                Ok((name.clone(), value))
+            })
+            .collect::<TestResult<_>>()?;
+
+        Ok(Self {
+            headers,
+            timeout: options.timeout,
+        })
+    }
+}
+
+#[surf::utils::async_trait]
+impl Middleware for TestClientMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        client: Client,
+        next: Next<'_>,
+    ) -> surf::Result<Response> {
+        for (name, value) in &self.headers {
+            if req.header(name).is_none() {
+                req.insert_header(name, value.clone());
+            }
+        }
+
+        match self.timeout {
+            Some(duration) => timeout(duration, next.run(req, client))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(surf::Error::from_str(
+                        StatusCode::RequestTimeout,
+                        format!("Test request did not complete within {:?}", duration),
+                    ))
+                }),
+            None => next.run(req, client).await,
+        }
+    }
+}
+
+/// Follows redirects, for [`TestClientOptions::follow_redirects`].
+///
+/// Unlike `surf::middleware::Redirect`, each request is only sent once, so that handlers see it once.
+#[derive(Debug)]
+pub(crate) struct FollowRedirects {
+    max_redirects: usize,
+}
+
+impl FollowRedirects {
+    pub(crate) fn new(max_redirects: usize) -> Self {
+        Self { max_redirects }
+    }
+}
+
+#[surf::utils::async_trait]
+impl Middleware for FollowRedirects {
+    async fn handle(
+        &self,
+        mut req: Request,
+        client: Client,
+        next: Next<'_>,
+    ) -> surf::Result<Response> {
+        // The body is kept for `307` and `308` redirects, which resend it.
+        let body = req.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+        let body = || {
+            let mut body = Body::from_bytes(bytes.clone());
+            body.set_mime(mime.clone());
+            body
+        };
+
+        let mut last = req.clone();
+        req.set_body(body());
+        let mut res = next.run(req, client.clone()).await?;
+        for _ in 0..self.max_redirects {
+            let location = match res.header(headers::LOCATION) {
+                Some(location) if res.status().is_redirection() => location.last().to_string(),
+                _ => break,
+            };
+
+            let mut redirect = last.clone();
+            let resend_body = matches!(
+                res.status(),
+                StatusCode::TemporaryRedirect | StatusCode::PermanentRedirect
+            );
+            {
+                let http_req: &mut surf::http::Request = redirect.as_mut();
+                let url = http_req.url().join(&location)?;
+                *http_req.url_mut() = url;
+                if !resend_body {
+                    http_req.set_method(Method::Get);
+                    http_req.remove_header(headers::CONTENT_TYPE);
+                }
+            }
+            last = redirect.clone();
+            if resend_body {
+                redirect.set_body(body());
+            }
+            res = next.run(redirect, client.clone()).await?;
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tide::{Redirect, Route};
+
+    use super::*;
+    use crate::test_utils::{self, assert_status};
+
+    #[async_std::test]
+    async fn follows_redirects_once() -> TestResult<()> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let options = TestClientOptions::new().follow_redirects(true);
+        let client = test_utils::create_client_with_options(
+            calls.clone(),
+            |mut server: Route<'_, Arc<Arc<AtomicUsize>>>| {
+                server
+                    .at("moved")
+                    .post(|req: tide::Request<Arc<Arc<AtomicUsize>>>| async move {
+                        req.state().fetch_add(1, Ordering::SeqCst);
+                        Ok(Redirect::temporary("/api/v1/echo"))
+                    });
+                server
+                    .at("created")
+                    .post(|req: tide::Request<Arc<Arc<AtomicUsize>>>| async move {
+                        req.state().fetch_add(1, Ordering::SeqCst);
+                        Ok(Redirect::see_other("/api/v1/echo"))
+                    });
+                server
+                    .at("echo")
+                    .all(|mut req: tide::Request<Arc<Arc<AtomicUsize>>>| async move {
+                        req.state().fetch_add(1, Ordering::SeqCst);
+                        Ok(format!("{} {}", req.method(), req.body_string().await?))
+                    });
+            },
+            options,
+        )
+        .await?;
+
+        let mut res = client.post("/api/v1/moved").body("hello").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "POST hello");
+
+        let mut res = client.post("/api/v1/created").body("hello").await?;
+        assert_eq!(assert_status(&mut res, 200).await, "GET ");
+        // Each handler ran once per request.
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use super::init_test_globals;
+
+type Sink = Arc<Mutex<Vec<CapturedSpan>>>;
+
+async_std::task_local! {
+    static CAPTURE: RefCell<Option<Sink>> = RefCell::new(None);
+}
+
+fn current_sink() -> Option<Sink> {
+    CAPTURE
+        .try_with(|capture| capture.borrow().clone())
+        .ok()
+        .flatten()
+}
+
+/// A tracing layer which retains spans in memory, for every span created from a task which is within [`capture_spans`].
+#[derive(Debug, Default)]
+pub(crate) struct SpanCaptureLayer {
+    _priv: (),
+}
+
+impl SpanCaptureLayer {
+    pub(crate) fn new() -> Self {
+        Self { _priv: () }
+    }
+}
+
+/// Where a span's captured data lives, stored in the span's extensions.
+struct CaptureSlot {
+    sink: Sink,
+    index: usize,
+}
+
+impl<S> Layer<S> for SpanCaptureLayer
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let sink = match current_sink() {
+            Some(sink) => sink,
+            None => return,
+        };
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let mut fields = BTreeMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        let captured = CapturedSpan {
+            name: span.name().to_string(),
+            target: span.metadata().target().to_string(),
+            parent: span.parent().map(|parent| parent.name().to_string()),
+            fields,
+            events: Vec::new(),
+        };
+
+        let index = {
+            let mut spans = lock(&sink);
+            spans.push(captured);
+            spans.len() - 1
+        };
+
+        span.extensions_mut().insert(CaptureSlot { sink, index });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(slot) = span.extensions().get::<CaptureSlot>() {
+                if let Some(captured) = lock(&slot.sink).get_mut(slot.index) {
+                    values.record(&mut FieldVisitor(&mut captured.fields));
+                }
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let span = match event.parent() {
+            Some(id) => ctx.span(id),
+            None => ctx.lookup_current(),
+        };
+
+        if let Some(span) = span {
+            if let Some(slot) = span.extensions().get::<CaptureSlot>() {
+                let mut fields = BTreeMap::new();
+                event.record(&mut FieldVisitor(&mut fields));
+                let message = fields.remove("message").unwrap_or_default();
+
+                if let Some(captured) = lock(&slot.sink).get_mut(slot.index) {
+                    captured.events.push(CapturedEvent { message, fields });
+                }
+            }
+        }
+    }
+}
+
+fn lock(sink: &Sink) -> std::sync::MutexGuard<'_, Vec<CapturedSpan>> {
+    sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct FieldVisitor<'f>(&'f mut BTreeMap<String, String>);
+
+impl<'f> Visit for FieldVisitor<'f> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+/// A span captured by [`capture_spans`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedSpan {
+    /// The name of the span, e.g. the function name for `#[instrument]`.
+    pub name: String,
+    /// The target of the span, usually the module path it was created in.
+    pub target: String,
+    /// The name of the parent span, if any.
+    pub parent: Option<String>,
+    /// The fields (attributes) of the span, including those recorded after creation, formatted as strings.
+    pub fields: BTreeMap<String, String>,
+    /// The events emitted within the span, in order.
+    pub events: Vec<CapturedEvent>,
+}
+
+/// An event emitted within a [`CapturedSpan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedEvent {
+    /// The message of the event.
+    pub message: String,
+    /// The fields of the event other than the message, formatted as strings.
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Criteria for matching [`CapturedSpan`]s. All specified criteria must match.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::SpanMatcher;
+///
+/// let matcher = SpanMatcher::new("handle")
+///     .event_field("path", "/api/v1/users")
+///     .event_field("status", 201);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpanMatcher {
+    name: String,
+    fields: Vec<(String, String)>,
+    event_fields: Vec<(String, String)>,
+}
+
+impl SpanMatcher {
+    /// Create a matcher which matches spans with this name.
+    #[must_use]
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            fields: Vec::new(),
+            event_fields: Vec::new(),
+        }
+    }
+
+    /// Only match spans with a field equal to this value, when formatted.
+    #[must_use]
+    pub fn field(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.fields.push((key.into(), value.to_string()));
+        self
+    }
+
+    /// Only match spans containing an event with a field equal to this value, when formatted.
+    #[must_use]
+    pub fn event_field(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.event_fields.push((key.into(), value.to_string()));
+        self
+    }
+
+    /// Whether a span satisfies all criteria of this matcher.
+    pub fn matches(&self, span: &CapturedSpan) -> bool {
+        span.name == self.name
+            && self
+                .fields
+                .iter()
+                .all(|(key, value)| span.fields.get(key) == Some(value))
+            && self.event_fields.iter().all(|(key, value)| {
+                span.events
+                    .iter()
+                    .any(|event| event.fields.get(key) == Some(value))
+            })
+    }
+}
+
+/// The spans created during a [`capture_spans`] closure, in order of creation.
+#[derive(Debug, Clone, Default)]
+pub struct CapturedSpans {
+    spans: Vec<CapturedSpan>,
+}
+
+impl CapturedSpans {
+    /// All captured spans, in order of creation.
+    pub fn spans(&self) -> &[CapturedSpan] {
+        &self.spans
+    }
+
+    /// All captured spans which match.
+    pub fn matching(&self, matcher: &SpanMatcher) -> Vec<&CapturedSpan> {
+        self.spans
+            .iter()
+            .filter(|span| matcher.matches(span))
+            .collect()
+    }
+
+    /// Assert that at least one span matches, and return the first one that does.
+    ///
+    /// On failure, all captured spans are included in the assertion message.
+    pub fn assert_span(&self, matcher: &SpanMatcher) -> &CapturedSpan {
+        self.spans
+            .iter()
+            .find(|span| matcher.matches(span))
+            .unwrap_or_else(|| {
+                panic!(
+                    "No captured span matched {:?}. Captured spans:\n{:#?}",
+                    matcher, self.spans
+                )
+            })
+    }
+
+    /// Assert that no span matches.
+    ///
+    /// On failure, all captured spans are included in the assertion message.
+    pub fn assert_no_span(&self, matcher: &SpanMatcher) {
+        if self.spans.iter().any(|span| matcher.matches(span)) {
+            panic!(
+                "A captured span unexpectedly matched {:?}. Captured spans:\n{:#?}",
+                matcher, self.spans
+            );
+        }
+    }
+}
+
+/// Capture all tracing spans, and the events within them, created while running a closure's future, for assertions.
+///
+/// Only spans created from the current task are captured, so parallel tests do not see each other's spans.
+/// This includes requests made with a client from [`create_client`][super::create_client], which are traced by
+/// [`TraceMiddleware`][crate::middleware::TraceMiddleware] just as with `preroll::main!`.
+///
+/// If some other global tracing subscriber was installed before test_utils, nothing is captured.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, capture_spans, SpanMatcher, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let spans = capture_spans(|| async {
+///         client.get("/monitor/ping").await.unwrap();
+///     })
+///     .await;
+///
+///     spans.assert_span(&SpanMatcher::new("handle").event_field("status", 200));
+///     Ok(())
+/// }
+/// ```
+pub async fn capture_spans<F, Fut>(f: F) -> CapturedSpans
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    init_test_globals();
+
+    let sink: Sink = Arc::new(Mutex::new(Vec::new()));
+    let previous = CAPTURE.with(|capture| capture.replace(Some(sink.clone())));
+
+    f().await;
+
+    CAPTURE.with(|capture| capture.replace(previous));
+
+    let spans = std::mem::take(&mut *lock(&sink));
+    CapturedSpans { spans }
+}
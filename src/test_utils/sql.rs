@@ -0,0 +1,196 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use async_std::sync::RwLock;
+use sqlx::postgres::Postgres;
+use surf::StatusCode;
+
+use super::TestResult;
+use crate::middleware::postgres::ConnectionWrapInner;
+
+/// Execute the statements of a SQL file within the shared test transaction from
+/// [`create_client_and_postgres`][super::create_client_and_postgres].
+///
+/// Statements are split on `;`, ignoring semicolons inside of quoted strings, quoted identifiers,
+/// dollar-quoted bodies (e.g. `$$ ... $$` in function definitions), and comments.
+/// They are then executed one at a time, in order.
+///
+/// If a statement fails, the returned error includes the file path, the (1-based) statement number, and the statement itself.
+///
+/// This takes the write lock of `pg_conn` for the duration of the file, and releases it when finished.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::test_utils::{self, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let (client, pg_conn) = test_utils::create_client_and_postgres((), setup_routes).await?;
+///
+///     test_utils::run_sql_file(&pg_conn, "tests/seed/base.sql").await?;
+///
+///     // ... (test cases) ...
+///
+///     Ok(())
+/// }
+/// ```
+pub async fn run_sql_file(
+    pg_conn: &Arc<RwLock<ConnectionWrapInner<Postgres>>>,
+    path: impl AsRef<Path>,
+) -> TestResult<()> {
+    let path = path.as_ref();
+
+    let sql = async_std::fs::read_to_string(path).await.map_err(|e| {
+        surf::Error::from_str(
+            StatusCode::InternalServerError,
+            format!("Could not read SQL file \"{}\": {}", path.display(), e),
+        )
+    })?;
+
+    let mut pg_conn = pg_conn.write().await;
+
+    for (index, statement) in split_statements(&sql).into_iter().enumerate() {
+        sqlx::query(statement)
+            .execute(&mut **pg_conn)
+            .await
+            .map_err(|e| {
+                surf::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!(
+                        "SQL file \"{}\", statement #{} failed: {}\nStatement:\n{}",
+                        path.display(),
+                        index + 1,
+                        e,
+                        statement
+                    ),
+                )
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Split a SQL script into its individual, trimmed, non-empty statements.
+fn split_statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let bytes = sql.as_bytes();
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' => {
+                let quote = bytes[i];
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += 1;
+                }
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < bytes.len() && !(bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/')) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'$' => {
+                // Dollar quoting, e.g. `$$` or `$body$`. Positional parameters such as `$1` are not tags.
+                let tag_end = sql[i + 1..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .map(|offset| i + 1 + offset);
+                if let Some(tag_end) = tag_end {
+                    let is_tag = bytes[tag_end] == b'$'
+                        && !bytes
+                            .get(i + 1)
+                            .map(|b| b.is_ascii_digit())
+                            .unwrap_or(false);
+                    if is_tag {
+                        let tag = &sql[i..=tag_end];
+                        match sql[tag_end + 1..].find(tag) {
+                            Some(offset) => i = tag_end + offset + tag.len(),
+                            None => i = bytes.len(),
+                        }
+                    }
+                }
+            }
+            b';' => {
+                statements.push(&sql[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    statements.push(&sql[start.min(sql.len())..]);
+
+    statements
+        .into_iter()
+        .map(str::trim)
+        .filter(|statement| !is_blank(statement))
+        .collect()
+}
+
+/// Whether a statement is empty or contains only comments.
+fn is_blank(statement: &str) -> bool {
+    let mut rest = statement.trim_start();
+    loop {
+        if rest.is_empty() {
+            return true;
+        } else if rest.starts_with("--") {
+            rest = rest
+                .find('\n')
+                .map(|i| &rest[i..])
+                .unwrap_or("")
+                .trim_start();
+        } else if rest.starts_with("/*") {
+            rest = rest
+                .find("*/")
+                .map(|i| &rest[i + 2..])
+                .unwrap_or("")
+                .trim_start();
+        } else {
+            return false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_simple_statements() {
+        assert_eq!(
+            split_statements("CREATE TABLE a (id INT);\n\nINSERT INTO a VALUES (1);\n"),
+            vec!["CREATE TABLE a (id INT)", "INSERT INTO a VALUES (1)"]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_in_strings_comments_and_dollar_quotes() {
+        let sql = r#"
+            -- seed; data
+            INSERT INTO a (name) VALUES ('semi;colon');
+            /* block; comment */
+            CREATE FUNCTION f() RETURNS INT AS $body$ SELECT 1; $body$ LANGUAGE sql;
+            SELECT "odd;name" FROM a WHERE id = $1;
+            -- trailing comment only
+        "#;
+        let statements = split_statements(sql);
+        assert_eq!(statements.len(), 3);
+        assert!(statements[0].ends_with("VALUES ('semi;colon')"));
+        assert!(statements[1].ends_with("$body$ SELECT 1; $body$ LANGUAGE sql"));
+        assert!(statements[2].starts_with("SELECT \"odd;name\""));
+    }
+}
@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use async_std::task::JoinHandle;
+use async_tungstenite::async_std::connect_async;
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+use async_tungstenite::WebSocketStream;
+use futures_util::{SinkExt, StreamExt};
+use surf::StatusCode;
+
+use super::{spawn_server, TestResult};
+use crate::VariadicRoutes;
+
+pub use async_tungstenite::tungstenite::Message;
+
+/// The default amount of time [`TestWebSocket::recv()`] will wait for a message.
+pub const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A WebSocket connection to a test server, as returned from [`create_websocket_client`].
+///
+/// All receiving methods time out, so that a misbehaving route fails the test rather than hanging it.
+#[derive(Debug)]
+pub struct TestWebSocket {
+    stream: WebSocketStream<TcpStream>,
+    recv_timeout: Duration,
+    _server: ServerGuard,
+}
+
+/// Cancels the test server's task once the connection to it is dropped, so that the server stops listening.
+#[derive(Debug)]
+struct ServerGuard(Option<JoinHandle<std::io::Result<()>>>);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        if let Some(server) = self.0.take() {
+            // Cancelling waits for the task to stop, which cannot be awaited here.
+            async_std::task::spawn(server.cancel());
+        }
+    }
+}
+
+/// Creates a test application with routes and mocks set up, and opens a WebSocket connection to `path`.
+///
+/// Unlike [`create_client`][super::create_client], this binds the test server to an ephemeral port on `127.0.0.1`
+/// via [`spawn_server`][super::spawn_server], as protocol upgrades require a real connection.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::test_utils::{self, TestResult};
+/// use preroll::test_utils::websocket::Message;
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let mut ws = test_utils::create_websocket_client((), setup_routes, "/api/v1/echo").await?;
+///
+///     ws.send_text("Hello World!").await?;
+///     assert_eq!(ws.recv().await?, Message::text("Hello World!"));
+///
+///     ws.close().await
+/// }
+/// ```
+pub async fn create_websocket_client<State>(
+    state: State,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+    path: &str,
+) -> TestResult<TestWebSocket>
+where
+    State: Send + Sync + 'static,
+{
+    let (base_url, server_handle) = spawn_server(state, setup_routes_fns).await?;
+
+    let mut url = base_url.join(path)?;
+    url.set_scheme("ws").ok();
+
+    let (stream, _response) = connect_async(url).await?;
+
+    Ok(TestWebSocket {
+        stream,
+        recv_timeout: DEFAULT_RECV_TIMEOUT,
+        _server: ServerGuard(Some(server_handle)),
+    })
+}
+
+impl TestWebSocket {
+    /// Set how long receiving methods wait for a message before failing. Defaults to [`DEFAULT_RECV_TIMEOUT`].
+    pub fn set_recv_timeout(&mut self, recv_timeout: Duration) {
+        self.recv_timeout = recv_timeout;
+    }
+
+    /// Send a message of any kind.
+    pub async fn send(&mut self, message: Message) -> TestResult<()> {
+        self.stream.send(message).await?;
+        Ok(())
+    }
+
+    /// Send a text message.
+    pub async fn send_text(&mut self, text: impl Into<String>) -> TestResult<()> {
+        self.send(Message::Text(text.into())).await
+    }
+
+    /// Send a binary message.
+    pub async fn send_binary(&mut self, data: impl Into<Vec<u8>>) -> TestResult<()> {
+        self.send(Message::Binary(data.into())).await
+    }
+
+    /// Serialize a value to JSON and send it as a text message.
+    pub async fn send_json(&mut self, json: &impl serde::Serialize) -> TestResult<()> {
+        self.send(Message::Text(serde_json::to_string(json)?)).await
+    }
+
+    /// Receive the next message, failing if none arrives within the receive timeout or the connection ends.
+    ///
+    /// Ping and Pong frames are returned like any other message.
+    pub async fn recv(&mut self) -> TestResult<Message> {
+        let recv_timeout = self.recv_timeout;
+        match timeout(recv_timeout, self.stream.next()).await {
+            Ok(Some(message)) => Ok(message?),
+            Ok(None) => Err(surf::Error::from_str(
+                StatusCode::InternalServerError,
+                "WebSocket connection ended while waiting for a message",
+            )),
+            Err(_) => Err(surf::Error::from_str(
+                StatusCode::RequestTimeout,
+                format!("No WebSocket message received within {:?}", recv_timeout),
+            )),
+        }
+    }
+
+    /// Receive the next message and expect it to be a text message.
+    pub async fn recv_text(&mut self) -> TestResult<String> {
+        match self.recv().await? {
+            Message::Text(text) => Ok(text),
+            other => Err(surf::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("Expected a WebSocket text message, got: {:?}", other),
+            )),
+        }
+    }
+
+    /// Receive the next message and parse it from JSON, accepting both text and binary messages.
+    pub async fn recv_json<T>(&mut self) -> TestResult<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        match self.recv().await? {
+            Message::Text(text) => Ok(serde_json::from_str(&text)?),
+            Message::Binary(data) => Ok(serde_json::from_slice(&data)?),
+            other => Err(surf::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("Expected a WebSocket data message, got: {:?}", other),
+            )),
+        }
+    }
+
+    /// Close the connection with a normal close frame.
+    pub async fn close(mut self) -> TestResult<()> {
+        self.stream.close(None::<CloseFrame<'static>>).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use tide::Route;
+
+    use super::*;
+    use crate::prelude::WebSocketRouteExt;
+    use crate::websocket::WebSocketConnection;
+
+    fn setup_routes(mut server: Route<'_, Arc<()>>) {
+        server
+            .at("echo")
+            .ws(|_req, mut conn: WebSocketConnection| async move {
+                while let Some(message) = conn.recv().await {
+                    conn.send(message?).await?;
+                }
+                Ok(())
+            });
+    }
+
+    #[async_std::test]
+    async fn round_trips_and_times_out() -> TestResult<()> {
+        let mut ws = create_websocket_client((), setup_routes, "/api/v1/echo").await?;
+
+        ws.send_binary(vec![1, 2, 3]).await?;
+        assert_eq!(ws.recv().await?, Message::binary(vec![1, 2, 3]));
+
+        ws.set_recv_timeout(Duration::from_millis(20));
+        let error = ws.recv().await.err();
+        assert_eq!(
+            error.map(|error| error.status()),
+            Some(StatusCode::RequestTimeout)
+        );
+
+        ws.close().await
+    }
+
+    #[async_std::test]
+    async fn stops_servers_when_dropped() {
+        struct Stopped(Arc<AtomicBool>);
+
+        impl Drop for Stopped {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let server = {
+            let stopped = Stopped(stopped.clone());
+            async_std::task::spawn(async move {
+                let _stopped = stopped;
+                std::future::pending::<std::io::Result<()>>().await
+            })
+        };
+
+        drop(ServerGuard(Some(server)));
+        for _ in 0..100 {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+}
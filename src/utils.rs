@@ -1,12 +1,62 @@
 //! Miscellaneous utilities.
 
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use lazy_static::lazy_static;
+use uuid::Uuid;
 
 lazy_static! {
     pub(crate) static ref HOSTNAME: String =
         gethostname::gethostname().to_string_lossy().to_string();
 }
 
+/// How much a wyrand generator's state advances per number.
+const WYRAND_INCREMENT: u64 = 0xa076_1d64_78bd_642f;
+
+thread_local! {
+    /// The state of this thread's random number generator, seeded from the OS.
+    static RANDOM: Cell<u64> = Cell::new(Uuid::new_v4().as_u128() as u64);
+}
+
+/// The wyrand output for an advanced `state`.
+fn wymix(state: u64) -> u64 {
+    let product = u128::from(state) * u128::from(state ^ 0xe703_7ed1_a0b4_28db);
+    (product as u64) ^ (product >> 64) as u64
+}
+
+/// The next number of a wyrand generator whose `state` is shared, e.g. by clones, or with a fixed seed.
+pub(crate) fn wyrand(state: &AtomicU64) -> u64 {
+    wymix(
+        state
+            .fetch_add(WYRAND_INCREMENT, Ordering::Relaxed)
+            .wrapping_add(WYRAND_INCREMENT),
+    )
+}
+
+/// 128 random bits, via wyrand. Fast, but not cryptographically secure.
+pub(crate) fn random() -> u128 {
+    let next = || {
+        RANDOM.with(|state| {
+            let next = state.get().wrapping_add(WYRAND_INCREMENT);
+            state.set(next);
+            wymix(next)
+        })
+    };
+    u128::from(next()) << 64 | u128::from(next())
+}
+
+/// `bits` as a number from `0.0` up to `1.0`.
+pub(crate) fn fraction(bits: u64) -> f64 {
+    // The top 53 bits, as many as the float's mantissa holds.
+    (bits >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// A random number from `0.0` up to `1.0`, e.g. to sample a fraction of requests.
+pub(crate) fn random_fraction() -> f64 {
+    fraction((random() >> 64) as u64)
+}
+
 /// This function is useful for inspecting variables that rust-analyzer has trouble extracting type information for,
 /// namely returns from awaited futures.
 ///
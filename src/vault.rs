@@ -0,0 +1,301 @@
+//! Loading secrets from [HashiCorp Vault](https://www.vaultproject.io/) into the environment, for the `"vault"` feature.
+
+use std::env;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use serde_json::{json, Value};
+use surf::{Client, RequestBuilder};
+
+use crate::setup::Result;
+
+/// A secret path from `VAULT_SECRETS`, with the prefix for the environment variables it sets.
+#[derive(Debug, PartialEq)]
+struct SecretSpec {
+    prefix: String,
+    path: String,
+}
+
+/// A renewable lease from a dynamic secret, such as database credentials.
+#[derive(Debug, PartialEq)]
+struct Lease {
+    id: String,
+    duration: Duration,
+}
+
+/// Connection details for the Vault server.
+#[derive(Debug, Clone)]
+struct Vault {
+    client: Client,
+    addr: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+impl Vault {
+    fn request(&self, builder: RequestBuilder) -> RequestBuilder {
+        let builder = builder.header("X-Vault-Token", self.token.as_str());
+        match &self.namespace {
+            Some(namespace) => builder.header("X-Vault-Namespace", namespace.as_str()),
+            None => builder,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/v1/{}",
+            self.addr.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    async fn read(&self, path: &str) -> Result<Value> {
+        let mut res = self
+            .request(self.client.get(self.url(path)))
+            .await
+            .map_err(|e| eyre!("Vault request for \"{}\" failed: {}", path, e))?;
+
+        if !res.status().is_success() {
+            let body = res.body_string().await.unwrap_or_default();
+            return Err(eyre!(
+                "Vault responded with {} for \"{}\": {}",
+                res.status(),
+                path,
+                body
+            ));
+        }
+
+        res.body_json()
+            .await
+            .map_err(|e| eyre!("Invalid Vault response for \"{}\": {}", path, e))
+    }
+
+    async fn renew(&self, lease: &Lease) -> Result<Duration> {
+        let body = json!({
+            "lease_id": lease.id,
+            "increment": lease.duration.as_secs(),
+        });
+        let mut res = self
+            .request(self.client.put(self.url("sys/leases/renew")))
+            .body(body)
+            .await
+            .map_err(|e| eyre!("Vault lease renewal failed: {}", e))?;
+
+        if !res.status().is_success() {
+            return Err(eyre!(
+                "Vault responded with {} to lease renewal",
+                res.status()
+            ));
+        }
+
+        let renewed: Value = res
+            .body_json()
+            .await
+            .map_err(|e| eyre!("Invalid Vault lease renewal response: {}", e))?;
+        Ok(renewed["lease_duration"]
+            .as_u64()
+            .map(Duration::from_secs)
+            .unwrap_or(lease.duration))
+    }
+}
+
+/// Fetch the secrets listed in `VAULT_SECRETS`, and set their keys as environment variables.
+///
+/// Does nothing if `VAULT_SECRETS` is not set.
+/// Values from Vault override any existing environment variables of the same name.
+pub(crate) async fn load_secrets() -> Result<()> {
+    let specs = match env::var("VAULT_SECRETS") {
+        Ok(secrets) => parse_specs(&secrets)?,
+        Err(_) => return Ok(()),
+    };
+
+    let vault = Vault {
+        client: Client::new(),
+        addr: env::var("VAULT_ADDR")
+            .map_err(|_| eyre!("VAULT_ADDR must be set to load VAULT_SECRETS"))?,
+        token: env::var("VAULT_TOKEN")
+            .map_err(|_| eyre!("VAULT_TOKEN must be set to load VAULT_SECRETS"))?,
+        namespace: env::var("VAULT_NAMESPACE").ok(),
+    };
+    let renew_leases = env::var("VAULT_RENEW_LEASES")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+
+    for spec in specs {
+        let secret = vault.read(&spec.path).await?;
+
+        for (key, value) in secret_values(&spec, &secret)? {
+            env::set_var(key, value);
+        }
+        log::info!("Loaded Vault secret \"{}\"", spec.path);
+
+        match secret_lease(&secret) {
+            Some(lease) if renew_leases => {
+                let vault = vault.clone();
+                async_std::task::spawn(renew_lease(vault, spec.path, lease));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep renewing a lease, at two thirds of its duration, for as long as Vault allows.
+async fn renew_lease(vault: Vault, path: String, mut lease: Lease) {
+    loop {
+        async_std::task::sleep(lease.duration * 2 / 3).await;
+
+        match vault.renew(&lease).await {
+            Ok(duration) if duration.as_secs() > 0 => {
+                log::debug!("Renewed Vault lease for \"{}\" for {:?}", path, duration);
+                lease.duration = duration;
+            }
+            Ok(_) => {
+                log::warn!("Vault lease for \"{}\" can no longer be renewed", path);
+                return;
+            }
+            Err(error) => log::error!("Vault lease for \"{}\": {}", path, error),
+        }
+    }
+}
+
+/// Parse `VAULT_SECRETS`, a comma-separated list of `path` or `PREFIX=path`.
+fn parse_specs(secrets: &str) -> Result<Vec<SecretSpec>> {
+    secrets
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .map(|spec| {
+            let (prefix, path) = match spec.split_once('=') {
+                Some((prefix, path)) => (prefix.trim(), path.trim()),
+                None => ("", spec),
+            };
+            if path.is_empty() {
+                return Err(eyre!(
+                    "Empty Vault secret path in VAULT_SECRETS: \"{}\"",
+                    spec
+                ));
+            }
+            Ok(SecretSpec {
+                prefix: prefix.to_string(),
+                path: path.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The environment variables for a secret: each key is upper-cased and prefixed.
+///
+/// Reads both KV version 1 (`data`) and KV version 2 (`data.data`) secrets.
+/// Non-string values are set as JSON.
+fn secret_values(spec: &SecretSpec, secret: &Value) -> Result<Vec<(String, String)>> {
+    let data = match &secret["data"] {
+        Value::Object(data) => match (data.get("data"), data.get("metadata")) {
+            (Some(Value::Object(kv2)), Some(_)) => kv2,
+            _ => data,
+        },
+        _ => return Err(eyre!("Vault secret \"{}\" has no data", spec.path)),
+    };
+
+    Ok(data
+        .iter()
+        .map(|(key, value)| {
+            let name = format!("{}{}", spec.prefix, key)
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect::<String>()
+                .to_ascii_uppercase();
+            let value = match value {
+                Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+            (name, value)
+        })
+        .collect())
+}
+
+fn secret_lease(secret: &Value) -> Option<Lease> {
+    let id = secret["lease_id"].as_str().filter(|id| !id.is_empty())?;
+    let duration = secret["lease_duration"].as_u64().filter(|d| *d > 0)?;
+    if secret["renewable"].as_bool() != Some(true) {
+        return None;
+    }
+
+    Some(Lease {
+        id: id.to_string(),
+        duration: Duration::from_secs(duration),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_secret_specs() -> Result<()> {
+        assert_eq!(
+            parse_specs("secret/data/service, PG=database/creds/service,")?,
+            vec![
+                SecretSpec {
+                    prefix: "".to_string(),
+                    path: "secret/data/service".to_string(),
+                },
+                SecretSpec {
+                    prefix: "PG".to_string(),
+                    path: "database/creds/service".to_string(),
+                },
+            ]
+        );
+        assert!(parse_specs("PG=").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_kv_and_dynamic_secrets() -> Result<()> {
+        let kv2 = json!({
+            "data": {
+                "data": { "api-key": "hunter2", "retries": 3 },
+                "metadata": { "version": 1 },
+            },
+        });
+        let spec = SecretSpec {
+            prefix: "".to_string(),
+            path: "secret/data/service".to_string(),
+        };
+        assert_eq!(
+            secret_values(&spec, &kv2)?,
+            vec![
+                ("API_KEY".to_string(), "hunter2".to_string()),
+                ("RETRIES".to_string(), "3".to_string()),
+            ]
+        );
+        assert_eq!(secret_lease(&kv2), None);
+
+        let creds = json!({
+            "lease_id": "database/creds/service/abc",
+            "lease_duration": 3600,
+            "renewable": true,
+            "data": { "username": "v-service", "password": "secret" },
+        });
+        let spec = SecretSpec {
+            prefix: "PG".to_string(),
+            path: "database/creds/service".to_string(),
+        };
+        assert_eq!(
+            secret_values(&spec, &creds)?,
+            vec![
+                ("PGPASSWORD".to_string(), "secret".to_string()),
+                ("PGUSERNAME".to_string(), "v-service".to_string()),
+            ]
+        );
+        assert_eq!(
+            secret_lease(&creds),
+            Some(Lease {
+                id: "database/creds/service/abc".to_string(),
+                duration: Duration::from_secs(3600),
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+//! WebSocket routes, via [`WebSocketRouteExt::ws`][crate::prelude::WebSocketRouteExt::ws].
+//!
+//! Each connection is handled by its own call to the route's handler, which gets the upgrade request and a
+//! [`WebSocketConnection`]. Per-connection state is whatever the handler keeps while it runs, or sets on the request
+//! with `Request::set_ext`.
+//!
+//! - Connections are sent a ping every [`PING_INTERVAL`], so that idle connections are kept open through proxies,
+//!   and dead ones are noticed.
+//! - When the server shuts down, connections waiting in [`recv`][WebSocketConnection::recv] are closed with
+//!   `1001 Going Away`, and `preroll::main!` waits up to [`DRAIN_TIMEOUT`] for their handlers to finish.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use preroll::websocket::WebSocketConnection;
+//! use serde::{Deserialize, Serialize};
+//! use tide::{Request, Route};
+//!
+//! #[derive(Deserialize, Serialize)]
+//! struct Chat {
+//!     text: String,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! pub fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("chat")
+//!         .ws(|_req: Request<Arc<()>>, mut conn: WebSocketConnection| async move {
+//!             let mut received = 0;
+//!             while let Some(chat) = conn.recv_json::<Chat>().await {
+//!                 received += 1;
+//!                 conn.send_json(&chat?).await?;
+//!             }
+//!             log::info!("Chat connection closed after {} messages", received);
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use async_tungstenite::tungstenite::handshake::derive_accept_key;
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use async_tungstenite::WebSocketStream;
+use futures_lite::FutureExt;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tide::http::headers::{CONNECTION, UPGRADE};
+use tide::http::upgrade::Connection;
+use tide::{http, Endpoint, Request, Response, Route, StatusCode};
+
+use crate::shutdown;
+
+pub use async_tungstenite::tungstenite::Message;
+
+/// How often connections are sent a ping.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long shutdown waits for WebSocket handlers to finish, after closing their connections.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of connections whose handlers are still running.
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+
+type Sink = SplitSink<WebSocketStream<Connection>, Message>;
+
+/// An extension trait for adding WebSocket routes.
+pub trait WebSocketRouteExt<State> {
+    /// Accept WebSocket upgrades for `GET` requests to this route, handling each connection with `handler`.
+    ///
+    /// Requests which are not WebSocket upgrades get a `426 Upgrade Required`.
+    fn ws<Handler, Fut>(&mut self, handler: Handler) -> &mut Self
+    where
+        Handler: Fn(Request<State>, WebSocketConnection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = tide::Result<()>> + Send + 'static;
+}
+
+impl<'a, State> WebSocketRouteExt<State> for Route<'a, State>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    fn ws<Handler, Fut>(&mut self, handler: Handler) -> &mut Self
+    where
+        Handler: Fn(Request<State>, WebSocketConnection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = tide::Result<()>> + Send + 'static,
+    {
+        self.get(WebSocketEndpoint {
+            handler: Arc::new(handler),
+        });
+        self
+    }
+}
+
+struct WebSocketEndpoint<Handler> {
+    handler: Arc<Handler>,
+}
+
+#[tide::utils::async_trait]
+impl<State, Handler, Fut> Endpoint<State> for WebSocketEndpoint<Handler>
+where
+    State: Clone + Send + Sync + 'static,
+    Handler: Fn(Request<State>, WebSocketConnection) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = tide::Result<()>> + Send + 'static,
+{
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let connection_upgrade = req.header(CONNECTION).is_some_and(|values| {
+            values.iter().any(|value| {
+                value
+                    .as_str()
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            })
+        });
+        let upgrade_websocket = req
+            .header(UPGRADE)
+            .is_some_and(|value| value.last().as_str().eq_ignore_ascii_case("websocket"));
+        let key = match req.header("Sec-WebSocket-Key") {
+            Some(key) if connection_upgrade && upgrade_websocket => key.last().to_string(),
+            _ => {
+                return Err(tide::Error::from_str(
+                    StatusCode::UpgradeRequired,
+                    "Expected a WebSocket upgrade request",
+                ))
+            }
+        };
+
+        let mut res = http::Response::new(StatusCode::SwitchingProtocols);
+        res.insert_header(UPGRADE, "websocket");
+        res.insert_header(CONNECTION, "Upgrade");
+        res.insert_header("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()));
+
+        let upgrade = res.recv_upgrade().await;
+        let handler = self.handler.clone();
+        async_std::task::spawn(async move {
+            let stream = match upgrade.await {
+                Some(connection) => {
+                    WebSocketStream::from_raw_socket(connection, Role::Server, None).await
+                }
+                None => return,
+            };
+
+            let path = req.url().path().to_string();
+            if let Err(error) = handler(req, WebSocketConnection::new(stream)).await {
+                log::error!("WebSocket handler for {} failed: {:?}", path, error);
+            }
+        });
+
+        Ok(Response::from(res))
+    }
+}
+
+/// An open WebSocket connection, as passed to a [`ws`][crate::prelude::WebSocketRouteExt::ws] handler.
+///
+/// Pings and pongs are handled automatically, and are never returned from [`recv`][WebSocketConnection::recv].
+#[derive(Debug)]
+pub struct WebSocketConnection {
+    sink: Arc<Mutex<Sink>>,
+    stream: SplitStream<WebSocketStream<Connection>>,
+}
+
+impl WebSocketConnection {
+    fn new(stream: WebSocketStream<Connection>) -> Self {
+        OPEN.fetch_add(1, Ordering::SeqCst);
+
+        let (sink, stream) = stream.split();
+        let sink = Arc::new(Mutex::new(sink));
+        async_std::task::spawn(ping(Arc::downgrade(&sink)));
+
+        Self { sink, stream }
+    }
+
+    /// Send a message of any kind.
+    pub async fn send(&self, message: Message) -> tide::Result<()> {
+        self.sink.lock().await.send(message).await?;
+        Ok(())
+    }
+
+    /// Send a text message.
+    pub async fn send_text(&self, text: impl Into<String>) -> tide::Result<()> {
+        self.send(Message::Text(text.into())).await
+    }
+
+    /// Send a binary message.
+    pub async fn send_binary(&self, data: impl Into<Vec<u8>>) -> tide::Result<()> {
+        self.send(Message::Binary(data.into())).await
+    }
+
+    /// Serialize a value to JSON and send it as a text message.
+    pub async fn send_json(&self, json: &impl Serialize) -> tide::Result<()> {
+        self.send(Message::Text(serde_json::to_string(json)?)).await
+    }
+
+    /// Receive the next text or binary message.
+    ///
+    /// Returns `None` once the client closes the connection, or once the server is shutting down, in which case the
+    /// connection is closed with `1001 Going Away`.
+    pub async fn recv(&mut self) -> Option<tide::Result<Message>> {
+        loop {
+            let shutdown = async {
+                shutdown::started().await;
+                None
+            };
+            match async { Some(self.stream.next().await) }
+                .race(shutdown)
+                .await
+            {
+                None => {
+                    self.close_with(CloseCode::Away, "Server shutting down")
+                        .await
+                        .ok();
+                    return None;
+                }
+                Some(None) | Some(Some(Ok(Message::Close(_)))) => return None,
+                Some(Some(Ok(Message::Ping(_)))) | Some(Some(Ok(Message::Pong(_)))) => continue,
+                Some(Some(message)) => return Some(message.map_err(Into::into)),
+            }
+        }
+    }
+
+    /// Receive the next message, deserialized from JSON.
+    ///
+    /// Messages which are not valid JSON for `T` are a `400 Bad Request` error, which does not close the connection.
+    pub async fn recv_json<T: DeserializeOwned>(&mut self) -> Option<tide::Result<T>> {
+        let message = match self.recv().await? {
+            Ok(message) => message,
+            Err(error) => return Some(Err(error)),
+        };
+        Some(
+            serde_json::from_slice(&message.into_data())
+                .map_err(|error| tide::Error::new(StatusCode::BadRequest, error)),
+        )
+    }
+
+    /// Close the connection with `1000 Normal Closure`.
+    pub async fn close(self) -> tide::Result<()> {
+        self.close_with(CloseCode::Normal, "").await
+    }
+
+    async fn close_with(&self, code: CloseCode, reason: &str) -> tide::Result<()> {
+        let mut sink = self.sink.lock().await;
+        sink.send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        })))
+        .await?;
+        sink.close().await?;
+        Ok(())
+    }
+}
+
+impl Drop for WebSocketConnection {
+    fn drop(&mut self) {
+        OPEN.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Ping the client every [`PING_INTERVAL`], until the connection is dropped or a ping fails.
+async fn ping(sink: Weak<Mutex<Sink>>) {
+    loop {
+        async_std::task::sleep(PING_INTERVAL).await;
+        let sink = match sink.upgrade() {
+            Some(sink) => sink,
+            None => break,
+        };
+        let result = sink.lock().await.send(Message::Ping(Vec::new())).await;
+        if result.is_err() {
+            break;
+        }
+    }
+}
+
+/// Wait up to [`DRAIN_TIMEOUT`] for WebSocket handlers to finish, once shutdown has closed their connections.
+pub(crate) async fn drain() {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while OPEN.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            log::warn!(
+                "{} WebSocket connection(s) still open after {:?}",
+                OPEN.load(Ordering::SeqCst),
+                DRAIN_TIMEOUT
+            );
+            break;
+        }
+        async_std::task::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_utils::{self, TestResult};
+
+    fn setup_routes(mut server: Route<'_, Arc<()>>) {
+        server
+            .at("echo")
+            .ws(|_req, mut conn: WebSocketConnection| async move {
+                while let Some(json) = conn.recv_json::<Value>().await {
+                    match json {
+                        Ok(json) => conn.send_json(&json).await?,
+                        Err(error) => conn.send_text(format!("error: {}", error)).await?,
+                    }
+                }
+                Ok(())
+            });
+    }
+
+    #[async_std::test]
+    async fn handles_websocket_connections() -> TestResult<()> {
+        let mut ws = test_utils::create_websocket_client((), setup_routes, "/api/v1/echo").await?;
+
+        ws.send_json(&json!({"hello": "world"})).await?;
+        assert_eq!(ws.recv().await?, Message::text(r#"{"hello":"world"}"#));
+
+        ws.send_text("not json").await?;
+        let error = ws.recv().await?.into_text()?;
+        assert!(error.starts_with("error: "), "{}", error);
+
+        ws.close().await
+    }
+
+    #[async_std::test]
+    async fn requires_upgrades() -> TestResult<()> {
+        let client = test_utils::create_client((), setup_routes).await?;
+
+        let res = client.get("/api/v1/echo").await?;
+        assert_eq!(res.status(), StatusCode::UpgradeRequired);
+        Ok(())
+    }
+}
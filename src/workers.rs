@@ -0,0 +1,214 @@
+//! Several acceptors sharing the server's port via `SO_REUSEPORT`, for `WORKERS`.
+//!
+//! Each worker has its own listening socket, so the kernel balances incoming connections between them,
+//! rather than all connections being accepted by one task.
+
+use std::env;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+
+use async_std::net::TcpListener;
+use cfg_if::cfg_if;
+use color_eyre::eyre::{eyre, WrapErr};
+use futures_lite::StreamExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tide::Server;
+
+use crate::setup::Result;
+
+cfg_if! {
+    if #[cfg(feature = "http2")] {
+        use crate::http2::serve_connection;
+    } else {
+        use async_std::net::TcpStream;
+    }
+}
+
+static STATS: Lazy<Mutex<Vec<WorkerStats>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// The connection count of a worker, as reported in `/monitor/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct WorkerStats {
+    worker: usize,
+    connections: u64,
+}
+
+/// The number of acceptors for the server, from `WORKERS`. Defaults to `1`.
+pub(crate) fn workers() -> Result<usize> {
+    let workers: usize = env::var("WORKERS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(1))
+        .wrap_err("WORKERS must be a positive number")?;
+
+    if workers == 0 {
+        return Err(eyre!("WORKERS must be a positive number"));
+    }
+    if workers > 1 && cfg!(not(unix)) {
+        return Err(eyre!(
+            "WORKERS requires SO_REUSEPORT, which is only available on Unix"
+        ));
+    }
+
+    Ok(workers)
+}
+
+/// Listen on `host:port` with `workers` acceptors, forever.
+pub(crate) async fn listen<State>(
+    server: Server<State>,
+    name: &str,
+    host: &str,
+    port: u16,
+    workers: usize,
+) -> Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| eyre!("{}:{} did not resolve to an address", host, port))?;
+
+    {
+        let mut stats = STATS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *stats = (0..workers)
+            .map(|worker| WorkerStats {
+                worker,
+                connections: 0,
+            })
+            .collect();
+    }
+
+    let mut acceptors = Vec::with_capacity(workers);
+    for worker in 0..workers {
+        let listener = bind(address)
+            .wrap_err_with(|| format!("Worker {} could not listen on {}", worker, address))?;
+        acceptors.push(async_std::task::spawn(accept(
+            server.clone(),
+            listener,
+            worker,
+        )));
+    }
+    log::info!(
+        "{} listening on http://{} with {} workers",
+        name,
+        address,
+        workers
+    );
+
+    // Acceptors only stop if the process does.
+    for acceptor in acceptors {
+        acceptor.await;
+    }
+
+    Ok(())
+}
+
+/// Bind a listening socket which shares its address with the other workers.
+fn bind(address: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from(std::net::TcpListener::from(socket)))
+}
+
+async fn accept<State>(server: Server<State>, listener: TcpListener, worker: usize)
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                log::error!("Worker {} failed to accept connection: {}", worker, error);
+                continue;
+            }
+        };
+
+        if let Some(stats) = STATS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get_mut(worker)
+        {
+            stats.connections += 1;
+        }
+
+        async_std::task::spawn(serve_connection(server.clone(), stream));
+    }
+}
+
+/// Serve HTTP/1.1 on an accepted connection, until it closes.
+#[cfg(not(feature = "http2"))]
+async fn serve_connection<State>(server: Server<State>, stream: TcpStream)
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let local_addr = stream.local_addr().ok();
+    let peer_addr = stream.peer_addr().ok();
+
+    let connection = async_h1::accept(stream, |mut req| async {
+        req.set_local_addr(local_addr);
+        req.set_peer_addr(peer_addr);
+        server.respond(req).await
+    });
+
+    if let Err(error) = connection.await {
+        log::debug!("Connection error: {}", error);
+    }
+}
+
+/// The connection counts of each worker, if there are several.
+pub(crate) fn worker_stats() -> Vec<WorkerStats> {
+    STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn accepts_on_every_worker() -> Result<()> {
+        let mut server = tide::new();
+        server.at("/").get(|_| async { Ok("Hello") });
+
+        // Find a free port, then release it for the workers.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        async_std::task::spawn(async move { listen(server, "Test", "127.0.0.1", port, 2).await });
+
+        let url = format!("http://127.0.0.1:{}/", port);
+        let mut connected = false;
+        for _ in 0..50_u32 {
+            match surf::get(&url).recv_string().await {
+                Ok(body) => {
+                    assert_eq!(body, "Hello");
+                    connected = true;
+                    break;
+                }
+                Err(_) => async_std::task::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+        assert!(connected);
+
+        let stats = worker_stats();
+        assert_eq!(stats.len(), 2);
+        assert!(stats.iter().map(|stats| stats.connections).sum::<u64>() >= 1);
+        Ok(())
+    }
+}